@@ -1,7 +1,13 @@
 use std::net::{Ipv4Addr, SocketAddrV4};
 
 use criterion::{black_box, criterion_group, criterion_main, Criterion};
-use matscan::scanner::targets::{Ipv4Range, Ipv4Ranges, ScanRange, ScanRanges, StaticScanRanges};
+use matscan::{
+    processing::ProcessableProtocol,
+    scanner::{
+        protocols::Minecraft,
+        targets::{Ipv4Range, Ipv4Ranges, ScanRange, ScanRanges, StaticScanRanges},
+    },
+};
 use rand::Rng;
 
 fn scan_ranges_index(scan_ranges: &StaticScanRanges, n: usize) -> SocketAddrV4 {
@@ -40,6 +46,17 @@ fn criterion_benchmark(c: &mut Criterion) {
     c.bench_function("scan_ranges_index", |b| {
         b.iter(|| scan_ranges_index(&ranges, black_box(rng.gen_range(0..ranges.count))))
     });
+
+    let garbage = b"HTTP/1.1 400 Bad Request\r\nContent-Length: 0\r\n\r\n";
+    c.bench_function("minecraft_is_plausible", |b| {
+        b.iter(|| Minecraft::is_plausible(black_box(garbage)))
+    });
+    c.bench_function("minecraft_parse_garbage", |b| {
+        b.iter(|| {
+            let data = String::from_utf8_lossy(black_box(garbage));
+            serde_json::from_str::<serde_json::Value>(&data).is_ok()
+        })
+    });
 }
 
 criterion_group!(benches, criterion_benchmark);
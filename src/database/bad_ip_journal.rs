@@ -0,0 +1,256 @@
+//! An append-only file of the bad IPs we flagged, so they aren't lost if the
+//! database is unreachable when we try to write them.
+//!
+//! Each line is `flag <ip>`, `synced <ip>`, or `unflag <ip>`. IPs that were
+//! flagged but not synced yet are written to the database again by
+//! [`BadIpJournal::sync`], which then compacts the file down to the IPs that
+//! are still pending.
+
+use std::{
+    collections::HashSet,
+    fs::{self, File, OpenOptions},
+    future::Future,
+    io::{self, Write},
+    net::Ipv4Addr,
+    path::{Path, PathBuf},
+};
+
+use parking_lot::Mutex;
+use tracing::warn;
+
+pub struct BadIpJournal {
+    path: PathBuf,
+    file: Mutex<File>,
+    /// The IPs that were flagged but haven't been written to the database yet.
+    pending: Mutex<HashSet<Ipv4Addr>>,
+}
+
+impl BadIpJournal {
+    /// Open the journal, creating it if it doesn't exist. Also returns every IP
+    /// that's currently flagged in it.
+    pub fn open(path: &Path) -> anyhow::Result<(Self, HashSet<Ipv4Addr>)> {
+        let contents = match fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => String::new(),
+            Err(err) => return Err(err.into()),
+        };
+
+        let mut bad_ips = HashSet::new();
+        let mut pending = HashSet::new();
+        for line in contents.lines() {
+            let Some((op, ip)) = line
+                .split_once(' ')
+                .and_then(|(op, ip)| Some((op, ip.parse::<Ipv4Addr>().ok()?)))
+            else {
+                // probably from a partial write when we crashed
+                warn!("Invalid line in bad ip journal: {line:?}");
+                continue;
+            };
+            match op {
+                "flag" => {
+                    bad_ips.insert(ip);
+                    pending.insert(ip);
+                }
+                "synced" => {
+                    pending.remove(&ip);
+                }
+                "unflag" => {
+                    bad_ips.remove(&ip);
+                    pending.remove(&ip);
+                }
+                _ => warn!("Invalid line in bad ip journal: {line:?}"),
+            }
+        }
+
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let journal = Self {
+            path: path.to_owned(),
+            file: Mutex::new(file),
+            pending: Mutex::new(pending),
+        };
+        Ok((journal, bad_ips))
+    }
+
+    /// Write the line and update the pending IPs. The file stays locked until
+    /// both are done so [`Self::compact`] can't drop a flag that isn't pending
+    /// yet.
+    fn append(&self, op: &str, ip: Ipv4Addr, is_pending: bool) {
+        let mut file = self.file.lock();
+        if let Err(err) = writeln!(file, "{op} {ip}") {
+            warn!("Couldn't write to bad ip journal: {err}");
+        }
+        let mut pending = self.pending.lock();
+        if is_pending {
+            pending.insert(ip);
+        } else {
+            pending.remove(&ip);
+        }
+    }
+
+    /// Record that the IP was flagged. This should be called before trying to
+    /// write it to the database.
+    pub fn flag(&self, ip: Ipv4Addr) {
+        self.append("flag", ip, true);
+    }
+
+    /// Record that the IP was written to the database.
+    pub fn synced(&self, ip: Ipv4Addr) {
+        self.append("synced", ip, false);
+    }
+
+    /// Record that the IP isn't bad anymore.
+    pub fn unflag(&self, ip: Ipv4Addr) {
+        self.append("unflag", ip, false);
+    }
+
+    /// Rewrite the file with only the IPs that are still pending, since the
+    /// synced ones are in the database now and the file would grow forever
+    /// otherwise. The new file is written next to the old one and renamed
+    /// over it, so if we crash partway through the old one is still there.
+    fn compact(&self) -> io::Result<()> {
+        let mut file = self.file.lock();
+        let pending = self.pending.lock();
+
+        let mut compacted_path = self.path.clone().into_os_string();
+        compacted_path.push(".compacting");
+        let compacted_path = PathBuf::from(compacted_path);
+        let mut compacted = File::create(&compacted_path)?;
+        for ip in pending.iter() {
+            writeln!(compacted, "flag {ip}")?;
+        }
+        compacted.sync_all()?;
+        fs::rename(&compacted_path, &self.path)?;
+
+        // the old handle still points at the file we just replaced
+        *file = OpenOptions::new().append(true).open(&self.path)?;
+        Ok(())
+    }
+
+    pub fn pending(&self) -> Vec<Ipv4Addr> {
+        self.pending.lock().iter().copied().collect()
+    }
+
+    /// Try to write every pending IP to the database with `write`, mark the
+    /// ones that succeeded as synced, and then compact the file. Returns the
+    /// number that were synced.
+    pub async fn sync<F, Fut>(&self, write: F) -> usize
+    where
+        F: Fn(Ipv4Addr) -> Fut,
+        Fut: Future<Output = anyhow::Result<()>>,
+    {
+        let mut synced = 0;
+        for ip in self.pending() {
+            match write(ip).await {
+                Ok(()) => {
+                    self.synced(ip);
+                    synced += 1;
+                }
+                Err(err) => warn!("Couldn't sync bad ip {ip}: {err}"),
+            }
+        }
+        // this also drops the ips that were synced by add_to_bad_ips since the
+        // last time
+        if let Err(err) = self.compact() {
+            warn!("Couldn't compact bad ip journal: {err}");
+        }
+        synced
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    use anyhow::bail;
+    use bson::doc;
+    use futures_util::stream;
+
+    use super::*;
+    use crate::database::bad_ips::BadIps;
+
+    #[tokio::test]
+    async fn flags_persist_through_outage() {
+        let path = std::env::temp_dir().join(format!("matscan-bad-ips-{}", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let ip = Ipv4Addr::new(1, 2, 3, 4);
+
+        let (journal, _) = BadIpJournal::open(&path).unwrap();
+        journal.flag(ip);
+
+        let database_up = AtomicBool::new(false);
+        let written = Mutex::new(Vec::new());
+        let write = |ip| {
+            let is_up = database_up.load(Ordering::Relaxed);
+            let written = &written;
+            async move {
+                if !is_up {
+                    bail!("server selection timeout");
+                }
+                written.lock().push(ip);
+                Ok(())
+            }
+        };
+
+        assert_eq!(journal.sync(write).await, 0);
+        drop(journal);
+
+        // we restarted while the database was still down
+        let (journal, bad_ips) = BadIpJournal::open(&path).unwrap();
+        assert!(bad_ips.contains(&ip));
+        assert_eq!(journal.pending(), [ip]);
+
+        database_up.store(true, Ordering::Relaxed);
+        assert_eq!(journal.sync(write).await, 1);
+        assert_eq!(*written.lock(), [ip]);
+        assert!(journal.pending().is_empty());
+        // it's in the database now so the journal doesn't need it anymore
+        assert_eq!(fs::read_to_string(&path).unwrap(), "");
+        assert!(!path
+            .with_file_name(format!("matscan-bad-ips-{}.compacting", std::process::id()))
+            .exists());
+
+        let other_ip = Ipv4Addr::new(5, 6, 7, 8);
+        journal.flag(other_ip);
+        drop(journal);
+
+        let (journal, bad_ips) = BadIpJournal::open(&path).unwrap();
+        assert_eq!(bad_ips, HashSet::from([other_ip]));
+        assert_eq!(journal.pending(), [other_ip]);
+
+        journal.unflag(other_ip);
+        let (_, bad_ips) = BadIpJournal::open(&path).unwrap();
+        assert!(bad_ips.is_empty());
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn synced_ips_are_loaded_after_a_restart() {
+        let path =
+            std::env::temp_dir().join(format!("matscan-bad-ips-restart-{}", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let ip = Ipv4Addr::new(1, 2, 3, 4);
+
+        let (journal, _) = BadIpJournal::open(&path).unwrap();
+        journal.flag(ip);
+        // like write_bad_ip, this stores the ip as a string
+        let bad_servers = Mutex::new(Vec::new());
+        let write = |ip: Ipv4Addr| {
+            bad_servers.lock().push(doc! { "ip": ip.to_string() });
+            async { Ok(()) }
+        };
+        assert_eq!(journal.sync(write).await, 1);
+        drop(journal);
+
+        // the journal was compacted so the ip is only in the collection now
+        let (_, journaled) = BadIpJournal::open(&path).unwrap();
+        assert!(journaled.is_empty());
+        let docs = bad_servers.into_inner().into_iter().map(Ok);
+        let mut bad_ips = BadIps::load(10, stream::iter(docs), journaled)
+            .await
+            .unwrap();
+        assert!(bad_ips.contains(&ip));
+
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,165 @@
+//! The bad IPs that are kept in memory for skipping servers quickly.
+//!
+//! There can be a lot of bad IPs on a hostile internet, so only the ones that
+//! were flagged or checked most recently are kept and the rest are evicted.
+//! Every bad IP is still in the `bad_servers` collection, and an evicted IP
+//! that keeps showing up will just get flagged again.
+
+use std::net::Ipv4Addr;
+
+use bson::Document;
+use futures_util::{Stream, StreamExt};
+use lru_cache::LruCache;
+
+use super::get_u32;
+
+/// The most bad IPs that are kept in memory if
+/// `database.max_bad_ips_in_memory` isn't set.
+pub const DEFAULT_MAX_BAD_IPS_IN_MEMORY: usize = 1_000_000;
+
+pub struct BadIps {
+    ips: LruCache<Ipv4Addr, ()>,
+}
+
+impl BadIps {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            ips: LruCache::new(capacity.max(1)),
+        }
+    }
+
+    /// Load the IPs from the documents in the bad servers collection, and then
+    /// the ones from the journal that might not have been written there yet.
+    /// If there are more than `capacity`, the ones loaded last are kept.
+    pub async fn load(
+        capacity: usize,
+        docs: impl Stream<Item = mongodb::error::Result<Document>>,
+        journaled: impl IntoIterator<Item = Ipv4Addr>,
+    ) -> anyhow::Result<Self> {
+        let mut bad_ips = Self::new(capacity);
+        let mut docs = std::pin::pin!(docs);
+        while let Some(doc) = docs.next().await {
+            if let Some(ip) = ip_from_doc(&doc?) {
+                bad_ips.insert(ip);
+            }
+        }
+        bad_ips.extend(journaled);
+        Ok(bad_ips)
+    }
+
+    /// Add the IP, evicting the least recently used one if we're at the cap.
+    pub fn insert(&mut self, ip: Ipv4Addr) {
+        self.ips.insert(ip, ());
+    }
+
+    /// Whether the IP is a bad IP that's in memory. This counts as using it,
+    /// so bad IPs that are still active don't get evicted.
+    pub fn contains(&mut self, ip: &Ipv4Addr) -> bool {
+        self.ips.get_mut(ip).is_some()
+    }
+
+    /// Remove the IP, returning whether it was there.
+    pub fn remove(&mut self, ip: &Ipv4Addr) -> bool {
+        self.ips.remove(ip).is_some()
+    }
+
+    pub fn len(&self) -> usize {
+        self.ips.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ips.is_empty()
+    }
+
+    /// The IPs in memory, from the least to the most recently used.
+    pub fn iter(&self) -> impl Iterator<Item = Ipv4Addr> + '_ {
+        self.ips.iter().map(|(ip, _)| *ip)
+    }
+}
+
+/// The IP of a document in the bad servers collection, which is usually
+/// stored as a string but is a number in older documents.
+pub fn ip_from_doc(doc: &Document) -> Option<Ipv4Addr> {
+    match doc.get_str("ip") {
+        Ok(ip) => ip.parse().ok(),
+        Err(_) => get_u32(doc, "ip").map(Ipv4Addr::from),
+    }
+}
+
+impl Default for BadIps {
+    fn default() -> Self {
+        Self::new(DEFAULT_MAX_BAD_IPS_IN_MEMORY)
+    }
+}
+
+impl Extend<Ipv4Addr> for BadIps {
+    fn extend<T: IntoIterator<Item = Ipv4Addr>>(&mut self, iter: T) {
+        for ip in iter {
+            self.insert(ip);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bson::doc;
+    use futures_util::stream;
+
+    use super::*;
+
+    #[test]
+    fn respects_the_cap() {
+        let mut bad_ips = BadIps::new(3);
+        bad_ips.extend((1..=10).map(|i| Ipv4Addr::new(1, 1, 1, i)));
+        assert_eq!(bad_ips.len(), 3);
+
+        bad_ips.insert(Ipv4Addr::new(1, 1, 1, 11));
+        // checking it keeps it around
+        assert!(bad_ips.contains(&Ipv4Addr::new(1, 1, 1, 9)));
+        bad_ips.insert(Ipv4Addr::new(1, 1, 1, 12));
+        assert_eq!(bad_ips.len(), 3);
+        assert_eq!(
+            bad_ips.iter().collect::<Vec<_>>(),
+            [
+                Ipv4Addr::new(1, 1, 1, 11),
+                Ipv4Addr::new(1, 1, 1, 9),
+                Ipv4Addr::new(1, 1, 1, 12),
+            ]
+        );
+        assert!(!bad_ips.contains(&Ipv4Addr::new(1, 1, 1, 1)));
+    }
+
+    #[tokio::test]
+    async fn load_respects_the_cap() {
+        // the ones we wrote are strings, and older ones are numbers
+        let docs = (1..=10).map(|i| {
+            let ip = Ipv4Addr::new(1, 1, 1, i);
+            Ok(if i % 2 == 0 {
+                doc! { "ip": ip.to_string() }
+            } else {
+                doc! { "ip": u32::from(ip) as i64 }
+            })
+        });
+        let journaled = [Ipv4Addr::new(2, 2, 2, 2)];
+        let mut bad_ips = BadIps::load(3, stream::iter(docs), journaled)
+            .await
+            .unwrap();
+
+        assert_eq!(bad_ips.len(), 3);
+        for ip in [
+            Ipv4Addr::new(1, 1, 1, 9),
+            Ipv4Addr::new(1, 1, 1, 10),
+            Ipv4Addr::new(2, 2, 2, 2),
+        ] {
+            assert!(bad_ips.contains(&ip), "{ip} wasn't loaded");
+        }
+    }
+
+    #[test]
+    fn gets_ip_in_either_format() {
+        let ip = Ipv4Addr::new(1, 2, 3, 4);
+        assert_eq!(ip_from_doc(&doc! { "ip": "1.2.3.4" }), Some(ip));
+        assert_eq!(ip_from_doc(&doc! { "ip": u32::from(ip) as i64 }), Some(ip));
+        assert_eq!(ip_from_doc(&doc! { "ip": "not an ip" }), None);
+    }
+}
@@ -0,0 +1,91 @@
+//! A bloom filter for remembering which servers were written recently with
+//! the same content, when keeping a hash for every server would take too much
+//! memory.
+
+use std::{
+    collections::hash_map::DefaultHasher,
+    hash::{Hash, Hasher},
+    time::Instant,
+};
+
+pub struct BloomFilter {
+    bits: Vec<u64>,
+    num_bits: u64,
+    num_hashes: u32,
+    /// When the filter was created, so it can be reset once it's too old.
+    pub created: Instant,
+}
+
+impl BloomFilter {
+    /// A filter that has about `false_positive_rate` false positives once
+    /// `capacity` items are in it.
+    pub fn new(capacity: usize, false_positive_rate: f64, now: Instant) -> Self {
+        let capacity = capacity.max(1) as f64;
+        let false_positive_rate = false_positive_rate.clamp(f64::MIN_POSITIVE, 0.5);
+
+        let ln2 = std::f64::consts::LN_2;
+        let num_bits = (-capacity * false_positive_rate.ln() / (ln2 * ln2)).ceil() as u64;
+        let num_bits = num_bits.max(64);
+        let num_hashes = ((num_bits as f64 / capacity) * ln2).round().max(1.) as u32;
+
+        Self {
+            bits: vec![0; num_bits.div_ceil(64) as usize],
+            num_bits,
+            num_hashes,
+            created: now,
+        }
+    }
+
+    /// The bits for the item, from two hashes combined like in "Less Hashing,
+    /// Same Performance" (Kirsch and Mitzenmacher).
+    fn bit_indexes<T: Hash>(&self, item: &T) -> impl Iterator<Item = u64> {
+        let mut hasher = DefaultHasher::new();
+        item.hash(&mut hasher);
+        let h1 = hasher.finish();
+        h1.hash(&mut hasher);
+        // odd so it never gets stuck on the same bit
+        let h2 = hasher.finish() | 1;
+
+        let num_bits = self.num_bits;
+        (0..self.num_hashes as u64).map(move |i| h1.wrapping_add(i.wrapping_mul(h2)) % num_bits)
+    }
+
+    pub fn contains<T: Hash>(&self, item: &T) -> bool {
+        self.bit_indexes(item)
+            .all(|bit| self.bits[(bit / 64) as usize] & (1 << (bit % 64)) != 0)
+    }
+
+    /// Add the item to the filter. Returns whether it was (probably) already
+    /// in it.
+    pub fn insert<T: Hash>(&mut self, item: &T) -> bool {
+        let mut was_present = true;
+        for bit in self.bit_indexes(item) {
+            let word = &mut self.bits[(bit / 64) as usize];
+            let mask = 1 << (bit % 64);
+            if *word & mask == 0 {
+                was_present = false;
+                *word |= mask;
+            }
+        }
+        was_present
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn remembers_inserted_items() {
+        let mut filter = BloomFilter::new(1000, 0.01, Instant::now());
+        for i in 0..1000 {
+            filter.insert(&i);
+        }
+        assert!((0..1000).all(|i| filter.contains(&i)));
+        assert!(filter.insert(&5));
+
+        let false_positives = (1000..11000).filter(|i| filter.contains(i)).count();
+        // about 100 are expected
+        assert!(false_positives < 300, "{false_positives} false positives");
+    }
+}
@@ -5,19 +5,233 @@
 
 use std::borrow::Borrow;
 
+use anyhow::bail;
 use async_trait::async_trait;
-use bson::{doc, oid::ObjectId, to_bson, Document};
+use bson::{doc, oid::ObjectId, to_bson, Bson, Document};
 use mongodb::options::UpdateOptions;
 use serde::Deserialize;
+use tracing::{debug, warn};
 
 /// Represents an individual update operation for the `bulk_update` function.
 #[derive(Debug, Clone)]
 pub struct BulkUpdate {
     pub query: Document,
     pub update: Document,
+    /// Fields that are set to the result of an aggregation expression, which
+    /// sees the document from before the update. If there are any, the update
+    /// is sent as a pipeline.
+    pub computed: Document,
     pub options: Option<UpdateOptions>,
 }
 
+impl BulkUpdate {
+    /// The update like it's sent to mongo, which is either the update document
+    /// or a pipeline if there are computed fields.
+    pub fn update_bson(&self) -> anyhow::Result<Bson> {
+        if self.computed.is_empty() {
+            return Ok(Bson::Document(self.update.clone()));
+        }
+        Ok(Bson::Array(
+            update_pipeline(&self.update, &self.computed)?
+                .into_iter()
+                .map(Bson::Document)
+                .collect(),
+        ))
+    }
+}
+
+/// Turn the operators into a pipeline with one `$set` stage, so they can be
+/// applied along with the computed fields. Every expression in a stage sees
+/// the document from before the stage, like the operators do.
+///
+/// `$setOnInsert` becomes setting the field if it's missing, and `$addToSet`
+/// doesn't keep the order of the array.
+fn update_pipeline(update: &Document, computed: &Document) -> anyhow::Result<Vec<Document>> {
+    let mut fields = Document::new();
+    for (operator, operator_fields) in update {
+        let Bson::Document(operator_fields) = operator_fields else {
+            bail!("invalid {operator}: {operator_fields}");
+        };
+        for (path, value) in operator_fields {
+            let current = format!("${path}");
+            let literal = |value: &Bson| doc! { "$literal": value };
+            let expression = match operator.as_str() {
+                "$set" => literal(value),
+                "$setOnInsert" => doc! { "$ifNull": [current, literal(value)] },
+                "$max" => doc! { "$max": [current, literal(value)] },
+                "$push" | "$addToSet" => {
+                    let (values, slice) = match value {
+                        Bson::Document(modifiers) if modifiers.contains_key("$each") => (
+                            modifiers.get("$each").cloned().unwrap_or_default(),
+                            modifiers.get("$slice").cloned(),
+                        ),
+                        value => (Bson::Array(vec![value.clone()]), None),
+                    };
+                    let existing = doc! { "$ifNull": [current, []] };
+                    let combined = if operator == "$push" {
+                        doc! { "$concatArrays": [existing, literal(&values)] }
+                    } else {
+                        doc! { "$setUnion": [existing, literal(&values)] }
+                    };
+                    match slice {
+                        Some(slice) => doc! { "$slice": [combined, slice] },
+                        None => combined,
+                    }
+                }
+                _ => bail!("{operator} can't be used with computed fields"),
+            };
+            fields.insert(path, expression);
+        }
+    }
+    fields.extend(computed.clone());
+    Ok(vec![doc! { "$set": fields }])
+}
+
+/// Builds the update document for a [`BulkUpdate`] out of fragments for
+/// different operators, so each part of an update can be added separately
+/// without clobbering the others.
+#[derive(Debug, Clone, Default)]
+pub struct BulkUpdateBuilder {
+    /// The operator names mapped to the fields that are updated with them.
+    operators: Document,
+    computed: Document,
+    upsert: Option<bool>,
+}
+
+impl BulkUpdateBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn set(self, fields: Document) -> Self {
+        self.operator("$set", fields)
+    }
+    pub fn push(self, fields: Document) -> Self {
+        self.operator("$push", fields)
+    }
+    pub fn add_to_set(self, fields: Document) -> Self {
+        self.operator("$addToSet", fields)
+    }
+    /// Fields that are only set when the document is inserted. They're dropped
+    /// if another operator updates the same field.
+    pub fn set_on_insert(self, fields: Document) -> Self {
+        self.operator("$setOnInsert", fields)
+    }
+    pub fn max(self, fields: Document) -> Self {
+        self.operator("$max", fields)
+    }
+    /// Fields that are set to the result of an aggregation expression, like
+    /// `{ "$add": ["$count", 1] }`. See [`BulkUpdate::computed`].
+    pub fn computed(mut self, fields: Document) -> Self {
+        self.computed.extend(fields);
+        self
+    }
+
+    /// Add the fields to the operator. If a field was already added to the same
+    /// operator, the new value replaces the old one.
+    pub fn operator(mut self, operator: &str, fields: Document) -> Self {
+        match self.operators.get_document_mut(operator) {
+            Ok(existing) => existing.extend(fields),
+            Err(_) => {
+                self.operators.insert(operator, fields);
+            }
+        }
+        self
+    }
+
+    pub fn upsert(mut self, upsert: bool) -> Self {
+        self.upsert = Some(upsert);
+        self
+    }
+
+    /// The fields that were added to the operator so far.
+    pub fn get(&self, operator: &str) -> Option<&Document> {
+        self.operators.get_document(operator).ok()
+    }
+
+    /// The fields in the `$set`, which is created if it doesn't exist yet.
+    pub fn set_fields_mut(&mut self) -> &mut Document {
+        if !self.operators.contains_key("$set") {
+            self.operators.insert("$set", Document::new());
+        }
+        self.operators
+            .get_document_mut("$set")
+            .expect("$set was just inserted")
+    }
+
+    /// Create the update. This fails if the same field (or a field and one of
+    /// its subfields) is updated by more than one operator, since mongo would
+    /// reject the whole update.
+    pub fn build(self, query: Document) -> anyhow::Result<BulkUpdate> {
+        let mut update = Document::new();
+        if !self.computed.is_empty() {
+            update.insert("computed", self.computed.clone());
+        }
+        let mut set_on_insert = None;
+        for (operator, fields) in self.operators {
+            let bson::Bson::Document(fields) = fields else {
+                continue;
+            };
+            if fields.is_empty() {
+                continue;
+            }
+            if operator == "$setOnInsert" {
+                set_on_insert = Some(fields);
+                continue;
+            }
+            for (other_operator, other_fields) in &update {
+                let other_fields = other_fields.as_document().expect("operators are documents");
+                for path in fields.keys() {
+                    if let Some(other_path) = other_fields.keys().find(|p| paths_conflict(path, p))
+                    {
+                        bail!("{operator} {path} conflicts with {other_operator} {other_path}");
+                    }
+                }
+            }
+            update.insert(operator, fields);
+        }
+
+        if let Some(set_on_insert) = set_on_insert {
+            let set_on_insert = set_on_insert
+                .into_iter()
+                .filter(|(path, _)| {
+                    update.values().all(|fields| {
+                        fields
+                            .as_document()
+                            .expect("operators are documents")
+                            .keys()
+                            .all(|other_path| !paths_conflict(path, other_path))
+                    })
+                })
+                .collect::<Document>();
+            if !set_on_insert.is_empty() {
+                update.insert("$setOnInsert", set_on_insert);
+            }
+        }
+        // it was only there to check for conflicts
+        update.remove("computed");
+
+        Ok(BulkUpdate {
+            query,
+            update,
+            computed: self.computed,
+            options: self
+                .upsert
+                .map(|upsert| UpdateOptions::builder().upsert(upsert).build()),
+        })
+    }
+}
+
+/// Whether the paths are the same, or one is inside of the other.
+fn paths_conflict(a: &str, b: &str) -> bool {
+    let is_inside = |inner: &str, outer: &str| {
+        inner
+            .strip_prefix(outer)
+            .is_some_and(|rest| rest.starts_with('.'))
+    };
+    a == b || is_inside(a, b) || is_inside(b, a)
+}
+
 /// Result of a `bulk_update` operation.
 #[derive(Debug, Deserialize)]
 pub struct BulkUpdateResult {
@@ -27,6 +241,10 @@ pub struct BulkUpdateResult {
     pub nb_modified: u64,
     #[serde(default)]
     pub upserted: Vec<BulkUpdateUpsertResult>,
+    /// The updates that failed. The rest of the updates are still applied
+    /// unless the write was ordered.
+    #[serde(rename = "writeErrors", default)]
+    pub write_errors: Vec<BulkUpdateWriteError>,
 }
 
 /// Individual update result of a `bulk_update` operation.
@@ -38,15 +256,34 @@ pub struct BulkUpdateUpsertResult {
     pub id: ObjectId,
 }
 
+/// The code mongo uses for duplicate key errors.
+pub const DUPLICATE_KEY: i32 = 11000;
+
+/// An update in a `bulk_update` operation that failed, like because of a
+/// duplicate key or a document that got too big.
+#[derive(Debug, Deserialize)]
+pub struct BulkUpdateWriteError {
+    /// The index of the update in the updates that were passed.
+    pub index: u64,
+    pub code: i32,
+    pub errmsg: String,
+}
+
 #[async_trait]
 pub trait CollectionExt {
+    /// Apply the updates in one command. If it's `ordered`, the first update
+    /// that fails stops the rest from being applied, otherwise the failed ones
+    /// are logged and returned in [`BulkUpdateResult::write_errors`].
+    /// Duplicate key errors are expected from upserts that race with another
+    /// write, so they're only logged at the debug level.
     async fn bulk_update<V, U>(
         &self,
         db: &mongodb::Database,
         updates: V,
+        ordered: bool,
     ) -> anyhow::Result<BulkUpdateResult>
     where
-        V: 'async_trait + Send + Sync + Borrow<Vec<U>>,
+        V: 'async_trait + Send + Sync + Borrow<[U]>,
         U: 'async_trait + Send + Sync + Borrow<BulkUpdate>;
 }
 
@@ -56,46 +293,203 @@ impl<M: Send + Sync> CollectionExt for mongodb::Collection<M> {
         &self,
         db: &mongodb::Database,
         updates: V,
+        ordered: bool,
     ) -> anyhow::Result<BulkUpdateResult>
     where
-        V: 'async_trait + Send + Sync + Borrow<Vec<U>>,
+        V: 'async_trait + Send + Sync + Borrow<[U]>,
         U: 'async_trait + Send + Sync + Borrow<BulkUpdate>,
     {
         let updates = updates.borrow();
-        let mut update_docs = Vec::with_capacity(updates.len());
-        for u in updates {
-            let u = u.borrow();
-            let mut doc = doc! {
-                "q": &u.query,
-                "u": &u.update,
-                "multi": false,
-            };
-            if let Some(options) = &u.options {
-                if let Some(upsert) = &options.upsert {
-                    doc.insert("upsert", upsert);
-                }
-                if let Some(collation) = &options.collation {
-                    doc.insert("collation", to_bson(collation)?);
-                }
-                if let Some(array_filters) = &options.array_filters {
-                    doc.insert("arrayFilters", array_filters);
-                }
-                if let Some(hint) = &options.hint {
-                    doc.insert("hint", to_bson(hint)?);
-                }
-            }
-            update_docs.push(doc);
-        }
-        let mut command = doc! {
-            "update": self.name(),
-            "updates": update_docs,
-            "ordered": false,
-        };
+        let mut command = update_command(self.name(), updates, ordered)?;
         if let Some(ref write_concern) = self.write_concern() {
             command.insert("writeConcern", to_bson(write_concern)?);
         }
         let res = db.run_command(command).await?;
 
-        Ok(bson::from_document(res)?)
+        let result: BulkUpdateResult = bson::from_document(res)?;
+        for error in &result.write_errors {
+            let query = updates.get(error.index as usize).map(|u| &u.borrow().query);
+            if error.code == DUPLICATE_KEY {
+                debug!(
+                    "Upsert in bulk write to {} hit a duplicate key (query: {query:?})",
+                    self.name()
+                );
+                continue;
+            }
+            warn!(
+                "Update in bulk write to {} failed with code {}: {} (query: {query:?})",
+                self.name(),
+                error.code,
+                error.errmsg
+            );
+        }
+        Ok(result)
+    }
+}
+
+/// The `update` command for the updates. If it's not `ordered`, a failed
+/// update doesn't stop the ones after it from being applied.
+fn update_command<U: Borrow<BulkUpdate>>(
+    collection_name: &str,
+    updates: &[U],
+    ordered: bool,
+) -> anyhow::Result<Document> {
+    let mut update_docs = Vec::with_capacity(updates.len());
+    for u in updates {
+        let u = u.borrow();
+        let mut doc = doc! {
+            "q": &u.query,
+            "u": u.update_bson()?,
+            "multi": false,
+        };
+        if let Some(options) = &u.options {
+            if let Some(upsert) = &options.upsert {
+                doc.insert("upsert", upsert);
+            }
+            if let Some(collation) = &options.collation {
+                doc.insert("collation", to_bson(collation)?);
+            }
+            if let Some(array_filters) = &options.array_filters {
+                doc.insert("arrayFilters", array_filters);
+            }
+            if let Some(hint) = &options.hint {
+                doc.insert("hint", to_bson(hint)?);
+            }
+        }
+        update_docs.push(doc);
+    }
+    Ok(doc! {
+        "update": collection_name,
+        "updates": update_docs,
+        "ordered": ordered,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn operators_are_merged() {
+        let update = BulkUpdateBuilder::new()
+            .set(doc! { "description": "a", "onlinePlayers": 1 })
+            .push(doc! { "motdHashes": 123_i64 })
+            .set(doc! { "onlinePlayers": 2, "players.abc": { "name": "Notch" } })
+            .set_on_insert(doc! { "firstSeen": 1, "description": "b" })
+            .max(doc! { "maxPlayersEver": 20 })
+            .upsert(true)
+            .build(doc! { "_id": 1 })
+            .unwrap();
+
+        assert_eq!(
+            update.update,
+            doc! {
+                "$set": { "description": "a", "onlinePlayers": 2, "players.abc": { "name": "Notch" } },
+                "$push": { "motdHashes": 123_i64 },
+                "$max": { "maxPlayersEver": 20 },
+                // description is dropped since $set already sets it
+                "$setOnInsert": { "firstSeen": 1 },
+            }
+        );
+        assert_eq!(update.options.unwrap().upsert, Some(true));
+    }
+
+    #[test]
+    fn computed_fields_make_a_pipeline() {
+        let update = BulkUpdateBuilder::new()
+            .set(doc! { "description": "$notAField" })
+            .push(doc! { "motdHashes": { "$each": [123_i64], "$slice": -10 } })
+            .computed(doc! { "pings": { "$add": ["$pings", 1] } })
+            .build(doc! { "_id": 1 })
+            .unwrap();
+        assert_eq!(
+            update.update_bson().unwrap(),
+            bson::bson!([{
+                "$set": {
+                    // values are literals so they're not mistaken for fields
+                    "description": { "$literal": "$notAField" },
+                    "motdHashes": {
+                        "$slice": [
+                            { "$concatArrays": [{ "$ifNull": ["$motdHashes", []] }, { "$literal": [123_i64] }] },
+                            -10
+                        ]
+                    },
+                    "pings": { "$add": ["$pings", 1] },
+                }
+            }])
+        );
+
+        // a computed field can't be updated by an operator too
+        let update = BulkUpdateBuilder::new()
+            .set(doc! { "pings": 1 })
+            .computed(doc! { "pings": { "$add": ["$pings", 1] } })
+            .build(doc! {});
+        assert!(update.is_err());
+
+        // the update document is used as-is without them
+        let update = BulkUpdateBuilder::new()
+            .set(doc! { "a": 1 })
+            .build(doc! {})
+            .unwrap();
+        assert_eq!(
+            update.update_bson().unwrap(),
+            bson::bson!({ "$set": { "a": 1 } })
+        );
+    }
+
+    #[test]
+    fn failed_update_is_isolated() {
+        let updates = [
+            BulkUpdateBuilder::new()
+                .set(doc! { "a": 1 })
+                .build(doc! { "_id": 1 })
+                .unwrap(),
+            // _id can't be changed
+            BulkUpdateBuilder::new()
+                .set(doc! { "_id": 5 })
+                .build(doc! { "_id": 2 })
+                .unwrap(),
+            BulkUpdateBuilder::new()
+                .set(doc! { "a": 3 })
+                .build(doc! { "_id": 3 })
+                .unwrap(),
+        ];
+        let command = update_command("servers", &updates, false).unwrap();
+        assert_eq!(command.get_bool("ordered"), Ok(false));
+        assert_eq!(command.get_array("updates").unwrap().len(), 3);
+
+        // what mongo responds with when the second update fails
+        let result: BulkUpdateResult = bson::from_document(doc! {
+            "n": 2,
+            "nModified": 2,
+            "writeErrors": [
+                { "index": 1, "code": 66, "errmsg": "Performing an update on the path '_id' would modify the immutable field '_id'" },
+            ],
+            "ok": 1.0,
+        })
+        .unwrap();
+        assert_eq!(result.nb_modified, 2);
+        assert_eq!(result.write_errors.len(), 1);
+        assert_eq!(result.write_errors[0].index, 1);
+        assert_eq!(result.write_errors[0].code, 66);
+
+        let command = update_command("servers", &updates, true).unwrap();
+        assert_eq!(command.get_bool("ordered"), Ok(true));
+    }
+
+    #[test]
+    fn conflicting_operators_are_rejected() {
+        let update = BulkUpdateBuilder::new()
+            .set(doc! { "players.abc": { "name": "Notch" } })
+            .add_to_set(doc! { "players": "abc" })
+            .build(doc! {});
+        assert!(update.is_err());
+
+        // fields that only share a prefix are fine
+        let update = BulkUpdateBuilder::new()
+            .set(doc! { "playersOnline": 1 })
+            .add_to_set(doc! { "players": "abc" })
+            .build(doc! {});
+        assert!(update.is_ok());
     }
 }
@@ -0,0 +1,936 @@
+//! A [`ServerStore`] that keeps the servers in memory, for testing the queries
+//! that modes make and the writes that processing makes without a real Mongo.
+//!
+//! Only the parts of the aggregation pipeline and the update operators that
+//! matscan uses are supported, and anything else is an error so a test can't
+//! silently pass with a query that wasn't evaluated.
+
+use std::{cmp::Ordering, collections::HashMap, net::Ipv4Addr};
+
+use anyhow::bail;
+use async_trait::async_trait;
+use bson::{oid::ObjectId, Bson, Document};
+use futures_util::{
+    stream::{self, BoxStream},
+    StreamExt,
+};
+use parking_lot::Mutex;
+
+use super::{
+    bulk_write::{
+        BulkUpdate, BulkUpdateResult, BulkUpdateUpsertResult, BulkUpdateWriteError, DUPLICATE_KEY,
+    },
+    store::{bad_ip_filter, RescanCheckpoint, ServerStore},
+};
+
+pub struct MemoryStore {
+    servers: Mutex<Vec<Document>>,
+    bad_servers: Mutex<Vec<Document>>,
+    schema_version: Mutex<i32>,
+    checkpoints: Mutex<HashMap<String, RescanCheckpoint>>,
+}
+
+impl MemoryStore {
+    pub fn new(servers: Vec<Document>) -> Self {
+        Self {
+            servers: Mutex::new(servers),
+            bad_servers: Mutex::new(Vec::new()),
+            schema_version: Mutex::new(0),
+            checkpoints: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn with_bad_servers(self, bad_servers: Vec<Document>) -> Self {
+        *self.bad_servers.lock() = bad_servers;
+        self
+    }
+
+    /// The servers, with the updates that were written so far.
+    pub fn servers(&self) -> Vec<Document> {
+        self.servers.lock().clone()
+    }
+
+    pub fn bad_servers(&self) -> Vec<Document> {
+        self.bad_servers.lock().clone()
+    }
+}
+
+#[async_trait]
+impl ServerStore for MemoryStore {
+    async fn aggregate_servers(
+        &self,
+        pipeline: Vec<Document>,
+    ) -> anyhow::Result<BoxStream<'_, anyhow::Result<Document>>> {
+        let docs = run_pipeline(self.servers(), &pipeline)?;
+        Ok(stream::iter(docs.into_iter().map(Ok)).boxed())
+    }
+
+    /// Like mongo, servers that are upserted can't have the same `ip` and
+    /// `port` as another server because of the unique index.
+    async fn bulk_update_servers(
+        &self,
+        updates: &[BulkUpdate],
+        ordered: bool,
+    ) -> anyhow::Result<BulkUpdateResult> {
+        let mut servers = self.servers.lock();
+        let mut result = BulkUpdateResult {
+            nb_affected: 0,
+            nb_modified: 0,
+            upserted: Vec::new(),
+            write_errors: Vec::new(),
+        };
+        for (index, update) in updates.iter().enumerate() {
+            let mut matching = None;
+            for (i, server) in servers.iter().enumerate() {
+                if matches(server, &update.query)? {
+                    matching = Some(i);
+                    break;
+                }
+            }
+            if let Some(i) = matching {
+                let updated = apply(&servers[i], update, false)?;
+                result.nb_affected += 1;
+                if updated != servers[i] {
+                    result.nb_modified += 1;
+                    servers[i] = updated;
+                }
+                continue;
+            }
+
+            let upsert = update.options.as_ref().and_then(|o| o.upsert);
+            if upsert != Some(true) {
+                continue;
+            }
+            let id = ObjectId::new();
+            let mut inserted = equality_fields(&update.query)?;
+            inserted.insert("_id", id);
+            let inserted = apply(&inserted, update, true)?;
+            if servers.iter().any(|server| same_address(server, &inserted)) {
+                result.write_errors.push(BulkUpdateWriteError {
+                    index: index as u64,
+                    code: DUPLICATE_KEY,
+                    errmsg: "E11000 duplicate key error".to_string(),
+                });
+                if ordered {
+                    break;
+                }
+                continue;
+            }
+            servers.push(inserted);
+            result.nb_affected += 1;
+            result.upserted.push(BulkUpdateUpsertResult {
+                index: index as u64,
+                id,
+            });
+        }
+        Ok(result)
+    }
+
+    async fn aggregate_bad_servers(
+        &self,
+        pipeline: Vec<Document>,
+    ) -> anyhow::Result<BoxStream<'_, anyhow::Result<Document>>> {
+        let docs = run_pipeline(self.bad_servers(), &pipeline)?;
+        Ok(stream::iter(docs.into_iter().map(Ok)).boxed())
+    }
+
+    async fn delete_bad_ip(&self, ip: Ipv4Addr) -> anyhow::Result<()> {
+        let filter = bad_ip_filter(ip);
+        let mut bad_servers = self.bad_servers.lock();
+        let mut kept = Vec::new();
+        for doc in bad_servers.iter() {
+            if !matches(doc, &filter)? {
+                kept.push(doc.clone());
+            }
+        }
+        *bad_servers = kept;
+        Ok(())
+    }
+
+    async fn load_schema_version(&self) -> anyhow::Result<i32> {
+        Ok(*self.schema_version.lock())
+    }
+
+    async fn save_schema_version(&self, version: i32) -> anyhow::Result<()> {
+        *self.schema_version.lock() = version;
+        Ok(())
+    }
+
+    async fn load_rescan_checkpoint(&self, name: &str) -> anyhow::Result<Option<RescanCheckpoint>> {
+        Ok(self.checkpoints.lock().get(name).cloned())
+    }
+
+    async fn save_rescan_checkpoint(
+        &self,
+        name: &str,
+        checkpoint: Option<&RescanCheckpoint>,
+    ) -> anyhow::Result<()> {
+        let mut checkpoints = self.checkpoints.lock();
+        match checkpoint {
+            Some(checkpoint) => checkpoints.insert(name.to_string(), checkpoint.clone()),
+            None => checkpoints.remove(name),
+        };
+        Ok(())
+    }
+}
+
+fn run_pipeline(mut docs: Vec<Document>, pipeline: &[Document]) -> anyhow::Result<Vec<Document>> {
+    for stage in pipeline {
+        let Some((operator, arg)) = stage.iter().next() else {
+            bail!("empty pipeline stage");
+        };
+        match (operator.as_str(), arg) {
+            ("$match", Bson::Document(filter)) => {
+                let mut matching = Vec::new();
+                for doc in docs {
+                    if matches(&doc, filter)? {
+                        matching.push(doc);
+                    }
+                }
+                docs = matching;
+            }
+            ("$sort", Bson::Document(sort)) => {
+                docs.sort_by(|a, b| {
+                    for (key, direction) in sort {
+                        let ordering = compare_sort(get_path(a, key), get_path(b, key));
+                        let ordering = if as_f64(direction) == Some(-1.) {
+                            ordering.reverse()
+                        } else {
+                            ordering
+                        };
+                        if ordering != Ordering::Equal {
+                            return ordering;
+                        }
+                    }
+                    Ordering::Equal
+                });
+            }
+            ("$limit", limit) => {
+                let Some(limit) = as_f64(limit) else {
+                    bail!("invalid $limit: {limit}");
+                };
+                docs.truncate(limit as usize);
+            }
+            // not random, but tests shouldn't depend on the order anyways
+            ("$sample", Bson::Document(sample)) => {
+                let Some(size) = sample.get("size").and_then(as_f64) else {
+                    bail!("invalid $sample: {sample}");
+                };
+                docs.truncate(size as usize);
+            }
+            ("$addFields", Bson::Document(fields)) => {
+                for doc in &mut docs {
+                    for (key, expression) in fields {
+                        let value = evaluate(doc, expression)?;
+                        doc.insert(key, value);
+                    }
+                }
+            }
+            ("$project", Bson::Document(projection)) => {
+                docs = docs.iter().map(|doc| project(doc, projection)).collect();
+            }
+            ("$unwind", Bson::String(path)) => {
+                let Some(key) = path.strip_prefix('$') else {
+                    bail!("invalid $unwind: {path}");
+                };
+                docs = docs.into_iter().flat_map(|doc| unwind(doc, key)).collect();
+            }
+            _ => bail!("unsupported pipeline stage: {stage}"),
+        }
+    }
+    Ok(docs)
+}
+
+fn matches(doc: &Document, filter: &Document) -> anyhow::Result<bool> {
+    for (key, condition) in filter {
+        let is_match = match (key.as_str(), condition) {
+            ("$or" | "$and", Bson::Array(filters)) => {
+                let mut results = Vec::new();
+                for filter in filters {
+                    let Bson::Document(filter) = filter else {
+                        bail!("invalid {key}: {condition}");
+                    };
+                    results.push(matches(doc, filter)?);
+                }
+                if key == "$or" {
+                    results.into_iter().any(|r| r)
+                } else {
+                    results.into_iter().all(|r| r)
+                }
+            }
+            ("$expr", expression) => is_truthy(&evaluate(doc, expression)?),
+            (operator, _) if operator.starts_with('$') => {
+                bail!("unsupported query operator: {operator}")
+            }
+            _ => matches_condition(get_path(doc, key), condition)?,
+        };
+        if !is_match {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+fn matches_condition(value: Option<&Bson>, condition: &Bson) -> anyhow::Result<bool> {
+    let operators = match condition {
+        Bson::Document(operators) if operators.keys().all(|k| k.starts_with('$')) => operators,
+        _ => return Ok(value.is_some_and(|value| equals(value, condition))),
+    };
+    for (operator, arg) in operators {
+        let compared = value.and_then(|value| compare(value, arg));
+        let is_match = match operator.as_str() {
+            "$eq" => value.is_some_and(|value| equals(value, arg)),
+            "$ne" => !value.is_some_and(|value| equals(value, arg)),
+            "$gt" => compared == Some(Ordering::Greater),
+            "$gte" => matches!(compared, Some(Ordering::Greater | Ordering::Equal)),
+            "$lt" => compared == Some(Ordering::Less),
+            "$lte" => matches!(compared, Some(Ordering::Less | Ordering::Equal)),
+            "$exists" => value.is_some() == arg.as_bool().unwrap_or(true),
+            "$regex" => {
+                let Bson::String(pattern) = arg else {
+                    bail!("invalid $regex: {arg}");
+                };
+                let pattern = match operators.get_str("$options") {
+                    Ok(options) if options.contains('i') => format!("(?i){pattern}"),
+                    _ => pattern.clone(),
+                };
+                let regex = regex::Regex::new(&pattern)?;
+                value
+                    .and_then(|value| value.as_str())
+                    .is_some_and(|value| regex.is_match(value))
+            }
+            // used by $regex
+            "$options" => true,
+            "$in" => {
+                let Bson::Array(options) = arg else {
+                    bail!("invalid $in: {arg}");
+                };
+                value.is_some_and(|value| options.iter().any(|option| equals(value, option)))
+            }
+            _ => bail!("unsupported query operator: {operator}"),
+        };
+        if !is_match {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+/// Get a field by its path, like `fingerprint.timestamp`.
+fn get_path<'a>(doc: &'a Document, path: &str) -> Option<&'a Bson> {
+    let mut parts = path.split('.');
+    let mut value = doc.get(parts.next()?)?;
+    for part in parts {
+        value = value.as_document()?.get(part)?;
+    }
+    Some(value)
+}
+
+fn as_f64(value: &Bson) -> Option<f64> {
+    match value {
+        Bson::Int32(n) => Some(*n as f64),
+        Bson::Int64(n) => Some(*n as f64),
+        Bson::Double(n) => Some(*n),
+        _ => None,
+    }
+}
+
+/// Compare values of the same type like mongo does. Values of different types
+/// can't be compared, so they never match a comparison.
+fn compare(a: &Bson, b: &Bson) -> Option<Ordering> {
+    match (a, b) {
+        (Bson::DateTime(a), Bson::DateTime(b)) => Some(a.cmp(b)),
+        (Bson::String(a), Bson::String(b)) => Some(a.cmp(b)),
+        (Bson::Boolean(a), Bson::Boolean(b)) => Some(a.cmp(b)),
+        (Bson::ObjectId(a), Bson::ObjectId(b)) => Some(a.cmp(b)),
+        _ => as_f64(a)?.partial_cmp(&as_f64(b)?),
+    }
+}
+
+fn equals(a: &Bson, b: &Bson) -> bool {
+    compare(a, b).map_or(a == b, |ordering| ordering == Ordering::Equal)
+}
+
+/// Missing fields are sorted before everything else, like in mongo.
+fn compare_sort(a: Option<&Bson>, b: Option<&Bson>) -> Ordering {
+    match (a, b) {
+        (Some(a), Some(b)) => compare(a, b).unwrap_or(Ordering::Equal),
+        (a, b) => a.is_some().cmp(&b.is_some()),
+    }
+}
+
+fn is_truthy(value: &Bson) -> bool {
+    match value {
+        Bson::Boolean(b) => *b,
+        Bson::Null => false,
+        value => as_f64(value) != Some(0.),
+    }
+}
+
+/// Evaluate an aggregation expression. Only the arithmetic, comparison and
+/// array operators that matscan uses are supported, and their arguments
+/// always have to be in an array.
+fn evaluate(doc: &Document, expression: &Bson) -> anyhow::Result<Bson> {
+    let (operator, args) = match expression {
+        Bson::String(path) if path.starts_with('$') => {
+            return Ok(get_path(doc, &path[1..]).cloned().unwrap_or(Bson::Null));
+        }
+        Bson::Document(expression) => match expression.iter().next() {
+            Some((operator, literal)) if operator == "$literal" && expression.len() == 1 => {
+                return Ok(literal.clone());
+            }
+            Some((operator, Bson::Array(args))) if expression.len() == 1 => (operator, args),
+            _ => bail!("unsupported expression: {expression}"),
+        },
+        literal => return Ok(literal.clone()),
+    };
+    let args = args
+        .iter()
+        .map(|arg| evaluate(doc, arg))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    match operator.as_str() {
+        "$ifNull" => {
+            return Ok(args
+                .into_iter()
+                .find(|arg| *arg != Bson::Null)
+                .unwrap_or(Bson::Null));
+        }
+        "$eq" | "$ne" | "$lt" | "$lte" | "$gt" | "$gte" => {
+            let [a, b] = args.as_slice() else {
+                bail!("{operator} needs two arguments");
+            };
+            let ordering = compare(a, b);
+            return Ok(Bson::Boolean(match operator.as_str() {
+                "$eq" => equals(a, b),
+                "$ne" => !equals(a, b),
+                "$lt" => ordering == Some(Ordering::Less),
+                "$lte" => matches!(ordering, Some(Ordering::Less | Ordering::Equal)),
+                "$gt" => ordering == Some(Ordering::Greater),
+                _ => matches!(ordering, Some(Ordering::Greater | Ordering::Equal)),
+            }));
+        }
+        // these ignore nulls, so a missing field doesn't win
+        "$max" | "$min" => {
+            let wanted = if operator == "$max" {
+                Ordering::Greater
+            } else {
+                Ordering::Less
+            };
+            let mut result = Bson::Null;
+            for arg in args.into_iter().filter(|arg| *arg != Bson::Null) {
+                if result == Bson::Null || compare(&arg, &result) == Some(wanted) {
+                    result = arg;
+                }
+            }
+            return Ok(result);
+        }
+        "$cond" => {
+            let [condition, then, otherwise] = args.as_slice() else {
+                bail!("$cond needs three arguments");
+            };
+            return Ok(if is_truthy(condition) {
+                then
+            } else {
+                otherwise
+            }
+            .clone());
+        }
+        "$concatArrays" | "$setUnion" => {
+            let mut result = Vec::new();
+            for arg in args {
+                // like in mongo, a null instead of an array makes the result null
+                let Bson::Array(array) = arg else {
+                    return Ok(Bson::Null);
+                };
+                for value in array {
+                    if operator == "$concatArrays" || !result.iter().any(|v| equals(v, &value)) {
+                        result.push(value);
+                    }
+                }
+            }
+            return Ok(Bson::Array(result));
+        }
+        "$size" => {
+            let [Bson::Array(array)] = args.as_slice() else {
+                bail!("$size needs an array");
+            };
+            return Ok(Bson::Int32(array.len() as i32));
+        }
+        "$slice" => {
+            let [Bson::Array(array), n] = args.as_slice() else {
+                bail!("$slice needs an array and a number");
+            };
+            let n = as_f64(n).unwrap_or_default();
+            let mut array = array.clone();
+            if n < 0. {
+                let keep = -n as usize;
+                if array.len() > keep {
+                    array.drain(..array.len() - keep);
+                }
+            } else {
+                array.truncate(n as usize);
+            }
+            return Ok(Bson::Array(array));
+        }
+        "$toLong" => {
+            let [value] = args.as_slice() else {
+                bail!("$toLong needs one argument");
+            };
+            return Ok(as_f64(value).map_or(Bson::Null, |n| Bson::Int64(n as i64)));
+        }
+        _ => {}
+    }
+
+    // adding to a date or subtracting from one gives a date, like in mongo
+    let date_count = args
+        .iter()
+        .filter(|arg| matches!(arg, Bson::DateTime(_)))
+        .count();
+    let is_date = match operator.as_str() {
+        "$add" => date_count == 1,
+        "$subtract" => date_count == 1 && matches!(args.first(), Some(Bson::DateTime(_))),
+        _ => false,
+    };
+    // dates are used as milliseconds, like when mongo subtracts them
+    let numbers = args
+        .iter()
+        .map(|arg| match arg {
+            Bson::DateTime(date) => Some(date.timestamp_millis() as f64),
+            arg => as_f64(arg),
+        })
+        .collect::<Option<Vec<_>>>();
+    let Some(numbers) = numbers else {
+        return Ok(Bson::Null);
+    };
+    let result = match (operator.as_str(), numbers.as_slice()) {
+        ("$add", numbers) => numbers.iter().sum(),
+        ("$multiply", numbers) => numbers.iter().product(),
+        ("$subtract", [a, b]) => a - b,
+        ("$divide", [a, b]) => a / b,
+        // mongo rounds halves to even
+        ("$round", [n]) => n.round_ties_even(),
+        ("$round", [n, places]) => {
+            let factor = 10_f64.powi(*places as i32);
+            (n * factor).round_ties_even() / factor
+        }
+        _ => bail!("unsupported expression operator: {operator}"),
+    };
+    if is_date {
+        return Ok(Bson::DateTime(bson::DateTime::from_millis(result as i64)));
+    }
+    Ok(Bson::Double(result))
+}
+
+/// The fields that an upsert inserts from the query, which are the ones it
+/// checks for equality.
+fn equality_fields(query: &Document) -> anyhow::Result<Document> {
+    let mut fields = Document::new();
+    for (key, condition) in query {
+        if key.starts_with('$') {
+            continue;
+        }
+        let value = match condition {
+            Bson::Document(operators) if operators.keys().all(|k| k.starts_with('$')) => {
+                match operators.get("$eq") {
+                    Some(value) => value.clone(),
+                    None => continue,
+                }
+            }
+            value => value.clone(),
+        };
+        set_path(&mut fields, key, value)?;
+    }
+    Ok(fields)
+}
+
+fn same_address(a: &Document, b: &Document) -> bool {
+    ["ip", "port"]
+        .iter()
+        .all(|key| match (a.get(key), b.get(key)) {
+            (Some(a), Some(b)) => equals(a, b),
+            _ => false,
+        })
+}
+
+/// Apply the update to a copy of the document, either as a pipeline if it has
+/// computed fields or as an update document.
+fn apply(doc: &Document, update: &BulkUpdate, inserting: bool) -> anyhow::Result<Document> {
+    match update.update_bson()? {
+        Bson::Array(pipeline) => apply_pipeline_update(doc, &pipeline),
+        _ => apply_update(doc, &update.update, inserting),
+    }
+}
+
+/// Apply an update pipeline to a copy of the document. Only `$set` stages are
+/// supported, and every expression in a stage sees the document from before
+/// the stage.
+fn apply_pipeline_update(doc: &Document, pipeline: &[Bson]) -> anyhow::Result<Document> {
+    let mut doc = doc.clone();
+    for stage in pipeline {
+        let Some(fields) = stage
+            .as_document()
+            .and_then(|stage| stage.get_document("$set").ok())
+        else {
+            bail!("unsupported update stage: {stage}");
+        };
+        let mut updated = doc.clone();
+        for (path, expression) in fields {
+            let value = evaluate(&doc, expression)?;
+            // mongo doesn't add a field that's set to a missing one, which we
+            // can't tell apart from null
+            if value == Bson::Null && get_path(&updated, path).is_none() {
+                continue;
+            }
+            set_path(&mut updated, path, value)?;
+        }
+        doc = updated;
+    }
+    Ok(doc)
+}
+
+/// Apply an update document with operators like `$set` and `$push` to a copy
+/// of the document. `$setOnInsert` is only applied if it's `inserting`.
+fn apply_update(doc: &Document, update: &Document, inserting: bool) -> anyhow::Result<Document> {
+    let mut doc = doc.clone();
+    for (operator, fields) in update {
+        let Bson::Document(fields) = fields else {
+            bail!("invalid {operator}: {fields}");
+        };
+        for (path, value) in fields {
+            match operator.as_str() {
+                "$set" => set_path(&mut doc, path, value.clone())?,
+                "$setOnInsert" => {
+                    if inserting {
+                        set_path(&mut doc, path, value.clone())?;
+                    }
+                }
+                "$unset" => remove_path(&mut doc, path),
+                "$max" => match get_path(&doc, path) {
+                    None | Some(Bson::Null) => set_path(&mut doc, path, value.clone())?,
+                    Some(current) => match compare(value, current) {
+                        Some(Ordering::Greater) => set_path(&mut doc, path, value.clone())?,
+                        Some(_) => {}
+                        None => bail!("can't compare {value} with {current} for $max"),
+                    },
+                },
+                "$push" | "$addToSet" => {
+                    let (values, slice) = match value {
+                        Bson::Document(modifiers) if modifiers.contains_key("$each") => (
+                            modifiers.get_array("$each")?.clone(),
+                            modifiers.get("$slice").and_then(as_f64),
+                        ),
+                        value => (vec![value.clone()], None),
+                    };
+                    let mut array = match get_path(&doc, path) {
+                        None | Some(Bson::Null) => Vec::new(),
+                        Some(Bson::Array(array)) => array.clone(),
+                        Some(other) => bail!("{operator} to {path}, which isn't an array: {other}"),
+                    };
+                    for value in values {
+                        if operator == "$push" || !array.iter().any(|v| equals(v, &value)) {
+                            array.push(value);
+                        }
+                    }
+                    match slice {
+                        Some(slice) if slice < 0. => {
+                            let keep = -slice as usize;
+                            if array.len() > keep {
+                                array.drain(..array.len() - keep);
+                            }
+                        }
+                        Some(slice) => array.truncate(slice as usize),
+                        None => {}
+                    }
+                    set_path(&mut doc, path, Bson::Array(array))?;
+                }
+                _ => bail!("unsupported update operator: {operator}"),
+            }
+        }
+    }
+    Ok(doc)
+}
+
+/// Set a field by its path, creating the documents it's in if they don't
+/// exist.
+fn set_path(doc: &mut Document, path: &str, value: Bson) -> anyhow::Result<()> {
+    let Some((first, rest)) = path.split_once('.') else {
+        doc.insert(path, value);
+        return Ok(());
+    };
+    if !doc.contains_key(first) {
+        doc.insert(first, Document::new());
+    }
+    match doc.get_mut(first) {
+        Some(Bson::Document(inner)) => set_path(inner, rest, value),
+        _ => bail!("can't set {path} since {first} isn't a document"),
+    }
+}
+
+fn remove_path(doc: &mut Document, path: &str) {
+    match path.split_once('.') {
+        None => {
+            doc.remove(path);
+        }
+        Some((first, rest)) => {
+            if let Some(Bson::Document(inner)) = doc.get_mut(first) {
+                remove_path(inner, rest);
+            }
+        }
+    }
+}
+
+/// An inclusion projection, where fields can also be set to another field
+/// (like `"$players.v.lastSeen"`) or to `{ "$objectToArray": "$field" }`.
+/// `_id` is kept unless it's excluded.
+fn project(doc: &Document, projection: &Document) -> Document {
+    let mut projected = Document::new();
+    if !projection.contains_key("_id") {
+        if let Some(id) = doc.get("_id") {
+            projected.insert("_id", id.clone());
+        }
+    }
+    for (key, spec) in projection {
+        let value = match spec {
+            Bson::String(path) => path
+                .strip_prefix('$')
+                .and_then(|path| get_path(doc, path))
+                .cloned(),
+            Bson::Document(expression) => expression
+                .get_str("$objectToArray")
+                .ok()
+                .and_then(|path| get_path(doc, path.strip_prefix('$')?))
+                .and_then(|value| value.as_document())
+                .map(|object| {
+                    Bson::Array(
+                        object
+                            .iter()
+                            .map(|(k, v)| Bson::Document(bson::doc! { "k": k, "v": v.clone() }))
+                            .collect(),
+                    )
+                }),
+            _ if as_f64(spec).map_or(spec.as_bool() == Some(true), |n| n != 0.) => {
+                doc.get(key).cloned()
+            }
+            _ => None,
+        };
+        if let Some(value) = value {
+            projected.insert(key, value);
+        }
+    }
+    projected
+}
+
+/// One document for each element of the array at `key`. Documents where it's
+/// missing or empty are dropped.
+fn unwind(doc: Document, key: &str) -> Vec<Document> {
+    let Some(Bson::Array(values)) = doc.get(key) else {
+        return Vec::new();
+    };
+    values
+        .iter()
+        .map(|value| {
+            let mut doc = doc.clone();
+            doc.insert(key, value.clone());
+            doc
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use bson::doc;
+
+    use crate::database::bulk_write::BulkUpdateBuilder;
+
+    use super::*;
+
+    #[test]
+    fn pipeline_stages() {
+        let docs = vec![
+            doc! { "_id": 1, "port": 25565, "lastSeen": bson::DateTime::from_millis(3) },
+            doc! { "_id": 2, "port": 25566, "lastSeen": bson::DateTime::from_millis(1) },
+            doc! { "_id": 3, "port": 25565 },
+            doc! { "_id": 4, "port": 25565, "lastSeen": bson::DateTime::from_millis(2) },
+        ];
+        let result = run_pipeline(
+            docs,
+            &[
+                doc! { "$match": { "$or": [{ "port": 25565 }, { "lastSeen": { "$lt": bson::DateTime::from_millis(2) } }] } },
+                doc! { "$sort": { "lastSeen": 1 } },
+                doc! { "$project": { "port": 1, "_id": 0 } },
+                doc! { "$limit": 3_i64 },
+            ],
+        )
+        .unwrap();
+        // the one without lastSeen is first
+        assert_eq!(
+            result,
+            [
+                doc! { "port": 25565 },
+                doc! { "port": 25566 },
+                doc! { "port": 25565 }
+            ]
+        );
+
+        assert!(run_pipeline(vec![], &[doc! { "$match": { "$where": "true" } }]).is_err());
+    }
+
+    #[test]
+    fn expr_compares_computed_values() {
+        let docs = vec![
+            doc! { "_id": 1, "lastSeen": bson::DateTime::from_millis(1_000), "interval": 5 },
+            doc! { "_id": 2, "lastSeen": bson::DateTime::from_millis(8_000), "interval": 5 },
+            doc! { "_id": 3, "lastSeen": bson::DateTime::from_millis(8_000) },
+        ];
+        let now = bson::DateTime::from_millis(10_000);
+        // lastSeen < now - (interval ?? 1) seconds
+        let filter = doc! {
+            "$expr": {
+                "$lt": [
+                    "$lastSeen",
+                    { "$subtract": [now, { "$multiply": [{ "$ifNull": ["$interval", 1] }, 1000] }] }
+                ]
+            }
+        };
+        let result = run_pipeline(docs, &[doc! { "$match": filter }]).unwrap();
+        assert_eq!(
+            result
+                .iter()
+                .map(|d| d.get_i32("_id").unwrap())
+                .collect::<Vec<_>>(),
+            [1, 3]
+        );
+    }
+
+    #[tokio::test]
+    async fn applies_bulk_updates() {
+        let store = MemoryStore::new(vec![
+            doc! { "ip": "1.1.1.1", "port": 25565, "motdHashes": [1, 2], "maxPlayers": 20 },
+        ]);
+        let upsert = Some(
+            mongodb::options::UpdateOptions::builder()
+                .upsert(true)
+                .build(),
+        );
+        let update = |ip: &str, options| BulkUpdate {
+            query: doc! { "ip": { "$eq": ip }, "port": { "$eq": 25565 } },
+            update: doc! {
+                "$set": { "description": "hi", "players.abc.name": "Notch" },
+                "$push": { "motdHashes": { "$each": [3], "$slice": -2 } },
+                "$max": { "maxPlayers": 10 },
+                "$setOnInsert": { "firstSeen": 1 },
+            },
+            computed: Document::new(),
+            options,
+        };
+        let result = store
+            .bulk_update_servers(
+                &[
+                    update("1.1.1.1", upsert.clone()),
+                    // not upserted, so nothing happens
+                    update("2.2.2.2", None),
+                    update("3.3.3.3", upsert.clone()),
+                ],
+                false,
+            )
+            .await
+            .unwrap();
+        assert_eq!((result.nb_affected, result.nb_modified), (2, 1));
+        assert_eq!(result.upserted.len(), 1);
+        assert_eq!(result.upserted[0].index, 2);
+
+        let mut servers = store.servers();
+        servers[1].remove("_id");
+        assert_eq!(
+            servers,
+            [
+                doc! {
+                    "ip": "1.1.1.1",
+                    "port": 25565,
+                    "motdHashes": [2, 3],
+                    "maxPlayers": 20,
+                    "description": "hi",
+                    "players": { "abc": { "name": "Notch" } },
+                },
+                doc! {
+                    "ip": "3.3.3.3",
+                    "port": 25565,
+                    "description": "hi",
+                    "players": { "abc": { "name": "Notch" } },
+                    "motdHashes": [3],
+                    "maxPlayers": 10,
+                    "firstSeen": 1,
+                },
+            ]
+        );
+
+        // an upsert that doesn't match because of another condition can't insert a
+        // second server with the same address
+        let mut duplicate = update("3.3.3.3", upsert);
+        duplicate.query.insert("lastSeen", doc! { "$exists": true });
+        let result = store
+            .bulk_update_servers(&[duplicate], false)
+            .await
+            .unwrap();
+        assert_eq!(result.write_errors.len(), 1);
+        assert_eq!(result.write_errors[0].code, DUPLICATE_KEY);
+        assert_eq!(store.servers().len(), 2);
+    }
+    #[tokio::test]
+    async fn computed_fields_make_a_pipeline() {
+        let store = MemoryStore::new(vec![
+            doc! { "ip": "1.1.1.1", "port": 25565, "motdHashes": [1, 2], "maxPlayers": 20, "firstSeen": 0, "pings": 5 },
+        ]);
+        let update = |ip: &str| {
+            BulkUpdateBuilder::new()
+                .set(doc! { "description": "hi", "players.abc.name": "Notch" })
+                .push(doc! { "motdHashes": { "$each": [3], "$slice": -2 } })
+                .max(doc! { "maxPlayers": 10 })
+                .set_on_insert(doc! { "firstSeen": 1 })
+                .computed(doc! { "pings": { "$add": [{ "$ifNull": ["$pings", 0] }, 1] } })
+                .upsert(true)
+                .build(doc! { "ip": { "$eq": ip }, "port": { "$eq": 25565 } })
+                .unwrap()
+        };
+        assert!(update("1.1.1.1")
+            .update_bson()
+            .unwrap()
+            .as_array()
+            .is_some());
+
+        let result = store
+            .bulk_update_servers(&[update("1.1.1.1"), update("3.3.3.3")], false)
+            .await
+            .unwrap();
+        assert_eq!((result.nb_affected, result.nb_modified), (2, 1));
+
+        let mut servers = store.servers();
+        servers[1].remove("_id");
+        assert_eq!(
+            servers,
+            [
+                doc! {
+                    "ip": "1.1.1.1",
+                    "port": 25565,
+                    "motdHashes": [2, 3],
+                    "maxPlayers": 20,
+                    "firstSeen": 0,
+                    "pings": 6.,
+                    "description": "hi",
+                    "players": { "abc": { "name": "Notch" } },
+                },
+                doc! {
+                    "ip": "3.3.3.3",
+                    "port": 25565,
+                    "description": "hi",
+                    "players": { "abc": { "name": "Notch" } },
+                    "motdHashes": [3],
+                    "maxPlayers": 10,
+                    "firstSeen": 1,
+                    "pings": 1.,
+                },
+            ]
+        );
+    }
+}
@@ -0,0 +1,208 @@
+//! One-time migrations for the database schema.
+//!
+//! The version of the last migration that was applied is stored in the `meta`
+//! collection, so each migration only ever runs once even if matscan is
+//! restarted.
+
+use bson::doc;
+use futures_util::future::BoxFuture;
+use mongodb::{error::ErrorKind, options::IndexOptions, IndexModel};
+
+use super::{store::ServerStore, Database};
+
+pub struct Migration {
+    /// The version this migration brings the database to. Versions must be
+    /// unique and in ascending order in [`MIGRATIONS`].
+    pub version: i32,
+    pub name: &'static str,
+    pub run: fn(&Database) -> BoxFuture<'_, anyhow::Result<()>>,
+}
+
+pub const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        name: "create ip+port and lastSeen indexes",
+        run: create_indexes,
+    },
+    Migration {
+        version: 2,
+        name: "merge duplicate servers and make the ip+port index unique",
+        run: make_ip_port_index_unique,
+    },
+    Migration {
+        version: 3,
+        name: "create versionNameResolved index",
+        run: create_resolved_version_index,
+    },
+];
+
+/// Run every migration that hasn't been applied yet, recording each one as it
+/// finishes so a crash partway through doesn't re-run the earlier ones.
+pub async fn run(database: &Database) -> anyhow::Result<()> {
+    run_migrations(database, database, MIGRATIONS).await
+}
+
+/// [`run`], with the store that the schema version is kept in and the
+/// migrations passed in so it can be tested.
+async fn run_migrations(
+    database: &Database,
+    store: &dyn ServerStore,
+    migrations: &[Migration],
+) -> anyhow::Result<()> {
+    let current_version = store.load_schema_version().await?;
+
+    for migration in pending_migrations(migrations, current_version) {
+        println!("running migration {} ({})", migration.version, migration.name);
+        (migration.run)(database).await?;
+        store.save_schema_version(migration.version).await?;
+    }
+
+    Ok(())
+}
+
+/// Get the migrations that haven't been applied to a database that's at
+/// `current_version`.
+pub fn pending_migrations(migrations: &[Migration], current_version: i32) -> &[Migration] {
+    let first_pending = migrations
+        .iter()
+        .position(|m| m.version > current_version)
+        .unwrap_or(migrations.len());
+    &migrations[first_pending..]
+}
+
+fn create_indexes(database: &Database) -> BoxFuture<'_, anyhow::Result<()>> {
+    Box::pin(async move {
+        database
+            .servers_coll()
+            .create_indexes([
+                IndexModel::builder().keys(doc! { "ip": 1, "port": 1 }).build(),
+                IndexModel::builder().keys(doc! { "lastSeen": 1 }).build(),
+            ])
+            .await?;
+        Ok(())
+    })
+}
+
+fn make_ip_port_index_unique(database: &Database) -> BoxFuture<'_, anyhow::Result<()>> {
+    Box::pin(async move {
+        crate::modes::dedup::merge(database).await?;
+
+        // an index with the same keys but different options can't be created
+        match database.servers_coll().drop_index("ip_1_port_1").await {
+            Ok(()) => {}
+            // it was never created, like if the index was made by hand with a
+            // different name
+            Err(err) if is_not_found(&err) => {}
+            Err(err) => return Err(err.into()),
+        }
+        database
+            .servers_coll()
+            .create_index(
+                IndexModel::builder()
+                    .keys(doc! { "ip": 1, "port": 1 })
+                    .options(IndexOptions::builder().unique(true).build())
+                    .build(),
+            )
+            .await?;
+        Ok(())
+    })
+}
+
+/// Whether the error is from an index or collection that doesn't exist.
+fn is_not_found(err: &mongodb::error::Error) -> bool {
+    const NAMESPACE_NOT_FOUND: i32 = 26;
+    const INDEX_NOT_FOUND: i32 = 27;
+    matches!(
+        &*err.kind,
+        ErrorKind::Command(err) if [NAMESPACE_NOT_FOUND, INDEX_NOT_FOUND].contains(&err.code)
+    )
+}
+
+fn create_resolved_version_index(database: &Database) -> BoxFuture<'_, anyhow::Result<()>> {
+    Box::pin(async move {
+        database
+            .servers_coll()
+            .create_index(
+                IndexModel::builder()
+                    .keys(doc! { "versionNameResolved": 1 })
+                    .build(),
+            )
+            .await?;
+        Ok(())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use crate::{config::DatabaseConfig, database::memory::MemoryStore};
+
+    use super::*;
+
+    fn noop(_: &Database) -> BoxFuture<'_, anyhow::Result<()>> {
+        Box::pin(async { Ok(()) })
+    }
+
+    static COUNTED_RUNS: AtomicUsize = AtomicUsize::new(0);
+
+    fn counted(_: &Database) -> BoxFuture<'_, anyhow::Result<()>> {
+        COUNTED_RUNS.fetch_add(1, Ordering::Relaxed);
+        Box::pin(async { Ok(()) })
+    }
+
+    const TEST_MIGRATIONS: &[Migration] = &[
+        Migration {
+            version: 1,
+            name: "first",
+            run: noop,
+        },
+        Migration {
+            version: 2,
+            name: "second",
+            run: noop,
+        },
+    ];
+
+    #[test]
+    fn runs_pending_migrations_once() {
+        let pending = pending_migrations(TEST_MIGRATIONS, 0);
+        assert_eq!(
+            pending.iter().map(|m| m.version).collect::<Vec<_>>(),
+            vec![1, 2]
+        );
+
+        // the runner records the last version it applied, so the second startup
+        // sees version 2 and skips everything
+        let current_version = pending.last().unwrap().version;
+        assert!(pending_migrations(TEST_MIGRATIONS, current_version).is_empty());
+    }
+
+    #[tokio::test]
+    async fn migration_runs_on_first_startup_only() {
+        let database = Database::unconnected(DatabaseConfig::default()).await;
+        let store = MemoryStore::new(vec![]);
+        let migrations = &[Migration {
+            version: 1,
+            name: "counted",
+            run: counted,
+        }];
+
+        run_migrations(&database, &store, migrations).await.unwrap();
+        assert_eq!(COUNTED_RUNS.load(Ordering::Relaxed), 1);
+        assert_eq!(store.load_schema_version().await.unwrap(), 1);
+
+        // restarting doesn't run it again
+        run_migrations(&database, &store, migrations).await.unwrap();
+        assert_eq!(COUNTED_RUNS.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn skips_already_applied_migrations() {
+        let pending = pending_migrations(TEST_MIGRATIONS, 1);
+        assert_eq!(
+            pending.iter().map(|m| m.version).collect::<Vec<_>>(),
+            vec![2]
+        );
+    }
+}
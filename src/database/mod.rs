@@ -1,32 +1,72 @@
+pub mod bad_ip_journal;
+pub mod bad_ips;
+pub mod bloom_filter;
 pub mod bulk_write;
+#[cfg(test)]
+pub mod memory;
+pub mod migrations;
+pub mod store;
+pub mod write_limiter;
 
 use std::{
-    collections::HashSet,
+    collections::{HashMap, HashSet},
     net::{Ipv4Addr, SocketAddrV4},
     sync::Arc,
     time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
+use anyhow::bail;
 use bson::{Bson, Document};
 use futures_util::{stream::StreamExt, TryStreamExt};
 use lru_cache::LruCache;
 use mongodb::{
     bson::doc,
-    options::{ClientOptions, Hint},
+    options::{self, ClientOptions, Hint, SelectionCriteria},
     Client, Collection,
 };
 use parking_lot::Mutex;
+use serde::Deserialize;
+
+use crate::config::{DatabaseConfig, SkipUnchangedConfig};
+
+use self::{
+    bad_ip_journal::BadIpJournal,
+    bad_ips::{BadIps, DEFAULT_MAX_BAD_IPS_IN_MEMORY},
+    bloom_filter::BloomFilter,
+    bulk_write::BulkUpdate,
+    store::ServerStore,
+    write_limiter::WriteLimiter,
+};
 
 #[derive(Clone)]
 pub struct Database {
     pub client: Client,
     pub shared: Arc<Mutex<DatabaseSharedData>>,
+    pub config: Arc<DatabaseConfig>,
+    pub write_limiter: Arc<WriteLimiter>,
+    pub bad_ip_journal: Option<Arc<BadIpJournal>>,
+    /// The port that's kept when an IP is bad, from
+    /// `bad_ip.protect_default_port`.
+    pub protected_port: Option<u16>,
 }
 
 pub struct DatabaseSharedData {
     pub ips_with_same_hash: LruCache<Ipv4Addr, (CachedIpHash, HashSet<u16>)>,
+    /// Like `ips_with_same_hash`, but for the hash of the favicon.
+    pub ips_with_same_favicon: LruCache<Ipv4Addr, (CachedIpHash, HashSet<u16>)>,
+
+    pub bad_ips: BadIps,
 
-    pub bad_ips: HashSet<Ipv4Addr>,
+    /// The hash of the content we last wrote for each server, used for only
+    /// updating the timestamps when nothing else changed.
+    pub content_hashes: LruCache<SocketAddrV4, u64>,
+    /// The servers and content hashes that were written since the filter was
+    /// last reset, when `skip_unchanged` is enabled. It's created on the first
+    /// write.
+    pub unchanged_filter: Option<BloomFilter>,
+
+    /// The last time a write to the servers collection succeeded.
+    pub last_write: Option<Instant>,
 
     cached_all_servers_30_days: Option<(Vec<SocketAddrV4>, Instant)>,
     cached_all_servers_365_days: Option<(Vec<SocketAddrV4>, Instant)>,
@@ -38,47 +78,311 @@ pub struct CachedIpHash {
     /// IP with a different hash.
     pub count: Option<usize>,
     pub hash: u64,
+    /// The last time `count` was incremented.
+    pub last_increment: Instant,
 }
 
-impl Database {
-    pub async fn connect(mongodb_uri: &str) -> anyhow::Result<Self> {
-        let client_options = ClientOptions::parse(mongodb_uri).await?;
+/// The number of servers on the same IP that have to have the same hash for it
+/// to be considered a bad IP.
+pub const BAD_IP_SAME_HASH_THRESHOLD: usize = 100;
 
-        let client = Client::with_options(client_options)?;
+/// The number of servers on the same IP that have to have the same favicon
+/// for them to be flagged with `faviconReused`.
+pub const FAVICON_REUSE_THRESHOLD: usize = 100;
 
-        // ping the database to make sure it's up
-        client
-            .database("cope_new")
-            .run_command(doc! {"ping": 1})
-            .await?;
+/// The most ports that are remembered for each IP when counting servers with
+/// the same hash. Past this only the count goes up, which can count a port
+/// twice, but by then the count is over both thresholds anyways.
+pub const MAX_CHECKED_PORTS_PER_IP: usize = 1024;
 
-        // download bad ips
-        let mut bad_ips = HashSet::new();
-        let mut cursor = client
-            .database("cope_new")
-            .collection::<Document>("bad_servers")
-            .find(doc! {})
-            .await
-            .expect("bad servers collection must exist");
-        while let Some(Ok(doc)) = cursor.next().await {
-            if let Some(addr) = get_u32(&doc, "ip") {
-                bad_ips.insert(Ipv4Addr::from(addr));
+/// Which members of the replica set the queries for getting servers to rescan
+/// or fingerprint read from. Writes always go to the primary.
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum ReadPreference {
+    #[default]
+    Primary,
+    SecondaryPreferred,
+    Nearest,
+}
+
+impl ReadPreference {
+    pub fn selection_criteria(self) -> SelectionCriteria {
+        let read_preference = match self {
+            ReadPreference::Primary => options::ReadPreference::Primary,
+            ReadPreference::SecondaryPreferred => {
+                options::ReadPreference::SecondaryPreferred { options: None }
+            }
+            ReadPreference::Nearest => options::ReadPreference::Nearest { options: None },
+        };
+        SelectionCriteria::ReadPreference(read_preference)
+    }
+}
+
+impl DatabaseSharedData {
+    pub fn new(bad_ips: BadIps) -> Self {
+        Self {
+            // arbitrary capacity (2^20)
+            ips_with_same_hash: LruCache::new(1048576),
+            ips_with_same_favicon: LruCache::new(1048576),
+
+            bad_ips,
+
+            // arbitrary capacity (2^20)
+            content_hashes: LruCache::new(1048576),
+            unchanged_filter: None,
+
+            last_write: None,
+
+            cached_all_servers_30_days: None,
+            cached_all_servers_365_days: None,
+            cached_all_servers_new: None,
+        }
+    }
+
+    /// Record that the server at `target` has the given hash. Returns true if
+    /// enough servers on the IP have had the same hash for it to be a bad IP.
+    ///
+    /// If it's been longer than `decay_window` since we last counted a server
+    /// with the same hash on this IP, the count starts over so noise from a
+    /// long time ago can't add up to a false positive.
+    pub fn record_ip_hash(
+        &mut self,
+        target: &SocketAddrV4,
+        hash: u64,
+        now: Instant,
+        decay_window: Duration,
+    ) -> bool {
+        record_same_hash(
+            &mut self.ips_with_same_hash,
+            target,
+            hash,
+            now,
+            decay_window,
+            BAD_IP_SAME_HASH_THRESHOLD,
+        )
+    }
+
+    /// Whether every port we've scanned on the IP so far had the same hash.
+    /// It's only true once there's more than one port to compare, and is a
+    /// softer signal than the IP being flagged as bad.
+    pub fn is_ip_uniform(&mut self, ip: &Ipv4Addr) -> bool {
+        self.ips_with_same_hash
+            .get_mut(ip)
+            .and_then(|(data, _)| data.count)
+            .is_some_and(|count| count > 1)
+    }
+
+    /// Record that the server at `target` has a favicon with the given hash.
+    /// Returns true if enough servers on the IP have had the same favicon for
+    /// it to look like a honeypot.
+    pub fn record_ip_favicon_hash(
+        &mut self,
+        target: &SocketAddrV4,
+        hash: u64,
+        now: Instant,
+        decay_window: Duration,
+    ) -> bool {
+        record_same_hash(
+            &mut self.ips_with_same_favicon,
+            target,
+            hash,
+            now,
+            decay_window,
+            FAVICON_REUSE_THRESHOLD,
+        );
+        self.ips_with_same_favicon
+            .get_mut(target.ip())
+            .and_then(|(data, _)| data.count)
+            .is_some_and(|count| count >= FAVICON_REUSE_THRESHOLD)
+    }
+
+    /// Forget that the IP was bad, including the servers with the same hash
+    /// that we counted for it.
+    pub fn unflag_bad_ip(&mut self, addr: Ipv4Addr) {
+        self.bad_ips.remove(&addr);
+        self.ips_with_same_hash.remove(&addr);
+    }
+
+    /// Record that the server at `target` is being written with the given
+    /// content hash. Returns true if it (probably) already was since the
+    /// filter was last reset, so only its timestamps have to be written.
+    pub fn record_unchanged_filter(
+        &mut self,
+        target: &SocketAddrV4,
+        content_hash: u64,
+        config: &SkipUnchangedConfig,
+        now: Instant,
+    ) -> bool {
+        let reset_after = config.reset_after();
+        let filter = match &mut self.unchanged_filter {
+            Some(filter) if now.duration_since(filter.created) < reset_after => filter,
+            filter => filter.insert(BloomFilter::new(
+                config.capacity.unwrap_or(1 << 24),
+                config.false_positive_rate.unwrap_or(0.001),
+                now,
+            )),
+        };
+        filter.insert(&(target, content_hash))
+    }
+
+    /// Forget the content hashes for servers on the IP, so they get a full
+    /// update (and are inserted again) the next time they're pinged.
+    fn forget_content_hashes(&mut self, ip: Ipv4Addr, except_port: Option<u16>) {
+        // servers can't be removed from a bloom filter
+        self.unchanged_filter = None;
+        let forgotten = self
+            .content_hashes
+            .iter()
+            .map(|(addr, _)| *addr)
+            .filter(|addr| *addr.ip() == ip && Some(addr.port()) != except_port)
+            .collect::<Vec<_>>();
+        for addr in forgotten {
+            self.content_hashes.remove(&addr);
+        }
+    }
+}
+
+/// Set the pool sizes and timeout from the config on the client options, if
+/// they're configured.
+fn apply_client_config(options: &mut ClientOptions, config: &DatabaseConfig) -> anyhow::Result<()> {
+    if config.max_pool_size == Some(0) {
+        bail!("database.max_pool_size must be at least 1");
+    }
+    if let (Some(min), Some(max)) = (config.min_pool_size, config.max_pool_size) {
+        if min > max {
+            bail!(
+                "database.min_pool_size ({min}) can't be more than database.max_pool_size ({max})"
+            );
+        }
+    }
+    if config.connect_timeout_ms == Some(0) {
+        bail!("database.connect_timeout_ms must be at least 1");
+    }
+
+    if let Some(max_pool_size) = config.max_pool_size {
+        options.max_pool_size = Some(max_pool_size);
+    }
+    if let Some(min_pool_size) = config.min_pool_size {
+        options.min_pool_size = Some(min_pool_size);
+    }
+    if let Some(connect_timeout_ms) = config.connect_timeout_ms {
+        options.connect_timeout = Some(Duration::from_millis(connect_timeout_ms));
+    }
+    Ok(())
+}
+
+/// Count the servers on an IP that have the same hash. Returns true when a new
+/// port makes the count reach `threshold`. A port that we already counted
+/// coming back with a different hash stops the count, the same as a new port
+/// with a different hash would.
+fn record_same_hash(
+    cache: &mut LruCache<Ipv4Addr, (CachedIpHash, HashSet<u16>)>,
+    target: &SocketAddrV4,
+    hash: u64,
+    now: Instant,
+    decay_window: Duration,
+    threshold: usize,
+) -> bool {
+    let fresh_entry = || {
+        (
+            CachedIpHash {
+                count: Some(1),
+                hash,
+                last_increment: now,
+            },
+            HashSet::from_iter(vec![target.port()]),
+        )
+    };
+
+    let Some((data, previously_checked_ports)) = cache.get_mut(target.ip()) else {
+        cache.insert(*target.ip(), fresh_entry());
+        return false;
+    };
+
+    if data.count.is_some() && now.duration_since(data.last_increment) > decay_window {
+        (*data, *previously_checked_ports) = fresh_entry();
+        return false;
+    }
+
+    if let Some(count) = &mut data.count {
+        if hash != data.hash {
+            // this server has a different hash than the other servers with the same IP,
+            // or it's a port we already counted that changed since
+            data.count = None;
+        } else if !previously_checked_ports.contains(&target.port()) {
+            *count += 1;
+            if previously_checked_ports.len() < MAX_CHECKED_PORTS_PER_IP {
+                previously_checked_ports.insert(target.port());
+            }
+            data.last_increment = now;
+
+            if *count >= threshold {
+                return true;
             }
         }
+    }
+
+    false
+}
+
+impl Database {
+    pub async fn connect(
+        mongodb_uri: &str,
+        config: DatabaseConfig,
+        protected_port: Option<u16>,
+    ) -> anyhow::Result<Self> {
+        let mut client_options = ClientOptions::parse(mongodb_uri).await?;
+        apply_client_config(&mut client_options, &config)?;
+
+        let client = Client::with_options(client_options)?;
+
+        let (bad_ip_journal, journaled_bad_ips) = match &config.bad_ip_journal {
+            Some(path) => {
+                let (journal, bad_ips) = BadIpJournal::open(path)?;
+                (Some(Arc::new(journal)), bad_ips)
+            }
+            None => (None, HashSet::new()),
+        };
 
         let db = Self {
             client,
-            shared: Arc::new(Mutex::new(DatabaseSharedData {
-                // arbitrary capacity (2^20)
-                ips_with_same_hash: LruCache::new(1048576),
+            shared: Arc::new(Mutex::new(DatabaseSharedData::new(BadIps::default()))),
+            write_limiter: Arc::new(WriteLimiter::new(config.max_writes_per_sec)),
+            config: Arc::new(config),
+            bad_ip_journal,
+            protected_port,
+        };
 
-                bad_ips,
+        // ping the database to make sure it's up
+        db.mcscanner_database()
+            .run_command(doc! {"ping": 1})
+            .await?;
 
-                cached_all_servers_30_days: None,
-                cached_all_servers_365_days: None,
-                cached_all_servers_new: None,
-            })),
-        };
+        // download bad ips
+        let cursor = db.bad_servers_coll().find(doc! {}).await?;
+        let bad_ips = BadIps::load(
+            db.config
+                .max_bad_ips_in_memory
+                .unwrap_or(DEFAULT_MAX_BAD_IPS_IN_MEMORY),
+            cursor,
+            journaled_bad_ips,
+        )
+        .await?;
+        db.shared.lock().bad_ips = bad_ips;
+
+        if let Some(journal) = db.bad_ip_journal.clone() {
+            let db_clone = db.clone();
+            tokio::spawn(async move {
+                loop {
+                    let synced = journal.sync(|ip| db_clone.write_bad_ip(ip)).await;
+                    if synced > 0 {
+                        println!("synced {synced} bad ips from the journal");
+                    }
+                    tokio::time::sleep(Duration::from_secs(60)).await;
+                }
+            });
+        }
 
         let db_clone = db.clone();
         tokio::spawn(async move {
@@ -93,27 +397,29 @@ impl Database {
     }
 
     /// Some servers randomize the server list ping every time and fill up our
-    /// database. This function deletes the `players` field from servers with
-    /// more than 1000 historical players.
+    /// database. This function trims the `players` field of servers with more
+    /// than `max_players_per_server` historical players down to the half of
+    /// them that were seen most recently.
     pub async fn delete_spam_historical_players(&self) {
-        let collection = self
-            .client
-            .database("cope_new")
-            .collection::<Document>("cachedservers");
+        let collection = self.servers_coll();
+        let max_players = self.config.max_players_per_server.unwrap_or(1000);
+        // trimming to less than the limit means the server has to get a lot of
+        // new players before it's trimmed again, instead of on every run
+        let kept_players = max_players / 2;
 
         let mut cursor = collection
             .aggregate(
                 vec![
                     doc! {"$match": {"players": {"$exists": true}}},
                     doc! {"$project": {"playerCount": {"$size": {"$objectToArray": "$players"}}, "players": "$players"}},
-                    doc! {"$match": {"playerCount": {"$gt": 1000}}},
+                    doc! {"$match": {"playerCount": {"$gt": max_players as i64}}},
                 ],
             )
             .await
             .expect("servers collection must exist");
 
         while let Some(Ok(doc)) = cursor.next().await {
-            // delete the players field and then add it again but with the 1000 most recent
+            // delete the players field and then add it again but with only the most recent
             // players
             let update = doc! { "$unset": { "players": "" } };
             collection
@@ -126,35 +432,12 @@ impl Database {
             // it might not actually be necessary to do two updates here, i'm guessing it is
             // though
 
-            // players looks like
-            // ```
-            // abcdundasheduuidefgh: { lastSeen: 2023-01-15T21:13:01.000Z, name: 'Herobrine' }
-            // ```
-            let players = doc
-                .get_document("players")
-                .expect("players must be present");
-            let mut players = players.into_iter().collect::<Vec<(&String, &Bson)>>();
-            players.sort_by(|(_, a), (_, b)| {
-                let a = a
-                    .as_document()
-                    .unwrap()
-                    .get_datetime("lastSeen")
-                    .expect("lastSeen must be present");
-                let b = b
-                    .as_document()
-                    .unwrap()
-                    .get_datetime("lastSeen")
-                    .expect("lastSeen must be present");
-                a.cmp(b)
-            });
-            let players = players
-                .into_iter()
-                .rev()
-                .take(500)
-                .map(|(k, v)| (k.clone(), v.clone()))
-                .collect::<Vec<(String, Bson)>>();
-
-            let update = doc! { "$set": { "players": bson::Document::from_iter(players) } };
+            let players = most_recent_players(
+                doc.get_document("players")
+                    .expect("players must be present"),
+                kept_players,
+            );
+            let update = doc! { "$set": { "players": players } };
             collection
                 .update_one(
                     doc! {"_id": doc.get_object_id("_id").expect("_id must be present")},
@@ -165,21 +448,77 @@ impl Database {
         }
     }
 
+    /// A database that isn't connected to anything, which is fine as long as
+    /// the test doesn't run any operations.
+    #[cfg(test)]
+    pub async fn unconnected(config: DatabaseConfig) -> Self {
+        Self {
+            client: Client::with_uri_str("mongodb://localhost:27017").await.unwrap(),
+            shared: Arc::new(Mutex::new(DatabaseSharedData::new(BadIps::default()))),
+            write_limiter: Arc::new(WriteLimiter::new(config.max_writes_per_sec)),
+            config: Arc::new(config),
+            bad_ip_journal: None,
+            protected_port: Some(25565),
+        }
+    }
+
     pub fn mcscanner_database(&self) -> mongodb::Database {
-        self.client.database("cope_new")
+        self.client.database(&self.config.name)
     }
 
     pub fn servers_coll(&self) -> Collection<Document> {
         self.mcscanner_database()
-            .collection::<Document>("cachedservers")
+            .collection::<Document>(&self.config.servers_collection)
+    }
+
+    pub fn bad_servers_coll(&self) -> Collection<Document> {
+        self.mcscanner_database()
+            .collection::<Document>(&self.config.bad_servers_collection)
+    }
+
+    /// The collection with one document per player uuid, used for finding where
+    /// a player was last seen.
+    pub fn players_coll(&self) -> Collection<Document> {
+        self.mcscanner_database()
+            .collection::<Document>(&self.config.players_collection)
+    }
+
+    /// The collection with one document per favicon, if they're being stored.
+    pub fn favicons_coll(&self) -> Collection<Document> {
+        self.mcscanner_database()
+            .collection::<Document>(&self.config.favicons_collection)
+    }
+
+    /// The collection where checkpointed rescans save where they got to.
+    pub fn checkpoints_coll(&self) -> Collection<Document> {
+        self.mcscanner_database()
+            .collection::<Document>(&self.config.checkpoints_collection)
+    }
+
+    /// The collection with matscan's own metadata, like the schema version.
+    pub fn meta_coll(&self) -> Collection<Document> {
+        self.mcscanner_database()
+            .collection::<Document>(&self.config.meta_collection)
     }
 
     pub async fn add_to_bad_ips(self, addr: Ipv4Addr) -> anyhow::Result<()> {
         self.shared.lock().bad_ips.insert(addr);
 
-        self.client
-            .database("cope_new")
-            .collection::<Document>("bad_servers")
+        match &self.bad_ip_journal {
+            Some(journal) => {
+                journal.flag(addr);
+                // if this fails it'll be tried again when the journal is synced
+                self.write_bad_ip(addr).await?;
+                journal.synced(addr);
+                Ok(())
+            }
+            None => self.write_bad_ip(addr).await,
+        }
+    }
+
+    /// Write the bad IP to the database and delete the servers on it.
+    async fn write_bad_ip(&self, addr: Ipv4Addr) -> anyhow::Result<()> {
+        self.bad_servers_coll()
             .update_one(
                 doc! { "ip": addr.to_string() },
                 doc! {
@@ -192,35 +531,231 @@ impl Database {
             .upsert(true)
             .await?;
 
-        // delete all servers with this ip that aren't on 25565
+        // delete all servers with this ip that aren't on the protected port
+        let deleted_count = self
+            .remove_servers_for_ip(addr, self.protected_port)
+            .await?;
+
+        println!("deleted {deleted_count} bad servers");
+
+        Ok(())
+    }
+
+    /// Whether the server is on a bad IP and isn't on the protected port, so
+    /// it should be ignored.
+    pub fn is_on_bad_ip(&self, addr: &SocketAddrV4) -> bool {
+        Some(addr.port()) != self.protected_port && self.shared.lock().bad_ips.contains(addr.ip())
+    }
+
+    /// Remove the IP from the bad IPs, both in memory and in the database.
+    pub async fn remove_from_bad_ips(&self, addr: Ipv4Addr) -> anyhow::Result<()> {
+        unflag_bad_ip(self, &self.shared, self.bad_ip_journal.as_deref(), addr).await
+    }
+
+    /// Delete the server at the given address. Returns whether it existed.
+    pub async fn remove_server(&self, ip: Ipv4Addr, port: u16) -> anyhow::Result<bool> {
         let r = self
-            .client
-            .database("cope_new")
-            .collection::<Document>("cachedservers")
-            .delete_many(doc! {
-                "ip": addr.to_string(),
-                "port": { "$ne": 25565 }
+            .servers_coll()
+            .delete_one(doc! {
+                "ip": ip.to_string(),
+                "port": port as u32
             })
             .await?;
+        let mut shared = self.shared.lock();
+        shared.content_hashes.remove(&SocketAddrV4::new(ip, port));
+        shared.unchanged_filter = None;
+        Ok(r.deleted_count > 0)
+    }
 
-        println!("deleted {} bad servers", r.deleted_count);
+    /// Bump the server's `lastSeen` without touching the rest of it, for when
+    /// we only know that it's still up (like from a SYN-ACK on its port) and
+    /// don't have a status to rebuild the document from. Servers that aren't
+    /// in the database aren't created. Returns whether the server existed.
+    pub async fn touch_last_seen(&self, ip: Ipv4Addr, port: u16) -> anyhow::Result<bool> {
+        self.write_limiter.acquire(1).await;
+        let update = touch_last_seen_update(ip, port, bson::DateTime::now());
+        let r = self
+            .servers_coll()
+            .update_one(update.query, update.update)
+            .with_options(update.options)
+            .await?;
+        Ok(r.matched_count > 0)
+    }
 
-        Ok(())
+    /// Delete every server on the given IP, optionally keeping the one on
+    /// `except_port`. Returns the number of servers that were deleted.
+    pub async fn remove_servers_for_ip(
+        &self,
+        ip: Ipv4Addr,
+        except_port: Option<u16>,
+    ) -> anyhow::Result<u64> {
+        let r = self
+            .servers_coll()
+            .delete_many(servers_for_ip_filter(ip, except_port))
+            .await?;
+        self.shared.lock().forget_content_hashes(ip, except_port);
+        Ok(r.deleted_count)
+    }
+
+    /// The `lastActive` of each of the servers, for the ones that have it.
+    pub async fn last_active_times(
+        &self,
+        servers: &[SocketAddrV4],
+    ) -> anyhow::Result<HashMap<SocketAddrV4, bson::DateTime>> {
+        let filter = servers
+            .iter()
+            .map(|addr| doc! { "ip": addr.ip().to_string(), "port": addr.port() as u32 })
+            .collect::<Vec<_>>();
+        let mut cursor = self
+            .servers_coll()
+            .find(doc! { "$or": filter, "lastActive": { "$exists": true } })
+            .projection(doc! { "ip": 1, "port": 1, "lastActive": 1, "_id": 0 })
+            .await?;
+
+        let mut last_active = HashMap::new();
+        while let Some(doc) = cursor.next().await {
+            let doc = doc?;
+            let ip = doc.get_str("ip").ok().and_then(|ip| ip.parse().ok());
+            if let (Some(ip), Some(port), Ok(time)) =
+                (ip, get_u32(&doc, "port"), doc.get_datetime("lastActive"))
+            {
+                last_active.insert(SocketAddrV4::new(ip, port as u16), *time);
+            }
+        }
+        Ok(last_active)
     }
+
+    /// The number of servers on each version, by their `versionNameResolved`.
+    /// The versions with the most servers are first.
+    pub async fn count_by_resolved_version(&self) -> anyhow::Result<Vec<(String, u64)>> {
+        let mut cursor = self
+            .servers_coll()
+            .aggregate(resolved_version_pipeline())
+            .batch_size(self.config.cursor_batch_size)
+            .await?;
+
+        let mut counts = Vec::new();
+        while let Some(doc) = cursor.next().await {
+            if let Some(count) = parse_version_count(&doc?) {
+                counts.push(count);
+            }
+        }
+        Ok(counts)
+    }
+}
+
+/// Remove the IP from the bad IPs in memory, from the journal if there is one,
+/// and from the bad servers collection.
+pub async fn unflag_bad_ip(
+    store: &dyn ServerStore,
+    shared: &Mutex<DatabaseSharedData>,
+    journal: Option<&BadIpJournal>,
+    addr: Ipv4Addr,
+) -> anyhow::Result<()> {
+    shared.lock().unflag_bad_ip(addr);
+    if let Some(journal) = journal {
+        journal.unflag(addr);
+    }
+    store.delete_bad_ip(addr).await
+}
+
+fn resolved_version_pipeline() -> Vec<Document> {
+    vec![
+        doc! { "$match": { "versionNameResolved": { "$exists": true } } },
+        doc! { "$group": { "_id": "$versionNameResolved", "count": { "$sum": 1 } } },
+        doc! { "$sort": { "count": -1, "_id": 1 } },
+    ]
 }
 
+fn parse_version_count(doc: &Document) -> Option<(String, u64)> {
+    Some((
+        doc.get_str("_id").ok()?.to_string(),
+        get_u32(doc, "count")? as u64,
+    ))
+}
+
+/// The `max_players` players that were seen most recently. Players without a
+/// `lastSeen` are treated as the oldest.
+///
+/// `players` looks like
+/// ```text
+/// abcdundasheduuidefgh: { lastSeen: 2023-01-15T21:13:01.000Z, name: 'Herobrine' }
+/// ```
+fn most_recent_players(players: &Document, max_players: usize) -> Document {
+    let mut players = players.iter().collect::<Vec<(&String, &Bson)>>();
+    players.sort_by_key(|(_, player)| {
+        std::cmp::Reverse(
+            player
+                .as_document()
+                .and_then(|p| p.get_datetime("lastSeen").ok())
+                .copied(),
+        )
+    });
+    players
+        .into_iter()
+        .take(max_players)
+        .map(|(k, v)| (k.clone(), v.clone()))
+        .collect()
+}
+
+/// The update for [`Database::touch_last_seen`]. It's not an upsert, since a
+/// document with only `lastSeen` wouldn't be a real server.
+fn touch_last_seen_update(ip: Ipv4Addr, port: u16, now: bson::DateTime) -> BulkUpdate {
+    BulkUpdate {
+        query: doc! { "ip": ip.to_string(), "port": port as u32 },
+        update: doc! { "$set": { "lastSeen": now } },
+        computed: Document::new(),
+        options: None,
+    }
+}
+
+fn servers_for_ip_filter(ip: Ipv4Addr, except_port: Option<u16>) -> Document {
+    let mut filter = doc! { "ip": ip.to_string() };
+    if let Some(except_port) = except_port {
+        filter.insert("port", doc! { "$ne": except_port as u32 });
+    }
+    filter
+}
+
+/// Get a number that's supposed to be a u32 (like a port or an IP) from the
+/// document.
+///
+/// - Int32s are reinterpreted as u32s, since big numbers like IPs were
+///   sometimes stored as negative i32s.
+/// - Int64s are used if they fit in a u32.
+/// - Doubles are used if they're a whole number that fits in a u32, like
+///   `25565.0`.
+///
+/// Anything else (including strings) is None.
 pub fn get_u32(doc: &Document, key: &str) -> Option<u32> {
-    get_i32(doc, key).map(|a| a as u32)
+    match doc.get(key)? {
+        Bson::Int32(n) => Some(*n as u32),
+        Bson::Int64(n) => u32::try_from(*n).ok(),
+        Bson::Double(n) => whole_double(*n).and_then(|n| u32::try_from(n).ok()),
+        _ => None,
+    }
 }
 
+/// Get an i32 from the document.
+///
+/// - Int32s are used as-is.
+/// - Int64s are used if they fit in an i32.
+/// - Doubles are used if they're a whole number that fits in an i32.
+///
+/// Anything else (including strings) is None.
 pub fn get_i32(doc: &Document, key: &str) -> Option<i32> {
-    doc.get(key).and_then(|a| {
-        if let Some(addr) = a.as_i32() {
-            Some(addr)
-        } else {
-            a.as_i64().map(|a| a as i32)
-        }
-    })
+    match doc.get(key)? {
+        Bson::Int32(n) => Some(*n),
+        Bson::Int64(n) => i32::try_from(*n).ok(),
+        Bson::Double(n) => whole_double(*n).and_then(|n| i32::try_from(n).ok()),
+        _ => None,
+    }
+}
+
+/// The double as an i64 if it doesn't have a fractional part.
+fn whole_double(n: f64) -> Option<i64> {
+    // the range check is so the cast can't saturate
+    (n.fract() == 0. && n.abs() < 2f64.powi(53)).then_some(n as i64)
 }
 
 pub enum UpdateResult {
@@ -352,3 +887,356 @@ pub async fn collect_all_servers(
 
     Ok(servers)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn removal_targets_servers_collection() {
+        let database = Database::unconnected(DatabaseConfig::default()).await;
+        let collection = database.servers_coll();
+        assert_eq!(collection.namespace().db, "cope_new");
+        assert_eq!(collection.name(), "cachedservers");
+
+        // everything else should be in the same database as the servers
+        for collection in [
+            database.bad_servers_coll(),
+            database.players_coll(),
+            database.meta_coll(),
+        ] {
+            assert_eq!(collection.namespace().db, "cope_new");
+        }
+    }
+
+    #[tokio::test]
+    async fn custom_collection_names() {
+        let database = Database::unconnected(DatabaseConfig {
+            name: "matscan2".to_string(),
+            servers_collection: "servers".to_string(),
+            ..Default::default()
+        })
+        .await;
+        let collection = database.servers_coll();
+        assert_eq!(collection.namespace().db, "matscan2");
+        assert_eq!(collection.name(), "servers");
+        assert_eq!(database.players_coll().namespace().db, "matscan2");
+    }
+
+    #[test]
+    fn player_cap_evicts_oldest() {
+        let seen = |millis| doc! { "lastSeen": bson::DateTime::from_millis(millis) };
+        let players = doc! {
+            "oldest": seen(1_000),
+            "newest": seen(4_000),
+            "never": { "name": "no lastSeen" },
+            "middle": seen(2_000),
+            "newer": seen(3_000),
+        };
+        let kept = most_recent_players(&players, 3);
+        assert_eq!(
+            kept.keys().collect::<Vec<_>>(),
+            ["newest", "newer", "middle"]
+        );
+        assert_eq!(most_recent_players(&players, 10).len(), 5);
+    }
+
+    #[test]
+    fn version_counts() {
+        // what the $group stage returns for servers on a mix of versions
+        let fixture = [
+            doc! { "_id": "1.20.1", "count": 5 },
+            doc! { "_id": "snapshot", "count": 2_i64 },
+            doc! { "_id": "1.8.8", "count": 1 },
+            doc! { "_id": null, "count": 3 },
+        ];
+        let counts = fixture
+            .iter()
+            .filter_map(parse_version_count)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            counts,
+            [
+                ("1.20.1".to_string(), 5),
+                ("snapshot".to_string(), 2),
+                ("1.8.8".to_string(), 1),
+            ]
+        );
+        assert_eq!(
+            resolved_version_pipeline()[1],
+            doc! { "$group": { "_id": "$versionNameResolved", "count": { "$sum": 1 } } }
+        );
+    }
+
+    #[test]
+    fn touching_only_updates_last_seen() {
+        let now = bson::DateTime::from_millis(1_000);
+        let update = touch_last_seen_update(Ipv4Addr::new(1, 2, 3, 4), 25565, now);
+        assert_eq!(update.query, doc! { "ip": "1.2.3.4", "port": 25565_u32 });
+        assert_eq!(update.update, doc! { "$set": { "lastSeen": now } });
+        // missing servers aren't created
+        assert!(update.options.and_then(|options| options.upsert) != Some(true));
+    }
+
+    #[test]
+    fn servers_for_ip_filter_keeps_except_port() {
+        let ip = Ipv4Addr::new(1, 2, 3, 4);
+        assert_eq!(servers_for_ip_filter(ip, None), doc! { "ip": "1.2.3.4" });
+        assert_eq!(
+            servers_for_ip_filter(ip, Some(25565)),
+            doc! { "ip": "1.2.3.4", "port": { "$ne": 25565_u32 } }
+        );
+    }
+
+    #[tokio::test]
+    async fn removing_servers_for_ip_keeps_protected_port() {
+        let ip = Ipv4Addr::new(1, 2, 3, 4);
+        let store = memory::MemoryStore::new(vec![
+            doc! { "ip": "1.2.3.4", "port": 25565 },
+            doc! { "ip": "1.2.3.4", "port": 25566 },
+            doc! { "ip": "5.6.7.8", "port": 25566 },
+        ]);
+        // the servers that remove_servers_for_ip deletes
+        let removed = store
+            .aggregate_servers(vec![
+                doc! { "$match": servers_for_ip_filter(ip, Some(25565)) },
+            ])
+            .await
+            .unwrap()
+            .map(|doc| doc.unwrap())
+            .collect::<Vec<_>>()
+            .await;
+        assert_eq!(removed, [doc! { "ip": "1.2.3.4", "port": 25566 }]);
+
+        let mut shared = DatabaseSharedData::new(BadIps::default());
+        let protected = SocketAddrV4::new(ip, 25565);
+        let other_ip = SocketAddrV4::new(Ipv4Addr::new(5, 6, 7, 8), 25566);
+        for addr in [protected, SocketAddrV4::new(ip, 25566), other_ip] {
+            shared.content_hashes.insert(addr, 1);
+        }
+        shared.unchanged_filter = Some(BloomFilter::new(100, 0.01, Instant::now()));
+
+        shared.forget_content_hashes(ip, Some(25565));
+        let mut remembered = shared
+            .content_hashes
+            .iter()
+            .map(|(addr, _)| *addr)
+            .collect::<Vec<_>>();
+        remembered.sort();
+        assert_eq!(remembered, [protected, other_ip]);
+        // the removed servers might still be in the filter
+        assert!(shared.unchanged_filter.is_none());
+    }
+
+    #[tokio::test]
+    async fn protected_port_is_exempt_from_bad_ips() {
+        let mut database = Database::unconnected(DatabaseConfig::default()).await;
+        let ip = Ipv4Addr::new(1, 2, 3, 4);
+        database.shared.lock().bad_ips.insert(ip);
+
+        assert!(!database.is_on_bad_ip(&SocketAddrV4::new(ip, 25565)));
+        assert!(database.is_on_bad_ip(&SocketAddrV4::new(ip, 25566)));
+        assert!(!database.is_on_bad_ip(&SocketAddrV4::new(Ipv4Addr::new(5, 6, 7, 8), 25566)));
+
+        database.protected_port = None;
+        assert!(database.is_on_bad_ip(&SocketAddrV4::new(ip, 25565)));
+    }
+
+    #[test]
+    fn unflagging_bad_ip_starts_count_over() {
+        let mut shared = DatabaseSharedData::new(BadIps::default());
+        let now = Instant::now();
+        let decay_window = Duration::from_secs(60);
+        let ip = Ipv4Addr::new(1, 2, 3, 4);
+
+        for port in 0..BAD_IP_SAME_HASH_THRESHOLD as u16 - 1 {
+            shared.record_ip_hash(&SocketAddrV4::new(ip, port), 1, now, decay_window);
+        }
+        shared.bad_ips.insert(ip);
+
+        shared.unflag_bad_ip(ip);
+        assert!(!shared.bad_ips.contains(&ip));
+        // if the count was kept then this would make it a bad ip again
+        assert!(!shared.record_ip_hash(&SocketAddrV4::new(ip, 1000), 1, now, decay_window));
+    }
+
+    #[test]
+    fn same_hash_count_reaches_threshold() {
+        let mut shared = DatabaseSharedData::new(BadIps::default());
+        let now = Instant::now();
+        let window = Duration::from_secs(60 * 60);
+
+        let ip = Ipv4Addr::new(1, 2, 3, 4);
+        for port in 0..BAD_IP_SAME_HASH_THRESHOLD as u16 - 1 {
+            assert!(!shared.record_ip_hash(&SocketAddrV4::new(ip, port), 1, now, window));
+        }
+        assert!(shared.record_ip_hash(&SocketAddrV4::new(ip, 1000), 1, now, window));
+    }
+
+    #[test]
+    fn stale_same_hash_count_decays() {
+        let mut shared = DatabaseSharedData::new(BadIps::default());
+        let start = Instant::now();
+        let window = Duration::from_secs(60 * 60);
+
+        let ip = Ipv4Addr::new(1, 2, 3, 4);
+        for port in 0..BAD_IP_SAME_HASH_THRESHOLD as u16 - 1 {
+            shared.record_ip_hash(&SocketAddrV4::new(ip, port), 1, start, window);
+        }
+
+        // a week later, one more server with the same hash shouldn't be enough
+        let later = start + Duration::from_secs(60 * 60 * 24 * 7);
+        assert!(!shared.record_ip_hash(&SocketAddrV4::new(ip, 1000), 1, later, window));
+        let (data, ports) = shared.ips_with_same_hash.get_mut(&ip).unwrap();
+        assert_eq!(data.count, Some(1));
+        assert_eq!(ports.len(), 1);
+    }
+
+    #[test]
+    fn ip_uniform_until_a_port_differs() {
+        let mut shared = DatabaseSharedData::new(BadIps::default());
+        let now = Instant::now();
+        let window = Duration::from_secs(60 * 60);
+
+        let ip = Ipv4Addr::new(1, 2, 3, 4);
+        shared.record_ip_hash(&SocketAddrV4::new(ip, 25565), 1, now, window);
+        // one port doesn't say anything
+        assert!(!shared.is_ip_uniform(&ip));
+        for port in 25566..25570 {
+            shared.record_ip_hash(&SocketAddrV4::new(ip, port), 1, now, window);
+            assert!(shared.is_ip_uniform(&ip));
+        }
+        shared.record_ip_hash(&SocketAddrV4::new(ip, 25570), 2, now, window);
+        assert!(!shared.is_ip_uniform(&ip));
+        assert!(!shared.is_ip_uniform(&Ipv4Addr::new(5, 6, 7, 8)));
+    }
+
+    #[test]
+    fn rescanned_port_with_a_different_hash_isnt_uniform() {
+        let mut shared = DatabaseSharedData::new(BadIps::default());
+        let now = Instant::now();
+        let window = Duration::from_secs(60 * 60);
+
+        let ip = Ipv4Addr::new(1, 2, 3, 4);
+        for port in 25565..25570 {
+            shared.record_ip_hash(&SocketAddrV4::new(ip, port), 1, now, window);
+        }
+        // rescanning a port we already counted doesn't change anything
+        shared.record_ip_hash(&SocketAddrV4::new(ip, 25565), 1, now, window);
+        assert!(shared.is_ip_uniform(&ip));
+
+        shared.record_ip_hash(&SocketAddrV4::new(ip, 25565), 2, now, window);
+        assert!(!shared.is_ip_uniform(&ip));
+    }
+
+    #[test]
+    fn checked_ports_are_capped() {
+        let mut shared = DatabaseSharedData::new(BadIps::default());
+        let now = Instant::now();
+        let window = Duration::from_secs(60 * 60);
+
+        let ip = Ipv4Addr::new(1, 2, 3, 4);
+        for port in 0..10_000 {
+            shared.record_ip_hash(&SocketAddrV4::new(ip, port), 1, now, window);
+        }
+        let (data, ports) = shared.ips_with_same_hash.get_mut(&ip).unwrap();
+        assert_eq!(ports.len(), MAX_CHECKED_PORTS_PER_IP);
+        assert_eq!(data.count, Some(10_000));
+        // still a bad ip
+        assert!(shared.record_ip_hash(&SocketAddrV4::new(ip, 20_000), 1, now, window));
+    }
+
+    #[test]
+    fn reused_favicon_is_flagged() {
+        let mut shared = DatabaseSharedData::new(BadIps::default());
+        let now = Instant::now();
+        let window = Duration::from_secs(60 * 60);
+
+        let ip = Ipv4Addr::new(1, 2, 3, 4);
+        for port in 0..FAVICON_REUSE_THRESHOLD as u16 - 1 {
+            assert!(!shared.record_ip_favicon_hash(&SocketAddrV4::new(ip, port), 1, now, window));
+        }
+        assert!(shared.record_ip_favicon_hash(&SocketAddrV4::new(ip, 1000), 1, now, window));
+        // servers we've already counted stay flagged
+        assert!(shared.record_ip_favicon_hash(&SocketAddrV4::new(ip, 0), 1, now, window));
+        // and it doesn't count towards being a bad ip
+        assert!(shared.ips_with_same_hash.get_mut(&ip).is_none());
+    }
+
+    #[test]
+    fn different_favicons_arent_flagged() {
+        let mut shared = DatabaseSharedData::new(BadIps::default());
+        let now = Instant::now();
+        let window = Duration::from_secs(60 * 60);
+
+        let ip = Ipv4Addr::new(1, 2, 3, 4);
+        for port in 0..FAVICON_REUSE_THRESHOLD as u16 * 2 {
+            let hash = port as u64 % 2;
+            assert!(!shared.record_ip_favicon_hash(
+                &SocketAddrV4::new(ip, port),
+                hash,
+                now,
+                window
+            ));
+        }
+    }
+
+    #[test]
+    fn number_coercion() {
+        let doc = bson::doc! {
+            "int32": 25565,
+            "int64": 25565_i64,
+            "double": 25565.0,
+            "fraction": 25565.5,
+            "string": "25565",
+            "negative": -1,
+            "big": 4_000_000_000_i64,
+            "huge": 1e300,
+        };
+
+        for (key, i32_value, u32_value) in [
+            ("int32", Some(25565), Some(25565)),
+            ("int64", Some(25565), Some(25565)),
+            ("double", Some(25565), Some(25565)),
+            ("fraction", None, None),
+            ("string", None, None),
+            ("negative", Some(-1), Some(u32::MAX)),
+            ("big", None, Some(4_000_000_000)),
+            ("huge", None, None),
+            ("missing", None, None),
+        ] {
+            assert_eq!(get_i32(&doc, key), i32_value, "get_i32 {key}");
+            assert_eq!(get_u32(&doc, key), u32_value, "get_u32 {key}");
+        }
+    }
+
+    #[test]
+    fn client_options_use_configured_pool() {
+        let mut options = ClientOptions::default();
+        apply_client_config(
+            &mut options,
+            &DatabaseConfig {
+                max_pool_size: Some(50),
+                min_pool_size: Some(5),
+                connect_timeout_ms: Some(2500),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(options.max_pool_size, Some(50));
+        assert_eq!(options.min_pool_size, Some(5));
+        assert_eq!(options.connect_timeout, Some(Duration::from_millis(2500)));
+
+        // unset options are left alone
+        let mut options = ClientOptions::default();
+        apply_client_config(&mut options, &DatabaseConfig::default()).unwrap();
+        assert_eq!(options.max_pool_size, None);
+
+        let invalid = DatabaseConfig {
+            max_pool_size: Some(5),
+            min_pool_size: Some(10),
+            ..Default::default()
+        };
+        assert!(apply_client_config(&mut ClientOptions::default(), &invalid).is_err());
+    }
+}
@@ -0,0 +1,318 @@
+//! The queries that modes use to pick servers from the servers collection and
+//! the writes that processing makes to it (and to the bad servers and meta
+//! collections), behind a trait so they can be tested without a real Mongo. The
+//! in-memory implementation for tests is in [`super::memory`].
+
+use std::net::{Ipv4Addr, SocketAddrV4};
+
+use async_trait::async_trait;
+use bson::{doc, Bson, Document};
+use futures_util::{stream::BoxStream, StreamExt};
+use mongodb::options::AggregateOptions;
+
+use crate::config::DatabaseConfig;
+
+use super::{
+    bulk_write::{BulkUpdate, BulkUpdateResult, CollectionExt},
+    get_i32, get_u32, Database,
+};
+
+/// A server that a player was seen on.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PlayerSighting {
+    pub addr: SocketAddrV4,
+    pub last_seen: Option<bson::DateTime>,
+}
+
+/// The last server that a rescan got to, so it can carry on after it when
+/// it's restarted.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RescanCheckpoint {
+    pub last_seen: bson::DateTime,
+    pub id: Bson,
+}
+
+#[async_trait]
+pub trait ServerStore: Send + Sync {
+    /// Run an aggregation pipeline on the servers collection.
+    async fn aggregate_servers(
+        &self,
+        pipeline: Vec<Document>,
+    ) -> anyhow::Result<BoxStream<'_, anyhow::Result<Document>>>;
+
+    /// Apply the updates to the servers collection in one bulk write. If it's
+    /// `ordered`, the first update that fails stops the rest.
+    async fn bulk_update_servers(
+        &self,
+        updates: &[BulkUpdate],
+        ordered: bool,
+    ) -> anyhow::Result<BulkUpdateResult>;
+
+    /// Run an aggregation pipeline on the bad servers collection.
+    async fn aggregate_bad_servers(
+        &self,
+        pipeline: Vec<Document>,
+    ) -> anyhow::Result<BoxStream<'_, anyhow::Result<Document>>>;
+
+    /// Delete the IP from the bad servers collection.
+    async fn delete_bad_ip(&self, ip: Ipv4Addr) -> anyhow::Result<()>;
+
+    /// The version of the last migration that was applied, or 0 if none were.
+    async fn load_schema_version(&self) -> anyhow::Result<i32>;
+
+    async fn save_schema_version(&self, version: i32) -> anyhow::Result<()>;
+
+    /// The rescan checkpoint that was saved with this name, if there is one.
+    async fn load_rescan_checkpoint(&self, name: &str) -> anyhow::Result<Option<RescanCheckpoint>>;
+
+    /// Save the rescan checkpoint with this name, or delete it if it's None.
+    async fn save_rescan_checkpoint(
+        &self,
+        name: &str,
+        checkpoint: Option<&RescanCheckpoint>,
+    ) -> anyhow::Result<()>;
+
+    /// The servers that a player with this name was seen on, with the most
+    /// recent sighting first. This has to look at the players of every server,
+    /// so it's slow on a big database.
+    async fn find_servers_by_player_name(
+        &self,
+        name: &str,
+        case_insensitive: bool,
+    ) -> anyhow::Result<Vec<PlayerSighting>> {
+        let mut docs = self
+            .aggregate_servers(player_name_pipeline(name, case_insensitive))
+            .await?;
+
+        let mut sightings = Vec::new();
+        while let Some(doc) = docs.next().await {
+            let doc = doc?;
+            let ip = doc
+                .get_str("ip")
+                .ok()
+                .and_then(|ip| ip.parse::<Ipv4Addr>().ok());
+            let (Some(ip), Some(port)) = (ip, get_u32(&doc, "port")) else {
+                continue;
+            };
+            sightings.push(PlayerSighting {
+                addr: SocketAddrV4::new(ip, port as u16),
+                last_seen: doc.get_datetime("lastSeen").ok().copied(),
+            });
+        }
+        Ok(sightings)
+    }
+}
+
+/// The options for the reads that modes make to pick servers, like the ones
+/// to rescan or fingerprint. These can go to a secondary, unlike the writes.
+fn server_read_options(config: &DatabaseConfig) -> AggregateOptions {
+    AggregateOptions::builder()
+        .batch_size(config.cursor_batch_size)
+        .selection_criteria(config.read_preference.selection_criteria())
+        .build()
+}
+
+/// Bad IPs are written as strings but older ones might be numbers.
+pub(super) fn bad_ip_filter(ip: Ipv4Addr) -> Document {
+    doc! { "ip": { "$in": [ip.to_string(), u32::from(ip) as i64] } }
+}
+
+fn player_name_pipeline(name: &str, case_insensitive: bool) -> Vec<Document> {
+    let name_filter = if case_insensitive {
+        doc! { "$regex": format!("^{}$", regex::escape(name)), "$options": "i" }
+    } else {
+        doc! { "$eq": name }
+    };
+    vec![
+        // the players are keyed by their uuid, so they have to be turned into
+        // an array to be searched
+        doc! { "$project": { "ip": 1, "port": 1, "players": { "$objectToArray": "$players" } } },
+        doc! { "$unwind": "$players" },
+        doc! { "$match": { "players.v.name": name_filter } },
+        doc! { "$project": { "_id": 0, "ip": 1, "port": 1, "lastSeen": "$players.v.lastSeen" } },
+        doc! { "$sort": { "lastSeen": -1 } },
+    ]
+}
+
+#[async_trait]
+impl ServerStore for Database {
+    async fn aggregate_servers(
+        &self,
+        pipeline: Vec<Document>,
+    ) -> anyhow::Result<BoxStream<'_, anyhow::Result<Document>>> {
+        let cursor = self
+            .servers_coll()
+            .aggregate(pipeline)
+            .with_options(server_read_options(&self.config))
+            .await?;
+        Ok(cursor.map(|doc| Ok(doc?)).boxed())
+    }
+
+    async fn bulk_update_servers(
+        &self,
+        updates: &[BulkUpdate],
+        ordered: bool,
+    ) -> anyhow::Result<BulkUpdateResult> {
+        self.servers_coll()
+            .bulk_update(&self.mcscanner_database(), updates, ordered)
+            .await
+    }
+
+    async fn aggregate_bad_servers(
+        &self,
+        pipeline: Vec<Document>,
+    ) -> anyhow::Result<BoxStream<'_, anyhow::Result<Document>>> {
+        let cursor = self.bad_servers_coll().aggregate(pipeline).await?;
+        Ok(cursor.map(|doc| Ok(doc?)).boxed())
+    }
+
+    async fn delete_bad_ip(&self, ip: Ipv4Addr) -> anyhow::Result<()> {
+        self.bad_servers_coll()
+            .delete_many(bad_ip_filter(ip))
+            .await?;
+        Ok(())
+    }
+
+    async fn load_schema_version(&self) -> anyhow::Result<i32> {
+        let doc = self
+            .meta_coll()
+            .find_one(doc! { "_id": "schema_version" })
+            .await?;
+        Ok(doc
+            .and_then(|doc| get_i32(&doc, "version"))
+            .unwrap_or_default())
+    }
+
+    async fn save_schema_version(&self, version: i32) -> anyhow::Result<()> {
+        self.meta_coll()
+            .update_one(
+                doc! { "_id": "schema_version" },
+                doc! { "$set": { "version": version } },
+            )
+            .upsert(true)
+            .await?;
+        Ok(())
+    }
+
+    async fn load_rescan_checkpoint(&self, name: &str) -> anyhow::Result<Option<RescanCheckpoint>> {
+        let Some(doc) = self
+            .checkpoints_coll()
+            .find_one(doc! { "_id": name })
+            .await?
+        else {
+            return Ok(None);
+        };
+        let (Ok(last_seen), Some(id)) = (doc.get_datetime("lastSeen"), doc.get("serverId")) else {
+            return Ok(None);
+        };
+        Ok(Some(RescanCheckpoint {
+            last_seen: *last_seen,
+            id: id.clone(),
+        }))
+    }
+
+    async fn save_rescan_checkpoint(
+        &self,
+        name: &str,
+        checkpoint: Option<&RescanCheckpoint>,
+    ) -> anyhow::Result<()> {
+        let coll = self.checkpoints_coll();
+        match checkpoint {
+            Some(checkpoint) => {
+                coll.replace_one(
+                    doc! { "_id": name },
+                    doc! {
+                        "_id": name,
+                        "lastSeen": checkpoint.last_seen,
+                        "serverId": checkpoint.id.clone(),
+                    },
+                )
+                .upsert(true)
+                .await?;
+            }
+            None => {
+                coll.delete_one(doc! { "_id": name }).await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mongodb::options;
+
+    use crate::database::{memory::MemoryStore, ReadPreference};
+
+    use super::*;
+
+    #[test]
+    fn server_reads_use_the_configured_options() {
+        let config = DatabaseConfig {
+            cursor_batch_size: 5000,
+            read_preference: ReadPreference::SecondaryPreferred,
+            ..Default::default()
+        };
+        let read_options = server_read_options(&config);
+        assert_eq!(read_options.batch_size, Some(5000));
+        assert_eq!(
+            read_options.selection_criteria,
+            Some(options::SelectionCriteria::ReadPreference(
+                options::ReadPreference::SecondaryPreferred { options: None }
+            ))
+        );
+    }
+
+    fn player(name: &str, last_seen_millis: i64) -> Document {
+        doc! { "lastSeen": bson::DateTime::from_millis(last_seen_millis), "name": name }
+    }
+
+    #[tokio::test]
+    async fn finds_every_server_with_the_player() {
+        let store = MemoryStore::new(vec![
+            doc! {
+                "ip": "1.1.1.1",
+                "port": 25565,
+                "players": {
+                    "069a79f444e94726a5befca90e38aaf5": player("Notch", 1_000),
+                    "853c80ef3c3749fdaa49938b674adae6": player("jeb_", 1_000),
+                },
+            },
+            doc! { "ip": "2.2.2.2", "port": 25566 },
+            doc! {
+                "ip": "3.3.3.3",
+                "port": 25565,
+                "players": { "069a79f444e94726a5befca90e38aaf5": player("notch", 2_000) },
+            },
+        ]);
+
+        let sighting = |ip: [u8; 4], port, millis| PlayerSighting {
+            addr: SocketAddrV4::new(Ipv4Addr::from(ip), port),
+            last_seen: Some(bson::DateTime::from_millis(millis)),
+        };
+        // the most recent first
+        assert_eq!(
+            store
+                .find_servers_by_player_name("Notch", true)
+                .await
+                .unwrap(),
+            [
+                sighting([3, 3, 3, 3], 25565, 2_000),
+                sighting([1, 1, 1, 1], 25565, 1_000)
+            ]
+        );
+        assert_eq!(
+            store
+                .find_servers_by_player_name("Notch", false)
+                .await
+                .unwrap(),
+            [sighting([1, 1, 1, 1], 25565, 1_000)]
+        );
+        // the name is matched exactly, not as a regex
+        assert!(store
+            .find_servers_by_player_name("N.tch", true)
+            .await
+            .unwrap()
+            .is_empty());
+    }
+}
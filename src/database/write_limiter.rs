@@ -0,0 +1,149 @@
+//! Limits how fast we write to the database so big bursts of results don't
+//! overwhelm it.
+
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+
+/// The most writes that can be waiting in the bucket, in seconds of writes.
+/// If a write would go past this, it waits until there's room before it's
+/// queued.
+const MAX_QUEUED_SECS: f64 = 10.;
+
+pub struct WriteLimiter {
+    bucket: Option<Mutex<TokenBucket>>,
+}
+
+impl WriteLimiter {
+    /// Create a limiter that allows up to `max_writes_per_sec` writes per
+    /// second, or any number of writes if it's None (or 0).
+    pub fn new(max_writes_per_sec: Option<u64>) -> Self {
+        Self {
+            bucket: max_writes_per_sec
+                .filter(|&rate| rate > 0)
+                .map(|rate| Mutex::new(TokenBucket::new(rate as f64, Instant::now()))),
+        }
+    }
+
+    /// Wait until we're allowed to do `writes` more writes.
+    pub async fn acquire(&self, writes: usize) {
+        let Some(bucket) = &self.bucket else {
+            return;
+        };
+
+        loop {
+            let wait = bucket.lock().reserve(writes as f64, Instant::now());
+            match wait {
+                Reservation::Ready(delay) => {
+                    if !delay.is_zero() {
+                        tokio::time::sleep(jitter(delay)).await;
+                    }
+                    return;
+                }
+                Reservation::QueueFull(delay) => tokio::time::sleep(jitter(delay)).await,
+            }
+        }
+    }
+}
+
+/// Add up to 10% to the delay so writers that were waiting for the same thing
+/// don't all wake up at once.
+fn jitter(delay: Duration) -> Duration {
+    delay.mul_f64(1. + rand::random::<f64>() * 0.1)
+}
+
+#[derive(Debug, PartialEq)]
+enum Reservation {
+    /// The writes were reserved and can be done after waiting this long.
+    Ready(Duration),
+    /// There are too many writes queued already, try again after this long.
+    QueueFull(Duration),
+}
+
+/// A token bucket where the tokens can go negative, which means there are
+/// writes waiting for their turn.
+struct TokenBucket {
+    rate: f64,
+    /// The number of writes that can be made instantly after being idle.
+    burst: f64,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: f64, now: Instant) -> Self {
+        Self {
+            rate,
+            burst: rate,
+            tokens: rate,
+            last_refill: now,
+        }
+    }
+
+    fn reserve(&mut self, writes: f64, now: Instant) -> Reservation {
+        let elapsed = now
+            .saturating_duration_since(self.last_refill)
+            .as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.burst);
+        self.last_refill = now;
+
+        let queued_secs = -self.tokens / self.rate;
+        if queued_secs > MAX_QUEUED_SECS {
+            return Reservation::QueueFull(Duration::from_secs_f64(queued_secs - MAX_QUEUED_SECS));
+        }
+
+        self.tokens -= writes;
+        if self.tokens >= 0. {
+            Reservation::Ready(Duration::ZERO)
+        } else {
+            Reservation::Ready(Duration::from_secs_f64(-self.tokens / self.rate))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rate_stays_under_cap() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(1000., start);
+
+        // write as fast as we're allowed to in batches of 100 for a minute
+        let mut now = start;
+        let mut writes = 0.;
+        while now - start < Duration::from_secs(60) {
+            match bucket.reserve(100., now) {
+                Reservation::Ready(delay) => {
+                    now += delay;
+                    writes += 100.;
+                }
+                Reservation::QueueFull(delay) => now += delay,
+            }
+        }
+
+        let elapsed = (now - start).as_secs_f64();
+        // the only writes over the rate are from the initial burst
+        assert!(
+            writes <= 1000. + 1000. * elapsed + 0.001,
+            "{writes} writes in {elapsed}s"
+        );
+        assert!(writes > 900. * elapsed, "{writes} writes in {elapsed}s");
+    }
+
+    #[test]
+    fn queue_is_bounded() {
+        let now = Instant::now();
+        let mut bucket = TokenBucket::new(10., now);
+
+        // the burst and then 10 seconds of writes can be queued at once
+        for _ in 0..12 {
+            assert!(matches!(bucket.reserve(10., now), Reservation::Ready(_)));
+        }
+        assert!(matches!(
+            bucket.reserve(10., now),
+            Reservation::QueueFull(_)
+        ));
+    }
+}
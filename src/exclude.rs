@@ -10,7 +10,8 @@ pub fn parse_file(input: &str) -> anyhow::Result<Ipv4Ranges> {
     parse(&input)
 }
 
-fn parse(input: &str) -> anyhow::Result<Ipv4Ranges> {
+/// Parse ranges like the ones in the exclude file, one per line.
+pub fn parse(input: &str) -> anyhow::Result<Ipv4Ranges> {
     let mut ranges = Vec::new();
 
     for line in input.lines() {
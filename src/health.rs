@@ -0,0 +1,202 @@
+//! A tiny HTTP server with a `/healthz` endpoint, for orchestrators that want
+//! to know whether matscan is alive.
+
+use std::{
+    future::Future,
+    time::{Duration, Instant},
+};
+
+use bson::doc;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+use tracing::warn;
+
+use crate::database::Database;
+
+pub struct HealthReport {
+    pub database_reachable: bool,
+    /// How long ago a write to the database last succeeded, or None if there
+    /// hasn't been one yet.
+    pub last_write_ago: Option<Duration>,
+    pub bad_ips: usize,
+}
+
+impl HealthReport {
+    /// Check the database and collect the report.
+    pub async fn check(database: &Database) -> Self {
+        let database_reachable = database
+            .mcscanner_database()
+            .run_command(doc! { "ping": 1 })
+            .await
+            .is_ok();
+        let shared = database.shared.lock();
+        Self {
+            database_reachable,
+            last_write_ago: shared.last_write.map(|t| t.elapsed()),
+            bad_ips: shared.bad_ips.len(),
+        }
+    }
+
+    /// Whether the database is reachable and we wrote to it recently enough.
+    pub fn is_healthy(&self, max_write_age: Duration) -> bool {
+        self.database_reachable && self.last_write_ago.is_some_and(|ago| ago <= max_write_age)
+    }
+
+    fn to_json(&self, healthy: bool) -> String {
+        serde_json::json!({
+            "healthy": healthy,
+            "databaseReachable": self.database_reachable,
+            "lastWriteSecsAgo": self.last_write_ago.map(|ago| ago.as_secs()),
+            "badIps": self.bad_ips,
+        })
+        .to_string()
+    }
+}
+
+/// Serve `/healthz` on the listener forever. `check` is called for every
+/// request, and the response is a 503 if the report isn't healthy.
+pub async fn serve<F, Fut>(listener: TcpListener, max_write_age: Duration, check: F)
+where
+    F: Fn() -> Fut,
+    Fut: Future<Output = HealthReport>,
+{
+    loop {
+        let (stream, addr) = match listener.accept().await {
+            Ok(conn) => conn,
+            Err(err) => {
+                warn!("Couldn't accept health check connection: {err}");
+                continue;
+            }
+        };
+        if let Err(err) = respond(stream, max_write_age, &check).await {
+            warn!("Error responding to health check from {addr}: {err}");
+        }
+    }
+}
+
+/// Start the health check server in the background.
+pub async fn start(database: Database, bind: &str, max_write_age: Duration) -> anyhow::Result<()> {
+    let listener = TcpListener::bind(bind).await?;
+    println!(
+        "health check listening on http://{}/healthz",
+        listener.local_addr()?
+    );
+    tokio::spawn(serve(listener, max_write_age, move || {
+        let database = database.clone();
+        async move { HealthReport::check(&database).await }
+    }));
+    Ok(())
+}
+
+async fn respond<F, Fut>(
+    mut stream: TcpStream,
+    max_write_age: Duration,
+    check: &F,
+) -> anyhow::Result<()>
+where
+    F: Fn() -> Fut,
+    Fut: Future<Output = HealthReport>,
+{
+    // we only care about the request line so one read is enough
+    let mut buf = [0; 1024];
+    let n = tokio::time::timeout(Duration::from_secs(5), stream.read(&mut buf)).await??;
+    let request = String::from_utf8_lossy(&buf[..n]);
+    let path = request.split_whitespace().nth(1).unwrap_or_default();
+
+    let (status, body) = if path == "/healthz" {
+        let report = check().await;
+        let healthy = report.is_healthy(max_write_age);
+        let status = if healthy {
+            "200 OK"
+        } else {
+            "503 Service Unavailable"
+        };
+        (status, report.to_json(healthy))
+    } else {
+        ("404 Not Found", String::new())
+    };
+
+    let response = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+/// Record that we just wrote to the database successfully.
+pub fn record_write(database: &Database) {
+    database.shared.lock().last_write = Some(Instant::now());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns the status line and the body.
+    async fn get_healthz(report: fn() -> HealthReport) -> (String, serde_json::Value) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve(
+            listener,
+            Duration::from_secs(60),
+            move || async move { report() },
+        ));
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(b"GET /healthz HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        let status_line = head.lines().next().unwrap().to_string();
+        (status_line, serde_json::from_str(body).unwrap())
+    }
+
+    #[tokio::test]
+    async fn healthy() {
+        let (status, body) = get_healthz(|| HealthReport {
+            database_reachable: true,
+            last_write_ago: Some(Duration::from_secs(5)),
+            bad_ips: 3,
+        })
+        .await;
+        assert_eq!(status, "HTTP/1.1 200 OK");
+        assert_eq!(
+            body,
+            serde_json::json!({
+                "healthy": true,
+                "databaseReachable": true,
+                "lastWriteSecsAgo": 5,
+                "badIps": 3,
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn degraded() {
+        let (status, body) = get_healthz(|| HealthReport {
+            database_reachable: false,
+            last_write_ago: Some(Duration::from_secs(5)),
+            bad_ips: 0,
+        })
+        .await;
+        assert_eq!(status, "HTTP/1.1 503 Service Unavailable");
+        assert_eq!(body["databaseReachable"], false);
+
+        // the database is fine but nothing was written for too long
+        let (status, _) = get_healthz(|| HealthReport {
+            database_reachable: true,
+            last_write_ago: Some(Duration::from_secs(600)),
+            bad_ips: 0,
+        })
+        .await;
+        assert_eq!(status, "HTTP/1.1 503 Service Unavailable");
+    }
+}
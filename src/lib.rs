@@ -2,8 +2,10 @@ pub mod asns;
 pub mod config;
 pub mod database;
 pub mod exclude;
+pub mod health;
 pub mod modes;
 pub mod net;
 pub mod processing;
 pub mod scanner;
+pub mod shutdown;
 pub mod terminal_colors;
@@ -1,10 +1,9 @@
 use std::{
-    collections::{HashMap, VecDeque},
-    env, fs, path,
+    env, path,
     str::FromStr,
     sync::{atomic::AtomicBool, Arc},
     thread,
-    time::{Duration, Instant},
+    time::{Duration, Instant, SystemTime},
 };
 
 use dotenv::dotenv;
@@ -14,15 +13,16 @@ use tracing_subscriber::{prelude::*, EnvFilter};
 
 use matscan::{
     config::{Config, RescanConfig},
-    database::Database,
+    database::{self, Database},
     exclude,
-    modes::{ModePicker, ScanMode},
-    processing::{process_pings, SharedData},
+    modes::{rescan::PendingCheckpoint, ModePicker, ScanMode},
+    processing::{filters, process_pings, ProcessableProtocol, ProtocolRegistry, SharedData},
     scanner::{
         protocols::{self},
-        targets::{Ipv4Range, Ipv4Ranges, ScanRange, ScanRanges},
+        targets::{Ipv4Range, Ipv4Ranges, RangeProgress, ScanRange, ScanRanges},
         ScanSession, Scanner, ScannerReceiver,
     },
+    shutdown::ShutdownToken,
     terminal_colors::*,
 };
 
@@ -31,6 +31,8 @@ enum ModeCategory {
     Normal,
     Rescan,
     Fingerprint,
+    File,
+    Discovery,
 }
 
 #[tokio::main]
@@ -48,7 +50,7 @@ async fn main() -> anyhow::Result<()> {
         "parsing config at {}",
         config_file_path.as_os_str().to_string_lossy()
     );
-    let config: Config = toml::from_str(&fs::read_to_string(config_file_path)?)?;
+    let config = Config::load(&config_file_path)?;
 
     init_tracing(&config);
     info!("Logging initialized");
@@ -64,10 +66,69 @@ async fn main() -> anyhow::Result<()> {
     let minecraft_protocol = protocols::Minecraft::new(
         &config.target.addr,
         config.target.port,
-        config.target.protocol_version,
-    );
-
-    let mut database = Database::connect(&config.mongodb_uri).await?;
+        &config.target.protocol_versions(),
+    )
+    .with_max_response_bytes(matscan::processing::minecraft::max_response_bytes(
+        &config.processing,
+    ));
+
+    let mut database = Database::connect(
+        &config.mongodb_uri,
+        config.database.clone(),
+        config.bad_ip.protected_port(),
+    )
+    .await?;
+    database::migrations::run(&database).await?;
+    if config.health.enabled {
+        matscan::health::start(
+            database.clone(),
+            config.health.bind.as_deref().unwrap_or("127.0.0.1:8080"),
+            Duration::from_secs(config.health.max_write_age_secs.unwrap_or(60 * 30)),
+        )
+        .await?;
+    }
+    if config.cleanup.enabled {
+        matscan::modes::cleanup::run(&database).await?;
+    }
+    // these can take a while, so they don't hold up the scan
+    if config.ptr.enabled {
+        let (database, ptr_config) = (database.clone(), config.ptr.clone());
+        tokio::spawn(async move {
+            if let Err(err) = matscan::modes::ptr::run(&database, &ptr_config).await {
+                eprintln!("failed to look up ptr records: {err}");
+                tracing::error!("Failed to look up PTR records: {err}");
+            }
+        });
+    }
+    if config.geo.enabled {
+        #[cfg(feature = "geo")]
+        {
+            let (database, geo_config) = (database.clone(), config.geo.clone());
+            tokio::spawn(async move {
+                if let Err(err) = matscan::modes::geo::run(&database, &geo_config).await {
+                    eprintln!("failed to look up coordinates: {err}");
+                    tracing::error!("Failed to look up coordinates: {err}");
+                }
+            });
+        }
+        #[cfg(not(feature = "geo"))]
+        tracing::warn!(
+            "geo is enabled in the config but matscan was built without the geo feature"
+        );
+    }
+    if config.stats.enabled {
+        matscan::modes::stats::run(&database, config.stats.top_ports.unwrap_or(20)).await?;
+    }
+    if config.report.enabled {
+        matscan::modes::report::bad_ips_by_asn(&database, config.report.top_asns.unwrap_or(20))
+            .await?;
+    }
+    if config.verify.enabled {
+        matscan::modes::verify::run(&database, config.verify.sample_size.unwrap_or(100)).await?;
+    }
+    if config.export.enabled {
+        matscan::modes::export::run(&database, &config.export).await?;
+    }
     let scanner = Scanner::new(config.source_port);
     let mut mode_picker = ModePicker::default();
 
@@ -98,29 +159,28 @@ async fn main() -> anyhow::Result<()> {
     if config.fingerprinting.enabled {
         mode_categories.push(ModeCategory::Fingerprint);
     }
+    if config.file.enabled {
+        mode_categories.push(ModeCategory::File);
+    }
+    if config.discovery.enabled {
+        mode_categories.push(ModeCategory::Discovery);
+    }
 
     if mode_categories.is_empty() {
-        panic!("Scanner, rescanner, and fingerprinting are all disabled in the config. You should probably at least enable scanner.");
+        panic!("Scanner, rescanner, fingerprinting, file, and discovery are all disabled in the config. You should probably at least enable scanner.");
     }
 
     // the protocol set here will be overwritten later so it doesn't actually matter
     let protocol: Arc<RwLock<Box<dyn protocols::Protocol>>> =
         Arc::new(RwLock::new(Box::new(minecraft_protocol.clone())));
 
-    let shared_process_data: Arc<Mutex<SharedData>> = Arc::new(Mutex::new(SharedData {
-        database: database.clone(),
-        queue: VecDeque::new(),
-        // we use the cache to check if someone just joined a server, so this
-        // will always stay empty if snipe mode is off
-        cached_servers: HashMap::new(),
-
-        total_new: 0,
-        total_new_on_default_port: 0,
-        revived: 0,
-        results: 0,
+    let shared_process_data: Arc<Mutex<SharedData>> =
+        Arc::new(Mutex::new(SharedData::new(database.clone(), &config)?));
 
-        is_processing: false,
-    }));
+    tokio::spawn(filters::reload_on_sighup(
+        config_file_path.clone(),
+        shared_process_data.clone(),
+    ));
 
     let mut receiver = ScannerReceiver {
         protocol: protocol.clone(),
@@ -134,6 +194,8 @@ async fn main() -> anyhow::Result<()> {
 
     let mut processing_task = ProcessingTask::new(shared_process_data.clone(), config.clone());
 
+    let shutdown = ShutdownToken::on_ctrl_c();
+
     // make sure the modes in config.scanner.modes are valid
     let scan_modes = config.scanner.modes.map(|modes| {
         modes
@@ -149,12 +211,17 @@ async fn main() -> anyhow::Result<()> {
         let start_time = Instant::now();
 
         let mut ranges = ScanRanges::new();
+        let mut rescan_checkpoints = Vec::new();
 
         let mode_category = mode_categories[i % mode_categories.len()];
         i += 1;
 
-        // if the mode is none then that means it's a special mode (either rescanning or
-        // fingerprinting)
+        // servers get pinged with a different protocol version every scan
+        let handshake_rotation = i as u64;
+        shared_process_data.lock().handshake_rotation = handshake_rotation;
+
+        // if the mode is none then that means it's a special mode (rescanning,
+        // fingerprinting, or scanning from a file)
         let mut mode: Option<ScanMode> = None;
         match mode_category {
             ModeCategory::Normal => {
@@ -168,8 +235,8 @@ async fn main() -> anyhow::Result<()> {
                 println!("get_ranges took {:?}", get_ranges_end - get_ranges_start);
 
                 mode = Some(chosen_mode);
-                *protocol.write() = Box::new(minecraft_protocol.clone());
-                processing_task.set_protocol::<protocols::Minecraft>();
+                *protocol.write() = Box::new(minecraft_protocol.with_rotation(handshake_rotation));
+                processing_task.set_protocol(protocols::Minecraft::NAME)?;
             }
             ModeCategory::Rescan => {
                 println!("chosen mode: rescanning");
@@ -182,32 +249,56 @@ async fn main() -> anyhow::Result<()> {
                     &config.rescan4,
                     &config.rescan5,
                 ] {
-                    maybe_rescan_with_config(&database, &mut ranges, rescan_config).await?;
+                    maybe_rescan_with_config(
+                        &database,
+                        &mut ranges,
+                        &mut rescan_checkpoints,
+                        rescan_config,
+                    )
+                    .await?;
                 }
 
-                *protocol.write() = Box::new(minecraft_protocol.clone());
-                processing_task.set_protocol::<protocols::Minecraft>();
+                *protocol.write() = Box::new(minecraft_protocol.with_rotation(handshake_rotation));
+                processing_task.set_protocol(protocols::Minecraft::NAME)?;
             }
             ModeCategory::Fingerprint => {
                 println!("chosen mode: fingerprinting");
 
-                let mut fingerprint_ranges = Vec::new();
-                let mut fingerprint_protocol_versions = HashMap::new();
-                for (addr, protocol_version) in
-                    matscan::modes::fingerprint::get_addrs_and_protocol_versions(&database)
-                        .await?
-                        .into_iter()
-                        .collect::<Vec<_>>()
-                {
-                    fingerprint_ranges.push(ScanRange::single(*addr.ip(), addr.port()));
-                    fingerprint_protocol_versions.insert(addr, protocol_version);
-                }
-                ranges.extend(fingerprint_ranges);
+                let targets = matscan::modes::fingerprint::get_addrs_and_protocol_versions(
+                    &database,
+                    SystemTime::now(),
+                )
+                .await?;
+
+                // the requests are sent by our own pool instead of the scanner, so the
+                // servers can be spaced out
+                processing_task.set_protocol(protocols::MinecraftFingerprinting::NAME)?;
+                let fingerprinted = matscan::modes::fingerprint::run(
+                    targets,
+                    &config.fingerprinting,
+                    &shutdown,
+                    shared_process_data.clone(),
+                )
+                .await;
+                println!("fingerprinted {fingerprinted} servers");
+            }
+            ModeCategory::File => {
+                println!("chosen mode: file");
+
+                ranges.extend(matscan::modes::file::get_ranges(&config.file.path)?);
 
-                *protocol.write() = Box::new(protocols::MinecraftFingerprinting::new(
-                    fingerprint_protocol_versions,
+                *protocol.write() = Box::new(minecraft_protocol.with_rotation(handshake_rotation));
+                processing_task.set_protocol(protocols::Minecraft::NAME)?;
+            }
+            ModeCategory::Discovery => {
+                println!("chosen mode: discovery");
+
+                ranges.extend(matscan::modes::discovery::get_ranges(
+                    &config.discovery.ports,
                 ));
-                processing_task.set_protocol::<protocols::MinecraftFingerprinting>();
+
+                *protocol.write() = Box::new(minecraft_protocol.with_rotation(handshake_rotation));
+                processing_task.set_protocol(protocols::Minecraft::NAME)?;
             }
         }
 
@@ -219,8 +310,7 @@ async fn main() -> anyhow::Result<()> {
                 .shared
                 .lock()
                 .bad_ips
-                .clone()
-                .into_iter()
+                .iter()
                 .map(Ipv4Range::single)
                 .collect::<Vec<_>>(),
         );
@@ -282,6 +372,20 @@ async fn main() -> anyhow::Result<()> {
         println!("joining scanner thread");
         let packets_sent = scanner_thread.join().unwrap();
 
+        // the servers were all pinged and processed now, unless the scan was cut
+        // short
+        if packets_sent >= target_count as u64 {
+            for checkpoint in rescan_checkpoints {
+                if let Err(err) = checkpoint.save(&database).await {
+                    eprintln!("couldn't save rescan checkpoint: {err}");
+                }
+            }
+        } else if !rescan_checkpoints.is_empty() {
+            println!(
+                "not saving rescan checkpoints since the scan didn't get through every target"
+            );
+        }
+
         let mut shared_process_data = shared_process_data.lock();
         process_results(
             &mut shared_process_data,
@@ -295,11 +399,18 @@ async fn main() -> anyhow::Result<()> {
             println!("exit_on_done is true, exiting");
             break;
         }
+        if shutdown.is_cancelled() {
+            break;
+        }
     }
 
     has_ended.store(true, std::sync::atomic::Ordering::Relaxed);
     println!("finished writing, telling recv loop to stop...");
     recv_loop_thread.join().unwrap();
+
+    let filter_counts = &matscan::processing::minecraft::FILTER_COUNTS;
+    println!("{filter_counts}");
+    info!("{filter_counts}");
     println!("done");
 
     Ok(())
@@ -384,27 +495,21 @@ fn process_results(
     }
 }
 
-/// Get targets to rescan based on the given config and add them to ranges
+/// Get targets to rescan based on the given config and add them to ranges,
+/// and its checkpoint to the checkpoints to save after the scan.
 async fn maybe_rescan_with_config(
     database: &Database,
     ranges: &mut ScanRanges,
+    checkpoints: &mut Vec<PendingCheckpoint>,
     rescan: &RescanConfig,
 ) -> anyhow::Result<()> {
     if rescan.enabled {
-        ranges.extend(
-            matscan::modes::rescan::get_ranges(
-                database,
-                &rescan.filter,
-                rescan.rescan_every_secs,
-                rescan.players_online_ago_max_secs,
-                rescan.last_ping_ago_max_secs.unwrap_or(60 * 60 * 2),
-                rescan.limit,
-                rescan.sort,
-            )
-            .await?
-            .into_iter()
-            .collect::<Vec<_>>(),
-        );
+        let progress = RangeProgress::new(rescan.limit);
+        let (rescan_ranges, checkpoint) =
+            matscan::modes::rescan::get_ranges_and_checkpoint(database, rescan, &progress).await?;
+        ranges.extend(rescan_ranges);
+        checkpoints.extend(checkpoint);
+        println!("finished getting ranges to rescan: {progress}");
     }
     Ok(())
 }
@@ -412,6 +517,7 @@ async fn maybe_rescan_with_config(
 pub struct ProcessingTask {
     pub shared_process_data: Arc<Mutex<SharedData>>,
     pub config: Config,
+    pub registry: ProtocolRegistry,
     join_handle: Option<tokio::task::JoinHandle<()>>,
 }
 
@@ -420,16 +526,24 @@ impl ProcessingTask {
         Self {
             shared_process_data,
             config,
+            registry: ProtocolRegistry::default(),
             join_handle: None,
         }
     }
-    pub fn set_protocol<P: matscan::processing::ProcessableProtocol>(&mut self) {
+    /// Start processing pings with the protocol that was registered with the
+    /// given name. The current protocol keeps being used if there isn't one.
+    pub fn set_protocol(&mut self, name: &str) -> anyhow::Result<()> {
+        let protocol = self.registry.resolve(name)?;
         if let Some(join_handle) = &mut self.join_handle {
             join_handle.abort();
         }
         let shared_process_data = self.shared_process_data.clone();
-        let join_handle =
-            tokio::task::spawn(process_pings::<P>(shared_process_data, self.config.clone()));
+        let join_handle = tokio::task::spawn(process_pings(
+            shared_process_data,
+            self.config.clone(),
+            protocol,
+        ));
         self.join_handle = Some(join_handle);
+        Ok(())
     }
 }
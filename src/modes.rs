@@ -2,11 +2,25 @@ use std::{collections::HashMap, str::FromStr};
 
 use rand::{distributions::WeightedIndex, prelude::*};
 
-use crate::{database::Database, scanner::targets::ScanRange};
+use crate::{
+    config::RescanConfig,
+    database::Database,
+    scanner::targets::{RangeProgress, ScanRange},
+};
 
 use self::rescan::Sort;
 
+pub mod cleanup;
+pub mod dedup;
+pub mod discovery;
+pub mod enrich;
+pub mod export;
+pub mod file;
 pub mod fingerprint;
+#[cfg(feature = "geo")]
+pub mod geo;
+pub mod ptr;
+pub mod report;
 pub mod rescan;
 pub mod slash0;
 pub mod slash0_few_ports;
@@ -29,6 +43,8 @@ pub mod slash32_all_ports;
 pub mod slash32_all_ports_new;
 pub mod slash32_range_ports;
 pub mod slash32_range_ports_new;
+pub mod stats;
+pub mod verify;
 
 #[derive(
     Clone, Copy, Debug, Eq, PartialEq, Hash, enum_utils::FromStr, enum_utils::IterVariants,
@@ -210,60 +226,75 @@ impl ScanMode {
             ScanMode::Rescan1day => {
                 rescan::get_ranges(
                     database,
-                    &Default::default(),
-                    60 * 60 * 2,
-                    None,
-                    60 * 60 * 24,
-                    Some(250_000),
-                    Some(Sort::Oldest),
+                    &RescanConfig {
+                        enabled: true,
+                        rescan_every_secs: 60 * 60 * 2,
+                        last_ping_ago_max_secs: Some(60 * 60 * 24),
+                        limit: Some(250_000),
+                        sort: Some(Sort::Oldest),
+                        ..Default::default()
+                    },
+                    &RangeProgress::default(),
                 )
                 .await
             }
             ScanMode::Rescan7days => {
                 rescan::get_ranges(
                     database,
-                    &Default::default(),
-                    60 * 60 * 24,
-                    None,
-                    60 * 60 * 24 * 7,
-                    Some(250_000),
-                    Some(Sort::Oldest),
+                    &RescanConfig {
+                        enabled: true,
+                        rescan_every_secs: 60 * 60 * 24,
+                        last_ping_ago_max_secs: Some(60 * 60 * 24 * 7),
+                        limit: Some(250_000),
+                        sort: Some(Sort::Oldest),
+                        ..Default::default()
+                    },
+                    &RangeProgress::default(),
                 )
                 .await
             }
             ScanMode::Rescan30days => {
                 rescan::get_ranges(
                     database,
-                    &Default::default(),
-                    60 * 60 * 24 * 7,
-                    None,
-                    60 * 60 * 24 * 30,
-                    Some(250_000),
-                    Some(Sort::Random),
+                    &RescanConfig {
+                        enabled: true,
+                        rescan_every_secs: 60 * 60 * 24 * 7,
+                        last_ping_ago_max_secs: Some(60 * 60 * 24 * 30),
+                        limit: Some(250_000),
+                        sort: Some(Sort::Random),
+                        ..Default::default()
+                    },
+                    &RangeProgress::default(),
                 )
                 .await
             }
             ScanMode::Rescan365days => {
                 rescan::get_ranges(
                     database,
-                    &Default::default(),
-                    60 * 60 * 24 * 30,
-                    None,
-                    60 * 60 * 24 * 365,
-                    Some(500_000),
-                    Some(Sort::Random),
+                    &RescanConfig {
+                        enabled: true,
+                        rescan_every_secs: 60 * 60 * 24 * 30,
+                        last_ping_ago_max_secs: Some(60 * 60 * 24 * 365),
+                        limit: Some(500_000),
+                        sort: Some(Sort::Random),
+                        ..Default::default()
+                    },
+                    &RangeProgress::default(),
                 )
                 .await
             }
             ScanMode::RescanOlderThan365days => {
                 rescan::get_ranges(
                     database,
-                    &Default::default(),
-                    60 * 60 * 24 * 365,
-                    None,
-                    60 * 60 * 24 * 365 * 10,
-                    Some(500_000),
-                    Some(Sort::Random),
+                    &RescanConfig {
+                        enabled: true,
+                        rescan_every_secs: 60 * 60 * 24 * 365,
+                        last_ping_ago_max_secs: Some(60 * 60 * 24 * 365 * 10),
+                        limit: Some(500_000),
+                        sort: Some(Sort::Random),
+                        ..Default::default()
+                    },
+                    &RangeProgress::default(),
                 )
                 .await
             }
@@ -0,0 +1,133 @@
+use std::{collections::HashMap, fmt, net::Ipv4Addr};
+
+use bson::{doc, Bson, Document};
+use futures_util::StreamExt;
+use tracing::info;
+
+use crate::database::{self, Database};
+
+/// The number of documents we delete at once.
+const DELETE_BATCH_SIZE: usize = 1000;
+
+/// Why a server document is invalid.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Problem {
+    MissingIp,
+    InvalidIp,
+    MissingPort,
+    InvalidPort,
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Problem::MissingIp => "missing ip",
+            Problem::InvalidIp => "invalid ip",
+            Problem::MissingPort => "missing port",
+            Problem::InvalidPort => "invalid port",
+        })
+    }
+}
+
+/// Delete the server documents that rescanning and fingerprinting would skip
+/// because their ip or port is missing or invalid. Returns the number of
+/// documents that were deleted for each problem.
+pub async fn run(database: &Database) -> anyhow::Result<HashMap<Problem, usize>> {
+    let mut cursor = database
+        .servers_coll()
+        .find(doc! {})
+        .projection(doc! { "ip": 1, "port": 1 })
+        .batch_size(database.config.cursor_batch_size)
+        .await?;
+
+    let mut counts = HashMap::<Problem, usize>::new();
+    let mut to_delete = Vec::new();
+    while let Some(doc) = cursor.next().await {
+        let doc = doc?;
+        let Some(problem) = problem(&doc) else {
+            continue;
+        };
+        let Some(id) = doc.get("_id") else {
+            continue;
+        };
+        *counts.entry(problem).or_default() += 1;
+        to_delete.push(id.clone());
+
+        if to_delete.len() >= DELETE_BATCH_SIZE {
+            delete(database, std::mem::take(&mut to_delete)).await?;
+        }
+    }
+    if !to_delete.is_empty() {
+        delete(database, to_delete).await?;
+    }
+
+    for (problem, count) in &counts {
+        println!("deleted {count} servers with {problem}");
+    }
+    info!("Cleaned up invalid servers: {counts:?}");
+
+    Ok(counts)
+}
+
+async fn delete(database: &Database, ids: Vec<Bson>) -> anyhow::Result<()> {
+    database
+        .servers_coll()
+        .delete_many(doc! { "_id": { "$in": ids } })
+        .await?;
+    Ok(())
+}
+
+/// What's wrong with the server document, or None if it's fine.
+fn problem(doc: &Document) -> Option<Problem> {
+    let Some(ip) = doc.get("ip") else {
+        return Some(Problem::MissingIp);
+    };
+    if !ip.as_str().is_some_and(|ip| ip.parse::<Ipv4Addr>().is_ok()) {
+        return Some(Problem::InvalidIp);
+    }
+
+    if !doc.contains_key("port") {
+        return Some(Problem::MissingPort);
+    }
+    let port = database::get_u32(doc, "port");
+    if !port.is_some_and(|port| (1..=u16::MAX as u32).contains(&port)) {
+        return Some(Problem::InvalidPort);
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn deletes_invalid_servers() {
+        let docs = [
+            doc! { "_id": 1, "ip": "1.2.3.4", "port": 25565 },
+            doc! { "_id": 2, "ip": "bad", "port": 25565 },
+            doc! { "_id": 3, "port": 25565 },
+            doc! { "_id": 4, "ip": "1.2.3.4", "port": 70000 },
+            doc! { "_id": 5, "ip": "1.2.3.4", "port": 25566_i64 },
+            doc! { "_id": 6, "ip": "1.2.3.4" },
+            doc! { "_id": 7, "ip": "1.2.3.4", "port": 25567.0 },
+            doc! { "_id": 8, "ip": "1.2.3.4", "port": 25567.5 },
+            doc! { "_id": 9, "ip": "1.2.3.4", "port": -1 },
+        ];
+        let problems = docs.iter().map(problem).collect::<Vec<_>>();
+        assert_eq!(
+            problems,
+            [
+                None,
+                Some(Problem::InvalidIp),
+                Some(Problem::MissingIp),
+                Some(Problem::InvalidPort),
+                None,
+                Some(Problem::MissingPort),
+                None,
+                Some(Problem::InvalidPort),
+                Some(Problem::InvalidPort),
+            ]
+        );
+    }
+}
@@ -0,0 +1,215 @@
+use std::net::Ipv4Addr;
+
+use bson::{doc, Bson, Document};
+use futures_util::{StreamExt, TryStreamExt};
+use tracing::{info, warn};
+
+use crate::database::{self, Database};
+
+/// The `ip` as a string like `1.2.3.4`, since older documents have it as a
+/// number. Otherwise a server that has both wouldn't be grouped together.
+fn ip_as_string() -> Document {
+    let octet = |shift: u32| {
+        doc! {
+            "$toString": {
+                "$toLong": {
+                    "$mod": [{ "$floor": { "$divide": ["$ip", 2_i64.pow(shift)] } }, 256]
+                }
+            }
+        }
+    };
+    doc! {
+        "$cond": [
+            { "$eq": [{ "$type": "$ip" }, "string"] },
+            "$ip",
+            { "$concat": [octet(24), ".", octet(16), ".", octet(8), ".", octet(0)] },
+        ]
+    }
+}
+
+/// Find the server documents that have the same ip and port, and merge each
+/// set of them into one document. Returns the number of documents that were
+/// deleted.
+///
+/// This has to happen before the unique ip+port index can be created, which
+/// then keeps new duplicates from being inserted.
+pub async fn merge(database: &Database) -> anyhow::Result<usize> {
+    let mut groups = database
+        .servers_coll()
+        .aggregate(vec![
+            doc! {
+                "$group": {
+                    "_id": { "ip": ip_as_string(), "port": "$port" },
+                    "ids": { "$push": "$_id" },
+                    "count": { "$sum": 1 },
+                }
+            },
+            doc! { "$match": { "count": { "$gt": 1 } } },
+        ])
+        .allow_disk_use(true)
+        .batch_size(database.config.cursor_batch_size)
+        .await?;
+
+    let mut merged_groups = 0;
+    let mut deleted = 0;
+    while let Some(group) = groups.next().await {
+        let group = group?;
+        let Ok(ids) = group.get_array("ids") else {
+            warn!("Duplicate group without ids: {group:?}");
+            continue;
+        };
+
+        let docs = database
+            .servers_coll()
+            .find(doc! { "_id": { "$in": ids.clone() } })
+            .await?
+            .try_collect::<Vec<_>>()
+            .await?;
+        let Some(merged) = merge_documents(docs) else {
+            continue;
+        };
+        let Some(id) = merged.get("_id").cloned() else {
+            continue;
+        };
+
+        database
+            .servers_coll()
+            .replace_one(doc! { "_id": id.clone() }, merged)
+            .await?;
+        let extra_ids = ids
+            .iter()
+            .filter(|i| **i != id)
+            .cloned()
+            .collect::<Vec<_>>();
+        let r = database
+            .servers_coll()
+            .delete_many(doc! { "_id": { "$in": extra_ids } })
+            .await?;
+
+        merged_groups += 1;
+        deleted += r.deleted_count as usize;
+    }
+
+    println!("merged {merged_groups} duplicated servers, deleted {deleted} documents");
+    info!("Merged {merged_groups} duplicated servers, deleted {deleted} documents");
+
+    Ok(deleted)
+}
+
+/// Merge documents for the same server into one. The newest one (by
+/// `lastSeen`) is kept, with the fields that only the older ones have added to
+/// it, and the players from all of them.
+fn merge_documents(mut docs: Vec<Document>) -> Option<Document> {
+    // newest first
+    docs.sort_by_key(|doc| std::cmp::Reverse(doc.get_datetime("lastSeen").ok().copied()));
+    let mut docs = docs.into_iter();
+    let mut merged = docs.next()?;
+
+    for doc in docs {
+        for (key, value) in doc {
+            if !merged.contains_key(&key) {
+                merged.insert(key, value);
+            } else if key == "players" {
+                if let (Ok(players), Bson::Document(other_players)) =
+                    (merged.get_document_mut("players"), value)
+                {
+                    merge_players(players, other_players);
+                }
+            }
+        }
+    }
+
+    // the newest one might have the ip as a number
+    if !matches!(merged.get("ip"), Some(Bson::String(_))) {
+        if let Some(ip) = database::get_u32(&merged, "ip") {
+            merged.insert("ip", Ipv4Addr::from(ip).to_string());
+        }
+    }
+
+    Some(merged)
+}
+
+/// Add the players from `other` to `players`, keeping whichever was seen more
+/// recently if a player is in both.
+fn merge_players(players: &mut Document, other: Document) {
+    let last_seen = |player: &Bson| {
+        player
+            .as_document()
+            .and_then(|p| p.get_datetime("lastSeen").ok().copied())
+    };
+    for (uuid, player) in other {
+        let is_newer = players
+            .get(&uuid)
+            .map_or(true, |existing| last_seen(&player) > last_seen(existing));
+        if is_newer {
+            players.insert(uuid, player);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn merges_duplicates() {
+        let old = bson::DateTime::from_millis(1_000);
+        let new = bson::DateTime::from_millis(2_000);
+        let docs = vec![
+            doc! {
+                "_id": 1,
+                "ip": "1.2.3.4",
+                "port": 25565,
+                "lastSeen": old,
+                "description": "old description",
+                "isCracked": false,
+                "players": {
+                    "069a79f444e94726a5befca90e38aaf5": { "name": "Notch", "lastSeen": old },
+                    "853c80ef3c3749fdaa49938b674adae6": { "name": "jeb_", "lastSeen": new },
+                },
+            },
+            doc! {
+                "_id": 2,
+                "ip": "1.2.3.4",
+                "port": 25565,
+                "lastSeen": new,
+                "description": "new description",
+                "players": {
+                    "853c80ef3c3749fdaa49938b674adae6": { "name": "jeb_", "lastSeen": old },
+                    "61699b2ed3274a019f1e46ec3d7745ef": { "name": "Dinnerbone", "lastSeen": new },
+                },
+            },
+        ];
+
+        let merged = merge_documents(docs).unwrap();
+        assert_eq!(
+            merged,
+            doc! {
+                "_id": 2,
+                "ip": "1.2.3.4",
+                "port": 25565,
+                "lastSeen": new,
+                "description": "new description",
+                "players": {
+                    "853c80ef3c3749fdaa49938b674adae6": { "name": "jeb_", "lastSeen": new },
+                    "61699b2ed3274a019f1e46ec3d7745ef": { "name": "Dinnerbone", "lastSeen": new },
+                    "069a79f444e94726a5befca90e38aaf5": { "name": "Notch", "lastSeen": old },
+                },
+                "isCracked": false,
+            }
+        );
+    }
+
+    #[test]
+    fn merged_ip_is_a_string() {
+        let ip = Ipv4Addr::new(1, 2, 3, 4);
+        let docs = vec![
+            doc! { "_id": 1, "ip": "1.2.3.4", "port": 25565, "lastSeen": bson::DateTime::from_millis(1_000) },
+            doc! { "_id": 2, "ip": u32::from(ip) as i64, "port": 25565, "lastSeen": bson::DateTime::from_millis(2_000) },
+        ];
+
+        let merged = merge_documents(docs).unwrap();
+        assert_eq!(merged.get("_id"), Some(&Bson::Int32(2)));
+        assert_eq!(merged.get_str("ip"), Ok("1.2.3.4"));
+    }
+}
@@ -0,0 +1,83 @@
+use std::net::Ipv4Addr;
+
+use rand::{seq::SliceRandom, Rng};
+
+use crate::scanner::targets::ScanRange;
+
+/// The number of /16s in the IPv4 space, which is how finely the ports are
+/// split up.
+const SLASH16_COUNT: u32 = 1 << 16;
+
+/// Scan the world on the given ports, where ports with a higher weight are
+/// scanned more densely. The port with the highest weight gets every /16, and
+/// the others get a random fraction of the /16s proportional to their weight.
+///
+/// The ranges alternate between ports so one port isn't scanned all at once.
+pub fn get_ranges(ports: &[(u16, f64)]) -> Vec<ScanRange> {
+    weighted_ranges(ports, &mut rand::thread_rng())
+}
+
+fn weighted_ranges(ports: &[(u16, f64)], rng: &mut impl Rng) -> Vec<ScanRange> {
+    let max_weight = ports.iter().map(|&(_, weight)| weight).fold(0., f64::max);
+    if max_weight <= 0. {
+        return Vec::new();
+    }
+
+    let slash16s_per_port = ports
+        .iter()
+        .map(|&(port, weight)| {
+            let fraction = (weight / max_weight).clamp(0., 1.);
+            let count = (fraction * SLASH16_COUNT as f64).round() as usize;
+
+            let mut slash16s = (0..SLASH16_COUNT).collect::<Vec<_>>();
+            let (chosen, _) = slash16s.partial_shuffle(rng, count);
+            (port, chosen.to_vec())
+        })
+        .collect::<Vec<_>>();
+
+    let mut ranges = Vec::new();
+    for i in 0..SLASH16_COUNT as usize {
+        for (port, slash16s) in &slash16s_per_port {
+            let Some(&slash16) = slash16s.get(i) else {
+                continue;
+            };
+            ranges.push(ScanRange::single_port(
+                Ipv4Addr::from(slash16 << 16),
+                Ipv4Addr::from((slash16 << 16) | 0xffff),
+                *port,
+            ));
+        }
+    }
+
+    ranges
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::StdRng, SeedableRng};
+
+    use super::*;
+
+    #[test]
+    fn ranges_follow_weights() {
+        let ports = [(25565, 1.), (25566, 0.25), (25567, 0.)];
+        let ranges = weighted_ranges(&ports, &mut StdRng::seed_from_u64(0));
+
+        let count = |port: u16| {
+            ranges
+                .iter()
+                .filter(|range| range.port_start == port)
+                .map(|range| range.count())
+                .sum::<usize>()
+        };
+        assert_eq!(count(25565), 1 << 32);
+        let ratio = count(25566) as f64 / count(25565) as f64;
+        assert!((ratio - 0.25).abs() < 0.01, "{ratio}");
+        assert_eq!(count(25567), 0);
+
+        // the ports are interleaved
+        assert_eq!(ranges[0].port_start, 25565);
+        assert_eq!(ranges[1].port_start, 25566);
+        assert_eq!(ranges[2].port_start, 25565);
+    }
+}
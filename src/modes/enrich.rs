@@ -0,0 +1,160 @@
+//! Looking something up for the IP of every server and storing it on them,
+//! which the [`super::ptr`] and [`super::geo`] modes both do.
+
+use std::{
+    collections::HashMap,
+    future::Future,
+    net::Ipv4Addr,
+    time::{Duration, SystemTime},
+};
+
+use bson::{doc, Bson, Document};
+use futures_util::StreamExt;
+use tracing::{info, warn};
+
+use crate::database::{
+    bulk_write::{BulkUpdate, BulkUpdateBuilder, CollectionExt},
+    Database,
+};
+
+/// The number of servers we update in one bulk write.
+const WRITE_BATCH_SIZE: usize = 1000;
+
+/// Look up the IPs of the servers where `updated_at_field` is missing or older
+/// than the TTL with `lookup_all`, and set the `fields` of what was found on
+/// them. IPs that weren't found only get `updated_at_field`, so they aren't
+/// looked up again until the TTL is over. Returns the number of IPs that were
+/// found.
+///
+/// `name` is what's being looked up, like "PTR records", for logging.
+pub async fn run<T, F, Fut>(
+    database: &Database,
+    name: &str,
+    updated_at_field: &str,
+    ttl: Duration,
+    lookup_all: F,
+    fields: impl Fn(&T) -> Document,
+) -> anyhow::Result<usize>
+where
+    F: FnOnce(Vec<Ipv4Addr>) -> Fut,
+    Fut: Future<Output = HashMap<Ipv4Addr, Option<T>>>,
+{
+    let stale_before = bson::DateTime::from(SystemTime::now() - ttl);
+    let mut cursor = database
+        .servers_coll()
+        .find(doc! {
+            "$or": [
+                { updated_at_field: { "$exists": false } },
+                { updated_at_field: { "$lt": stale_before } },
+            ]
+        })
+        .projection(doc! { "ip": 1, "_id": 1 })
+        .batch_size(database.config.cursor_batch_size)
+        .await?;
+
+    let mut servers_by_ip = HashMap::<Ipv4Addr, Vec<Bson>>::new();
+    while let Some(doc) = cursor.next().await {
+        let doc = doc?;
+        match (
+            doc.get_str("ip").ok().and_then(|ip| ip.parse().ok()),
+            doc.get("_id"),
+        ) {
+            (Some(ip), Some(id)) => servers_by_ip.entry(ip).or_default().push(id.clone()),
+            _ => warn!("Couldn't get IP for doc: {doc:?}"),
+        }
+    }
+
+    info!("Looking up {name} for {} IPs", servers_by_ip.len());
+    let results = lookup_all(servers_by_ip.keys().copied().collect()).await;
+    let found = results.values().filter(|r| r.is_some()).count();
+
+    let updates = updates(
+        &servers_by_ip,
+        &results,
+        updated_at_field,
+        bson::DateTime::now(),
+        fields,
+    )?;
+    for batch in updates.chunks(WRITE_BATCH_SIZE) {
+        database.write_limiter.acquire(batch.len()).await;
+        database
+            .servers_coll()
+            .bulk_update(&database.mcscanner_database(), batch, false)
+            .await?;
+    }
+
+    info!("Stored {name} for {found} IPs");
+    Ok(found)
+}
+
+/// Updates that set the fields of what was found for each server by its
+/// `_id`, along with `updated_at_field`. The servers on IPs that weren't found
+/// keep their old fields.
+pub fn updates<T>(
+    servers_by_ip: &HashMap<Ipv4Addr, Vec<Bson>>,
+    results: &HashMap<Ipv4Addr, Option<T>>,
+    updated_at_field: &str,
+    now: bson::DateTime,
+    fields: impl Fn(&T) -> Document,
+) -> anyhow::Result<Vec<BulkUpdate>> {
+    let mut updates = Vec::new();
+    for (ip, result) in results {
+        let mut set = result.as_ref().map(&fields).unwrap_or_default();
+        set.insert(updated_at_field, now);
+        for id in servers_by_ip.get(ip).into_iter().flatten() {
+            updates.push(
+                BulkUpdateBuilder::new()
+                    .set(set.clone())
+                    .build(doc! { "_id": id.clone() })?,
+            );
+        }
+    }
+    Ok(updates)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn failed_lookups_are_stamped() {
+        let now = bson::DateTime::from_millis(1_000);
+        let servers_by_ip = HashMap::from([
+            (
+                Ipv4Addr::new(1, 2, 3, 4),
+                vec![Bson::Int32(1), Bson::Int32(2)],
+            ),
+            (Ipv4Addr::new(5, 6, 7, 8), vec![Bson::Int32(3)]),
+        ]);
+        let ptrs = HashMap::from([
+            (
+                Ipv4Addr::new(1, 2, 3, 4),
+                Some("mc.example.com".to_string()),
+            ),
+            (Ipv4Addr::new(5, 6, 7, 8), None),
+        ]);
+
+        let mut updates = updates(&servers_by_ip, &ptrs, "ptrUpdatedAt", now, |ptr| {
+            doc! { "ptr": ptr }
+        })
+        .unwrap()
+        .into_iter()
+        .map(|u| (u.query, u.update))
+        .collect::<Vec<_>>();
+        updates.sort_by_key(|(query, _)| query.get_i32("_id").unwrap());
+        assert_eq!(
+            updates,
+            [
+                (
+                    doc! { "_id": 1 },
+                    doc! { "$set": { "ptr": "mc.example.com", "ptrUpdatedAt": now } }
+                ),
+                (
+                    doc! { "_id": 2 },
+                    doc! { "$set": { "ptr": "mc.example.com", "ptrUpdatedAt": now } }
+                ),
+                (doc! { "_id": 3 }, doc! { "$set": { "ptrUpdatedAt": now } }),
+            ]
+        );
+    }
+}
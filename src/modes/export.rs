@@ -0,0 +1,150 @@
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+};
+
+use bson::{doc, Bson, Document};
+use futures_util::TryStreamExt;
+use tracing::info;
+
+use crate::{config::ExportConfig, database::Database};
+
+/// Write the servers to a file as JSON lines, in the order of their `_id`s.
+///
+/// Instead of skipping documents (which gets slower the more are skipped), it
+/// continues after the last `_id` that was exported, so a big export can be
+/// done in chunks by setting `after_id` to the one that was printed at the end
+/// of the previous chunk. Returns the number of servers that were exported.
+pub async fn run(database: &Database, config: &ExportConfig) -> anyhow::Result<usize> {
+    let after_id = match &config.after_id {
+        Some(id) => Some(Bson::ObjectId(id.parse()?)),
+        None => None,
+    };
+    let mut pager = Pager::new(after_id, config.limit, config.page_size.unwrap_or(1000));
+    let mut out = BufWriter::new(File::create(&config.path)?);
+
+    let mut exported = 0;
+    while let Some((filter, limit)) = pager.next_query() {
+        let page = database
+            .servers_coll()
+            .find(filter)
+            .sort(doc! { "_id": 1 })
+            .limit(limit)
+            .await?
+            .try_collect::<Vec<_>>()
+            .await?;
+        for doc in &page {
+            serde_json::to_writer(
+                &mut out,
+                &Bson::Document(doc.clone()).into_relaxed_extjson(),
+            )?;
+            out.write_all(b"\n")?;
+        }
+        exported += page.len();
+        pager.advance(&page);
+    }
+    out.flush()?;
+
+    match pager.after_id().and_then(|id| id.as_object_id()) {
+        Some(id) => println!("exported {exported} servers, the last _id was {id}"),
+        None => println!("exported {exported} servers"),
+    }
+    info!("Exported {exported} servers to {:?}", config.path);
+
+    Ok(exported)
+}
+
+/// Keeps track of where the next page of an `_id`-ordered export starts.
+struct Pager {
+    after_id: Option<Bson>,
+    /// How many more documents can be exported, if there's a limit.
+    remaining: Option<usize>,
+    page_size: usize,
+    done: bool,
+}
+
+impl Pager {
+    fn new(after_id: Option<Bson>, limit: Option<usize>, page_size: usize) -> Self {
+        Self {
+            after_id,
+            remaining: limit,
+            page_size: page_size.max(1),
+            done: false,
+        }
+    }
+
+    /// The filter and limit for the next page, or None if the export is done.
+    fn next_query(&self) -> Option<(Document, i64)> {
+        let limit = match self.remaining {
+            Some(remaining) => remaining.min(self.page_size),
+            None => self.page_size,
+        };
+        if self.done || limit == 0 {
+            return None;
+        }
+        let filter = match &self.after_id {
+            Some(after_id) => doc! { "_id": { "$gt": after_id } },
+            None => doc! {},
+        };
+        Some((filter, limit as i64))
+    }
+
+    fn advance(&mut self, page: &[Document]) {
+        if let Some(remaining) = &mut self.remaining {
+            *remaining = remaining.saturating_sub(page.len());
+        }
+        match page.last().and_then(|doc| doc.get("_id")) {
+            Some(id) => self.after_id = Some(id.clone()),
+            None => self.done = true,
+        }
+        if page.len() < self.page_size {
+            // there's nothing after this page
+            self.done = true;
+        }
+    }
+
+    fn after_id(&self) -> Option<&Bson> {
+        self.after_id.as_ref()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Run the query like mongo would on documents sorted by `_id`.
+    fn query(docs: &[Document], filter: &Document, limit: i64) -> Vec<Document> {
+        let after = filter
+            .get_document("_id")
+            .ok()
+            .and_then(|id| id.get_i32("$gt").ok());
+        docs.iter()
+            .filter(|doc| after.map_or(true, |after| doc.get_i32("_id").unwrap() > after))
+            .take(limit as usize)
+            .cloned()
+            .collect()
+    }
+
+    fn export(docs: &[Document], after_id: Option<Bson>, limit: Option<usize>) -> Vec<i32> {
+        let mut pager = Pager::new(after_id, limit, 2);
+        let mut exported = Vec::new();
+        while let Some((filter, limit)) = pager.next_query() {
+            let page = query(docs, &filter, limit);
+            exported.extend(page.iter().map(|doc| doc.get_i32("_id").unwrap()));
+            pager.advance(&page);
+        }
+        exported
+    }
+
+    #[test]
+    fn pages_cover_every_document_once() {
+        let docs = (1..=5).map(|id| doc! { "_id": id }).collect::<Vec<_>>();
+
+        assert_eq!(export(&docs, None, None), [1, 2, 3, 4, 5]);
+
+        // in chunks of 3, resuming after the last _id of the previous chunk
+        assert_eq!(export(&docs, None, Some(3)), [1, 2, 3]);
+        assert_eq!(export(&docs, Some(Bson::Int32(3)), Some(3)), [4, 5]);
+        assert!(export(&docs, Some(Bson::Int32(5)), Some(3)).is_empty());
+    }
+}
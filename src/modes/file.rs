@@ -0,0 +1,81 @@
+use std::{fs, net::Ipv4Addr, path::Path, str::FromStr};
+
+use tracing::warn;
+
+use crate::scanner::targets::ScanRange;
+
+/// Read the targets to scan from a file.
+///
+/// Each line is either `ip:port` or `ip/prefix:port`, and the port defaults to
+/// 25565 if it's omitted. Everything after a `#` is ignored. Invalid lines are
+/// skipped with a warning.
+pub fn get_ranges(path: &Path) -> anyhow::Result<Vec<ScanRange>> {
+    let input = fs::read_to_string(path)?;
+
+    Ok(parse(&input))
+}
+
+fn parse(input: &str) -> Vec<ScanRange> {
+    let mut ranges = Vec::new();
+
+    for (i, line) in input.lines().enumerate() {
+        // remove everything after the first #
+        let line = line.split('#').next().unwrap().trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        match parse_line(line) {
+            Some(range) => ranges.push(range),
+            None => warn!("Invalid target on line {}: {line:?}", i + 1),
+        }
+    }
+
+    ranges
+}
+
+fn parse_line(line: &str) -> Option<ScanRange> {
+    let (addr, port) = match line.split_once(':') {
+        Some((addr, port)) => (addr, port.parse::<u16>().ok()?),
+        None => (line, 25565),
+    };
+
+    if let Some((ip, prefix)) = addr.split_once('/') {
+        let prefix = prefix.parse::<u8>().ok()?;
+        ScanRange::cidr(Ipv4Addr::from_str(ip).ok()?, prefix, port)
+    } else {
+        Some(ScanRange::single(Ipv4Addr::from_str(addr).ok()?, port))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_targets_file() {
+        let input = "
+# some servers
+1.2.3.4:25565
+5.6.7.8:25566 # trailing comment
+10.0.0.0/30:25565
+9.9.9.9
+not an ip:25565
+1.2.3.4:99999
+1.2.3.0/33:25565
+";
+        assert_eq!(
+            parse(input),
+            vec![
+                ScanRange::single(Ipv4Addr::new(1, 2, 3, 4), 25565),
+                ScanRange::single(Ipv4Addr::new(5, 6, 7, 8), 25566),
+                ScanRange::single_port(
+                    Ipv4Addr::new(10, 0, 0, 0),
+                    Ipv4Addr::new(10, 0, 0, 3),
+                    25565,
+                ),
+                ScanRange::single(Ipv4Addr::new(9, 9, 9, 9), 25565),
+            ]
+        );
+    }
+}
@@ -1,12 +1,35 @@
 use std::{
+    collections::HashMap,
+    future::Future,
     net::{Ipv4Addr, SocketAddrV4},
-    time::{Duration, SystemTime},
+    sync::Arc,
+    time::{Duration, Instant, SystemTime},
 };
 
 use bson::{doc, Document};
 use futures_util::StreamExt;
+use parking_lot::Mutex;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+    sync::Semaphore,
+    task::JoinSet,
+};
+
+use crate::{
+    config::FingerprintingConfig,
+    database::{self, store::ServerStore},
+    modes::rescan::{self, ServerField, Sort},
+    processing::SharedData,
+    scanner::protocols::{MinecraftFingerprinting, Protocol},
+    shutdown::ShutdownToken,
+};
 
-use crate::database::{self, Database};
+/// How long we wait for a server to respond to the fingerprint request.
+const FINGERPRINT_TIMEOUT: Duration = Duration::from_secs(10);
+/// The most bytes we read from each server. The errors we're looking for are
+/// a lot shorter than this.
+const MAX_RESPONSE_BYTES: usize = 64 * 1024;
 
 // pub async fn get_addrs_and_protocol_versions(
 //     database: &Database,
@@ -62,12 +85,13 @@ use crate::database::{self, Database};
 // }
 
 pub async fn get_addrs_and_protocol_versions(
-    database: &Database,
+    store: &dyn ServerStore,
+    now: SystemTime,
 ) -> anyhow::Result<Vec<(SocketAddrV4, i32)>> {
     let mut results = Vec::new();
 
-    let two_hours_ago = SystemTime::now() - Duration::from_secs(60 * 60 * 2);
-    let over_a_week_ago = SystemTime::now() - Duration::from_secs(60 * 60 * 24 * 7);
+    let two_hours_ago = now - Duration::from_secs(60 * 60 * 2);
+    let over_a_week_ago = now - Duration::from_secs(60 * 60 * 24 * 7);
     let filter = doc! {
         "lastSeen": {
             "$gt": bson::DateTime::from(two_hours_ago),
@@ -91,42 +115,34 @@ pub async fn get_addrs_and_protocol_versions(
     println!("Over a week ago: {:?}", over_a_week_ago);
     println!("Filter: {:?}", filter);
 
-    let mut pipeline: Vec<Document> = vec![doc! { "$match": filter.clone() }];
-    pipeline.push(doc! { "$project": { "ip": 1, "port": 1, "protocol": 1, "_id": 0 } });
-    pipeline.push(doc! { "$sort": { "timestamp": 1 } });
-    println!("Full pipeline: {:?}", pipeline);
-
-    let mut cursor = database.servers_coll().find(filter).await?;
+    let mut docs = store.aggregate_servers(pipeline(filter)).await?;
 
     let mut count = 0;
     let mut ip_missing = 0;
     let mut port_missing = 0;
-    while let Some(result) = cursor.next().await {
+    while let Some(result) = docs.next().await {
         count += 1;
         if count % 1000 == 0 {
             println!("Processed {} documents", count);
         }
 
         let doc = result?;
-        let ip_str = doc.get_str("ip").ok();
+        let ip = doc
+            .get_str("ip")
+            .ok()
+            .and_then(|ip| ip.parse::<Ipv4Addr>().ok());
         let port = database::get_u32(&doc, "port");
 
-        if ip_str.is_none() {
+        if ip.is_none() {
             ip_missing += 1;
         }
         if port.is_none() {
             port_missing += 1;
         }
 
-        if let (Some(ip_str), Some(port)) = (ip_str, port) {
-            let protocol_version = doc.get_i32("protocol").unwrap_or(47);
-            let addr = ip_str.parse::<Ipv4Addr>().unwrap();
-            results.push((SocketAddrV4::new(addr, port as u16), protocol_version));
-        }
-
-        // Debug print for every 10000th document
-        if count % 10000 == 0 {
-            println!("Sample document: {:?}", doc);
+        if let (Some(ip), Some(port)) = (ip, port) {
+            let protocol_version = database::get_i32(&doc, "protocol").unwrap_or(47);
+            results.push((SocketAddrV4::new(ip, port as u16), protocol_version));
         }
     }
 
@@ -137,3 +153,260 @@ pub async fn get_addrs_and_protocol_versions(
 
     Ok(results)
 }
+
+/// Only get the fields we need, with the servers that need to be fingerprinted
+/// the most first.
+fn pipeline(filter: Document) -> Vec<Document> {
+    let mut pipeline = vec![doc! { "$match": filter }];
+    pipeline.extend(
+        Sort::LeastRecentlyFingerprinted
+            .sort_doc()
+            .map(|sort| doc! { "$sort": sort }),
+    );
+    pipeline.push(doc! { "$project": rescan::projection(&[ServerField::Protocol], false) });
+    pipeline
+}
+
+/// Actively fingerprint the servers, and queue their responses to be processed
+/// like the scanner's responses are. Returns the number of servers that were
+/// fingerprinted.
+pub async fn run(
+    targets: Vec<(SocketAddrV4, i32)>,
+    config: &FingerprintingConfig,
+    shutdown: &ShutdownToken,
+    shared: Arc<Mutex<SharedData>>,
+) -> usize {
+    let protocol = Arc::new(MinecraftFingerprinting::new(
+        targets.iter().copied().collect(),
+    ));
+    fingerprint_limited(
+        targets.into_iter().map(|(addr, _)| addr).collect(),
+        config.concurrency.unwrap_or(64),
+        Duration::from_millis(config.per_host_delay_ms.unwrap_or(1000)),
+        shutdown,
+        move |addr| {
+            let request = protocol.payload(addr);
+            let shared = shared.clone();
+            async move {
+                let start = Instant::now();
+                if let Some(response) = fingerprint_server(addr, &request).await {
+                    shared
+                        .lock()
+                        .queue
+                        .push_back((addr, response, Some(start.elapsed())));
+                }
+            }
+        },
+    )
+    .await
+}
+
+/// Send the fingerprint request and return whatever the server sent back
+/// before it closed the connection, or None if it didn't send anything.
+async fn fingerprint_server(addr: SocketAddrV4, request: &[u8]) -> Option<Vec<u8>> {
+    let mut response = Vec::new();
+    let _ = tokio::time::timeout(FINGERPRINT_TIMEOUT, async {
+        let mut stream = TcpStream::connect(addr).await?;
+        stream.write_all(request).await?;
+        let mut buf = [0; 4096];
+        while response.len() < MAX_RESPONSE_BYTES {
+            let n = stream.read(&mut buf).await?;
+            if n == 0 {
+                break;
+            }
+            response.extend_from_slice(&buf[..n]);
+        }
+        anyhow::Ok(())
+    })
+    .await;
+
+    (!response.is_empty()).then_some(response)
+}
+
+/// Run `fingerprint` on each of the targets, with at most `concurrency` of
+/// them at once and at least `per_host_delay` between starting targets on the
+/// same IP, so a server with a lot of ports doesn't get hammered. The targets
+/// are interleaved by IP first so the ones waiting for their host's turn
+/// don't hold up the rest. Once `shutdown` is cancelled the targets that
+/// haven't started yet are skipped. Returns the number of targets that were
+/// fingerprinted.
+pub async fn fingerprint_limited<F, Fut>(
+    targets: Vec<SocketAddrV4>,
+    concurrency: usize,
+    per_host_delay: Duration,
+    shutdown: &ShutdownToken,
+    fingerprint: F,
+) -> usize
+where
+    F: Fn(SocketAddrV4) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    let semaphore = Arc::new(Semaphore::new(concurrency.max(1)));
+    let fingerprint = Arc::new(fingerprint);
+    // when the next target on each ip is allowed to start
+    let mut next_starts = HashMap::<Ipv4Addr, Instant>::new();
+    let mut tasks = JoinSet::new();
+
+    for addr in interleave_by_ip(targets) {
+        let permit = tokio::select! {
+            permit = semaphore.clone().acquire_owned() => permit.expect("the semaphore is never closed"),
+            _ = shutdown.cancelled() => break,
+        };
+
+        let now = Instant::now();
+        let start = next_starts
+            .get(addr.ip())
+            .map_or(now, |&next_start| next_start.max(now));
+        next_starts.insert(*addr.ip(), start + per_host_delay);
+
+        let (fingerprint, shutdown) = (fingerprint.clone(), shutdown.clone());
+        tasks.spawn(async move {
+            tokio::select! {
+                _ = tokio::time::sleep_until(start.into()) => {}
+                _ = shutdown.cancelled() => return false,
+            }
+            fingerprint(addr).await;
+            drop(permit);
+            true
+        });
+    }
+
+    let mut fingerprinted = 0;
+    while let Some(result) = tasks.join_next().await {
+        if result.unwrap_or_default() {
+            fingerprinted += 1;
+        }
+    }
+    fingerprinted
+}
+
+/// Reorder the targets so they go through the IPs round-robin, keeping the
+/// order of the ports on each IP.
+fn interleave_by_ip(targets: Vec<SocketAddrV4>) -> Vec<SocketAddrV4> {
+    let mut by_ip = Vec::<Vec<SocketAddrV4>>::new();
+    let mut ip_indexes = HashMap::<Ipv4Addr, usize>::new();
+    for addr in targets {
+        let index = *ip_indexes.entry(*addr.ip()).or_insert_with(|| {
+            by_ip.push(Vec::new());
+            by_ip.len() - 1
+        });
+        by_ip[index].push(addr);
+    }
+
+    let mut interleaved = Vec::new();
+    for round in 0..by_ip.iter().map(Vec::len).max().unwrap_or_default() {
+        interleaved.extend(by_ip.iter().filter_map(|addrs| addrs.get(round)));
+    }
+    interleaved
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use crate::database::memory::MemoryStore;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn gets_servers_that_need_fingerprinting() {
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000);
+        let ago = |secs| bson::DateTime::from(now - Duration::from_secs(secs));
+        let store = MemoryStore::new(vec![
+            doc! { "ip": "1.1.1.1", "port": 25565, "lastSeen": ago(60), "protocol": 767,
+            "fingerprintTimestamp": ago(60 * 60 * 24 * 30) },
+            // fingerprinted recently
+            doc! { "ip": "2.2.2.2", "port": 25565, "lastSeen": ago(60), "fingerprintTimestamp": ago(60) },
+            // offline
+            doc! { "ip": "3.3.3.3", "port": 25565, "lastSeen": ago(60 * 60 * 24) },
+            // never fingerprinted, so it's first
+            doc! { "ip": "4.4.4.4", "port": 25566, "lastSeen": ago(60) },
+        ]);
+
+        let addr = |ip: [u8; 4], port| SocketAddrV4::new(Ipv4Addr::from(ip), port);
+        assert_eq!(
+            get_addrs_and_protocol_versions(&store, now).await.unwrap(),
+            [
+                (addr([4, 4, 4, 4], 25566), 47),
+                (addr([1, 1, 1, 1], 25565), 767)
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn stays_under_the_concurrency_cap_and_spaces_out_hosts() {
+        let delay = Duration::from_millis(30);
+        let running = Arc::new(AtomicUsize::new(0));
+        let max_running = Arc::new(AtomicUsize::new(0));
+        let starts = Arc::new(Mutex::new(HashMap::<Ipv4Addr, Vec<Instant>>::new()));
+        let (_trigger, shutdown) = ShutdownToken::new();
+
+        // 5 hosts with 4 ports each
+        let targets = (1..=5)
+            .flat_map(|i| {
+                (0..4).map(move |port| SocketAddrV4::new(Ipv4Addr::new(1, 1, 1, i), 25565 + port))
+            })
+            .collect();
+        let fingerprinted = fingerprint_limited(targets, 3, delay, &shutdown, {
+            let (running, max_running, starts) =
+                (running.clone(), max_running.clone(), starts.clone());
+            move |addr| {
+                let (running, max_running, starts) =
+                    (running.clone(), max_running.clone(), starts.clone());
+                async move {
+                    starts
+                        .lock()
+                        .entry(*addr.ip())
+                        .or_default()
+                        .push(Instant::now());
+                    let now_running = running.fetch_add(1, Ordering::SeqCst) + 1;
+                    max_running.fetch_max(now_running, Ordering::SeqCst);
+                    tokio::time::sleep(Duration::from_millis(5)).await;
+                    running.fetch_sub(1, Ordering::SeqCst);
+                }
+            }
+        })
+        .await;
+
+        assert_eq!(fingerprinted, 20);
+        assert!(max_running.load(Ordering::SeqCst) <= 3);
+        let starts = starts.lock();
+        assert_eq!(starts.len(), 5);
+        for starts in starts.values() {
+            assert_eq!(starts.len(), 4);
+            for pair in starts.windows(2) {
+                assert!(pair[1] - pair[0] >= delay);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn stops_on_shutdown() {
+        let (trigger, shutdown) = ShutdownToken::new();
+        let trigger = Arc::new(trigger);
+        let targets = (0..5)
+            .map(|port| SocketAddrV4::new(Ipv4Addr::new(1, 1, 1, 1), port))
+            .collect();
+
+        let started = Instant::now();
+        let fingerprinted =
+            fingerprint_limited(targets, 5, Duration::from_secs(60), &shutdown, move |_| {
+                let trigger = trigger.clone();
+                async move { trigger.cancel() }
+            })
+            .await;
+
+        // only the first one started before we were shut down
+        assert_eq!(fingerprinted, 1);
+        assert!(started.elapsed() < Duration::from_secs(60));
+    }
+
+    #[test]
+    fn interleaves_targets_by_ip() {
+        let addr = |ip: u8, port| SocketAddrV4::new(Ipv4Addr::new(1, 1, 1, ip), port);
+        assert_eq!(
+            interleave_by_ip(vec![addr(1, 1), addr(1, 2), addr(1, 3), addr(2, 1)]),
+            [addr(1, 1), addr(2, 1), addr(1, 2), addr(1, 3)]
+        );
+    }
+}
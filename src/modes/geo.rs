@@ -0,0 +1,171 @@
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr},
+    time::Duration,
+};
+
+use bson::{doc, Document};
+use maxminddb::{geoip2, Reader};
+
+use crate::{config::GeoConfig, database::Database, modes::enrich};
+
+/// The number of characters in the geohashes we store. 9 characters is a cell
+/// of about 5 meters, which is far more precise than the city database is.
+const GEOHASH_LENGTH: usize = 9;
+const GEOHASH_ALPHABET: &[u8; 32] = b"0123456789bcdefghjkmnpqrstuvwxyz";
+
+pub trait GeoLookup {
+    /// Look up the latitude and longitude of the IP, or None if the database
+    /// doesn't have coordinates for it.
+    fn lookup_coordinates(&self, ip: Ipv4Addr) -> Option<(f64, f64)>;
+}
+
+impl GeoLookup for Reader<Vec<u8>> {
+    fn lookup_coordinates(&self, ip: Ipv4Addr) -> Option<(f64, f64)> {
+        let city: geoip2::City = self.lookup(IpAddr::V4(ip)).ok()?;
+        let location = city.location?;
+        Some((location.latitude?, location.longitude?))
+    }
+}
+
+/// The coordinates and geohash of an IP.
+type Location = (f64, f64, String);
+
+/// Look up the coordinates for the IPs of servers that don't have them or
+/// where they're older than the TTL, and store them as `lat`, `lon` and
+/// `geohash`. IPs without coordinates only get `geoUpdatedAt`, so they aren't
+/// looked up again until the TTL is over. Returns the number of IPs that got
+/// coordinates.
+pub async fn run(database: &Database, config: &GeoConfig) -> anyhow::Result<usize> {
+    let reader = Reader::open_readfile(&config.city_database)?;
+
+    enrich::run(
+        database,
+        "coordinates",
+        "geoUpdatedAt",
+        Duration::from_secs(config.ttl_secs.unwrap_or(60 * 60 * 24 * 30)),
+        |ips| async move { lookup_all(&reader, ips) },
+        location_fields,
+    )
+    .await
+}
+
+/// Look up the coordinates and geohash for the IPs. IPs without coordinates
+/// are None.
+pub fn lookup_all(
+    lookup: &impl GeoLookup,
+    ips: impl IntoIterator<Item = Ipv4Addr>,
+) -> HashMap<Ipv4Addr, Option<Location>> {
+    ips.into_iter()
+        .map(|ip| {
+            let location = lookup
+                .lookup_coordinates(ip)
+                .map(|(lat, lon)| (lat, lon, geohash(lat, lon, GEOHASH_LENGTH)));
+            (ip, location)
+        })
+        .collect()
+}
+
+fn location_fields((lat, lon, geohash): &Location) -> Document {
+    doc! { "lat": lat, "lon": lon, "geohash": geohash }
+}
+
+/// Encode the coordinates as a geohash with the given number of characters.
+pub fn geohash(lat: f64, lon: f64, length: usize) -> String {
+    let mut lat_range = (-90.0, 90.0);
+    let mut lon_range = (-180.0, 180.0);
+
+    let mut hash = String::with_capacity(length);
+    // geohashes interleave the bits starting with longitude
+    let mut is_lon = true;
+    let mut bits = 0;
+    let mut char_index = 0;
+    while hash.len() < length {
+        let (range, value) = if is_lon {
+            (&mut lon_range, lon)
+        } else {
+            (&mut lat_range, lat)
+        };
+        let mid = (range.0 + range.1) / 2.;
+        char_index <<= 1;
+        if value >= mid {
+            char_index |= 1;
+            range.0 = mid;
+        } else {
+            range.1 = mid;
+        }
+        is_lon = !is_lon;
+
+        bits += 1;
+        if bits == 5 {
+            hash.push(GEOHASH_ALPHABET[char_index] as char);
+            bits = 0;
+            char_index = 0;
+        }
+    }
+    hash
+}
+
+#[cfg(test)]
+mod tests {
+    use bson::Bson;
+
+    use super::*;
+
+    struct MockLookup;
+
+    impl GeoLookup for MockLookup {
+        fn lookup_coordinates(&self, ip: Ipv4Addr) -> Option<(f64, f64)> {
+            // pretend every other ip isn't in the database
+            (ip == Ipv4Addr::new(1, 2, 3, 4)).then_some((57.64911, 10.40744))
+        }
+    }
+
+    #[test]
+    fn known_ip_has_expected_geohash() {
+        let ips = [Ipv4Addr::new(1, 2, 3, 4), Ipv4Addr::new(5, 6, 7, 8)];
+        let locations = lookup_all(&MockLookup, ips);
+        assert_eq!(locations[&Ipv4Addr::new(5, 6, 7, 8)], None);
+        let (lat, lon, geohash) = locations[&Ipv4Addr::new(1, 2, 3, 4)].as_ref().unwrap();
+        assert_eq!((*lat, *lon), (57.64911, 10.40744));
+        assert!(geohash.starts_with("u4pruyd"), "{geohash}");
+    }
+
+    #[test]
+    fn ips_without_coordinates_are_stamped() {
+        let now = bson::DateTime::from_millis(1_000);
+        let servers_by_ip = HashMap::from([
+            (Ipv4Addr::new(1, 2, 3, 4), vec![Bson::Int32(1)]),
+            (Ipv4Addr::new(5, 6, 7, 8), vec![Bson::Int32(2)]),
+        ]);
+        let locations = lookup_all(&MockLookup, servers_by_ip.keys().copied());
+
+        let mut updates = enrich::updates(
+            &servers_by_ip,
+            &locations,
+            "geoUpdatedAt",
+            now,
+            location_fields,
+        )
+        .unwrap()
+        .into_iter()
+        .map(|u| (u.query, u.update))
+        .collect::<Vec<_>>();
+        updates.sort_by_key(|(query, _)| query.get_i32("_id").unwrap());
+        assert_eq!(updates[0].0, doc! { "_id": 1 });
+        assert!(updates[0]
+            .1
+            .get_document("$set")
+            .unwrap()
+            .contains_key("geohash"));
+        assert_eq!(
+            updates[1],
+            (doc! { "_id": 2 }, doc! { "$set": { "geoUpdatedAt": now } })
+        );
+    }
+
+    #[test]
+    fn geohash_of_origin() {
+        assert_eq!(geohash(0., 0., 5), "s0000");
+    }
+}
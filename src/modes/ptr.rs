@@ -0,0 +1,92 @@
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr},
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use bson::doc;
+use futures_util::{stream, StreamExt};
+use hickory_resolver::TokioAsyncResolver;
+
+use crate::{config::PtrConfig, database::Database, modes::enrich};
+
+#[async_trait]
+pub trait PtrResolver {
+    /// Look up the PTR record for the IP, or None if it doesn't have one or
+    /// the lookup failed.
+    async fn lookup_ptr(&self, ip: Ipv4Addr) -> Option<String>;
+}
+
+#[async_trait]
+impl PtrResolver for TokioAsyncResolver {
+    async fn lookup_ptr(&self, ip: Ipv4Addr) -> Option<String> {
+        let lookup = self.reverse_lookup(IpAddr::V4(ip)).await.ok()?;
+        let name = lookup.iter().next()?.to_utf8();
+        Some(name.trim_end_matches('.').to_string())
+    }
+}
+
+/// Look up the PTR records for the IPs of servers that don't have one or where
+/// it's older than the TTL, and store them as `ptr`. IPs that fail to resolve
+/// only get `ptrUpdatedAt`, so they aren't looked up again until the TTL is
+/// over. Returns the number of IPs that got a PTR.
+pub async fn run(database: &Database, config: &PtrConfig) -> anyhow::Result<usize> {
+    let resolver = TokioAsyncResolver::tokio_from_system_conf()?;
+    let concurrency = config.concurrency.unwrap_or(50);
+
+    enrich::run(
+        database,
+        "PTR records",
+        "ptrUpdatedAt",
+        Duration::from_secs(config.ttl_secs.unwrap_or(60 * 60 * 24 * 30)),
+        |ips| lookup_all(&resolver, ips, concurrency),
+        |ptr: &String| doc! { "ptr": ptr },
+    )
+    .await
+}
+
+/// Look up the PTR records for the IPs, doing at most `concurrency` lookups at
+/// once. IPs without a PTR are None.
+pub async fn lookup_all(
+    resolver: &(impl PtrResolver + Sync),
+    ips: impl IntoIterator<Item = Ipv4Addr>,
+    concurrency: usize,
+) -> HashMap<Ipv4Addr, Option<String>> {
+    stream::iter(ips)
+        .map(|ip| async move { (ip, resolver.lookup_ptr(ip).await) })
+        .buffer_unordered(concurrency.max(1))
+        .collect()
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct MockResolver;
+
+    #[async_trait]
+    impl PtrResolver for MockResolver {
+        async fn lookup_ptr(&self, ip: Ipv4Addr) -> Option<String> {
+            // pretend every other ip is NXDOMAIN
+            (ip == Ipv4Addr::new(1, 2, 3, 4)).then(|| "mc.example.com".to_string())
+        }
+    }
+
+    #[tokio::test]
+    async fn stores_known_ptr() {
+        let ips = [Ipv4Addr::new(1, 2, 3, 4), Ipv4Addr::new(5, 6, 7, 8)];
+        let ptrs = lookup_all(&MockResolver, ips, 2).await;
+        assert_eq!(
+            ptrs,
+            HashMap::from([
+                (
+                    Ipv4Addr::new(1, 2, 3, 4),
+                    Some("mc.example.com".to_string())
+                ),
+                (Ipv4Addr::new(5, 6, 7, 8), None),
+            ])
+        );
+    }
+}
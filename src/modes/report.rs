@@ -0,0 +1,103 @@
+use std::{collections::HashMap, net::Ipv4Addr};
+
+use bson::doc;
+use futures_util::StreamExt;
+use tracing::{info, warn};
+
+use crate::{
+    asns::{self, AsnRanges},
+    database::{bad_ips, Database},
+};
+
+/// Count the bad IPs in each ASN and print the `top_asns` ASNs with the most,
+/// to help decide whether a whole provider should be excluded. The bad IPs
+/// that aren't in any ASN are left out.
+pub async fn bad_ips_by_asn(
+    database: &Database,
+    top_asns: usize,
+) -> anyhow::Result<Vec<(u32, usize)>> {
+    let mut cursor = database
+        .bad_servers_coll()
+        .find(doc! {})
+        .projection(doc! { "ip": 1, "_id": 0 })
+        .batch_size(database.config.cursor_batch_size)
+        .await?;
+
+    let mut ips = Vec::new();
+    while let Some(doc) = cursor.next().await {
+        let doc = doc?;
+        match bad_ips::ip_from_doc(&doc) {
+            Some(ip) => ips.push(ip),
+            None => warn!("Couldn't get IP for bad ip doc: {doc:?}"),
+        }
+    }
+
+    let asns = asns::get().await?;
+    let mut counts = group_by_asn(&ips, asns);
+    counts.truncate(top_asns);
+
+    println!("asn        bad ips");
+    for (asn, count) in &counts {
+        println!("{:<10} {count}", format!("AS{asn}"));
+    }
+    info!("Got the top {} ASNs by bad IPs", counts.len());
+
+    Ok(counts)
+}
+
+/// The number of IPs in each ASN, with the most first.
+fn group_by_asn(ips: &[Ipv4Addr], asns: &AsnRanges) -> Vec<(u32, usize)> {
+    let mut counts = HashMap::<u32, usize>::new();
+    for &ip in ips {
+        if let Some(asn) = asns.get_asn(ip) {
+            *counts.entry(asn).or_default() += 1;
+        }
+    }
+    let mut counts = counts.into_iter().collect::<Vec<_>>();
+    counts
+        .sort_by(|(a_asn, a_count), (b_asn, b_count)| b_count.cmp(a_count).then(a_asn.cmp(b_asn)));
+    counts
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::scanner::targets::Ipv4Range;
+
+    use super::*;
+
+    #[test]
+    fn groups_bad_ips_by_asn() {
+        let asns = AsnRanges(vec![
+            (
+                Ipv4Range {
+                    start: Ipv4Addr::new(1, 0, 0, 0),
+                    end: Ipv4Addr::new(1, 0, 0, 255),
+                },
+                13335,
+            ),
+            (
+                Ipv4Range {
+                    start: Ipv4Addr::new(2, 0, 0, 0),
+                    end: Ipv4Addr::new(2, 0, 255, 255),
+                },
+                16276,
+            ),
+        ]);
+        let fixture = [
+            doc! { "ip": "2.0.0.1" },
+            doc! { "ip": "1.0.0.1" },
+            doc! { "ip": "2.0.5.5" },
+            // stored as a number
+            doc! { "ip": u32::from(Ipv4Addr::new(2, 0, 10, 10)) as i64 },
+            // not in any asn
+            doc! { "ip": "3.3.3.3" },
+        ];
+        let ips = fixture
+            .iter()
+            .filter_map(bad_ips::ip_from_doc)
+            .collect::<Vec<_>>();
+        assert_eq!(ips.len(), 5);
+
+        assert_eq!(group_by_asn(&ips, &asns), [(16276, 3), (13335, 1)]);
+    }
+}
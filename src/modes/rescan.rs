@@ -1,16 +1,23 @@
 use std::{
-    net::Ipv4Addr,
+    collections::HashSet,
+    future,
+    net::{Ipv4Addr, SocketAddrV4},
     time::{Duration, SystemTime},
 };
 
-use bson::{doc, Document};
-use futures_util::StreamExt;
+use bson::{doc, Bson, Document};
+use futures_util::{stream::BoxStream, StreamExt};
 use serde::Deserialize;
 use tracing::warn;
 
 use crate::{
-    database::{self, Database},
-    scanner::targets::ScanRange,
+    config::RescanConfig,
+    database::{
+        self,
+        store::{RescanCheckpoint, ServerStore},
+        Database,
+    },
+    scanner::targets::{RangeProgress, ScanRange},
 };
 
 #[derive(Deserialize, Clone, Copy, Debug)]
@@ -18,112 +25,840 @@ use crate::{
 pub enum Sort {
     Random,
     Oldest,
+    /// Servers that were never fingerprinted first, and then the ones that
+    /// were fingerprinted the longest time ago.
+    LeastRecentlyFingerprinted,
+    /// The highest score first, where the score is `staleness_weight` times
+    /// the seconds since the last ping plus `players_weight` times the online
+    /// players. Busy servers are rescanned sooner than with `Oldest`, and empty
+    /// ones still get their turn eventually.
+    Weighted {
+        staleness_weight: f64,
+        players_weight: f64,
+    },
 }
 
+impl Sort {
+    /// The document for the `$sort`, or None if the order is random.
+    pub fn sort_doc(self) -> Option<Document> {
+        match self {
+            Sort::Random => None,
+            Sort::Oldest => Some(doc! { "lastSeen": 1 }),
+            // mongo sorts missing fields before everything else when ascending
+            Sort::LeastRecentlyFingerprinted => Some(doc! { "fingerprintTimestamp": 1 }),
+            Sort::Weighted { .. } => Some(doc! { "rescanScore": -1 }),
+        }
+    }
+
+    /// The `$addFields` stage that computes the fields the sort needs, if it
+    /// sorts by something that isn't stored.
+    pub fn score_stage(self, now: SystemTime) -> Option<Document> {
+        let Sort::Weighted {
+            staleness_weight,
+            players_weight,
+        } = self
+        else {
+            return None;
+        };
+        let now = bson::DateTime::from(now);
+        Some(doc! {
+            "$addFields": {
+                "rescanScore": {
+                    "$add": [
+                        {
+                            "$multiply": [
+                                staleness_weight,
+                                // subtracting dates gives milliseconds
+                                { "$divide": [{ "$subtract": [now, { "$ifNull": ["$lastSeen", now] }] }, 1000] }
+                            ]
+                        },
+                        { "$multiply": [players_weight, { "$ifNull": ["$onlinePlayers", 0] }] }
+                    ]
+                }
+            }
+        })
+    }
+}
+
+/// The maximum number of ranges a single rescan will return if
+/// `max_ranges` isn't set, to avoid using too much memory.
+pub const DEFAULT_MAX_RANGES: usize = 10_000_000;
+
+/// A field that can be got for each server besides its address, for modes
+/// that need more than that.
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ServerField {
+    /// The protocol version the server responded with.
+    Protocol,
+}
+
+impl ServerField {
+    fn key(self) -> &'static str {
+        match self {
+            ServerField::Protocol => "protocol",
+        }
+    }
+}
+
+/// A server that was picked for rescanning.
+pub struct RescanServer {
+    pub addr: SocketAddrV4,
+    /// The server's protocol version, or None if `protocol` wasn't in the
+    /// rescan's `fields` or the server doesn't have one.
+    pub protocol: Option<i32>,
+    /// Where the rescan carries on from if it's restarted after this server.
+    /// Only set if the rescan is checkpointed.
+    pub checkpoint: Option<RescanCheckpoint>,
+}
+
+/// Where a rescan carries on from next time. It's only saved once the
+/// servers the rescan picked were pinged and their responses were processed,
+/// since otherwise the ones that weren't would be skipped.
+pub struct PendingCheckpoint {
+    name: String,
+    /// The last server that was picked, or None if every server was picked
+    /// and the next rescan should start from the beginning.
+    last: Option<RescanCheckpoint>,
+}
+
+impl PendingCheckpoint {
+    pub async fn save(&self, store: &dyn ServerStore) -> anyhow::Result<()> {
+        store
+            .save_rescan_checkpoint(&self.name, self.last.as_ref())
+            .await
+    }
+}
+
+/// Get the servers to rescan based on the given config. `progress` is updated
+/// as ranges are emitted. This doesn't save the rescan's checkpoint, see
+/// [`get_ranges_and_checkpoint`].
 pub async fn get_ranges(
     database: &Database,
-    extra_filter: &toml::Table,
-    rescan_every_secs: u64,
-    players_online_ago_max_secs: Option<u64>,
-    last_ping_ago_max_secs: u64,
-    limit: Option<usize>,
-    sort: Option<Sort>,
+    rescan: &RescanConfig,
+    progress: &RangeProgress,
 ) -> anyhow::Result<Vec<ScanRange>> {
+    Ok(get_ranges_and_checkpoint(database, rescan, progress)
+        .await?
+        .0)
+}
+
+/// Get the servers to rescan, and the checkpoint to save once they're
+/// scanned if the rescan is checkpointed.
+pub async fn get_ranges_and_checkpoint(
+    database: &Database,
+    rescan: &RescanConfig,
+    progress: &RangeProgress,
+) -> anyhow::Result<(Vec<ScanRange>, Option<PendingCheckpoint>)> {
+    let max_ranges = rescan.max_ranges.unwrap_or(DEFAULT_MAX_RANGES);
+
+    if let Some(limit) = rescan.limit {
+        progress.set_total(limit);
+    }
+
     let mut ranges = Vec::new();
 
-    let mut filter = doc! {
-        "lastSeen": {
-            "$gt": bson::DateTime::from(SystemTime::now() - Duration::from_secs(last_ping_ago_max_secs)),
-            "$lt": bson::DateTime::from(SystemTime::now() - Duration::from_secs(rescan_every_secs))
+    let mut bad_ips = database
+        .shared
+        .lock()
+        .bad_ips
+        .iter()
+        .collect::<HashSet<_>>();
+
+    let mut picked = 0;
+    let mut last_checkpoint = None;
+
+    let mut servers = servers_to_rescan(database, rescan, SystemTime::now()).await?;
+    while let Some(server) = servers.next().await {
+        picked += 1;
+        // the servers after max_ranges are dropped, so they aren't scanned
+        if picked <= max_ranges && server.checkpoint.is_some() {
+            last_checkpoint = server.checkpoint;
+        }
+
+        let (ip, port) = (*server.addr.ip(), server.addr.port());
+        // there shouldn't be any bad ips...
+        if bad_ips.contains(&ip) && Some(port) != database.protected_port {
+            println!("we encountered a bad ip while getting ips to rescan :/ deleting {ip} from database.");
+            database
+                .remove_servers_for_ip(ip, database.protected_port)
+                .await?;
+            // this doesn't actually remove it from the bad_ips database, it just makes it
+            // so we don't delete twice
+            bad_ips.remove(&ip);
+            continue;
+        }
+
+        let range = ScanRange::single(ip, port);
+        progress.record(&range);
+        ranges.push(range);
+        if ranges.len() % 1000 == 0 {
+            println!("{progress}");
+        }
+    }
+
+    let is_truncated = truncate_to_max_ranges(&mut ranges, max_ranges);
+    if is_truncated {
+        warn!("Rescan returned more than {max_ranges} ranges, only the first {max_ranges} will be scanned");
+    }
+
+    let got_every_server = !is_truncated && rescan.limit.map_or(true, |limit| picked < limit);
+    let checkpoint = checkpoint_name(rescan).map(|name| PendingCheckpoint {
+        name: name.to_string(),
+        last: if got_every_server {
+            None
+        } else {
+            last_checkpoint
+        },
+    });
+
+    Ok((ranges, checkpoint))
+}
+
+/// The servers that a rescan with the given config picks, in the order
+/// they'll be scanned. If the rescan is checkpointed and a checkpoint was
+/// saved, only the servers after it are picked.
+pub async fn servers_to_rescan<'a>(
+    store: &'a dyn ServerStore,
+    rescan: &RescanConfig,
+    now: SystemTime,
+) -> anyhow::Result<BoxStream<'a, RescanServer>> {
+    let mut filter = build_filter(rescan, now)?;
+
+    let checkpoint_name = checkpoint_name(rescan);
+    if let Some(name) = rescan
+        .checkpoint
+        .as_deref()
+        .filter(|_| checkpoint_name.is_none())
+    {
+        warn!("Ignoring rescan checkpoint {name:?}, checkpoints only work with the oldest sort");
+    }
+    if let Some(name) = checkpoint_name {
+        if let Some(checkpoint) = store.load_rescan_checkpoint(name).await? {
+            println!("resuming rescan from checkpoint {name:?}: {checkpoint:?}");
+            merge_filter(&mut filter, after_checkpoint_filter(&checkpoint));
+        }
+    }
+    println!("filter: {:?}", filter);
+
+    let sort = rescan.sort.unwrap_or(Sort::Oldest);
+    let max_ranges = rescan.max_ranges.unwrap_or(DEFAULT_MAX_RANGES);
+    let checkpointed = checkpoint_name.is_some();
+    let pipeline = build_pipeline(
+        filter,
+        sort,
+        rescan.limit,
+        max_ranges,
+        now,
+        projection(&rescan.fields, checkpointed),
+        checkpointed,
+    );
+
+    let docs = store.aggregate_servers(pipeline).await?;
+    Ok(docs
+        .take_while(|doc| future::ready(doc.is_ok()))
+        .filter_map(move |doc| {
+            future::ready(doc.ok().and_then(|doc| {
+                Some(RescanServer {
+                    addr: server_addr(&doc)?,
+                    protocol: database::get_i32(&doc, "protocol"),
+                    checkpoint: checkpointed.then(|| server_checkpoint(&doc)).flatten(),
+                })
+            }))
+        })
+        .boxed())
+}
+
+/// The name of the rescan's checkpoint, or None if it isn't checkpointed or
+/// its sort can't be resumed.
+fn checkpoint_name(rescan: &RescanConfig) -> Option<&str> {
+    match rescan.sort.unwrap_or(Sort::Oldest) {
+        Sort::Oldest => rescan.checkpoint.as_deref(),
+        _ => None,
+    }
+}
+
+/// Only the servers that come after the checkpoint in the `Oldest` order.
+/// Servers with the same `lastSeen` are ordered by their `_id`.
+fn after_checkpoint_filter(checkpoint: &RescanCheckpoint) -> Document {
+    doc! {
+        "$or": [
+            { "lastSeen": { "$gt": checkpoint.last_seen } },
+            { "lastSeen": checkpoint.last_seen, "_id": { "$gt": checkpoint.id.clone() } },
+        ]
+    }
+}
+
+/// Only get the servers' addresses and the fields that were asked for, plus
+/// what the checkpoint needs if it's checkpointed.
+pub fn projection(fields: &[ServerField], checkpointed: bool) -> Document {
+    let mut projection = doc! { "ip": 1, "port": 1 };
+    for field in fields {
+        projection.insert(field.key(), 1);
+    }
+    if checkpointed {
+        projection.insert("lastSeen", 1);
+        projection.insert("_id", 1);
+    } else {
+        projection.insert("_id", 0);
+    }
+    projection
+}
+
+fn server_checkpoint(doc: &Document) -> Option<RescanCheckpoint> {
+    Some(RescanCheckpoint {
+        last_seen: *doc.get_datetime("lastSeen").ok()?,
+        id: doc.get("_id")?.clone(),
+    })
+}
+
+fn server_addr(doc: &Document) -> Option<SocketAddrV4> {
+    let ip_str = match doc.get_str("ip") {
+        Ok(ip) => ip,
+        Err(_) => {
+            warn!("Couldn't get IP for doc: {:?}", doc);
+            return None;
+        }
+    };
+
+    let ip = match ip_str.parse::<Ipv4Addr>() {
+        Ok(addr) => addr,
+        Err(_) => {
+            warn!("Invalid IP address: {}", ip_str);
+            return None;
+        }
+    };
+
+    let Some(port) = database::get_u32(doc, "port") else {
+        warn!("couldn't get port for doc: {doc:?}");
+        return None;
+    };
+    Some(SocketAddrV4::new(ip, port as u16))
+}
+
+fn build_filter(rescan: &RescanConfig, now: SystemTime) -> anyhow::Result<Document> {
+    let rescan_every_secs = rescan.rescan_every_secs;
+    let last_ping_ago_max_secs = rescan.last_ping_ago_max_secs.unwrap_or(60 * 60 * 2);
+
+    let last_seen_after = bson::DateTime::from(now - Duration::from_secs(last_ping_ago_max_secs));
+    let mut filter = if let Some([min_protocol, max_protocol]) = rescan.protocol_range {
+        // a targeted rescan, which wants every matching server
+        doc! { "protocol": { "$gte": min_protocol, "$lte": max_protocol } }
+    } else if rescan.adaptive_interval {
+        // there's no lower bound, since an interval can be longer than
+        // last_ping_ago_max_secs and those servers would never be rescanned
+        doc! {
+            // lastSeen < now - (rescanInterval ?? rescan_every_secs)
+            "$expr": {
+                "$lt": [
+                    "$lastSeen",
+                    {
+                        "$subtract": [
+                            bson::DateTime::from(now),
+                            { "$multiply": [{ "$ifNull": ["$rescanInterval", rescan_every_secs as i64] }, 1000] }
+                        ]
+                    }
+                ]
+            }
+        }
+    } else {
+        doc! {
+            "lastSeen": {
+                "$gt": last_seen_after,
+                "$lt": bson::DateTime::from(now - Duration::from_secs(rescan_every_secs))
+            }
         }
     };
 
-    for (key, value) in extra_filter {
+    for (key, value) in &rescan.filter {
         filter.insert(key, bson::to_bson(&value)?);
     }
+    if rescan.only_incomplete {
+        // servers that we haven't gotten the full data for yet
+        merge_filter(
+            &mut filter,
+            doc! {
+                "$or": [
+                    { "isCracked": { "$exists": false } },
+                    { "version": { "$exists": false } },
+                ]
+            },
+        );
+    }
 
-    if let Some(players_online_ago_max_secs) = players_online_ago_max_secs {
+    if let Some(players_online_ago_max_secs) = rescan.players_online_ago_max_secs {
         filter.insert(
             "lastActive",
             doc! {
-                "$gt": bson::DateTime::from(SystemTime::now() - Duration::from_secs(players_online_ago_max_secs))
+                "$gt": bson::DateTime::from(now - Duration::from_secs(players_online_ago_max_secs))
             },
         );
     }
 
-    println!("filter: {:?}", filter);
+    Ok(filter)
+}
 
-    let mut bad_ips = database.shared.lock().bad_ips.to_owned();
+/// Add the conditions in `extra` to the filter. Keys that are in both (like
+/// two `$or`s) are combined with an `$and` so neither of them is lost.
+fn merge_filter(filter: &mut Document, extra: Document) {
+    for (key, value) in extra {
+        match filter.remove(&key) {
+            Some(existing) => {
+                let mut and = match filter.remove("$and") {
+                    Some(Bson::Array(and)) => and,
+                    _ => Vec::new(),
+                };
+                and.push(Bson::Document(doc! { &key: existing }));
+                and.push(Bson::Document(doc! { key: value }));
+                filter.insert("$and", and);
+            }
+            None => {
+                filter.insert(key, value);
+            }
+        }
+    }
+}
 
+fn build_pipeline(
+    filter: Document,
+    sort: Sort,
+    limit: Option<usize>,
+    max_ranges: usize,
+    now: SystemTime,
+    projection: Document,
+    checkpointed: bool,
+) -> Vec<Document> {
     let mut pipeline: Vec<Document> = Vec::new();
     pipeline.push(doc! { "$match": filter });
-    pipeline.push(doc! { "$project": { "ip": 1, "port": 1, "_id": 0 } });
-
-    let sort = sort.unwrap_or(Sort::Oldest);
+    pipeline.extend(sort.score_stage(now));
 
-    match sort {
-        Sort::Random => {
+    match sort.sort_doc() {
+        None => {
             pipeline.push(doc! { "$sample": { "size": limit.unwrap_or(10000000) as i64 } });
         }
-        Sort::Oldest => {
-            pipeline.push(doc! { "$sort": { "lastSeen": 1 } });
+        Some(mut sort) => {
+            if checkpointed {
+                // the checkpoint needs a total order to resume from
+                sort.insert("_id", 1);
+            }
+            pipeline.push(doc! { "$sort": sort });
             if let Some(limit) = limit {
                 pipeline.push(doc! { "$limit": limit as i64 });
             }
         }
     }
 
-    let mut cursor = database
-        .servers_coll()
-        .aggregate(pipeline)
-        .batch_size(2000)
-        .await
-        .unwrap();
+    // this has to be after the sort, since it removes the fields we sort by
+    pipeline.push(doc! { "$project": projection });
 
-    while let Some(Ok(doc)) = cursor.next().await {
-        let ip_str = match doc.get_str("ip") {
-            Ok(ip) => ip,
-            Err(_) => {
-                warn!("Couldn't get IP for doc: {:?}", doc);
-                continue;
-            }
+    // get one more than the maximum so we know whether it was exceeded
+    pipeline.push(doc! { "$limit": max_ranges as i64 + 1 });
+
+    pipeline
+}
+
+/// Truncate the ranges to `max_ranges`, returning whether there were too many.
+fn truncate_to_max_ranges(ranges: &mut Vec<ScanRange>, max_ranges: usize) -> bool {
+    if ranges.len() > max_ranges {
+        ranges.truncate(max_ranges);
+        true
+    } else {
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::database::memory::MemoryStore;
+
+    use super::*;
+
+    fn server(ip: &str, last_seen_secs: u64) -> Document {
+        doc! {
+            "ip": ip,
+            "port": 25565,
+            "lastSeen": bson::DateTime::from(
+                SystemTime::UNIX_EPOCH + Duration::from_secs(last_seen_secs)
+            ),
+        }
+    }
+
+    async fn selected(store: &MemoryStore, rescan: &RescanConfig, now: SystemTime) -> Vec<String> {
+        servers_to_rescan(store, rescan, now)
+            .await
+            .unwrap()
+            .map(|server| server.addr.ip().to_string())
+            .collect()
+            .await
+    }
+
+    #[tokio::test]
+    async fn selects_servers_in_the_time_window() {
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000);
+        let store = MemoryStore::new(vec![
+            // pinged too recently
+            server("1.1.1.1", 999_990),
+            server("2.2.2.2", 999_500),
+            // too long ago
+            server("3.3.3.3", 998_000),
+            server("4.4.4.4", 999_100),
+            doc! { "ip": "5.5.5.5", "port": 25565 },
+        ]);
+        let rescan = RescanConfig {
+            rescan_every_secs: 60,
+            last_ping_ago_max_secs: Some(1000),
+            ..Default::default()
         };
+        // the oldest first
+        assert_eq!(selected(&store, &rescan, now).await, ["4.4.4.4", "2.2.2.2"]);
 
-        let ip = match ip_str.parse::<Ipv4Addr>() {
-            Ok(addr) => addr,
-            Err(_) => {
-                warn!("Invalid IP address: {}", ip_str);
-                continue;
-            }
+        let rescan = RescanConfig {
+            limit: Some(1),
+            ..rescan
         };
+        assert_eq!(selected(&store, &rescan, now).await, ["4.4.4.4"]);
+    }
 
-        let Some(port) = database::get_u32(&doc, "port") else {
-            warn!("couldn't get port for doc: {doc:?}");
-            continue;
+    #[tokio::test]
+    async fn selects_incomplete_servers_with_the_custom_filter() {
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000);
+        let mut complete = server("1.1.1.1", 999_500);
+        complete.insert("isCracked", false);
+        complete.insert("version", doc! { "name": "1.21" });
+        let mut other_port = server("2.2.2.2", 999_500);
+        other_port.insert("port", 25566);
+        let store = MemoryStore::new(vec![complete, other_port, server("3.3.3.3", 999_500)]);
+
+        let rescan = RescanConfig {
+            rescan_every_secs: 60,
+            last_ping_ago_max_secs: Some(1000),
+            only_incomplete: true,
+            filter: toml::toml! { port = 25565 },
+            ..Default::default()
         };
-        // there shouldn't be any bad ips...
-        if bad_ips.contains(&ip) && port != 25565 {
-            println!("we encountered a bad ip while getting ips to rescan :/ deleting {ip} from database.");
-            database
-                .client
-                .database("cope_new")
-                .collection::<bson::Document>("cachedservers")
-                .delete_many(doc! {
-                    "ip": ip_str,
-                    "port": { "$ne": 25565 }
+        assert_eq!(selected(&store, &rescan, now).await, ["3.3.3.3"]);
+    }
+
+    #[test]
+    fn max_ranges_applies_to_every_sort() {
+        for sort in [
+            Sort::Random,
+            Sort::Oldest,
+            Sort::LeastRecentlyFingerprinted,
+            Sort::Weighted {
+                staleness_weight: 1.,
+                players_weight: 15.,
+            },
+        ] {
+            let pipeline = build_pipeline(
+                doc! {},
+                sort,
+                Some(1000),
+                10,
+                SystemTime::now(),
+                projection(&[], false),
+                false,
+            );
+            assert_eq!(pipeline.last(), Some(&doc! { "$limit": 11_i64 }));
+
+            let pipeline = build_pipeline(
+                doc! {},
+                sort,
+                None,
+                10,
+                SystemTime::now(),
+                projection(&[], false),
+                false,
+            );
+            assert_eq!(pipeline.last(), Some(&doc! { "$limit": 11_i64 }));
+        }
+    }
+
+    #[test]
+    fn least_recently_fingerprinted_first() {
+        let pipeline = build_pipeline(
+            doc! {},
+            Sort::LeastRecentlyFingerprinted,
+            Some(5),
+            10,
+            SystemTime::now(),
+            projection(&[], false),
+            false,
+        );
+        assert_eq!(
+            pipeline,
+            vec![
+                doc! { "$match": {} },
+                doc! { "$sort": { "fingerprintTimestamp": 1 } },
+                doc! { "$limit": 5_i64 },
+                doc! { "$project": { "ip": 1, "port": 1, "_id": 0 } },
+                doc! { "$limit": 11_i64 },
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn weighted_sort_by_score() {
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000);
+        let fixture = [
+            // (ip, lastSeen, onlinePlayers)
+            ("1.1.1.1", 999_000, 0),
+            ("2.2.2.2", 999_900, 50),
+            ("3.3.3.3", 999_500, 10),
+            ("4.4.4.4", 999_800, 40),
+        ];
+        let store = MemoryStore::new(
+            fixture
+                .iter()
+                .map(|&(ip, last_seen, players)| {
+                    let mut server = server(ip, last_seen);
+                    server.insert("onlinePlayers", players as i32);
+                    server
                 })
-                .await?;
-            // this doesn't actually remove it from the bad_ips database, it just makes it
-            // so we don't delete twice
-            bad_ips.remove(&ip);
-            continue;
+                .collect(),
+        );
+
+        let (staleness_weight, players_weight) = (1., 15.);
+        let rescan = RescanConfig {
+            rescan_every_secs: 60,
+            last_ping_ago_max_secs: Some(10_000),
+            sort: Some(Sort::Weighted {
+                staleness_weight,
+                players_weight,
+            }),
+            ..Default::default()
+        };
+
+        let mut expected = fixture
+            .iter()
+            .map(|&(ip, last_seen, players)| {
+                let staleness = (1_000_000 - last_seen) as f64;
+                (
+                    ip,
+                    staleness_weight * staleness + players_weight * players as f64,
+                )
+            })
+            .collect::<Vec<_>>();
+        expected.sort_by(|(_, a), (_, b)| b.total_cmp(a));
+        // 1000, 850, 800, 650
+        assert_eq!(
+            expected.iter().map(|(ip, _)| *ip).collect::<Vec<_>>(),
+            ["1.1.1.1", "2.2.2.2", "4.4.4.4", "3.3.3.3"]
+        );
+        assert_eq!(
+            selected(&store, &rescan, now).await,
+            expected.iter().map(|(ip, _)| *ip).collect::<Vec<_>>()
+        );
+    }
+
+    #[tokio::test]
+    async fn resumes_after_the_checkpoint() {
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000);
+        let store = MemoryStore::new(
+            [
+                ("1.1.1.1", 999_100),
+                // the same lastSeen, so the _id decides the order
+                ("2.2.2.2", 999_200),
+                ("3.3.3.3", 999_200),
+                ("4.4.4.4", 999_300),
+                ("5.5.5.5", 999_400),
+            ]
+            .iter()
+            .enumerate()
+            .map(|(i, &(ip, last_seen))| {
+                let mut server = server(ip, last_seen);
+                server.insert("_id", i as i32);
+                server
+            })
+            .collect(),
+        );
+        let rescan = RescanConfig {
+            rescan_every_secs: 60,
+            last_ping_ago_max_secs: Some(1000),
+            checkpoint: Some("test".to_string()),
+            ..Default::default()
+        };
+
+        // get three servers, and then save the checkpoint like they were scanned
+        let mut servers = servers_to_rescan(&store, &rescan, now).await.unwrap();
+        let mut first_ips = Vec::new();
+        let mut last = None;
+        for _ in 0..3 {
+            let server = servers.next().await.unwrap();
+            first_ips.push(server.addr.ip().to_string());
+            last = server.checkpoint;
         }
+        drop(servers);
+        assert_eq!(first_ips, ["1.1.1.1", "2.2.2.2", "3.3.3.3"]);
+        let checkpoint = |last| PendingCheckpoint {
+            name: "test".to_string(),
+            last,
+        };
+        checkpoint(last).save(&store).await.unwrap();
 
-        ranges.push(ScanRange::single(ip, port as u16));
-        if ranges.len() % 1000 == 0 {
-            println!("{} ips", ranges.len());
+        assert_eq!(selected(&store, &rescan, now).await, ["4.4.4.4", "5.5.5.5"]);
+
+        // once it got through every server the next rescan starts over
+        checkpoint(None).save(&store).await.unwrap();
+        assert_eq!(selected(&store, &rescan, now).await.len(), 5);
+
+        // a random order isn't resumed
+        checkpoint(server_checkpoint(
+            &doc! { "_id": 2, "lastSeen": bson::DateTime::from_millis(999_200_000) },
+        ))
+        .save(&store)
+        .await
+        .unwrap();
+        let rescan = RescanConfig {
+            sort: Some(Sort::Random),
+            ..rescan
+        };
+        assert_eq!(selected(&store, &rescan, now).await.len(), 5);
+    }
+
+    #[tokio::test]
+    async fn gets_the_protocol_if_its_requested() {
+        async fn protocols(
+            store: &MemoryStore,
+            rescan: RescanConfig,
+            now: SystemTime,
+        ) -> Vec<(String, Option<i32>)> {
+            servers_to_rescan(store, &rescan, now)
+                .await
+                .unwrap()
+                .map(|server| (server.addr.ip().to_string(), server.protocol))
+                .collect()
+                .await
         }
+
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000);
+        let mut with_protocol = server("1.1.1.1", 999_500);
+        with_protocol.insert("protocol", 767);
+        let store = MemoryStore::new(vec![with_protocol, server("2.2.2.2", 999_400)]);
+
+        let rescan = RescanConfig {
+            rescan_every_secs: 60,
+            last_ping_ago_max_secs: Some(1000),
+            ..Default::default()
+        };
+        assert_eq!(
+            protocols(
+                &store,
+                RescanConfig {
+                    fields: vec![ServerField::Protocol],
+                    ..rescan.clone()
+                },
+                now
+            )
+            .await,
+            [
+                ("2.2.2.2".to_string(), None),
+                ("1.1.1.1".to_string(), Some(767))
+            ]
+        );
+        // the protocol isn't projected so it's never there
+        assert_eq!(
+            protocols(&store, rescan, now).await,
+            [("2.2.2.2".to_string(), None), ("1.1.1.1".to_string(), None)]
+        );
     }
 
-    Ok(ranges)
+    #[test]
+    fn only_incomplete_filter() {
+        let rescan = RescanConfig {
+            rescan_every_secs: 60,
+            only_incomplete: true,
+            ..Default::default()
+        };
+        let filter = build_filter(&rescan, SystemTime::now()).unwrap();
+        // complete documents have both fields, so they can't match
+        assert_eq!(
+            filter.get_array("$or").unwrap(),
+            &vec![
+                Bson::Document(doc! { "isCracked": { "$exists": false } }),
+                Bson::Document(doc! { "version": { "$exists": false } }),
+            ]
+        );
+        assert!(filter.contains_key("lastSeen"));
+
+        let rescan = RescanConfig {
+            only_incomplete: false,
+            ..rescan
+        };
+        let filter = build_filter(&rescan, SystemTime::now()).unwrap();
+        assert!(!filter.contains_key("$or"));
+    }
+
+    #[test]
+    fn adaptive_interval_filter() {
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000);
+        let rescan = RescanConfig {
+            rescan_every_secs: 60,
+            last_ping_ago_max_secs: Some(1000),
+            adaptive_interval: true,
+            ..Default::default()
+        };
+        let filter = build_filter(&rescan, now).unwrap();
+        assert_eq!(
+            filter,
+            doc! {
+                "$expr": {
+                    "$lt": [
+                        "$lastSeen",
+                        {
+                            "$subtract": [
+                                bson::DateTime::from_millis(1_000_000_000),
+                                { "$multiply": [{ "$ifNull": ["$rescanInterval", 60_i64] }, 1000] }
+                            ]
+                        }
+                    ]
+                }
+            }
+        );
+    }
+
+    #[test]
+    fn protocol_range_filter() {
+        let rescan = RescanConfig {
+            rescan_every_secs: 60,
+            protocol_range: Some([763, 765]),
+            filter: toml::toml! { isCracked = true },
+            ..Default::default()
+        };
+        let filter = build_filter(&rescan, SystemTime::now()).unwrap();
+        // the time window isn't used, so servers that were pinged recently are
+        // included too
+        assert_eq!(
+            filter,
+            doc! {
+                "protocol": { "$gte": 763, "$lte": 765 },
+                "isCracked": true,
+            }
+        );
+    }
+
+    #[test]
+    fn merged_filters_keep_both_conditions() {
+        let mut filter = doc! { "$or": [{ "a": 1 }] };
+        merge_filter(&mut filter, doc! { "$or": [{ "b": 1 }], "c": 1 });
+        assert_eq!(
+            filter,
+            doc! {
+                "$and": [{ "$or": [{ "a": 1 }] }, { "$or": [{ "b": 1 }] }],
+                "c": 1,
+            }
+        );
+    }
+
+    #[test]
+    fn truncates_to_max_ranges() {
+        let mut ranges = (0..11)
+            .map(|i| ScanRange::single(Ipv4Addr::from(i), 25565))
+            .collect::<Vec<_>>();
+        assert!(truncate_to_max_ranges(&mut ranges, 10));
+        assert_eq!(ranges.len(), 10);
+
+        assert!(!truncate_to_max_ranges(&mut ranges, 10));
+        assert_eq!(ranges.len(), 10);
+    }
 }
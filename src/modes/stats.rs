@@ -0,0 +1,127 @@
+use bson::{doc, Document};
+use futures_util::StreamExt;
+use tracing::{info, warn};
+
+use crate::database::{self, Database};
+
+/// How many servers are on a port, and how many of them are cracked.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PortStats {
+    pub port: u16,
+    pub servers: u64,
+    pub cracked: u64,
+}
+
+impl PortStats {
+    /// The fraction of the servers on this port that are cracked.
+    pub fn cracked_ratio(&self) -> f64 {
+        if self.servers == 0 {
+            0.
+        } else {
+            self.cracked as f64 / self.servers as f64
+        }
+    }
+}
+
+/// Count the servers on each port, and print the `top_ports` ports with the
+/// most servers. The number of servers on each version is printed too.
+pub async fn run(database: &Database, top_ports: usize) -> anyhow::Result<Vec<PortStats>> {
+    let mut cursor = database
+        .servers_coll()
+        .aggregate(per_port_pipeline(top_ports))
+        .batch_size(database.config.cursor_batch_size)
+        .await?;
+
+    let mut stats = Vec::new();
+    while let Some(doc) = cursor.next().await {
+        let doc = doc?;
+        match parse_port_stats(&doc) {
+            Some(port_stats) => stats.push(port_stats),
+            None => warn!("Couldn't parse port stats: {doc:?}"),
+        }
+    }
+
+    println!("port   servers  cracked");
+    for port_stats in &stats {
+        println!(
+            "{:<6} {:<8} {:.1}%",
+            port_stats.port,
+            port_stats.servers,
+            port_stats.cracked_ratio() * 100.
+        );
+    }
+    info!("Got stats for the top {} ports", stats.len());
+
+    println!("version    servers");
+    for (version, servers) in database.count_by_resolved_version().await? {
+        println!("{version:<10} {servers}");
+    }
+
+    Ok(stats)
+}
+
+fn per_port_pipeline(top_ports: usize) -> Vec<Document> {
+    vec![
+        doc! {
+            "$group": {
+                "_id": "$port",
+                "servers": { "$sum": 1 },
+                "cracked": {
+                    "$sum": { "$cond": [{ "$eq": ["$isCracked", true] }, 1, 0] }
+                },
+            }
+        },
+        doc! { "$sort": { "servers": -1, "_id": 1 } },
+        doc! { "$limit": top_ports as i64 },
+    ]
+}
+
+fn parse_port_stats(doc: &Document) -> Option<PortStats> {
+    Some(PortStats {
+        port: database::get_u32(doc, "_id")?.try_into().ok()?,
+        servers: database::get_u32(doc, "servers")? as u64,
+        cracked: database::get_u32(doc, "cracked")? as u64,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn per_port_counts() {
+        // what the $group stage returns for 3 servers on 25565 (one cracked) and 1
+        // cracked server on 25566
+        let fixture = [
+            doc! { "_id": 25565, "servers": 3, "cracked": 1 },
+            doc! { "_id": 25566_i64, "servers": 1, "cracked": 1 },
+            doc! { "_id": null, "servers": 2, "cracked": 0 },
+        ];
+        let stats = fixture
+            .iter()
+            .filter_map(parse_port_stats)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            stats,
+            [
+                PortStats {
+                    port: 25565,
+                    servers: 3,
+                    cracked: 1
+                },
+                PortStats {
+                    port: 25566,
+                    servers: 1,
+                    cracked: 1
+                },
+            ]
+        );
+        assert!((stats[0].cracked_ratio() - 1. / 3.).abs() < f64::EPSILON);
+        assert_eq!(stats[1].cracked_ratio(), 1.);
+
+        assert_eq!(
+            per_port_pipeline(10).last(),
+            Some(&doc! { "$limit": 10_i64 })
+        );
+    }
+}
@@ -0,0 +1,249 @@
+use std::{
+    collections::{HashMap, HashSet},
+    future::Future,
+    net::SocketAddrV4,
+    time::Duration,
+};
+
+use bson::doc;
+use futures_util::{future::join_all, StreamExt};
+use parking_lot::Mutex;
+use rand::Rng;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+};
+use tracing::{info, warn};
+
+use crate::{
+    database::{
+        self, bad_ip_journal::BadIpJournal, bad_ips, store::ServerStore, Database,
+        DatabaseSharedData,
+    },
+    processing::{
+        description::{self, DescriptionFormatter, Plain},
+        minecraft,
+    },
+    scanner::protocols::{self, ParseResponseError, Protocol, Response},
+};
+
+/// The number of random ports we ping on each bad IP.
+const PORTS_TO_CHECK: usize = 20;
+const PING_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Re-check a random sample of the bad IPs by pinging a few random ports on
+/// each of them, and un-flag the ones that don't respond with the same server
+/// on most of them anymore. Returns the number of IPs that were un-flagged.
+pub async fn run(database: &Database, sample_size: usize) -> anyhow::Result<usize> {
+    verify_sample(
+        database,
+        &database.shared,
+        database.bad_ip_journal.as_deref(),
+        sample_size,
+        ping,
+    )
+    .await
+}
+
+/// [`run`], with where the bad IPs are kept and how ports are pinged passed in
+/// so it can be tested.
+async fn verify_sample<F, Fut>(
+    store: &dyn ServerStore,
+    shared: &Mutex<DatabaseSharedData>,
+    journal: Option<&BadIpJournal>,
+    sample_size: usize,
+    ping: F,
+) -> anyhow::Result<usize>
+where
+    F: Fn(SocketAddrV4) -> Fut,
+    Fut: Future<Output = Option<u64>>,
+{
+    let mut cursor = store
+        .aggregate_bad_servers(vec![doc! { "$sample": { "size": sample_size as i64 } }])
+        .await?;
+
+    let mut ips = Vec::new();
+    while let Some(doc) = cursor.next().await {
+        let doc = doc?;
+        match bad_ips::ip_from_doc(&doc) {
+            Some(ip) => ips.push(ip),
+            None => warn!("Couldn't get IP for bad ip doc: {doc:?}"),
+        }
+    }
+
+    let mut unflagged = 0;
+    for &ip in &ips {
+        let ports = random_ports(PORTS_TO_CHECK);
+        let hashes = join_all(
+            ports
+                .into_iter()
+                .map(|port| ping(SocketAddrV4::new(ip, port))),
+        )
+        .await;
+
+        if !is_still_bad(&hashes) {
+            println!("{ip} doesn't look like a bad ip anymore, un-flagging it");
+            database::unflag_bad_ip(store, shared, journal, ip).await?;
+            unflagged += 1;
+        }
+    }
+
+    println!("un-flagged {unflagged}/{} bad ips", ips.len());
+    info!("Verified {} bad IPs, un-flagged {unflagged}", ips.len());
+
+    Ok(unflagged)
+}
+
+/// Pick `count` different ports, not including 25565 since that one is still
+/// scanned on bad IPs anyways.
+fn random_ports(count: usize) -> Vec<u16> {
+    let mut rng = rand::thread_rng();
+    let mut ports = HashSet::new();
+    while ports.len() < count {
+        let port = rng.gen_range(1024..=65535);
+        if port != 25565 {
+            ports.insert(port);
+        }
+    }
+    ports.into_iter().collect()
+}
+
+/// Whether most of the pinged ports responded with the same server. A bad IP
+/// responds on every port, so this doesn't need to be anywhere near
+/// [`crate::database::BAD_IP_SAME_HASH_THRESHOLD`].
+fn is_still_bad(hashes: &[Option<u64>]) -> bool {
+    let mut counts = HashMap::<u64, usize>::new();
+    for hash in hashes.iter().flatten() {
+        *counts.entry(*hash).or_default() += 1;
+    }
+    let most_common = counts.into_values().max().unwrap_or_default();
+    most_common * 2 > hashes.len()
+}
+
+/// Ping the server and return a hash of its response, or None if it didn't
+/// respond with a valid status.
+async fn ping(addr: SocketAddrV4) -> Option<u64> {
+    let protocol = protocols::Minecraft::new(
+        &addr.ip().to_string(),
+        addr.port(),
+        &[protocols::DEFAULT_PROTOCOL_VERSION],
+    );
+
+    let status = tokio::time::timeout(PING_TIMEOUT, async {
+        let mut stream = TcpStream::connect(addr).await.ok()?;
+        stream.write_all(&protocol.payload(addr)).await.ok()?;
+
+        let mut response = Vec::new();
+        let mut buf = [0; 4096];
+        loop {
+            let n = stream.read(&mut buf).await.ok()?;
+            if n == 0 {
+                return None;
+            }
+            response.extend_from_slice(&buf[..n]);
+            match protocol.parse_response(Response::Data(response.clone())) {
+                Ok(status) => return Some(status),
+                Err(ParseResponseError::Incomplete { .. }) => continue,
+                Err(ParseResponseError::Invalid) => return None,
+            }
+        }
+    })
+    .await
+    .ok()??;
+
+    hash_status(&status)
+}
+
+/// Hash the parts of the status that would be the same for every port on a
+/// bad IP, with the same hash that's used for flagging them in the first place.
+fn hash_status(status: &[u8]) -> Option<u64> {
+    let status: serde_json::Value = serde_json::from_slice(status).ok()?;
+
+    // the plain description, like it's stored
+    let description = status
+        .get("description")
+        .map(|raw| Plain.format(&description::parse(raw), raw))
+        .unwrap_or_default();
+    let as_i32 = |value: &serde_json::Value| value.as_i64().map(|n| n as i32);
+
+    Some(minecraft::same_server_hash(
+        &description,
+        status["version"]["name"].as_str().unwrap_or_default(),
+        as_i32(&status["version"]["protocol"]),
+        as_i32(&status["players"]["max"]),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, net::Ipv4Addr};
+
+    use crate::database::{bad_ips::BadIps, memory::MemoryStore};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn unflags_ips_everywhere() {
+        let path = std::env::temp_dir().join(format!("matscan-verify-{}", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let still_bad = Ipv4Addr::new(1, 1, 1, 1);
+        let changed = Ipv4Addr::new(2, 2, 2, 2);
+
+        let store = MemoryStore::new(vec![]).with_bad_servers(vec![
+            doc! { "ip": still_bad.to_string() },
+            // an older one that was stored as a number
+            doc! { "ip": u32::from(changed) as i64 },
+        ]);
+        let mut bad_ips = BadIps::new(10);
+        bad_ips.insert(still_bad);
+        bad_ips.insert(changed);
+        let shared = Mutex::new(DatabaseSharedData::new(bad_ips));
+        // they haven't been synced yet, so they're still in the journal
+        let (journal, _) = BadIpJournal::open(&path).unwrap();
+        journal.flag(still_bad);
+        journal.flag(changed);
+
+        let ping = |addr: SocketAddrV4| async move { (*addr.ip() == still_bad).then_some(1) };
+        let unflagged = verify_sample(&store, &shared, Some(&journal), 10, ping)
+            .await
+            .unwrap();
+        assert_eq!(unflagged, 1);
+
+        let mut shared = shared.lock();
+        assert!(shared.bad_ips.contains(&still_bad));
+        assert!(!shared.bad_ips.contains(&changed));
+        assert_eq!(store.bad_servers(), [doc! { "ip": "1.1.1.1" }]);
+        drop(journal);
+        let (_, journaled) = BadIpJournal::open(&path).unwrap();
+        assert_eq!(journaled, HashSet::from([still_bad]));
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn changed_ip_gets_unflagged() {
+        let same = hash_status(br#"{"description":"same","version":{"name":"1.20.1"}}"#);
+        assert!(is_still_bad(&[same; PORTS_TO_CHECK]));
+
+        // only a couple ports have a server now and they're different
+        let mut hashes = [None; PORTS_TO_CHECK];
+        hashes[0] = hash_status(br#"{"description":"a server"}"#);
+        hashes[1] = hash_status(br#"{"description":"another server"}"#);
+        assert!(!is_still_bad(&hashes));
+
+        assert!(!is_still_bad(&[None; PORTS_TO_CHECK]));
+    }
+
+    #[test]
+    fn unknown_numbers_hash_like_cleaned_ones() {
+        let proxy = hash_status(
+            br#"{"description":"a","version":{"name":"Velocity","protocol":-1},"players":{"max":-1,"online":-1}}"#,
+        );
+        let missing = hash_status(br#"{"description":"a","version":{"name":"Velocity"}}"#);
+        assert_eq!(proxy, missing);
+        assert_eq!(
+            proxy,
+            Some(minecraft::same_server_hash("a", "Velocity", None, Some(0)))
+        );
+    }
+}
@@ -1,22 +1,35 @@
+pub mod backpressure;
+pub mod dedup;
+pub mod description;
+pub mod events;
+pub mod filters;
+pub mod forge;
+pub mod impersonation;
 pub mod minecraft;
 pub mod minecraft_fingerprinting;
+pub mod proxy;
+pub mod recent;
+pub mod webhook;
 
 use std::{
-    collections::{HashMap, VecDeque},
+    collections::{HashMap, HashSet, VecDeque},
+    marker::PhantomData,
     mem,
     net::{Ipv4Addr, SocketAddrV4},
     sync::Arc,
-    time::{Duration, SystemTime},
+    time::{Duration, Instant, SystemTime},
 };
 
 use async_trait::async_trait;
-use bson::{doc, Bson};
+use bson::{doc, Bson, Document};
+use futures_util::StreamExt;
+use lru_cache::LruCache;
 use parking_lot::Mutex;
-use tracing::trace;
+use tracing::{debug, trace};
 
 use crate::{
     config::Config,
-    database::{self, bulk_write::CollectionExt, Database},
+    database::{self, bulk_write::CollectionExt, store::ServerStore, Database},
     terminal_colors::*,
 };
 
@@ -24,10 +37,32 @@ pub struct SharedData {
     pub database: Database,
     /// The queue of servers to process, along with their server list ping
     /// response.
-    pub queue: VecDeque<(SocketAddrV4, Vec<u8>)>,
+    pub queue: VecDeque<(SocketAddrV4, Vec<u8>, Option<Duration>)>,
     /// Data from the previous scan, used for identifying players that just
     /// joined or left a server.
     pub cached_servers: HashMap<SocketAddrV4, serde_json::Value>,
+    /// Updates for the players collection, written after the server updates.
+    pub player_updates: Vec<database::bulk_write::BulkUpdate>,
+    /// Updates for the favicons collection, written after the server updates.
+    pub favicon_updates: Vec<database::bulk_write::BulkUpdate>,
+    /// The rotation that the Minecraft protocol is currently using to pick
+    /// which protocol version to ping each server with.
+    pub handshake_rotation: u64,
+    /// How many responses and updates are waiting to be processed, which the
+    /// scanner can poll to slow down when processing can't keep up.
+    pub backpressure: Arc<backpressure::Backpressure>,
+    /// Where events like new servers being found are published to.
+    pub events: events::EventBus,
+    /// The description and version name filters, which are swapped out when
+    /// they're reloaded.
+    pub filters: Arc<filters::Filters>,
+    /// The networks that servers are checked for impersonating.
+    pub known_networks: Arc<impersonation::KnownNetworks>,
+    /// When each server last sent a snipe webhook notification.
+    pub webhook_cooldowns: webhook::WebhookCooldowns,
+    /// The number of responses from each IP that couldn't be parsed, for
+    /// `processing.junk_response_threshold`.
+    pub junk_responses: LruCache<Ipv4Addr, u32>,
 
     pub total_new: usize,
     pub total_new_on_default_port: usize,
@@ -38,25 +73,202 @@ pub struct SharedData {
     pub is_processing: bool,
 }
 
+impl SharedData {
+    pub fn new(database: Database, config: &Config) -> anyhow::Result<Self> {
+        Ok(Self {
+            database,
+            queue: VecDeque::new(),
+            // we use the cache to check if someone just joined a server, so this
+            // will always stay empty if snipe mode is off
+            cached_servers: HashMap::new(),
+            player_updates: Vec::new(),
+            favicon_updates: Vec::new(),
+            handshake_rotation: 0,
+            backpressure: Arc::new(backpressure::Backpressure::new()),
+            events: events::EventBus::new(),
+            filters: Arc::new(filters::Filters::new(&config.filters)),
+            known_networks: Arc::new(impersonation::KnownNetworks::new(
+                &config.processing.known_networks,
+            )?),
+            webhook_cooldowns: webhook::WebhookCooldowns::new(Duration::from_secs(
+                config.webhook.per_server_cooldown_secs.unwrap_or(60 * 10),
+            )),
+            // arbitrary capacity (2^20)
+            junk_responses: LruCache::new(1048576),
+
+            total_new: 0,
+            total_new_on_default_port: 0,
+            revived: 0,
+            results: 0,
+
+            is_processing: false,
+        })
+    }
+}
+
+impl SharedData {
+    /// Count a response from the IP that couldn't be parsed. Returns true once
+    /// it's sent `threshold` of them, and then the count starts over.
+    pub fn record_junk_response(&mut self, ip: Ipv4Addr, threshold: u32) -> bool {
+        let count = match self.junk_responses.get_mut(&ip) {
+            Some(count) => {
+                *count += 1;
+                *count
+            }
+            None => {
+                self.junk_responses.insert(ip, 1);
+                1
+            }
+        };
+        if count < threshold {
+            return false;
+        }
+        self.junk_responses.remove(&ip);
+        true
+    }
+}
+
+/// What happened to a response after it was processed.
+#[derive(Debug)]
+pub enum ProcessResult {
+    /// The server should be updated with this.
+    Update(database::bulk_write::BulkUpdate),
+    /// The response was dropped because it doesn't look like a real server.
+    Filtered(minecraft::FilterReason),
+    /// There's nothing to do with the response, like when the update couldn't
+    /// be created.
+    NotApplicable,
+    /// The server is on a bad IP, either one that was already known or one that
+    /// was just flagged, so it's ignored.
+    BadIp,
+}
+
 #[async_trait]
 pub trait ProcessableProtocol: Send + 'static {
+    /// The name the protocol is registered with in the [`ProtocolRegistry`].
+    const NAME: &'static str;
+
+    /// A cheap check for whether the data could be a valid response, so
+    /// obvious garbage can be dropped before it's fully parsed in `process`.
+    fn is_plausible(_data: &[u8]) -> bool {
+        true
+    }
+
+    /// `rtt` is how long the server took to respond to the ping, if the
+    /// scanner measured it.
     fn process(
         shared: &Arc<Mutex<SharedData>>,
         config: &Config,
         target: SocketAddrV4,
         data: &[u8],
+        rtt: Option<Duration>,
         database: &Database,
-    ) -> Option<database::bulk_write::BulkUpdate>;
+    ) -> ProcessResult;
+}
+
+/// An object-safe version of [`ProcessableProtocol`], so different protocols
+/// can be stored together in a [`ProtocolRegistry`].
+pub trait DynProcessableProtocol: Send + Sync {
+    fn is_plausible(&self, data: &[u8]) -> bool;
+    fn process(
+        &self,
+        shared: &Arc<Mutex<SharedData>>,
+        config: &Config,
+        target: SocketAddrV4,
+        data: &[u8],
+        rtt: Option<Duration>,
+        database: &Database,
+    ) -> ProcessResult;
+}
+
+struct ProtocolHandler<P>(PhantomData<fn() -> P>);
+
+impl<P: ProcessableProtocol> DynProcessableProtocol for ProtocolHandler<P> {
+    fn is_plausible(&self, data: &[u8]) -> bool {
+        P::is_plausible(data)
+    }
+    fn process(
+        &self,
+        shared: &Arc<Mutex<SharedData>>,
+        config: &Config,
+        target: SocketAddrV4,
+        data: &[u8],
+        rtt: Option<Duration>,
+        database: &Database,
+    ) -> ProcessResult {
+        P::process(shared, config, target, data, rtt, database)
+    }
 }
 
-/// A task that processes pings from the queue.
-pub async fn process_pings<P>(shared: Arc<Mutex<SharedData>>, config: Config)
-where
-    P: ProcessableProtocol + Send + 'static,
-{
+/// The protocols that the processing task can process responses for, by name.
+#[derive(Clone)]
+pub struct ProtocolRegistry {
+    protocols: HashMap<&'static str, Arc<dyn DynProcessableProtocol>>,
+}
+
+impl ProtocolRegistry {
+    /// A registry without any protocols. Use [`ProtocolRegistry::default`] for
+    /// one with the built-in protocols.
+    pub fn empty() -> Self {
+        Self {
+            protocols: HashMap::new(),
+        }
+    }
+
+    /// Add the protocol to the registry under [`ProcessableProtocol::NAME`],
+    /// replacing any protocol that was already registered with that name.
+    pub fn register<P: ProcessableProtocol>(&mut self) {
+        self.protocols
+            .insert(P::NAME, Arc::new(ProtocolHandler::<P>(PhantomData)));
+    }
+
+    pub fn get(&self, name: &str) -> Option<Arc<dyn DynProcessableProtocol>> {
+        self.protocols.get(name).cloned()
+    }
+
+    pub fn names(&self) -> impl Iterator<Item = &'static str> + '_ {
+        self.protocols.keys().copied()
+    }
+
+    /// Like [`ProtocolRegistry::get`], but with an error that lists the
+    /// registered protocols if there isn't one with the name.
+    pub fn resolve(&self, name: &str) -> anyhow::Result<Arc<dyn DynProcessableProtocol>> {
+        self.get(name).ok_or_else(|| {
+            let mut names = self.names().collect::<Vec<_>>();
+            names.sort_unstable();
+            anyhow::anyhow!(
+                "protocol {name} isn't registered (expected one of {})",
+                names.join(", ")
+            )
+        })
+    }
+}
+
+impl Default for ProtocolRegistry {
+    fn default() -> Self {
+        let mut registry = Self::empty();
+        registry.register::<crate::scanner::protocols::Minecraft>();
+        registry.register::<crate::scanner::protocols::MinecraftFingerprinting>();
+        registry
+    }
+}
+
+/// A task that processes pings from the queue with the given protocol.
+pub async fn process_pings(
+    shared: Arc<Mutex<SharedData>>,
+    config: Config,
+    protocol: Arc<dyn DynProcessableProtocol>,
+) {
     let database = shared.lock().database.clone();
+    let backpressure = shared.lock().backpressure.clone();
+    let mut deduplicator = config
+        .processing
+        .dedup_window_ms
+        .map(|ms| dedup::ResponseDeduplicator::new(Duration::from_millis(ms)));
     loop {
-        if shared.lock().queue.is_empty() {
+        let queued = shared.lock().queue.len();
+        backpressure.record_queued(queued);
+        if queued == 0 {
             // wait a bit until next loop
             tokio::time::sleep(Duration::from_millis(100)).await;
             continue;
@@ -66,10 +278,27 @@ where
 
         let mut bulk_updates: Vec<database::bulk_write::BulkUpdate> = Vec::new();
         let updating = shared.lock().queue.drain(..).collect::<Vec<_>>();
-        for (target, data) in updating {
-            let Some(bulk_update) = P::process(&shared, &config, target, &data, &database) else {
+        let updating_count = updating.len();
+        for (i, (target, data, rtt)) in updating.into_iter().enumerate() {
+            // the scanner keeps adding to the queue while we're processing
+            backpressure.record_queued(updating_count - i + shared.lock().queue.len());
+            if !protocol.is_plausible(&data) {
                 continue;
-            };
+            }
+            if let Some(deduplicator) = &mut deduplicator {
+                if deduplicator.is_duplicate(target, &data, Instant::now()) {
+                    continue;
+                }
+            }
+            let bulk_update =
+                match protocol.process(&shared, &config, target, &data, rtt, &database) {
+                    ProcessResult::Update(bulk_update) => bulk_update,
+                    ProcessResult::Filtered(reason) => {
+                        minecraft::FILTER_COUNTS.record(reason);
+                        continue;
+                    }
+                    ProcessResult::NotApplicable | ProcessResult::BadIp => continue,
+                };
             // check if there's already a bulk update for this server
             let is_already_updating = bulk_updates.iter().any(|bulk_update| {
                 bulk_update
@@ -84,19 +313,56 @@ where
                 continue;
             }
             bulk_updates.push(bulk_update);
+            backpressure.record_pending_writes(bulk_updates.len());
             if bulk_updates.len() >= 100 {
+                notify_revivals(&database, &shared, &config, &bulk_updates).await;
                 if let Err(err) =
                     flush_bulk_updates(&database, mem::take(&mut bulk_updates), &shared).await
                 {
                     eprintln!("{err}");
                 }
+                backpressure.record_pending_writes(0);
             }
         }
 
         if !bulk_updates.is_empty() {
+            notify_revivals(&database, &shared, &config, &bulk_updates).await;
             if let Err(err) = flush_bulk_updates(&database, bulk_updates, &shared).await {
                 eprintln!("{err}");
             }
+            backpressure.record_pending_writes(0);
+        }
+
+        let player_updates = mem::take(&mut shared.lock().player_updates);
+        if !player_updates.is_empty() {
+            database.write_limiter.acquire(player_updates.len()).await;
+            if let Err(err) = database
+                .players_coll()
+                .bulk_update(
+                    &database.mcscanner_database(),
+                    player_updates,
+                    database.config.ordered_bulk_writes,
+                )
+                .await
+            {
+                eprintln!("{err}");
+            }
+        }
+
+        let favicon_updates = mem::take(&mut shared.lock().favicon_updates);
+        if !favicon_updates.is_empty() {
+            database.write_limiter.acquire(favicon_updates.len()).await;
+            if let Err(err) = database
+                .favicons_coll()
+                .bulk_update(
+                    &database.mcscanner_database(),
+                    favicon_updates,
+                    database.config.ordered_bulk_writes,
+                )
+                .await
+            {
+                eprintln!("{err}");
+            }
         }
 
         shared.lock().is_processing = false;
@@ -104,17 +370,148 @@ where
     }
 }
 
-async fn flush_bulk_updates(
+/// Publish an event (and notify the snipe webhook) for the servers in the
+/// updates that have players again after being inactive for longer than
+/// `webhook.revived_after_secs`. This has to run before the updates are
+/// written, since it reads the `lastActive` they replace.
+async fn notify_revivals(
     database: &Database,
-    bulk_updates: Vec<database::bulk_write::BulkUpdate>,
     shared: &Arc<Mutex<SharedData>>,
-) -> anyhow::Result<()> {
-    let updated_count: usize;
-    let updated_but_not_revived_count: usize;
-    let inserted_count: usize;
-    let inserted_on_default_port_count: usize;
-    let revived_count: usize;
+    config: &Config,
+    bulk_updates: &[database::bulk_write::BulkUpdate],
+) {
+    let Some(threshold) = config.webhook.revived_after_secs.map(Duration::from_secs) else {
+        return;
+    };
+    let active = bulk_updates
+        .iter()
+        .filter(|bulk_update| {
+            bulk_update
+                .update
+                .get_document("$set")
+                .is_ok_and(|set| set.contains_key("lastActive"))
+        })
+        .filter_map(|bulk_update| query_addr(&bulk_update.query))
+        .collect::<Vec<_>>();
+    if active.is_empty() {
+        return;
+    }
+    let last_active = match database.last_active_times(&active).await {
+        Ok(last_active) => last_active,
+        Err(err) => {
+            eprintln!("{err}");
+            return;
+        }
+    };
+
+    let events = shared.lock().events.clone();
+    for (server, inactive_for) in find_revivals(&active, &last_active, SystemTime::now(), threshold)
+    {
+        events.publish(events::ProcessingEvent::ServerRevived {
+            server,
+            inactive_for,
+        });
+        if config.snipe.webhook_url.is_empty()
+            || !shared
+                .lock()
+                .webhook_cooldowns
+                .should_notify(server, Instant::now())
+        {
+            continue;
+        }
+        let message = format!(
+            "{server} has players again after {} hours",
+            inactive_for.as_secs() / 3600
+        );
+        tokio::spawn(webhook::send_to_webhook(
+            config.snipe.webhook_url.clone(),
+            message,
+        ));
+    }
+}
+
+/// The servers that are becoming active again after not having players for at
+/// least `threshold`, and how long they were inactive. Servers that were never
+/// active before don't count.
+fn find_revivals(
+    active: &[SocketAddrV4],
+    last_active: &HashMap<SocketAddrV4, bson::DateTime>,
+    now: SystemTime,
+    threshold: Duration,
+) -> Vec<(SocketAddrV4, Duration)> {
+    active
+        .iter()
+        .filter_map(|server| {
+            let inactive_for = now
+                .duration_since(last_active.get(server)?.to_system_time())
+                .ok()?;
+            (inactive_for >= threshold).then_some((*server, inactive_for))
+        })
+        .collect()
+}
+
+/// Get the address of the server that the query for a server update matches.
+fn query_addr(query: &Document) -> Option<SocketAddrV4> {
+    let ip = query.get_document("ip").ok()?.get_str("$eq").ok()?;
+    let port = database::get_u32(query.get_document("port").ok()?, "$eq")?;
+    Some(SocketAddrV4::new(ip.parse().ok()?, port.try_into().ok()?))
+}
+
+/// How many servers a batch of updates changed.
+#[derive(Debug, Default, PartialEq, Eq)]
+struct WriteCounts {
+    updated: usize,
+    updated_but_not_revived: usize,
+    inserted: usize,
+    revived: usize,
+    /// Upserts that failed because the server was inserted by another write
+    /// in the meantime.
+    duplicates: usize,
+    /// The servers that were inserted.
+    new_servers: Vec<SocketAddrV4>,
+}
+
+/// The servers that were seen after the cutoff, out of the ones the updates
+/// are for.
+async fn recently_seen_servers(
+    store: &dyn ServerStore,
+    bulk_updates: &[database::bulk_write::BulkUpdate],
+    cutoff: &Bson,
+) -> anyhow::Result<HashSet<SocketAddrV4>> {
+    let addrs = bulk_updates
+        .iter()
+        .filter_map(|bulk_update| query_addr(&bulk_update.query))
+        .map(|addr| doc! { "ip": addr.ip().to_string(), "port": addr.port() as u32 })
+        .collect::<Vec<_>>();
+    if addrs.is_empty() {
+        return Ok(HashSet::new());
+    }
 
+    let mut cursor = store
+        .aggregate_servers(vec![
+            doc! { "$match": { "$or": addrs, "lastSeen": { "$gt": cutoff } } },
+            doc! { "$project": { "ip": 1, "port": 1, "_id": 0 } },
+        ])
+        .await?;
+    let mut recently_seen = HashSet::new();
+    while let Some(doc) = cursor.next().await {
+        let doc = doc?;
+        let ip = doc.get_str("ip").ok().and_then(|ip| ip.parse().ok());
+        if let (Some(ip), Some(port)) = (ip, database::get_u32(&doc, "port")) {
+            recently_seen.insert(SocketAddrV4::new(ip, port as u16));
+        }
+    }
+    Ok(recently_seen)
+}
+
+/// Write the server updates. Servers that were seen in the last two hours are
+/// updated, and the rest are revived or inserted.
+async fn write_server_updates(
+    store: &dyn ServerStore,
+    bulk_updates: Vec<database::bulk_write::BulkUpdate>,
+    ordered: bool,
+    now: SystemTime,
+) -> anyhow::Result<WriteCounts> {
     let is_upserting = bulk_updates.iter().any(|bulk_update| {
         bulk_update
             .options
@@ -123,86 +520,133 @@ async fn flush_bulk_updates(
             .unwrap_or_default()
     });
 
-    if is_upserting {
-        // to detect what how many updates "revived" servers, we have to do two bulk
-        // updates
-
-        let reviving_cutoff = Bson::DateTime(bson::DateTime::from_system_time(
-            SystemTime::now() - Duration::from_secs(60 * 60 * 2),
-        ));
-
-        let bulk_updates_not_reviving = bulk_updates
-            .clone()
-            .into_iter()
-            .map(|mut bulk_update| {
-                bulk_update.query.insert(
-                    "lastSeen",
-                    doc! {
-                        "$gt": &reviving_cutoff,
-                    },
-                );
-                // disable upserting for not_reviving
-                if let Some(options) = &mut bulk_update.options {
-                    options.upsert = Some(false);
-                }
-                bulk_update
-            })
-            .collect::<Vec<_>>();
-        let bulk_updates_reviving = bulk_updates
-            .into_iter()
-            .map(|mut bulk_update| {
-                bulk_update
-                    .query
-                    .insert("lastSeen", doc! { "$lte": &reviving_cutoff });
-                bulk_update
-            })
-            .collect::<Vec<_>>();
-        trace!("bulk_updates_not_reviving: {bulk_updates_not_reviving:?}");
-        trace!("bulk_updates_reviving: {bulk_updates_reviving:?}");
-
-        let db = database.mcscanner_database();
-        let result_not_reviving = db
-            .collection::<bson::Document>("cachedservers")
-            .bulk_update(&db, bulk_updates_not_reviving)
-            .await?;
-        let result_reviving = db
-            .collection::<bson::Document>("cachedservers")
-            .bulk_update(&db, &bulk_updates_reviving)
-            .await?;
-
-        trace!("result_not_reviving: {result_not_reviving:?}");
-        trace!("result_reviving: {result_reviving:?}");
-
-        revived_count = result_reviving.nb_modified as usize;
-        updated_but_not_revived_count = result_not_reviving.nb_modified as usize;
-        inserted_count = result_reviving.upserted.len();
-
-        updated_count = revived_count + updated_but_not_revived_count + inserted_count;
-
-        inserted_on_default_port_count = result_reviving
-            .upserted
-            .iter()
-            .filter(|server_update_result| {
-                let server_update = &bulk_updates_reviving[server_update_result.index as usize];
-                let port = database::get_i32(&server_update.query, "port").unwrap_or_default();
-                port == 25565
-            })
-            .count();
-    } else {
+    if !is_upserting {
         // if we're not upserting then we're probably doing something like
         // fingerprinting so reviving/inserting doesn't make sense
-        let db = database.mcscanner_database();
-        let result = db
-            .collection::<bson::Document>("cachedservers")
-            .bulk_update(&db, bulk_updates)
-            .await?;
-        updated_count = result.nb_modified as usize;
-        updated_but_not_revived_count = 0;
-        inserted_count = 0;
-        revived_count = 0;
-        inserted_on_default_port_count = 0;
+        let result = store.bulk_update_servers(&bulk_updates, ordered).await?;
+        return Ok(WriteCounts {
+            updated: result.nb_modified as usize,
+            ..Default::default()
+        });
+    }
+
+    // to detect what how many updates "revived" servers, we have to do two bulk
+    // updates
+
+    let reviving_cutoff = Bson::DateTime(bson::DateTime::from_system_time(
+        now - Duration::from_secs(60 * 60 * 2),
+    ));
+    // the servers that the first pass updates would otherwise be upserted again
+    // by the second one, which fails with a duplicate key error for each of them
+    // and stops an ordered write at the first one
+    let recently_seen = recently_seen_servers(store, &bulk_updates, &reviving_cutoff).await?;
+
+    let bulk_updates_not_reviving = bulk_updates
+        .clone()
+        .into_iter()
+        .map(|mut bulk_update| {
+            bulk_update.query.insert(
+                "lastSeen",
+                doc! {
+                    "$gt": &reviving_cutoff,
+                },
+            );
+            // disable upserting for not_reviving
+            if let Some(options) = &mut bulk_update.options {
+                options.upsert = Some(false);
+            }
+            bulk_update
+        })
+        .collect::<Vec<_>>();
+    let bulk_updates_reviving = bulk_updates
+        .into_iter()
+        .filter(|bulk_update| {
+            query_addr(&bulk_update.query).map_or(true, |addr| !recently_seen.contains(&addr))
+        })
+        .map(|mut bulk_update| {
+            bulk_update
+                .query
+                .insert("lastSeen", doc! { "$lte": &reviving_cutoff });
+            bulk_update
+        })
+        .collect::<Vec<_>>();
+    trace!("bulk_updates_not_reviving: {bulk_updates_not_reviving:?}");
+    trace!("bulk_updates_reviving: {bulk_updates_reviving:?}");
+
+    let result_not_reviving = store
+        .bulk_update_servers(&bulk_updates_not_reviving, ordered)
+        .await?;
+    let result_reviving = store
+        .bulk_update_servers(&bulk_updates_reviving, ordered)
+        .await?;
+
+    trace!("result_not_reviving: {result_not_reviving:?}");
+    trace!("result_reviving: {result_reviving:?}");
+
+    let revived = result_reviving.nb_modified as usize;
+    let updated_but_not_revived = result_not_reviving.nb_modified as usize;
+    let inserted = result_reviving.upserted.len();
+    let duplicates = result_reviving
+        .write_errors
+        .iter()
+        .filter(|error| error.code == database::bulk_write::DUPLICATE_KEY)
+        .count();
+
+    let new_servers = result_reviving
+        .upserted
+        .iter()
+        .filter_map(|server_update_result| {
+            let server_update = bulk_updates_reviving.get(server_update_result.index as usize)?;
+            query_addr(&server_update.query)
+        })
+        .collect::<Vec<_>>();
+
+    Ok(WriteCounts {
+        updated: revived + updated_but_not_revived + inserted,
+        updated_but_not_revived,
+        inserted,
+        revived,
+        duplicates,
+        new_servers,
+    })
+}
+
+async fn flush_bulk_updates(
+    database: &Database,
+    bulk_updates: Vec<database::bulk_write::BulkUpdate>,
+    shared: &Arc<Mutex<SharedData>>,
+) -> anyhow::Result<()> {
+    database.write_limiter.acquire(bulk_updates.len()).await;
+
+    let WriteCounts {
+        updated: updated_count,
+        updated_but_not_revived: updated_but_not_revived_count,
+        inserted: inserted_count,
+        revived: revived_count,
+        duplicates: duplicate_count,
+        new_servers,
+    } = write_server_updates(
+        database,
+        bulk_updates,
+        database.config.ordered_bulk_writes,
+        SystemTime::now(),
+    )
+    .await?;
+    if duplicate_count > 0 {
+        debug!("{duplicate_count} servers were inserted by another write before we could");
     }
 
+    let inserted_on_default_port_count = new_servers
+        .iter()
+        .filter(|addr| addr.port() == 25565)
+        .count();
+    let events = shared.lock().events.clone();
+    for addr in new_servers {
+        events.publish(events::ProcessingEvent::NewServer(addr));
+    }
+
+    crate::health::record_write(database);
+
     let mut shared = shared.lock();
     shared.results += updated_count;
     shared.total_new += inserted_count;
@@ -235,3 +679,133 @@ async fn flush_bulk_updates(
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Dummy;
+
+    impl ProcessableProtocol for Dummy {
+        const NAME: &'static str = "dummy";
+
+        fn is_plausible(data: &[u8]) -> bool {
+            data.starts_with(b"dummy")
+        }
+
+        fn process(
+            _shared: &Arc<Mutex<SharedData>>,
+            _config: &Config,
+            _target: SocketAddrV4,
+            _data: &[u8],
+            _rtt: Option<Duration>,
+            _database: &Database,
+        ) -> ProcessResult {
+            ProcessResult::NotApplicable
+        }
+    }
+
+    #[test]
+    fn registered_protocol_gets_payloads() {
+        let mut registry = ProtocolRegistry::default();
+        registry.register::<Dummy>();
+
+        let dummy = registry.get("dummy").unwrap();
+        assert!(dummy.is_plausible(b"dummy payload"));
+        // the built-in protocols are still there and don't accept it
+        let minecraft = registry.get("minecraft").unwrap();
+        assert!(!minecraft.is_plausible(b"dummy payload"));
+
+        assert!(registry.get("bedrock").is_none());
+        assert_eq!(
+            registry.resolve("bedrock").err().map(|err| err.to_string()),
+            Some(
+                "protocol bedrock isn't registered (expected one of dummy, minecraft, \
+                 minecraft_fingerprinting)"
+                    .to_string()
+            )
+        );
+        let mut names = registry.names().collect::<Vec<_>>();
+        names.sort();
+        assert_eq!(names, ["dummy", "minecraft", "minecraft_fingerprinting"]);
+    }
+
+    #[test]
+    fn revival_after_long_inactivity() {
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000);
+        let day = Duration::from_secs(60 * 60 * 24);
+        let server = |port| SocketAddrV4::new(Ipv4Addr::new(1, 2, 3, 4), port);
+        let last_active = HashMap::from([
+            // dead for a month
+            (server(1), bson::DateTime::from(now - day * 30)),
+            // had players yesterday
+            (server(2), bson::DateTime::from(now - day)),
+        ]);
+
+        assert_eq!(
+            find_revivals(
+                // server 3 was never active before
+                &[server(1), server(2), server(3)],
+                &last_active,
+                now,
+                day * 7
+            ),
+            [(server(1), day * 30)]
+        );
+    }
+
+    #[tokio::test]
+    async fn writes_updates_to_the_store() {
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000);
+        let seen = |secs_ago| bson::DateTime::from(now - Duration::from_secs(secs_ago));
+        let store = database::memory::MemoryStore::new(vec![
+            doc! { "ip": "1.1.1.1", "port": 25565, "lastSeen": seen(60) },
+            // dead for a day
+            doc! { "ip": "2.2.2.2", "port": 25565, "lastSeen": seen(60 * 60 * 24) },
+        ]);
+        let update = |ip: &str| {
+            database::bulk_write::BulkUpdateBuilder::new()
+                .set(doc! { "lastSeen": bson::DateTime::from(now) })
+                .upsert(true)
+                .build(doc! { "ip": { "$eq": ip }, "port": { "$eq": 25565_u32 } })
+                .unwrap()
+        };
+
+        // the recently seen server is first, so an ordered write would stop
+        // there if it was upserted again
+        let counts = write_server_updates(
+            &store,
+            vec![update("1.1.1.1"), update("2.2.2.2"), update("3.3.3.3")],
+            true,
+            now,
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            counts,
+            WriteCounts {
+                updated: 3,
+                updated_but_not_revived: 1,
+                inserted: 1,
+                revived: 1,
+                duplicates: 0,
+                new_servers: vec![SocketAddrV4::new(Ipv4Addr::new(3, 3, 3, 3), 25565)],
+            }
+        );
+        assert_eq!(store.servers().len(), 3);
+        assert!(store
+            .servers()
+            .iter()
+            .all(|server| server.get_datetime("lastSeen") == Ok(&bson::DateTime::from(now))));
+    }
+
+    #[test]
+    fn new_server_address_from_query() {
+        let query = doc! { "ip": { "$eq": "1.2.3.4" }, "port": { "$eq": 25565_u32 } };
+        assert_eq!(
+            query_addr(&query),
+            Some(SocketAddrV4::new(Ipv4Addr::new(1, 2, 3, 4), 25565))
+        );
+        assert_eq!(query_addr(&doc! { "ip": "1.2.3.4" }), None);
+    }
+}
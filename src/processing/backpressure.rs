@@ -0,0 +1,63 @@
+//! How far behind the processing task is, so the scanner can slow down
+//! instead of responses piling up faster than they can be written.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+#[derive(Debug, Default)]
+pub struct Backpressure {
+    /// The number of responses that were received but not processed yet.
+    queued: AtomicUsize,
+    /// The number of updates that were created but not written to the
+    /// database yet.
+    pending_writes: AtomicUsize,
+}
+
+impl Backpressure {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn record_queued(&self, queued: usize) {
+        self.queued.store(queued, Ordering::Relaxed);
+    }
+
+    pub fn record_pending_writes(&self, pending_writes: usize) {
+        self.pending_writes.store(pending_writes, Ordering::Relaxed);
+    }
+
+    /// The total number of responses and updates that are waiting.
+    pub fn depth(&self) -> usize {
+        self.queued.load(Ordering::Relaxed) + self.pending_writes.load(Ordering::Relaxed)
+    }
+
+    /// Whether at least `max_depth` things are waiting, which is when the
+    /// scanner should send pings more slowly.
+    pub fn is_saturated(&self, max_depth: usize) -> bool {
+        self.depth() >= max_depth
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn queue_growth_raises_depth() {
+        let backpressure = Backpressure::new();
+        assert_eq!(backpressure.depth(), 0);
+
+        backpressure.record_queued(100);
+        backpressure.record_pending_writes(20);
+        assert_eq!(backpressure.depth(), 120);
+        assert!(!backpressure.is_saturated(1000));
+
+        backpressure.record_queued(5000);
+        assert_eq!(backpressure.depth(), 5020);
+        assert!(backpressure.is_saturated(1000));
+
+        // the processing task caught up
+        backpressure.record_queued(0);
+        backpressure.record_pending_writes(0);
+        assert!(!backpressure.is_saturated(1000));
+    }
+}
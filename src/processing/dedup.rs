@@ -0,0 +1,65 @@
+//! Drops responses that we already got from the same server very recently,
+//! like the ones from retransmits at high packet rates.
+
+use std::{
+    collections::hash_map::DefaultHasher,
+    hash::{Hash, Hasher},
+    net::SocketAddrV4,
+    time::{Duration, Instant},
+};
+
+use super::recent::RecentServers;
+
+pub struct ResponseDeduplicator {
+    /// A hash of the last response from each server.
+    recent: RecentServers<u64>,
+}
+
+impl ResponseDeduplicator {
+    pub fn new(window: Duration) -> Self {
+        Self {
+            recent: RecentServers::new(window),
+        }
+    }
+
+    /// Whether we got the exact same response from the server within the
+    /// window. If it's not a duplicate, it's remembered for next time.
+    pub fn is_duplicate(&mut self, target: SocketAddrV4, data: &[u8], now: Instant) -> bool {
+        let mut hasher = DefaultHasher::new();
+        data.hash(&mut hasher);
+        self.recent.seen_recently(target, hasher.finish(), now)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    #[test]
+    fn duplicates_only_within_window() {
+        let mut dedup = ResponseDeduplicator::new(Duration::from_secs(5));
+        let target = SocketAddrV4::new(Ipv4Addr::new(1, 2, 3, 4), 25565);
+        let response = br#"{"description":"A Minecraft Server"}"#;
+        let start = Instant::now();
+
+        let writes = |dedup: &mut ResponseDeduplicator, times: &[Duration]| {
+            times
+                .iter()
+                .filter(|&&t| !dedup.is_duplicate(target, response, start + t))
+                .count()
+        };
+
+        // a retransmit a moment later is dropped
+        assert_eq!(
+            writes(&mut dedup, &[Duration::ZERO, Duration::from_millis(100)]),
+            1
+        );
+        // but a ping after the window isn't
+        assert_eq!(writes(&mut dedup, &[Duration::from_secs(10)]), 1);
+
+        // and neither is a different response
+        assert!(!dedup.is_duplicate(target, b"{}", start + Duration::from_secs(10)));
+    }
+}
@@ -0,0 +1,375 @@
+//! Different ways of turning a server's description into a string.
+
+use std::collections::HashMap;
+
+use azalea_chat::FormattedText;
+use serde::Deserialize;
+
+pub trait DescriptionFormatter {
+    /// The field in the server document that this format is stored in.
+    fn field(&self) -> &'static str;
+    /// Format the description. `raw` is the description from the response,
+    /// before it was parsed into `text`.
+    fn format(&self, text: &FormattedText, raw: &serde_json::Value) -> String;
+}
+
+/// The description formats that can be selected in
+/// `config.processing.description_formats`.
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum DescriptionFormat {
+    /// The text without any formatting, stored in `description`.
+    Plain,
+    /// The text with ANSI escape codes for the colors, stored in
+    /// `descriptionAnsi`.
+    Ansi,
+    /// The plain text without any leftover section codes and with the
+    /// whitespace collapsed, stored in `descriptionNormalized`.
+    Normalized,
+    /// The text with Minecraft's legacy `§` formatting codes, stored in
+    /// `descriptionSection`.
+    Section,
+}
+
+impl DescriptionFormat {
+    pub fn formatter(self) -> &'static dyn DescriptionFormatter {
+        match self {
+            DescriptionFormat::Plain => &Plain,
+            DescriptionFormat::Ansi => &Ansi,
+            DescriptionFormat::Normalized => &Normalized,
+            DescriptionFormat::Section => &Section,
+        }
+    }
+}
+
+pub struct Plain;
+impl DescriptionFormatter for Plain {
+    fn field(&self) -> &'static str {
+        "description"
+    }
+    fn format(&self, text: &FormattedText, _raw: &serde_json::Value) -> String {
+        text.to_string()
+    }
+}
+
+pub struct Ansi;
+impl DescriptionFormatter for Ansi {
+    fn field(&self) -> &'static str {
+        "descriptionAnsi"
+    }
+    fn format(&self, text: &FormattedText, _raw: &serde_json::Value) -> String {
+        text.to_ansi()
+    }
+}
+
+pub struct Normalized;
+impl DescriptionFormatter for Normalized {
+    fn field(&self) -> &'static str {
+        "descriptionNormalized"
+    }
+    fn format(&self, text: &FormattedText, _raw: &serde_json::Value) -> String {
+        normalize(&text.to_string())
+    }
+}
+
+pub struct Section;
+impl DescriptionFormatter for Section {
+    fn field(&self) -> &'static str {
+        "descriptionSection"
+    }
+    fn format(&self, _text: &FormattedText, raw: &serde_json::Value) -> String {
+        let mut out = String::new();
+        write_section_codes(
+            raw,
+            &SectionStyle::default(),
+            &mut SectionStyle::default(),
+            &mut out,
+        );
+        out
+    }
+}
+
+/// Parse a description from a response, or an empty one if it's invalid.
+///
+/// Descriptions can be arrays of components (some older BungeeCord configs
+/// send them like that), which are turned into the first component with the
+/// rest as its `extra` so they're parsed the same way the client does.
+pub fn parse(raw: &serde_json::Value) -> FormattedText {
+    FormattedText::deserialize(normalize_component(raw)).unwrap_or_default()
+}
+
+fn normalize_component(component: &serde_json::Value) -> serde_json::Value {
+    use serde_json::Value;
+
+    match component {
+        Value::Array(components) => {
+            let mut components = components.iter().map(normalize_component);
+            let Some(first) = components.next() else {
+                return Value::String(String::new());
+            };
+            let mut first = match first {
+                Value::Object(first) => first,
+                text => serde_json::Map::from_iter([("text".to_string(), text)]),
+            };
+            let siblings = components.collect::<Vec<_>>();
+            if !siblings.is_empty() {
+                match first
+                    .entry("extra")
+                    .or_insert_with(|| Value::Array(Vec::new()))
+                {
+                    Value::Array(extra) => extra.extend(siblings),
+                    extra => *extra = Value::Array(siblings),
+                }
+            }
+            Value::Object(first)
+        }
+        Value::Object(component) => {
+            let mut component = component.clone();
+            if let Some(extra) = component.get_mut("extra") {
+                *extra = match extra {
+                    Value::Array(extra) => extra.iter().map(normalize_component).collect(),
+                    extra => Value::Array(vec![normalize_component(extra)]),
+                };
+            }
+            Value::Object(component)
+        }
+        Value::Null => Value::String(String::new()),
+        Value::Bool(_) | Value::Number(_) => Value::String(component.to_string()),
+        Value::String(_) => component.clone(),
+    }
+}
+
+/// Remove any section codes from the text and collapse the whitespace, which is
+/// what the [`Normalized`] format does.
+pub fn normalize(text: &str) -> String {
+    let plain = strip_section_codes(text);
+    plain.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+/// Cut the text down to at most `max_chars` characters, with an ellipsis at
+/// the end if anything was removed. This never splits a character, since
+/// that would make the string invalid UTF-8.
+pub fn truncate(text: &str, max_chars: usize) -> String {
+    let Some((end, _)) = text.char_indices().nth(max_chars) else {
+        // it's already short enough
+        return text.to_string();
+    };
+    // make room for the ellipsis
+    let end = text[..end]
+        .char_indices()
+        .nth(max_chars.saturating_sub(1))
+        .map_or(end, |(i, _)| i);
+    format!("{}…", &text[..end])
+}
+
+/// Descriptions with fewer characters than this are never garbage, since
+/// short ones like "..." are normal.
+const MIN_GARBAGE_CHARS: usize = 16;
+
+/// Whether the description is mostly non-printable characters or one
+/// character repeated over and over, which is what honeypots send to get
+/// around string matching (usually with `§k` so it's obfuscated in the
+/// client). `max_ratio` is the fraction of the non-whitespace characters that
+/// can be non-printable or the same character before it's garbage.
+pub fn is_garbage(text: &str, max_ratio: f64) -> bool {
+    let plain = strip_section_codes(text);
+    let chars = plain
+        .chars()
+        .filter(|c| !c.is_whitespace())
+        .collect::<Vec<_>>();
+    if chars.len() < MIN_GARBAGE_CHARS {
+        return false;
+    }
+
+    let non_printable = chars
+        .iter()
+        .filter(|&&c| c.is_control() || c == char::REPLACEMENT_CHARACTER)
+        .count();
+    let mut counts = HashMap::<char, usize>::new();
+    for &c in &chars {
+        *counts.entry(c).or_default() += 1;
+    }
+    let most_repeated = counts.values().copied().max().unwrap_or_default();
+
+    let total = chars.len() as f64;
+    non_printable as f64 / total > max_ratio || most_repeated as f64 / total > max_ratio
+}
+
+/// Remove the `§` codes that some servers put in their descriptions directly.
+fn strip_section_codes(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c == '§' {
+            // skip the code too
+            chars.next();
+        } else {
+            out.push(c);
+        }
+    }
+    out
+}
+
+#[derive(Clone, Default, PartialEq)]
+struct SectionStyle {
+    color: Option<char>,
+    bold: bool,
+    italic: bool,
+    underlined: bool,
+    strikethrough: bool,
+    obfuscated: bool,
+}
+
+/// Write the chat component as text with section codes. `last` is the style
+/// that was last written, so we only write codes when the style changes.
+fn write_section_codes(
+    component: &serde_json::Value,
+    parent: &SectionStyle,
+    last: &mut SectionStyle,
+    out: &mut String,
+) {
+    match component {
+        serde_json::Value::String(text) => write_styled(text, parent, last, out),
+        serde_json::Value::Array(components) => {
+            for component in components {
+                write_section_codes(component, parent, last, out);
+            }
+        }
+        serde_json::Value::Object(component) => {
+            let mut style = parent.clone();
+            if let Some(color) = component
+                .get("color")
+                .and_then(|c| c.as_str())
+                .and_then(color_code)
+            {
+                style.color = Some(color);
+            }
+            for (key, value) in [
+                ("bold", &mut style.bold),
+                ("italic", &mut style.italic),
+                ("underlined", &mut style.underlined),
+                ("strikethrough", &mut style.strikethrough),
+                ("obfuscated", &mut style.obfuscated),
+            ] {
+                if let Some(b) = component.get(key).and_then(|b| b.as_bool()) {
+                    *value = b;
+                }
+            }
+
+            if let Some(text) = component.get("text").and_then(|t| t.as_str()) {
+                write_styled(text, &style, last, out);
+            }
+            if let Some(extra) = component.get("extra") {
+                write_section_codes(extra, &style, last, out);
+            }
+        }
+        _ => {}
+    }
+}
+
+fn write_styled(text: &str, style: &SectionStyle, last: &mut SectionStyle, out: &mut String) {
+    if text.is_empty() {
+        return;
+    }
+    if style != last {
+        if *last != SectionStyle::default() {
+            out.push_str("§r");
+        }
+        if let Some(color) = style.color {
+            out.push('§');
+            out.push(color);
+        }
+        for (enabled, code) in [
+            (style.bold, 'l'),
+            (style.italic, 'o'),
+            (style.underlined, 'n'),
+            (style.strikethrough, 'm'),
+            (style.obfuscated, 'k'),
+        ] {
+            if enabled {
+                out.push('§');
+                out.push(code);
+            }
+        }
+        *last = style.clone();
+    }
+    out.push_str(text);
+}
+
+/// The legacy code for a named color. Hex colors don't have one.
+fn color_code(name: &str) -> Option<char> {
+    Some(match name {
+        "black" => '0',
+        "dark_blue" => '1',
+        "dark_green" => '2',
+        "dark_aqua" => '3',
+        "dark_red" => '4',
+        "dark_purple" => '5',
+        "gold" => '6',
+        "gray" => '7',
+        "dark_gray" => '8',
+        "blue" => '9',
+        "green" => 'a',
+        "aqua" => 'b',
+        "red" => 'c',
+        "light_purple" => 'd',
+        "yellow" => 'e',
+        "white" => 'f',
+        _ => return None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn section_codes() {
+        let raw = json!({
+            "text": "",
+            "extra": [
+                { "text": "Hello ", "color": "gold", "bold": true },
+                { "text": "world", "color": "#123456" },
+                "!"
+            ]
+        });
+        let text = FormattedText::deserialize(&raw).unwrap();
+        assert_eq!(Section.format(&text, &raw), "§6§lHello §rworld!");
+    }
+
+    #[test]
+    fn array_description() {
+        let raw = json!([
+            { "text": "Hello ", "color": "gold", "extra": [["nested", " "]] },
+            "world",
+            { "text": "!" },
+            42,
+        ]);
+        assert_eq!(parse(&raw).to_string(), "Hello nested world!42");
+        assert_eq!(parse(&json!([])).to_string(), "");
+        assert_eq!(parse(&json!(["only text"])).to_string(), "only text");
+    }
+
+    #[test]
+    fn truncate_keeps_whole_chars() {
+        // the cut would be in the middle of the emoji if it was done by bytes
+        let text = "ab😀cd";
+        let truncated = truncate(text, 3);
+        assert_eq!(truncated, "ab…");
+        assert!(std::str::from_utf8(truncated.as_bytes()).is_ok());
+        assert_eq!(truncate("服务器😀😀", 4), "服务器…");
+
+        assert_eq!(truncate(text, 5), text);
+        assert_eq!(truncate(text, 100), text);
+        assert_eq!(truncate(text, 0), "…");
+    }
+
+    #[test]
+    fn normalized() {
+        let raw = json!("§aA   Minecraft\n  Server ");
+        let text = FormattedText::deserialize(&raw).unwrap();
+        assert_eq!(Normalized.format(&text, &raw), "A Minecraft Server");
+    }
+}
@@ -0,0 +1,86 @@
+//! Events from the processing task that other code can subscribe to.
+
+use std::{
+    net::{Ipv4Addr, SocketAddrV4},
+    time::Duration,
+};
+
+use tokio::sync::broadcast;
+
+/// The number of events a subscriber can fall behind by before it starts
+/// missing them.
+const CAPACITY: usize = 4096;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProcessingEvent {
+    /// A server that wasn't in the database before was inserted.
+    NewServer(SocketAddrV4),
+    /// We got a valid response from a server and are going to update it.
+    ServerUpdated(SocketAddrV4),
+    /// Too many servers on the IP had the same response, so it was flagged as
+    /// a bad IP.
+    BadIpFlagged(Ipv4Addr),
+    /// A server has players online again after not having any for longer
+    /// than `webhook.revived_after_secs`.
+    ServerRevived {
+        server: SocketAddrV4,
+        inactive_for: Duration,
+    },
+    /// A player was in the sample of a server.
+    PlayerSeen {
+        server: SocketAddrV4,
+        /// The key that the player is stored under, which is their uuid
+        /// without dashes (or a hash of it, if `hash_player_uuids` is enabled).
+        uuid: String,
+        name: String,
+    },
+}
+
+/// A broadcast channel for [`ProcessingEvent`]s. Publishing never blocks, and
+/// subscribers that fall too far behind skip the events they missed.
+#[derive(Clone)]
+pub struct EventBus {
+    sender: broadcast::Sender<ProcessingEvent>,
+}
+
+impl EventBus {
+    pub fn new() -> Self {
+        let (sender, _) = broadcast::channel(CAPACITY);
+        Self { sender }
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<ProcessingEvent> {
+        self.sender.subscribe()
+    }
+
+    pub fn publish(&self, event: ProcessingEvent) {
+        // this only fails if nobody is subscribed, which is fine
+        let _ = self.sender.send(event);
+    }
+}
+
+impl Default for EventBus {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn subscribers_get_events() {
+        let bus = EventBus::new();
+        // nobody is listening yet, so this is just dropped
+        bus.publish(ProcessingEvent::BadIpFlagged(Ipv4Addr::new(1, 2, 3, 4)));
+
+        let mut receiver = bus.subscribe();
+        let addr = SocketAddrV4::new(Ipv4Addr::new(5, 6, 7, 8), 25565);
+        bus.clone().publish(ProcessingEvent::NewServer(addr));
+        assert_eq!(
+            receiver.recv().await.unwrap(),
+            ProcessingEvent::NewServer(addr)
+        );
+    }
+}
@@ -0,0 +1,148 @@
+//! The lists of descriptions and version names that responses are filtered
+//! by. Unlike the rest of the config, these can be reloaded without
+//! restarting by sending matscan a SIGHUP.
+
+use std::{fs, path::Path, sync::Arc};
+
+use parking_lot::Mutex;
+use serde::Deserialize;
+use tracing::{info, warn};
+
+use super::{minecraft::FilterReason, SharedData};
+use crate::config::FiltersConfig;
+
+#[derive(Debug, Clone)]
+pub struct Filters {
+    blocked_descriptions: Vec<String>,
+    blocked_version_names: Vec<String>,
+    whitelist: Vec<String>,
+}
+
+impl Filters {
+    pub fn new(config: &FiltersConfig) -> Self {
+        Self {
+            // empty strings would match everything
+            blocked_descriptions: non_empty(&config.blocked_descriptions),
+            blocked_version_names: config.blocked_version_names.clone(),
+            whitelist: non_empty(&config.whitelist),
+        }
+    }
+
+    /// Read the filters from the `[filters]` section of the config file. The
+    /// rest of the file is ignored.
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        #[derive(Deserialize)]
+        struct FiltersOnly {
+            #[serde(default)]
+            filters: FiltersConfig,
+        }
+
+        let config: FiltersOnly = toml::from_str(&fs::read_to_string(path)?)?;
+        Ok(Self::new(&config.filters))
+    }
+
+    /// Returns the reason that a response with this description and version
+    /// name should be filtered out, if any.
+    pub fn check(&self, description: &str, version_name: &str) -> Result<(), FilterReason> {
+        if self.whitelist.iter().any(|d| description.contains(d)) {
+            return Ok(());
+        }
+        if self
+            .blocked_descriptions
+            .iter()
+            .any(|d| description.contains(d))
+        {
+            return Err(FilterReason::PlaceholderDescription);
+        }
+        if self.blocked_version_names.iter().any(|v| v == version_name) {
+            return Err(FilterReason::MitigationVersion);
+        }
+        Ok(())
+    }
+}
+
+impl Default for Filters {
+    fn default() -> Self {
+        Self::new(&FiltersConfig::default())
+    }
+}
+
+fn non_empty(list: &[String]) -> Vec<String> {
+    list.iter().filter(|s| !s.is_empty()).cloned().collect()
+}
+
+/// Reload the filters from the config file every time we get a SIGHUP. If the
+/// file can't be parsed, the old filters are kept.
+pub async fn reload_on_sighup(
+    config_path: impl AsRef<Path>,
+    shared: Arc<Mutex<SharedData>>,
+) -> anyhow::Result<()> {
+    let mut signal = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())?;
+    while signal.recv().await.is_some() {
+        reload(config_path.as_ref(), &shared);
+    }
+    Ok(())
+}
+
+/// Replace the filters with the ones in the config file. Responses that are
+/// processed after this use the new filters, even if it happens in the middle
+/// of processing the queue.
+pub fn reload(config_path: &Path, shared: &Mutex<SharedData>) {
+    match Filters::load(config_path) {
+        Ok(filters) => {
+            println!("reloaded filters");
+            info!("Reloaded filters: {filters:?}");
+            shared.lock().filters = Arc::new(filters);
+        }
+        Err(err) => warn!("Couldn't reload filters, keeping the old ones: {err}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn load_only_reads_filters() {
+        let path =
+            std::env::temp_dir().join(format!("matscan-filters-{}.toml", std::process::id()));
+        fs::write(
+            &path,
+            r#"
+            mongodb_uri = "mongodb://localhost"
+            rate = 1000
+
+            [filters]
+            blocked_descriptions = ["honeypot"]
+            "#,
+        )
+        .unwrap();
+        let filters = Filters::load(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(
+            filters.check("a honeypot", "1.20.1"),
+            Err(FilterReason::PlaceholderDescription)
+        );
+        // the version names weren't set so they're the defaults
+        assert_eq!(
+            filters.check("A Minecraft Server", "TCPShield.com"),
+            Err(FilterReason::MitigationVersion)
+        );
+    }
+
+    #[test]
+    fn whitelist_overrides_blocked() {
+        let filters = Filters::new(&FiltersConfig {
+            blocked_descriptions: vec!["hosting".to_string(), String::new()],
+            blocked_version_names: vec![],
+            whitelist: vec!["my server".to_string()],
+        });
+        assert!(filters.check("A Minecraft Server", "1.20.1").is_ok());
+        assert!(filters.check("my server hosting", "1.20.1").is_ok());
+        assert_eq!(
+            filters.check("cheap hosting", "1.20.1"),
+            Err(FilterReason::PlaceholderDescription)
+        );
+    }
+}
@@ -0,0 +1,263 @@
+//! Gets the plugin channels that Forge servers list in `forgeData`, which can
+//! tell us about specific mods being installed even when the mod list is
+//! missing or truncated.
+//!
+//! Before Forge 40 (1.18.2) the channels are sent as a normal JSON array, but
+//! newer versions pack the mods and channels into a binary buffer that's
+//! encoded into the `d` string with 15 bits per character.
+
+use bson::{doc, Bson, Document};
+
+/// The most channels that are stored for a server, since modpacks can have
+/// a lot of them.
+pub const MAX_CHANNELS: usize = 128;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Channel {
+    /// The channel's resource location, like `fml:handshake`.
+    pub name: String,
+    pub version: String,
+}
+
+impl From<Channel> for Bson {
+    fn from(channel: Channel) -> Self {
+        Bson::Document(doc! { "name": channel.name, "version": channel.version })
+    }
+}
+
+/// Get the channels from the server's `forgeData`, from either the encoded
+/// `d` string or the `channels` array. At most [`MAX_CHANNELS`] are returned.
+pub fn channels(forge_data: &Document) -> Vec<Channel> {
+    if let Ok(encoded) = forge_data.get_str("d") {
+        let Some(bytes) = decode_optimized(encoded) else {
+            return Vec::new();
+        };
+        let mut channels = Vec::new();
+        // a truncated buffer still has the channels before where it was cut off
+        let _ = read_optimized_channels(&mut Reader { bytes: &bytes }, &mut channels);
+        return channels;
+    }
+
+    let Ok(channels) = forge_data.get_array("channels") else {
+        return Vec::new();
+    };
+    channels
+        .iter()
+        .filter_map(|channel| channel.as_document())
+        .filter_map(|channel| {
+            Some(Channel {
+                name: channel.get_str("res").ok()?.to_string(),
+                version: channel.get_str("version").unwrap_or_default().to_string(),
+            })
+        })
+        .take(MAX_CHANNELS)
+        .collect()
+}
+
+/// Turn the `d` string back into bytes, the same way Forge's
+/// `ServerStatusPing.decodeOptimized` does. The first two characters are the
+/// length, and every character after that has 15 bits of the data.
+fn decode_optimized(encoded: &str) -> Option<Vec<u8>> {
+    let chars = encoded.chars().map(|c| c as u32).collect::<Vec<_>>();
+    let size = (*chars.first()? | (*chars.get(1)? << 15)) as usize;
+    // each character has less than two bytes, so anything bigger is a lie that
+    // would make us allocate a huge buffer
+    if size > chars.len() * 2 {
+        return None;
+    }
+
+    let mut bytes = Vec::with_capacity(size);
+    let mut buffer = 0_u32;
+    let mut bits_in_buffer = 0;
+    for &c in &chars[2..] {
+        while bits_in_buffer >= 8 {
+            bytes.push(buffer as u8);
+            buffer >>= 8;
+            bits_in_buffer -= 8;
+        }
+        buffer |= (c & 0x7fff) << bits_in_buffer;
+        bits_in_buffer += 15;
+    }
+    while bytes.len() < size {
+        bytes.push(buffer as u8);
+        buffer >>= 8;
+    }
+    bytes.truncate(size);
+    Some(bytes)
+}
+
+/// Read the channels from the decoded buffer. Returns None if the buffer ended
+/// early, but the channels that were read before that are still added.
+fn read_optimized_channels(reader: &mut Reader, channels: &mut Vec<Channel>) -> Option<()> {
+    let _truncated = reader.bool()?;
+
+    let mod_count = reader.u16()?;
+    for _ in 0..mod_count {
+        let channel_count_and_flag = reader.varint()? as u32;
+        let mod_id = reader.string()?;
+        // the version is left out for mods that are only on the server
+        if channel_count_and_flag & 1 == 0 {
+            reader.string()?;
+        }
+        for _ in 0..channel_count_and_flag >> 1 {
+            let name = reader.string()?;
+            let version = reader.string()?;
+            let _required_on_client = reader.bool()?;
+            if channels.len() >= MAX_CHANNELS {
+                return Some(());
+            }
+            channels.push(Channel {
+                name: format!("{mod_id}:{name}"),
+                version,
+            });
+        }
+    }
+
+    // channels that don't belong to a mod, which have their full name
+    let other_channel_count = reader.varint()? as u32;
+    for _ in 0..other_channel_count {
+        let name = reader.string()?;
+        let version = reader.string()?;
+        let _required_on_client = reader.bool()?;
+        if channels.len() >= MAX_CHANNELS {
+            return Some(());
+        }
+        channels.push(Channel { name, version });
+    }
+
+    Some(())
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl Reader<'_> {
+    fn u8(&mut self) -> Option<u8> {
+        let (&byte, rest) = self.bytes.split_first()?;
+        self.bytes = rest;
+        Some(byte)
+    }
+
+    fn bool(&mut self) -> Option<bool> {
+        Some(self.u8()? != 0)
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        Some(u16::from_be_bytes([self.u8()?, self.u8()?]))
+    }
+
+    fn varint(&mut self) -> Option<i32> {
+        let mut value = 0_u32;
+        for i in 0..5 {
+            let byte = self.u8()?;
+            value |= ((byte & 0x7f) as u32) << (7 * i);
+            if byte & 0x80 == 0 {
+                return Some(value as i32);
+            }
+        }
+        None
+    }
+
+    fn string(&mut self) -> Option<String> {
+        let length = self.varint()? as usize;
+        if length > self.bytes.len() {
+            return None;
+        }
+        let (string, rest) = self.bytes.split_at(length);
+        self.bytes = rest;
+        String::from_utf8(string.to_vec()).ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The reverse of `decode_optimized`, like Forge's
+    /// `ServerStatusPing.encodeOptimized`.
+    fn encode_optimized(bytes: &[u8]) -> String {
+        let mut encoded = vec![
+            (bytes.len() & 0x7fff) as u32,
+            ((bytes.len() >> 15) & 0x7fff) as u32,
+        ];
+        let mut buffer = 0_u32;
+        let mut bits_in_buffer = 0_i32;
+        for &byte in bytes {
+            while bits_in_buffer >= 15 {
+                encoded.push(buffer & 0x7fff);
+                buffer >>= 15;
+                bits_in_buffer -= 15;
+            }
+            buffer |= (byte as u32) << bits_in_buffer;
+            bits_in_buffer += 8;
+        }
+        while bits_in_buffer > 0 {
+            encoded.push(buffer & 0x7fff);
+            buffer >>= 15;
+            bits_in_buffer -= 15;
+        }
+        encoded.into_iter().filter_map(char::from_u32).collect()
+    }
+
+    fn write_string(out: &mut Vec<u8>, string: &str) {
+        out.push(string.len() as u8);
+        out.extend(string.as_bytes());
+    }
+
+    #[test]
+    fn optimized_channels() {
+        let mut bytes = vec![0, 0, 2];
+        // forge with two channels and a version
+        bytes.push(2 << 1);
+        write_string(&mut bytes, "forge");
+        write_string(&mut bytes, "47.1.0");
+        for (name, version) in [("tier_sorting", "1.0"), ("split", "1.1")] {
+            write_string(&mut bytes, name);
+            write_string(&mut bytes, version);
+            bytes.push(1);
+        }
+        // a server-only mod without a version or channels
+        bytes.push(1);
+        write_string(&mut bytes, "spark");
+        // one channel that isn't from a mod
+        bytes.push(1);
+        write_string(&mut bytes, "minecraft:register");
+        write_string(&mut bytes, "FML3");
+        bytes.push(0);
+
+        let encoded = encode_optimized(&bytes);
+        assert_eq!(decode_optimized(&encoded).unwrap(), bytes);
+
+        let channel = |name: &str, version: &str| Channel {
+            name: name.to_string(),
+            version: version.to_string(),
+        };
+        assert_eq!(
+            channels(&doc! { "d": encoded, "truncated": false, "fmlNetworkVersion": 3 }),
+            [
+                channel("forge:tier_sorting", "1.0"),
+                channel("forge:split", "1.1"),
+                channel("minecraft:register", "FML3"),
+            ]
+        );
+
+        // cut off in the middle of the last channel
+        let truncated = encode_optimized(&bytes[..bytes.len() - 3]);
+        assert_eq!(
+            channels(&doc! { "d": truncated }),
+            [
+                channel("forge:tier_sorting", "1.0"),
+                channel("forge:split", "1.1"),
+            ]
+        );
+    }
+
+    #[test]
+    fn bad_encoded_length() {
+        // says it has a gigabyte of data
+        let encoded = ['\u{7fff}', '\u{7fff}', 'a'].iter().collect::<String>();
+        assert_eq!(decode_optimized(&encoded), None);
+        assert!(channels(&doc! { "d": encoded }).is_empty());
+    }
+}
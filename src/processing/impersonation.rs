@@ -0,0 +1,99 @@
+//! Detects servers that copy a big network's description or favicon to look
+//! legitimate, which honeypots sometimes do.
+
+use std::net::Ipv4Addr;
+
+use crate::{config::KnownNetworkConfig, exclude, scanner::targets::Ipv4Ranges};
+
+use super::description;
+
+struct KnownNetwork {
+    name: String,
+    /// Already normalized.
+    description: Option<String>,
+    favicon_hash: Option<i64>,
+    ranges: Ipv4Ranges,
+}
+
+#[derive(Default)]
+pub struct KnownNetworks {
+    networks: Vec<KnownNetwork>,
+}
+
+impl KnownNetworks {
+    pub fn new(config: &[KnownNetworkConfig]) -> anyhow::Result<Self> {
+        let networks = config
+            .iter()
+            .map(|network| {
+                Ok(KnownNetwork {
+                    name: network.name.clone(),
+                    description: network.description.as_deref().map(description::normalize),
+                    favicon_hash: network.favicon_hash,
+                    ranges: exclude::parse(&network.ranges.join("\n"))?,
+                })
+            })
+            .collect::<anyhow::Result<_>>()?;
+        Ok(Self { networks })
+    }
+
+    /// The name of the network that the server is pretending to be, if any.
+    /// A server matches a network if its normalized description and favicon
+    /// hash are the same as the ones configured for it (networks without one
+    /// of them only compare the other), but its IP isn't in the network's
+    /// ranges.
+    pub fn impersonated(
+        &self,
+        ip: Ipv4Addr,
+        description: &str,
+        favicon_hash: Option<i64>,
+    ) -> Option<&str> {
+        let description = description::normalize(description);
+        self.networks
+            .iter()
+            .find(|network| {
+                if network.description.is_none() && network.favicon_hash.is_none() {
+                    return false;
+                }
+                let description_matches = network
+                    .description
+                    .as_ref()
+                    .map_or(true, |d| *d == description);
+                let favicon_matches = network
+                    .favicon_hash
+                    .map_or(true, |hash| Some(hash) == favicon_hash);
+                description_matches && favicon_matches && !network.ranges.contains(ip)
+            })
+            .map(|network| network.name.as_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matching_motd_on_foreign_ip() {
+        let networks = KnownNetworks::new(&[KnownNetworkConfig {
+            name: "Hypixel".to_string(),
+            description: Some("Hypixel Network [1.8-1.20]".to_string()),
+            favicon_hash: None,
+            ranges: vec!["209.222.114.0/23".to_string()],
+        }])
+        .unwrap();
+
+        let motd = "§aHypixel Network  §c[1.8-1.20]";
+        assert_eq!(
+            networks.impersonated(Ipv4Addr::new(1, 2, 3, 4), motd, None),
+            Some("Hypixel")
+        );
+        // the real network
+        assert_eq!(
+            networks.impersonated(Ipv4Addr::new(209, 222, 115, 10), motd, None),
+            None
+        );
+        assert_eq!(
+            networks.impersonated(Ipv4Addr::new(1, 2, 3, 4), "A Minecraft Server", None),
+            None
+        );
+    }
+}
@@ -1,83 +1,459 @@
 use std::{
-    collections::{hash_map::DefaultHasher, HashMap, HashSet},
+    collections::hash_map::DefaultHasher,
+    fmt,
     hash::{Hash, Hasher},
-    net::SocketAddrV4,
-    sync::{Arc, LazyLock},
-    time::SystemTime,
+    io::Read,
+    net::{Ipv4Addr, SocketAddrV4},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, LazyLock,
+    },
+    time::{Duration, Instant, SystemTime},
 };
 
-use anyhow::bail;
 use async_trait::async_trait;
-use azalea_chat::FormattedText;
 use bson::{doc, Bson, Document};
+use hmac::{Hmac, Mac};
+use md5::{Digest, Md5};
 use mongodb::options::UpdateOptions;
 use parking_lot::Mutex;
 use regex::Regex;
 use serde::Deserialize;
-use tracing::error;
+use sha2::Sha256;
+use tracing::{debug, error};
 
 use crate::{
-    config::Config,
-    database::{self, bulk_write::BulkUpdate, CachedIpHash, Database},
+    config::{AdaptiveRescanConfig, Config, FaviconMode, ProcessingConfig},
+    database::{
+        self,
+        bulk_write::{BulkUpdate, BulkUpdateBuilder},
+        Database,
+    },
     scanner::protocols,
 };
 
-use super::{ProcessableProtocol, SharedData};
+use super::{
+    description::{self, DescriptionFormat, DescriptionFormatter, Plain},
+    events::{EventBus, ProcessingEvent},
+    filters::Filters,
+    forge,
+    proxy::{self, ProxySignals, ProxyType},
+    ProcessResult, ProcessableProtocol, SharedData,
+};
 
 const ANONYMOUS_PLAYER_NAME: &str = "Anonymous Player";
 
+/// The largest response that we'll store in the `raw` field, in bytes.
+const MAX_RAW_SAMPLE_SIZE: usize = 64 * 1024;
+
+/// The number of description hashes we keep for each server. If all of them are
+/// different, the server is considered to rotate its MOTD.
+const MOTD_HISTORY_LENGTH: usize = 3;
+
 #[async_trait]
 impl ProcessableProtocol for protocols::Minecraft {
+    const NAME: &'static str = "minecraft";
+
+    fn is_plausible(data: &[u8]) -> bool {
+        // the status is always a json object, other protocols are only let
+        // through so they're counted
+        data.first() == Some(&b'{')
+            || data.starts_with(&protocols::GZIP_MAGIC)
+            || protocols::OtherProtocol::detect(data).is_some()
+    }
+
     fn process(
         shared: &Arc<Mutex<SharedData>>,
         config: &Config,
         target: SocketAddrV4,
         data: &[u8],
+        rtt: Option<Duration>,
         database: &Database,
-    ) -> Option<BulkUpdate> {
-        let data = String::from_utf8_lossy(data);
+    ) -> ProcessResult {
+        if let Some(other_protocol) = protocols::OtherProtocol::detect(data) {
+            if config.bad_ip.count_other_protocols {
+                // every server that speaks the same protocol gets the same hash, so an
+                // ip that responds with tls on every port gets flagged
+                let mut hasher = DefaultHasher::new();
+                other_protocol.hash(&mut hasher);
+                let events = shared.lock().events.clone();
+                record_bad_ip_hash(database, &events, config, &target, hasher.finish());
+            }
+            return ProcessResult::Filtered(match other_protocol {
+                protocols::OtherProtocol::Tls => FilterReason::Tls,
+                protocols::OtherProtocol::Http => FilterReason::Http,
+            });
+        }
+
+        if is_oversized(data, &config.processing) {
+            if config.processing.count_oversized_responses {
+                // the length is the hash so servers sending the same huge response
+                // on every port get flagged
+                let mut hasher = DefaultHasher::new();
+                data.len().hash(&mut hasher);
+                let events = shared.lock().events.clone();
+                record_bad_ip_hash(database, &events, config, &target, hasher.finish());
+            }
+            return ProcessResult::Filtered(FilterReason::Oversized);
+        }
 
         // let passive_fingerprint = generate_passive_fingerprint(&data).ok();
 
-        let data: serde_json::Value = match serde_json::from_str(&data) {
+        let data = match parse_response(data, max_response_bytes(&config.processing)) {
             Ok(json) => json,
-            Err(_) => {
-                // not a minecraft server ig
-                return None;
+            // not a minecraft server ig
+            Err(reason) => {
+                if let Some(threshold) = config.processing.junk_response_threshold {
+                    let (is_junk_ip, events) = {
+                        let mut shared = shared.lock();
+                        let is_junk_ip = shared.record_junk_response(*target.ip(), threshold);
+                        (is_junk_ip, shared.events.clone())
+                    };
+                    if is_junk_ip {
+                        flag_bad_ip(database, &events, *target.ip());
+                    }
+                }
+                return ProcessResult::Filtered(reason);
             }
         };
 
-        if let Some(cleaned_data) = clean_response_data(&data) {
-            let mongo_update = doc! { "$set": cleaned_data };
-            match create_bulk_update(database, &target, mongo_update) {
-                Ok(r) => Some(r),
-                Err(err) => {
-                    error!("Error updating server {target}: {err}");
-                    None
+        let filters = shared.lock().filters.clone();
+        match clean_response_data(&data, &config.processing, &filters, rtt, SystemTime::now()) {
+            Ok(mut cleaned_data) => {
+                if is_sampled(config.debug.dump_sample_rate) {
+                    dump_sample(target, &data, &cleaned_data, &config.processing);
+                }
+                let player_updates = create_player_updates(&target, &cleaned_data);
+                let cleaned_players = cleaned_data
+                    .iter()
+                    .filter_map(|(key, player)| {
+                        let uuid = key.strip_prefix("players.")?;
+                        let name = player.as_document()?.get_str("name").ok()?;
+                        Some((uuid.to_string(), name.to_string()))
+                    })
+                    .collect::<Vec<_>>();
+
+                let motd_hash =
+                    hash_description(cleaned_data.get_str("description").unwrap_or_default());
+                let known_networks = shared.lock().known_networks.clone();
+                if known_networks
+                    .impersonated(
+                        *target.ip(),
+                        cleaned_data.get_str("description").unwrap_or_default(),
+                        cleaned_data.get_i64("faviconHash").ok(),
+                    )
+                    .is_some()
+                {
+                    cleaned_data.insert("impersonation", true);
+                }
+
+                let favicon_update = apply_favicon_mode(
+                    &mut cleaned_data,
+                    data.get("favicon").and_then(|f| f.as_str()),
+                    config.favicons.mode,
+                );
+
+                let handshake_rotation = shared.lock().handshake_rotation;
+                cleaned_data.insert(
+                    "respondedToProtocol",
+                    protocols::pick_protocol_version(
+                        &config.target.protocol_versions(),
+                        target,
+                        handshake_rotation,
+                    ),
+                );
+
+                let update = BulkUpdateBuilder::new()
+                    .set(cleaned_data)
+                    .push(doc! {
+                        "motdHashes": {
+                            "$each": [motd_hash],
+                            "$slice": -(MOTD_HISTORY_LENGTH as i32),
+                        }
+                    })
+                    .computed(doc! { "motdRotates": motd_rotates_expression(motd_hash) });
+                let events = shared.lock().events.clone();
+                match create_bulk_update(database, &events, config, &target, update) {
+                    Ok(r) => {
+                        events.publish(ProcessingEvent::ServerUpdated(target));
+                        for (key, player) in &cleaned_players {
+                            events.publish(ProcessingEvent::PlayerSeen {
+                                server: target,
+                                uuid: key.to_string(),
+                                name: player.to_string(),
+                            });
+                        }
+                        let mut shared = shared.lock();
+                        shared.player_updates.extend(player_updates);
+                        shared.favicon_updates.extend(favicon_update);
+                        ProcessResult::Update(r)
+                    }
+                    Err(err) if err.is::<OnBadIp>() => ProcessResult::BadIp,
+                    Err(err) => {
+                        error!("Error updating server {target}: {err}");
+                        ProcessResult::NotApplicable
+                    }
                 }
             }
-        } else {
+            Err(reason) => ProcessResult::Filtered(reason),
+        }
+    }
+}
+
+/// Set `hasFavicon` on the server if the mode says to, and return the update
+/// for the favicons collection if the favicon should be stored there.
+fn apply_favicon_mode(
+    cleaned_data: &mut Document,
+    favicon: Option<&str>,
+    mode: FaviconMode,
+) -> Option<BulkUpdate> {
+    let favicon = favicon.filter(|f| !f.is_empty())?;
+    if mode == FaviconMode::None {
+        return None;
+    }
+    cleaned_data.insert("hasFavicon", true);
+    if mode != FaviconMode::Store {
+        return None;
+    }
+
+    let favicon_hash = cleaned_data.get_i64("faviconHash").ok()?;
+    let update = BulkUpdateBuilder::new()
+        .set_on_insert(doc! { "favicon": favicon })
+        .set(doc! { "lastSeen": bson::DateTime::now() })
+        .upsert(true)
+        .build(doc! { "_id": favicon_hash });
+    match update {
+        Ok(update) => Some(update),
+        Err(err) => {
+            error!("Error building favicon update: {err}");
             None
         }
     }
 }
 
+/// Whether the description is one that proxies like BungeeCord show when the
+/// server behind them is offline. The signatures are matched case-insensitively
+/// anywhere in the description.
+fn is_backend_offline_description(description: &str, signatures: &[String]) -> bool {
+    let description = description.to_lowercase();
+    signatures
+        .iter()
+        .any(|signature| description.contains(&signature.to_lowercase()))
+}
+
+/// Whether a uuid (without dashes) from the sample could be a real player's.
+///
+/// Normally the uuid only has to contain something that looks like a v3 or v4
+/// uuid, and anyone named "Anonymous Player" is allowed. With `strict`, the
+/// uuid has to be exactly a v3 or v4 uuid, and anonymous players have to have
+/// the nil uuid.
+fn is_plausible_sample_uuid(uuid: &str, name: &str, strict: bool) -> bool {
+    static UUID_REGEX: LazyLock<Regex> =
+        LazyLock::new(|| Regex::new("[0-9a-f]{12}[34][0-9a-f]{19}").unwrap());
+    static STRICT_UUID_REGEX: LazyLock<Regex> =
+        LazyLock::new(|| Regex::new("^[0-9a-f]{12}[34][0-9a-f]{19}$").unwrap());
+
+    // anonymous player is a nil uuid so it wouldn't match the regex
+    if strict {
+        STRICT_UUID_REGEX.is_match(uuid)
+            || (name == ANONYMOUS_PLAYER_NAME && uuid.len() == 32 && uuid.chars().all(|c| c == '0'))
+    } else {
+        UUID_REGEX.is_match(uuid) || name == ANONYMOUS_PLAYER_NAME
+    }
+}
+
+/// The largest response we parse. The scanner drops responses that say
+/// they're bigger than this before they're buffered.
+pub fn max_response_bytes(config: &ProcessingConfig) -> usize {
+    config.max_response_bytes.unwrap_or(2 * 1024 * 1024)
+}
+
+/// Whether the response is too big to bother parsing.
+fn is_oversized(data: &[u8], config: &ProcessingConfig) -> bool {
+    data.len() > max_response_bytes(config)
+}
+
+/// Parse the response as JSON, decompressing it first if it's gzipped. It's
+/// dropped if it decompresses to more than `max_bytes`.
+///
+/// If that fails, it's dropped as invalid UTF-8 or invalid JSON. It's never
+/// parsed again with the invalid bytes replaced, since that could turn a
+/// binary blob into something that looks like a real response.
+fn parse_response(data: &[u8], max_bytes: usize) -> Result<serde_json::Value, FilterReason> {
+    let decompressed;
+    let data = if data.starts_with(&protocols::GZIP_MAGIC) {
+        decompressed = gunzip(data, max_bytes)?;
+        &decompressed[..]
+    } else {
+        data
+    };
+
+    if let Ok(json) = serde_json::from_slice(data) {
+        return Ok(json);
+    }
+    std::str::from_utf8(data).map_or(Err(FilterReason::InvalidUtf8), |_| {
+        Err(FilterReason::InvalidJson)
+    })
+}
+
+/// Decompress the gzipped data, without reading more than `max_bytes` of it so
+/// a zip bomb can't use up all our memory.
+fn gunzip(data: &[u8], max_bytes: usize) -> Result<Vec<u8>, FilterReason> {
+    let mut decompressed = Vec::new();
+    flate2::read::GzDecoder::new(data)
+        .take(max_bytes as u64 + 1)
+        .read_to_end(&mut decompressed)
+        .map_err(|_| FilterReason::InvalidJson)?;
+    if decompressed.len() > max_bytes {
+        return Err(FilterReason::Oversized);
+    }
+    Ok(decompressed)
+}
+
+/// Why [`clean_response_data`] didn't return a document for a server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FilterReason {
+    /// The response wasn't valid JSON.
+    InvalidJson,
+    /// The response wasn't a JSON object that we could convert to BSON.
+    Malformed,
+    /// There was no description, so it's probably not a Minecraft server.
+    NoDescription,
+    /// The description is one that hosts and proxies show when the actual
+    /// server is offline.
+    PlaceholderDescription,
+    /// The version name is one used by DDoS mitigation services.
+    MitigationVersion,
+    /// A player in the sample wasn't an object.
+    InvalidSample,
+    /// The response was the start of a TLS handshake.
+    Tls,
+    /// The response was an HTTP response.
+    Http,
+    /// The response was bigger than `max_response_bytes`.
+    Oversized,
+    /// The response wasn't valid JSON, and it wasn't valid UTF-8 either.
+    InvalidUtf8,
+}
+
+impl FilterReason {
+    /// Every reason, in the same order as they're declared.
+    pub const ALL: [FilterReason; 10] = [
+        FilterReason::InvalidJson,
+        FilterReason::Malformed,
+        FilterReason::NoDescription,
+        FilterReason::PlaceholderDescription,
+        FilterReason::MitigationVersion,
+        FilterReason::InvalidSample,
+        FilterReason::Tls,
+        FilterReason::Http,
+        FilterReason::Oversized,
+        FilterReason::InvalidUtf8,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            FilterReason::InvalidJson => "invalid json",
+            FilterReason::Malformed => "malformed",
+            FilterReason::NoDescription => "no description",
+            FilterReason::PlaceholderDescription => "placeholder description",
+            FilterReason::MitigationVersion => "mitigation version",
+            FilterReason::InvalidSample => "invalid sample",
+            FilterReason::Tls => "tls",
+            FilterReason::Http => "http",
+            FilterReason::Oversized => "oversized",
+            FilterReason::InvalidUtf8 => "invalid utf-8",
+        }
+    }
+}
+
+/// The number of servers that were filtered for each [`FilterReason`].
+pub struct FilterCounts {
+    counts: [AtomicUsize; FilterReason::ALL.len()],
+}
+
+impl FilterCounts {
+    pub const fn new() -> Self {
+        Self {
+            counts: [const { AtomicUsize::new(0) }; FilterReason::ALL.len()],
+        }
+    }
+
+    pub fn record(&self, reason: FilterReason) {
+        self.counts[reason as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn get(&self, reason: FilterReason) -> usize {
+        self.counts[reason as usize].load(Ordering::Relaxed)
+    }
+
+    pub fn total(&self) -> usize {
+        FilterReason::ALL
+            .iter()
+            .map(|&reason| self.get(reason))
+            .sum()
+    }
+}
+
+impl Default for FilterCounts {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Display for FilterCounts {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "filtered servers:")?;
+        for reason in FilterReason::ALL {
+            writeln!(f, "  {:<24}{:>10}", reason.name(), self.get(reason))?;
+        }
+        write!(f, "  {:<24}{:>10}", "total", self.total())
+    }
+}
+
+/// The filter counts for every server we've processed since matscan started.
+pub static FILTER_COUNTS: FilterCounts = FilterCounts::new();
+
 /// Clean up the response data from the server into something we can insert into
 /// our database.
-fn clean_response_data(data: &serde_json::Value) -> Option<Document> {
-    let data_serde_json = data.as_object()?.to_owned();
-    let mut data = Bson::deserialize(data).ok()?;
-    let mut data = data.as_document_mut()?.to_owned();
-    // default to empty string if description is missing
-    let Some(description) = data_serde_json
-        .get("description")
-        .map(|d| FormattedText::deserialize(d).unwrap_or_default())
-    else {
+///
+/// `now` is used for every timestamp in the document.
+fn clean_response_data(
+    data: &serde_json::Value,
+    config: &ProcessingConfig,
+    filters: &Filters,
+    rtt: Option<Duration>,
+    now: SystemTime,
+) -> Result<Document, FilterReason> {
+    let now = bson::DateTime::from_system_time(now);
+
+    let original_data = data;
+    let data_serde_json = data.as_object().ok_or(FilterReason::Malformed)?.to_owned();
+    let mut data = Bson::deserialize(data).map_err(|_| FilterReason::Malformed)?;
+    let mut data = data
+        .as_document_mut()
+        .ok_or(FilterReason::Malformed)?
+        .to_owned();
+    let Some(raw_description) = data_serde_json.get("description") else {
         // no description, so probably not even a minecraft server
-        return None;
+        return Err(FilterReason::NoDescription);
     };
+    // default to empty string if the description is invalid
+    let formatted_description = description::parse(raw_description);
 
-    let description = description.to_string();
+    let description = Plain.format(&formatted_description, raw_description);
+    let mut formatted_descriptions = Document::new();
+    for format in &config.description_formats {
+        // the plain description is always stored
+        if *format != DescriptionFormat::Plain {
+            let formatter = format.formatter();
+            let mut formatted = formatter.format(&formatted_description, raw_description);
+            if let Some(max_length) = config.max_description_length {
+                formatted = description::truncate(&formatted, max_length);
+            }
+            formatted_descriptions.insert(formatter.field(), formatted);
+        }
+    }
 
     // update description to be a string
     data.insert(
@@ -85,36 +461,12 @@ fn clean_response_data(data: &serde_json::Value) -> Option<Document> {
         Bson::String(description.to_string()),
     );
 
-    // maybe in the future we can store favicons in a separate collection
-    // if data.contains_key("favicon") {
-    //     data.insert("favicon", Bson::Boolean(true));
-    // }
-
-    if data.contains_key("modinfo") {
-        // forge server
-        data.insert("isModded", Bson::Boolean(true));
-    }
-
-    if data.contains_key("forgeData")
-        || data.contains_key("modinfo")
-        || data.contains_key("modpackData")
-    {
-        data.insert("isModded", Bson::Boolean(true));
-    }
-
-    let version_name = data
-        .get("version")
-        .and_then(|v| v.as_document())
-        .and_then(|v| v.get("name"))
-        .and_then(|n| n.as_str())
-        .unwrap_or_default();
+    let mod_ids = get_mod_ids(&data);
 
-    let version_protocol = data
-        .get("version")
-        .and_then(|v| v.as_document())
-        .and_then(|v| v.get("protocol"))
-        .and_then(|p| p.as_i32())
-        .unwrap_or_default();
+    let (version_name, version_protocol) = get_version(&data);
+    // real servers always send a version, so it being missing is suspicious in
+    // itself and shouldn't be stored as an empty one
+    let version_missing = matches!(data.get("version"), None | Some(Bson::Null));
 
     let max_players = data
         .get("players")
@@ -129,33 +481,26 @@ fn clean_response_data(data: &serde_json::Value) -> Option<Document> {
         .and_then(|m| m.as_i32())
         .unwrap_or_default();
 
-    if description.contains("Craftserve.pl - wydajny hosting Minecraft!")
-        || description.contains("Ochrona DDoS: Przekroczono limit polaczen.")
-        || description.contains("¨ |  ")
-        || description.contains("Start the server at FalixNodes.net/start")
-        || description.contains("This server is offline Powered by FalixNodes.net")
-        || description.contains("Serwer jest aktualnie wy")
-        || description.contains("Blad pobierania statusu. Polacz sie bezposrednio!")
-        || matches!(
-            version_name,
-            "COSMIC GUARD" | "TCPShield.com" | "â  Error" | "⚠ Error"
-        )
-    {
-        return None;
-    }
+    filters.check(&description, version_name)?;
 
     let mut is_online_mode: Option<bool> = None;
     let mut mixed_online_mode = false;
     let mut fake_sample = false;
+    let mut spoofed_online_mode = false;
+    let mut suspicious_sample = false;
     let mut has_players = false;
 
     let mut players_data = bson::Document::default();
+    // the uuids in this sample, so we can tell who left between pings
+    let mut sample_uuids = Vec::<String>::new();
     let mut extra_data = bson::Document::default();
 
     // servers with this motd randomize the online players
     let should_ignore_players = description == "To protect the privacy of this server and its\nusers, you must log in once to see ping data.";
+    // a server that says it can't have anyone online can't have a real sample
+    let sample_without_online = is_sample_without_online(&data, online_players, max_players);
 
-    if !should_ignore_players {
+    if !should_ignore_players && !sample_without_online {
         for player in data
             .get("players")
             .and_then(|p| p.as_document())
@@ -165,7 +510,7 @@ fn clean_response_data(data: &serde_json::Value) -> Option<Document> {
             .map(|s| s.iter().take(100).collect::<Vec<_>>())
             .unwrap_or_default()
         {
-            let player = player.as_document()?;
+            let player = player.as_document().ok_or(FilterReason::InvalidSample)?;
 
             let uuid = player
                 .get("id")
@@ -178,13 +523,12 @@ fn clean_response_data(data: &serde_json::Value) -> Option<Document> {
 
             let uuid = uuid.replace('-', "");
 
-            static UUID_REGEX: LazyLock<Regex> =
-                LazyLock::new(|| Regex::new("[0-9a-f]{12}[34][0-9a-f]{19}").unwrap());
-
-            // anonymous player is a nil uuid so it wouldn't match the regex
-            if !UUID_REGEX.is_match(&uuid) && name != ANONYMOUS_PLAYER_NAME {
+            if !is_plausible_sample_uuid(&uuid, name, config.require_players_sample_match) {
                 fake_sample = true;
             }
+            if is_spoofed_online_uuid(&uuid, name) {
+                spoofed_online_mode = true;
+            }
 
             if !mixed_online_mode {
                 // ignore nil uuids (anonymous players)
@@ -203,53 +547,163 @@ fn clean_response_data(data: &serde_json::Value) -> Option<Document> {
                 }
             }
 
+            // names this long can't be real, but they might be big enough to bloat the
+            // document so they're cut off
+            let max_name_length = config.max_player_name_length.unwrap_or(48);
+            let name = if name.chars().count() > max_name_length {
+                suspicious_sample = true;
+                name.chars().take(max_name_length).collect::<String>()
+            } else {
+                name.to_string()
+            };
+
             let mut player_doc = Document::new();
-            player_doc.insert(
-                "lastSeen",
-                Bson::DateTime(bson::DateTime::from_system_time(SystemTime::now())),
-            );
-            player_doc.insert("name", Bson::String(name.to_string()));
+            player_doc.insert("lastSeen", Bson::DateTime(now));
+            player_doc.insert("name", Bson::String(name));
 
-            players_data.insert(format!("players.{}", uuid), player_doc);
+            let player_key = match player_uuid_hash_key(config) {
+                Some(key) => hash_player_uuid(&uuid, key),
+                None => uuid,
+            };
+            players_data.insert(format!("players.{}", player_key), player_doc);
+            if !sample_uuids.contains(&player_key) {
+                sample_uuids.push(player_key);
+            }
 
             has_players = true;
         }
     }
 
+    if mod_ids.iter().any(|id| config.no_chat_reports_mod_ids.contains(id)) {
+        extra_data.insert("noChatReports", Bson::Boolean(true));
+    }
+    if let Some(fml_network_version) = data
+        .get_document("forgeData")
+        .ok()
+        .and_then(|f| database::get_i32(f, "fmlNetworkVersion"))
+    {
+        extra_data.insert("fmlNetworkVersion", fml_network_version);
+    }
+    if let Ok(forge_data) = data.get_document("forgeData") {
+        let channels = forge::channels(forge_data);
+        if !channels.is_empty() {
+            extra_data.insert("channels", channels);
+        }
+    }
+    if let Some(prevents_chat_reports) = get_prevents_chat_reports(&data) {
+        extra_data.insert("preventsChatReports", Bson::Boolean(prevents_chat_reports));
+    }
+    if data.contains_key("forgeData")
+        || data.contains_key("modinfo")
+        || data.contains_key("modpackData")
+    {
+        extra_data.insert("isModded", Bson::Boolean(true));
+    }
+    if let Some(modpack) = get_modpack(&data) {
+        extra_data.insert("modpack", modpack);
+    }
+    match data.get("favicon") {
+        Some(Bson::String(favicon)) => {
+            // the same hash as the description, so the favicon can be compared
+            // without loading it
+            extra_data.insert("faviconHash", hash_description(favicon));
+        }
+        None | Some(Bson::Null) => {}
+        // it's supposed to be a data uri, so there's nothing to store
+        Some(_) => {
+            extra_data.insert("faviconInvalid", true);
+        }
+    }
+
+    let backend_offline = online_players == 0
+        && is_backend_offline_description(&description, &config.backend_offline_motds);
+    // always set so it's cleared when the backend comes back
+    extra_data.insert("backendOffline", backend_offline);
+    extra_data.insert(
+        "garbageMotd",
+        description::is_garbage(&description, config.garbage_motd_ratio.unwrap_or(0.8)),
+    );
+    let proxy_type = proxy::classify(ProxySignals {
+        version_name,
+        description: &description,
+        backend_offline,
+    });
+    extra_data.insert("behindProxy", proxy_type.is_some());
+    extra_data.insert("proxyType", proxy_type.map(ProxyType::name));
+    extra_data.insert("suspiciousSample", suspicious_sample);
+    if sample_without_online {
+        extra_data.insert("sampleWithoutOnline", true);
+    }
+    extra_data.insert(
+        "sampleInconsistent",
+        !should_ignore_players
+            && is_sample_inconsistent(
+                &data,
+                online_players,
+                config.sample_online_tolerance.unwrap_or(2),
+            ),
+    );
+
     if !fake_sample {
         if mixed_online_mode {
             extra_data.insert("isCracked", Bson::Null);
         } else if let Some(is_online_mode) = is_online_mode {
             extra_data.insert("isCracked", Bson::Boolean(is_online_mode));
         }
-        extra_data.insert(
-            "lastSeen",
-            Bson::DateTime(bson::DateTime::from_system_time(SystemTime::now())),
-        );
+        if spoofed_online_mode {
+            extra_data.insert("spoofedOnlineMode", Bson::Boolean(true));
+        }
+        extra_data.insert("lastSeen", Bson::DateTime(now));
         if has_players {
-            extra_data.insert(
-                "lastActive",
-                Bson::DateTime(bson::DateTime::from_system_time(SystemTime::now())),
-            );
-        } else {
-            extra_data.insert(
-                "lastEmpty",
-                Bson::DateTime(bson::DateTime::from_system_time(SystemTime::now())),
-            );
+            extra_data.insert("lastActive", Bson::DateTime(now));
+        } else if !backend_offline {
+            // the server isn't really empty if it's not even up
+            extra_data.insert("lastEmpty", Bson::DateTime(now));
         }
     }
 
     let mut final_cleaned = doc! {
-        "updatedAt": bson::DateTime::from_system_time(SystemTime::now()),
+        "updatedAt": now,
         "onlinePlayers": online_players,
         "maxPlayers": max_players,
-        "version": version_name,
-        "protocol": version_protocol,
-        "description": description,
     };
+    if !version_missing {
+        final_cleaned.insert("version", version_name);
+        final_cleaned.insert("versionNameResolved", resolve_version_name(version_name));
+        final_cleaned.insert("protocol", version_protocol);
+    }
+    final_cleaned.insert(
+        "description",
+        match config.max_description_length {
+            Some(max_length) => description::truncate(&description, max_length),
+            None => description,
+        },
+    );
+    // always set so they're cleared if the server starts sending a real version
+    final_cleaned.insert("protocolUnknown", version_protocol.is_none());
+    final_cleaned.insert("versionMissing", version_missing);
+    if let Some(rtt) = rtt.filter(|_| config.store_rtt) {
+        final_cleaned.insert("rttMs", rtt.as_millis() as i64);
+    }
+    final_cleaned.extend(formatted_descriptions);
 
+    // if any of the players in the sample look fake then the whole sample is
+    // ignored, since we can't tell which of them are real
     if !fake_sample {
         final_cleaned.extend(players_data);
+        if !should_ignore_players {
+            final_cleaned.insert("sampleUuids", sample_uuids);
+        }
+    } else if config.require_players_sample_match {
+        final_cleaned.insert("fakeSample", true);
+    }
+
+    if let Some(raw) = get_raw_sample(
+        &original_data,
+        config.store_raw_sample_rate,
+        player_uuid_hash_key(config),
+    ) {
+        final_cleaned.insert("raw", raw);
     }
 
     // if let Some(passive_minecraft_fingerprint) = passive_minecraft_fingerprint {
@@ -276,152 +730,704 @@ fn clean_response_data(data: &serde_json::Value) -> Option<Document> {
     // final_cleaned.extend(data);
     final_cleaned.extend(extra_data);
 
-    Some(final_cleaned)
+    Ok(final_cleaned)
 }
 
-pub fn create_bulk_update(
-    database: &Database,
-    target: &SocketAddrV4,
-    mongo_update: Document,
-) -> anyhow::Result<BulkUpdate> {
-    if database.shared.lock().bad_ips.contains(target.ip()) && target.port() != 25565 {
-        // no
-        bail!("bad ip");
+/// Get the version name and protocol from the response. The protocol is None
+/// if it's unknown, which is when it's missing, -1 (which a lot of proxies and
+/// legacy pings send), or the version is just a string like some nonstandard
+/// servers send.
+fn get_version(data: &Document) -> (&str, Option<i32>) {
+    match data.get("version") {
+        Some(Bson::String(name)) => (name, None),
+        Some(Bson::Document(version)) => (
+            version.get_str("name").unwrap_or_default(),
+            database::get_i32(version, "protocol").filter(|&protocol| protocol != -1),
+        ),
+        _ => ("", None),
     }
+}
 
-    fn determine_hash(mongo_update: &Document) -> anyhow::Result<u64> {
-        let set_data = mongo_update.get_document("$set")?;
-        // let minecraft = set_data.get_document("minecraft")?;
+/// The Minecraft version that a version name is for, so servers running
+/// different software on the same version can be grouped together. For
+/// example, "Paper 1.20.1" and "Velocity 3.2.0 1.20.1" are both "1.20.1".
+///
+/// Pre-releases and release candidates are grouped with their release, and all
+/// snapshots are "snapshot". Names that don't have a version in them are kept
+/// as-is.
+fn resolve_version_name(name: &str) -> String {
+    static SNAPSHOT_REGEX: LazyLock<Regex> =
+        LazyLock::new(|| Regex::new(r"\b\d{2}w\d{2}[a-z]\b").unwrap());
+    // proxies put the range of versions they support, and the last one is usually
+    // the newest
+    static VERSION_REGEX: LazyLock<Regex> =
+        LazyLock::new(|| Regex::new(r"\b1\.\d{1,2}(\.\d{1,2})?\b").unwrap());
 
-        // let version = set_data.get_document("version")?;
+    if SNAPSHOT_REGEX.is_match(name) {
+        return "snapshot".to_string();
+    }
+    match VERSION_REGEX.find_iter(name).last() {
+        Some(version) => version.as_str().to_string(),
+        None => name.to_string(),
+    }
+}
 
-        let description = set_data.get_str("description").unwrap_or_default();
-        let version_name = set_data.get_str("version").unwrap_or_default();
-        let version_protocol = database::get_i32(set_data, "protocol").unwrap_or_default();
-        let max_players = database::get_i32(set_data, "maxPlayers").unwrap_or_default();
+fn hash_description(description: &str) -> i64 {
+    let mut hasher = DefaultHasher::new();
+    description.hash(&mut hasher);
+    hasher.finish() as i64
+}
 
-        let mut hasher = DefaultHasher::new();
-        (description, version_name, version_protocol, max_players).hash(&mut hasher);
-        Ok(hasher.finish())
+/// The expression for whether the server looks like it changes its MOTD every
+/// ping, which is when the last few `motdHashes` with the new one are all
+/// different.
+fn motd_rotates_expression(motd_hash: i64) -> Document {
+    let history = doc! {
+        "$slice": [
+            { "$concatArrays": [{ "$ifNull": ["$motdHashes", []] }, [motd_hash]] },
+            -(MOTD_HISTORY_LENGTH as i32),
+        ]
+    };
+    doc! {
+        "$eq": [
+            { "$size": [{ "$setUnion": [history, []] }] },
+            MOTD_HISTORY_LENGTH as i32,
+        ]
     }
+}
 
-    let mut is_bad_ip = false;
-    {
-        let mut shared = database.shared.lock();
-        let ips_with_same_hash = shared.ips_with_same_hash.get_mut(target.ip());
-        if let Some((data, previously_checked_ports)) = ips_with_same_hash {
-            if !previously_checked_ports.contains(&target.port()) {
-                if let Some(count) = &mut data.count {
-                    let this_server_hash = determine_hash(&mongo_update)?;
-
-                    if this_server_hash == data.hash {
-                        *count += 1;
-                        previously_checked_ports.insert(target.port());
-
-                        if *count >= 100 {
-                            // too many servers with the same hash... add to bad ips!
-                            println!("found a new bad ip: {} :(", target.ip());
-                            // calls add_to_bad_ips slightly lower down
-                            // we have to do it like that to avoid keeping the lock during the await
-                            is_bad_ip = true;
-                        }
-                    } else {
-                        // this server has a different hash than the other servers with the same IP
-                        data.count = None;
+/// The uuid that offline-mode servers give to a player with this name, which
+/// is a UUIDv3 of `OfflinePlayer:<name>` (the same as Java's
+/// `UUID.nameUUIDFromBytes`).
+fn offline_player_uuid(name: &str) -> [u8; 16] {
+    let mut uuid: [u8; 16] = Md5::digest(format!("OfflinePlayer:{name}")).into();
+    // version 3
+    uuid[6] = (uuid[6] & 0x0f) | 0x30;
+    // IETF variant
+    uuid[8] = (uuid[8] & 0x3f) | 0x80;
+    uuid
+}
+
+/// Whether the (dashless) uuid is the player's offline uuid with the version
+/// changed to 4, which some cracked servers do so they look like they're in
+/// online mode.
+fn is_spoofed_online_uuid(uuid: &str, name: &str) -> bool {
+    let mut spoofed = offline_player_uuid(name);
+    spoofed[6] = (spoofed[6] & 0x0f) | 0x40;
+    let spoofed = spoofed
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect::<String>();
+    uuid.eq_ignore_ascii_case(&spoofed)
+}
+
+/// Hash a player's uuid with HMAC-SHA256 so it can't be reversed without the
+/// key, but is still the same every time we see the same player.
+fn hash_player_uuid(uuid: &str, key: &str) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(key.as_bytes()).expect("HMAC can take a key of any size");
+    mac.update(uuid.as_bytes());
+    mac.finalize()
+        .into_bytes()
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect()
+}
+
+/// Get the ids of the mods in the server's mod list, from either `forgeData`
+/// (1.13+) or `modinfo` (older versions).
+fn get_mod_ids(data: &Document) -> Vec<String> {
+    let forge_mods = data
+        .get_document("forgeData")
+        .ok()
+        .and_then(|f| f.get_array("mods").ok())
+        .map(|mods| (mods, "modId"));
+    let legacy_mods = data
+        .get_document("modinfo")
+        .ok()
+        .and_then(|m| m.get_array("modList").ok())
+        .map(|mods| (mods, "modid"));
+
+    let Some((mods, id_key)) = forge_mods.or(legacy_mods) else {
+        return vec![];
+    };
+
+    mods.iter()
+        .filter_map(|m| m.as_document())
+        .filter_map(|m| m.get_str(id_key).ok())
+        .map(|id| id.to_string())
+        .collect()
+}
+
+/// Randomly decide whether something should be sampled, for a rate between 0
+/// and 1.
+fn is_sampled(rate: f64) -> bool {
+    rate > 0. && rand::random::<f64>() < rate
+}
+
+/// Log the response and the document it was cleaned into. If player uuids are
+/// hashed, the ones in the response are hashed too and the names are replaced,
+/// since they'd make the hashed uuids pointless.
+fn dump_sample(
+    target: SocketAddrV4,
+    raw: &serde_json::Value,
+    cleaned: &Document,
+    config: &ProcessingConfig,
+) {
+    let mut raw = raw.clone();
+    let mut cleaned = cleaned.clone();
+    if let Some(key) = player_uuid_hash_key(config) {
+        redact_player_names(&mut raw, &mut cleaned, key);
+    }
+    debug!("Sampled response from {target}: {raw} cleaned into {cleaned}");
+}
+
+/// The key for hashing player uuids, if they should be hashed.
+fn player_uuid_hash_key(config: &ProcessingConfig) -> Option<&str> {
+    config
+        .player_uuid_hash_key
+        .as_deref()
+        .filter(|_| config.hash_player_uuids)
+}
+
+const REDACTED: &str = "[redacted]";
+
+fn redact_player_names(raw: &mut serde_json::Value, cleaned: &mut Document, hash_key: &str) {
+    redact_sample(raw, hash_key);
+    for (key, player) in cleaned.iter_mut() {
+        if !key.starts_with("players.") {
+            continue;
+        }
+        if let Some(player) = player.as_document_mut() {
+            if player.contains_key("name") {
+                player.insert("name", REDACTED);
+            }
+        }
+    }
+}
+
+/// Replace the names in the response's player sample and hash the uuids like
+/// they're hashed in the cleaned document.
+fn redact_sample(raw: &mut serde_json::Value, hash_key: &str) {
+    let Some(sample) = raw
+        .pointer_mut("/players/sample")
+        .and_then(|s| s.as_array_mut())
+    else {
+        return;
+    };
+    for player in sample {
+        if let Some(name) = player.get_mut("name") {
+            *name = REDACTED.into();
+        }
+        if let Some(id) = player.get_mut("id") {
+            let hashed = id
+                .as_str()
+                .map(|uuid| hash_player_uuid(&uuid.replace('-', ""), hash_key));
+            *id = hashed.map_or_else(|| REDACTED.into(), Into::into);
+        }
+    }
+}
+
+/// Randomly decide whether the original response should be stored, and if so
+/// convert it to BSON. If there's a `hash_key`, the player sample is redacted
+/// like it is in dumped samples.
+fn get_raw_sample(data: &serde_json::Value, rate: f64, hash_key: Option<&str>) -> Option<Bson> {
+    if !is_sampled(rate) {
+        return None;
+    }
+    // don't let huge responses make our documents huge
+    let size = serde_json::to_vec(data).map_or(usize::MAX, |raw| raw.len());
+    if size > MAX_RAW_SAMPLE_SIZE {
+        return None;
+    }
+    match hash_key {
+        Some(key) => {
+            let mut data = data.clone();
+            redact_sample(&mut data, key);
+            bson::to_bson(&data).ok()
+        }
+        None => bson::to_bson(data).ok(),
+    }
+}
+
+/// Get the name, version, and project id from the `modpackData` that some
+/// launchers (like Technic and ATLauncher) add. It's usually an object, but
+/// sometimes it's just a string with the name.
+fn get_modpack(data: &Document) -> Option<Document> {
+    let mut modpack = Document::new();
+    match data.get("modpackData")? {
+        Bson::String(name) => {
+            modpack.insert("name", name.as_str());
+        }
+        Bson::Document(modpack_data) => {
+            for key in ["name", "version"] {
+                if let Ok(value) = modpack_data.get_str(key) {
+                    modpack.insert(key, value);
+                }
+            }
+            // it's a number on curseforge, but some launchers send it as a string
+            match modpack_data.get("projectID") {
+                Some(Bson::String(project_id)) => {
+                    modpack.insert("projectId", project_id.as_str());
+                }
+                Some(_) => {
+                    if let Some(project_id) = database::get_i32(modpack_data, "projectID") {
+                        modpack.insert("projectId", project_id);
                     }
                 }
+                None => {}
             }
-        } else {
-            let this_server_hash = determine_hash(&mongo_update)?;
-            shared.ips_with_same_hash.insert(
-                *target.ip(),
-                (
-                    CachedIpHash {
-                        count: Some(1),
-                        hash: this_server_hash,
-                    },
-                    HashSet::from_iter(vec![target.port()]),
-                ),
-            );
         }
+        _ => return None,
     }
+    (!modpack.is_empty()).then_some(modpack)
+}
 
-    if is_bad_ip {
-        tokio::spawn(database.to_owned().add_to_bad_ips(*target.ip()));
-        bail!("bad ip {target:?}");
-    }
+/// Whether the player sample has more than `tolerance` more players than the
+/// server says are online. A sample smaller than that is normal, since servers
+/// only send a few players.
+fn is_sample_inconsistent(data: &Document, online_players: i32, tolerance: u32) -> bool {
+    let sample_size = data
+        .get_document("players")
+        .and_then(|p| p.get_array("sample"))
+        .map_or(0, |s| s.len());
+    sample_size as i64 > online_players.max(0) as i64 + tolerance as i64
+}
 
-    // println!("{addr}:{port} -> {mongo_update:?}");
-    // println!("{}:{}", target.ip(), target.port());
+/// Whether the server says 0/0 players are online but still sends a sample,
+/// which some honeypots do.
+fn is_sample_without_online(data: &Document, online_players: i32, max_players: i32) -> bool {
+    online_players == 0
+        && max_players == 0
+        && data
+            .get_document("players")
+            .and_then(|p| p.get_array("sample"))
+            .is_ok_and(|s| !s.is_empty())
+}
 
-    Ok(BulkUpdate {
-        query: doc! {
-            "ip": { "$eq": target.ip().to_string() },
-            "port": { "$eq": target.port() as u32 }
-        },
-        update: mongo_update,
-        options: Some(UpdateOptions::builder().upsert(true).build()),
+/// Get the `preventsChatReports` field that the No Chat Reports mod adds,
+/// which is either at the top level or inside `modinfo` depending on the
+/// setup.
+fn get_prevents_chat_reports(data: &Document) -> Option<bool> {
+    data.get_bool("preventsChatReports").ok().or_else(|| {
+        data.get_document("modinfo")
+            .ok()
+            .and_then(|m| m.get_bool("preventsChatReports").ok())
     })
 }
 
-async fn send_to_webhook(webhook_url: String, message: String) {
-    let client = reqwest::Client::new();
-    if let Err(e) = client
-        .post(webhook_url)
-        .json(
-            &vec![("content".to_string(), message.to_string())]
-                .into_iter()
-                .collect::<HashMap<String, String>>(),
-        )
-        .send()
-        .await
-    {
-        println!("{}", e);
+/// The error from [`create_bulk_update`] when the server is on a bad IP.
+#[derive(Debug)]
+pub struct OnBadIp(pub SocketAddrV4);
+
+impl fmt::Display for OnBadIp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "bad ip {}", self.0)
     }
 }
 
-// pub struct PassiveMinecraftFingerprint {
-//     pub incorrect_order: bool,
-//     pub field_order: Option<String>,
-//     /// Servers shouldn't have the sample field if there are no players
-// online.     pub empty_sample: bool,
-//     /// A favicon that has the string ""
-//     pub empty_favicon: bool,
-// }
-// pub fn generate_passive_fingerprint(data: &str) ->
-// anyhow::Result<PassiveMinecraftFingerprint> {     let data: serde_json::Value
-// = serde_json::from_str(data)?;
+impl std::error::Error for OnBadIp {}
 
-//     let protocol_version = data
-//         .get("version")
-//         .and_then(|s| s.as_object())
-//         .and_then(|s| s.get("protocol"))
-//         .and_then(|s| s.as_u64())
-//         .unwrap_or_default();
+/// Hash the parts of a server that would be the same on every port of a bad
+/// IP. This is used both for cleaned updates and for raw statuses when
+/// verifying bad IPs, so unknown numbers are normalized with
+/// [`known_or_zero`] first.
+pub fn same_server_hash(
+    description: &str,
+    version_name: &str,
+    protocol: Option<i32>,
+    max_players: Option<i32>,
+) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    (
+        description,
+        version_name,
+        known_or_zero(protocol),
+        known_or_zero(max_players),
+    )
+        .hash(&mut hasher);
+    hasher.finish()
+}
 
-//     let empty_favicon = data.get("favicon").map(|s| s.as_str()) ==
-// Some(Some(""));
+/// A protocol or player count that's missing or negative (usually -1, which
+/// proxies send when they don't know) counts as 0.
+pub fn known_or_zero(value: Option<i32>) -> i32 {
+    value.unwrap_or_default().max(0)
+}
 
-//     let mut incorrect_order = false;
-//     let mut field_order = None;
-//     let mut empty_sample = false;
+pub fn create_bulk_update(
+    database: &Database,
+    events: &EventBus,
+    config: &Config,
+    target: &SocketAddrV4,
+    mut update: BulkUpdateBuilder,
+) -> anyhow::Result<BulkUpdate> {
+    if database.is_on_bad_ip(target) {
+        // no
+        return Err(OnBadIp(*target).into());
+    }
 
-//     // the correct field order is description, players, version (ignore
-// everything     // else)
+    fn determine_hash(set_data: &Document) -> u64 {
+        // let minecraft = set_data.get_document("minecraft")?;
 
-//     if let Some(data) = data.as_object() {
-//         // mojang changed the order in 23w07a/1.19.4
-//         let correct_order = if matches!(protocol_version, 1073741943.. |
-// 762..=0x40000000 ) {             ["version", "description", "players"]
-//         } else {
-//             ["description", "players", "version"]
-//         };
+        // let version = set_data.get_document("version")?;
 
-//         let keys = data
-//             .keys()
-//             .filter(|&k| correct_order.contains(&k.as_str()))
-//             .cloned()
-//             .collect::<Vec<_>>();
+        same_server_hash(
+            set_data.get_str("description").unwrap_or_default(),
+            set_data.get_str("version").unwrap_or_default(),
+            database::get_i32(set_data, "protocol"),
+            database::get_i32(set_data, "maxPlayers"),
+        )
+    }
+
+    let this_server_hash = determine_hash(update.set_fields_mut());
+    if record_bad_ip_hash(database, events, config, target, this_server_hash) {
+        return Err(OnBadIp(*target).into());
+    }
+    // always set so it's cleared once a port on the ip has something different
+    let per_ip_uniform = database.shared.lock().is_ip_uniform(target.ip());
+    update
+        .set_fields_mut()
+        .insert("perIpUniform", per_ip_uniform);
+    let decay_window_secs = same_hash_window_secs(config);
+
+    // println!("{addr}:{port} -> {mongo_update:?}");
+    // println!("{}:{}", target.ip(), target.port());
+
+    if config.bad_ip.detect_reused_favicons {
+        let favicon_hash = update.set_fields_mut().get_i64("faviconHash").ok();
+        if let Some(favicon_hash) = favicon_hash {
+            let is_reused = database.shared.lock().record_ip_favicon_hash(
+                target,
+                favicon_hash as u64,
+                Instant::now(),
+                Duration::from_secs(decay_window_secs),
+            );
+            if is_reused {
+                update.set_fields_mut().insert("faviconReused", true);
+            }
+        }
+    }
+
+    let mut previous_content_hash = database
+        .shared
+        .lock()
+        .content_hashes
+        .get_mut(target)
+        .copied();
+    if config.skip_unchanged.enabled {
+        // the filter remembers a lot more servers than content_hashes, so it can
+        // still tell that the server didn't change after content_hashes forgot it
+        let content_hash = content_hash(update.set_fields_mut())?;
+        if database.shared.lock().record_unchanged_filter(
+            target,
+            content_hash,
+            &config.skip_unchanged,
+            Instant::now(),
+        ) {
+            previous_content_hash = Some(content_hash);
+        }
+    }
+    let (content_hash, is_unchanged) =
+        deduplicate_update(update.set_fields_mut(), previous_content_hash)?;
+    if !is_unchanged {
+        database
+            .shared
+            .lock()
+            .content_hashes
+            .insert(*target, content_hash);
+    }
+    update = set_last_changed(update, content_hash);
+
+    if config.adaptive_rescan.enabled {
+        // this is after the content hash so it doesn't make the server look changed
+        update = update.computed(doc! {
+            "rescanInterval": rescan_interval_expression(content_hash, &config.adaptive_rescan),
+        });
+    }
+
+    let mut bulk_update = build_server_update(
+        target,
+        update,
+        config.processing.upsert.unwrap_or(true),
+        config.processing.min_players_to_store,
+    )?;
+    if is_unchanged {
+        // the timestamps alone shouldn't be inserted if the server was deleted
+        if let Some(options) = &mut bulk_update.options {
+            options.upsert = Some(false);
+        }
+    }
+    Ok(bulk_update)
+}
+
+/// The expression for the number of seconds to wait before rescanning the
+/// server again, computed from the `rescanInterval` that's stored on it. If
+/// the stored `contentHash` is the same as the new one the interval gets
+/// longer, and otherwise it gets shorter.
+fn rescan_interval_expression(content_hash: u64, config: &AdaptiveRescanConfig) -> Document {
+    let min = config.min_interval_secs.unwrap_or(60 * 60);
+    let max = config
+        .max_interval_secs
+        .unwrap_or(60 * 60 * 24 * 7)
+        .max(min);
+    let growth_factor = config.growth_factor.unwrap_or(2.).max(1.);
+
+    let interval = doc! {
+        "$cond": [
+            { "$eq": ["$contentHash", content_hash as i64] },
+            { "$multiply": ["$rescanInterval", growth_factor] },
+            { "$divide": ["$rescanInterval", growth_factor] },
+        ]
+    };
+    // the interval is null for new servers, which $max ignores so they start at
+    // the minimum
+    doc! {
+        "$toLong": [{
+            "$min": [max as i64, { "$max": [min as i64, { "$round": [interval, 0] }] }]
+        }]
+    }
+}
+
+fn same_hash_window_secs(config: &Config) -> u64 {
+    config
+        .bad_ip
+        .same_hash_window_secs
+        .unwrap_or(60 * 60 * 24 * 7)
+}
+
+/// Count the server towards the bad-IP counter for its IP, and flag the IP if
+/// there are too many servers with the same hash on it. Returns whether it was
+/// flagged.
+fn record_bad_ip_hash(
+    database: &Database,
+    events: &EventBus,
+    config: &Config,
+    target: &SocketAddrV4,
+    hash: u64,
+) -> bool {
+    // calls add_to_bad_ips slightly lower down
+    // we have to do it like that to avoid keeping the lock during the await
+    let is_bad_ip = database.shared.lock().record_ip_hash(
+        target,
+        hash,
+        Instant::now(),
+        Duration::from_secs(same_hash_window_secs(config)),
+    );
+
+    if is_bad_ip {
+        // too many servers with the same hash... add to bad ips!
+        flag_bad_ip(database, events, *target.ip());
+    }
+    is_bad_ip
+}
+
+fn flag_bad_ip(database: &Database, events: &EventBus, ip: Ipv4Addr) {
+    println!("found a new bad ip: {ip} :(");
+    tokio::spawn(database.to_owned().add_to_bad_ips(ip));
+    events.publish(ProcessingEvent::BadIpFlagged(ip));
+}
+
+/// Fields in the `$set` that change on every ping even if nothing about the
+/// server did. The RTT isn't a timestamp but it's treated like one.
+fn is_timestamp_field(key: &str) -> bool {
+    matches!(
+        key,
+        "updatedAt" | "lastSeen" | "lastActive" | "lastEmpty" | "rttMs"
+    )
+}
+
+/// Fields in the `$set` that can be different between pings even if the
+/// server didn't change, like the protocol version we happened to send or a
+/// sampled raw response. They aren't part of the content hash, but they're
+/// still written when nothing else changed.
+fn is_volatile_field(key: &str) -> bool {
+    matches!(key, "respondedToProtocol" | "raw")
+}
+
+/// Hash the fields in the `$set` that only change when the server does. The
+/// fields are sorted first, since the `players.<uuid>` ones are in the order of
+/// the sample and servers list their players in any order.
+fn content_hash(set_data: &Document) -> anyhow::Result<u64> {
+    let mut fields = set_data.iter().collect::<Vec<_>>();
+    fields.sort_unstable_by_key(|(key, _)| *key);
+
+    let mut content = Document::new();
+    for (key, value) in fields {
+        if is_timestamp_field(key) || is_volatile_field(key) {
+            continue;
+        }
+        match value {
+            // players look like `players.<uuid>: { lastSeen, name }`
+            Bson::Document(player) if key.starts_with("players.") => {
+                let mut player = player.clone();
+                player.remove("lastSeen");
+                content.insert(key, player);
+            }
+            Bson::Array(uuids) if key == "sampleUuids" => {
+                let mut uuids = uuids.iter().filter_map(|u| u.as_str()).collect::<Vec<_>>();
+                uuids.sort_unstable();
+                content.insert(key, uuids);
+            }
+            _ => {
+                content.insert(key, value.clone());
+            }
+        }
+    }
+
+    let mut hasher = DefaultHasher::new();
+    bson::to_vec(&content)?.hash(&mut hasher);
+    Ok(hasher.finish())
+}
+
+/// Set `lastChanged` to `updatedAt` if the content hash is different from the
+/// one that's stored on the server, which includes when it's inserted.
+fn set_last_changed(mut update: BulkUpdateBuilder, content_hash: u64) -> BulkUpdateBuilder {
+    let Some(updated_at) = update.set_fields_mut().get("updatedAt").cloned() else {
+        return update;
+    };
+    update.computed(doc! {
+        "lastChanged": {
+            "$cond": [
+                { "$eq": ["$contentHash", content_hash as i64] },
+                "$lastChanged",
+                updated_at,
+            ]
+        }
+    })
+}
+
+/// If nothing but the timestamps changed since the last time we wrote the
+/// server, replace the `$set` with just the timestamps so we don't rewrite the
+/// whole document. Otherwise the new `contentHash` is added to it.
+///
+/// Returns the content hash and whether it was the same as
+/// `previous_content_hash`.
+fn deduplicate_update(
+    set_data: &mut Document,
+    previous_content_hash: Option<u64>,
+) -> anyhow::Result<(u64, bool)> {
+    let content_hash = content_hash(set_data)?;
+
+    if previous_content_hash != Some(content_hash) {
+        set_data.insert("contentHash", content_hash as i64);
+        return Ok((content_hash, false));
+    }
+
+    let mut timestamps = Document::new();
+    for (key, value) in set_data.iter() {
+        if is_timestamp_field(key) || is_volatile_field(key) {
+            timestamps.insert(key, value.clone());
+        } else if key.starts_with("players.") {
+            if let Some(last_seen) = value.as_document().and_then(|p| p.get("lastSeen")) {
+                timestamps.insert(format!("{key}.lastSeen"), last_seen.clone());
+            }
+        }
+    }
+    *set_data = timestamps;
+
+    Ok((content_hash, true))
+}
+
+/// Create the update for a server. If `upsert` is false, or
+/// `min_players_to_store` is set and the server doesn't have enough players
+/// online, it's only updated if it's already in the database.
+fn build_server_update(
+    target: &SocketAddrV4,
+    update: BulkUpdateBuilder,
+    upsert: bool,
+    min_players_to_store: Option<i32>,
+) -> anyhow::Result<BulkUpdate> {
+    let online_players = update
+        .get("$set")
+        .and_then(|set_data| database::get_i32(set_data, "onlinePlayers"))
+        .unwrap_or_default();
+    let upsert = upsert && min_players_to_store.map_or(true, |min| online_players >= min);
+
+    update.upsert(upsert).build(doc! {
+        "ip": { "$eq": target.ip().to_string() },
+        "port": { "$eq": target.port() as u32 }
+    })
+}
+
+/// Create updates for the players collection so we can find the server each
+/// player was most recently seen on.
+fn create_player_updates(target: &SocketAddrV4, cleaned_data: &Document) -> Vec<BulkUpdate> {
+    let mut updates = Vec::new();
+    for (key, player) in cleaned_data {
+        let Some(uuid) = key.strip_prefix("players.") else {
+            continue;
+        };
+        let Some(last_seen) = player
+            .as_document()
+            .and_then(|p| p.get_datetime("lastSeen").ok())
+        else {
+            continue;
+        };
+
+        // mongo compares documents field by field, so having lastSeen first makes
+        // $max keep whichever sighting is the most recent even if the updates are
+        // applied out of order
+        updates.push(BulkUpdate {
+            query: doc! { "_id": uuid },
+            update: doc! {
+                "$max": {
+                    "lastServer": {
+                        "lastSeen": last_seen,
+                        "ip": target.ip().to_string(),
+                        "port": target.port() as u32,
+                    }
+                }
+            },
+            computed: Document::new(),
+            options: Some(UpdateOptions::builder().upsert(true).build()),
+        });
+    }
+    updates
+}
+
+// pub struct PassiveMinecraftFingerprint {
+//     pub incorrect_order: bool,
+//     pub field_order: Option<String>,
+//     /// Servers shouldn't have the sample field if there are no players
+// online.     pub empty_sample: bool,
+//     /// A favicon that has the string ""
+//     pub empty_favicon: bool,
+// }
+// pub fn generate_passive_fingerprint(data: &str) ->
+// anyhow::Result<PassiveMinecraftFingerprint> {     let data: serde_json::Value
+// = serde_json::from_str(data)?;
+
+//     let protocol_version = data
+//         .get("version")
+//         .and_then(|s| s.as_object())
+//         .and_then(|s| s.get("protocol"))
+//         .and_then(|s| s.as_u64())
+//         .unwrap_or_default();
+
+//     let empty_favicon = data.get("favicon").map(|s| s.as_str()) ==
+// Some(Some(""));
+
+//     let mut incorrect_order = false;
+//     let mut field_order = None;
+//     let mut empty_sample = false;
+
+//     // the correct field order is description, players, version (ignore
+// everything     // else)
+
+//     if let Some(data) = data.as_object() {
+//         // mojang changed the order in 23w07a/1.19.4
+//         let correct_order = if matches!(protocol_version, 1073741943.. |
+// 762..=0x40000000 ) {             ["version", "description", "players"]
+//         } else {
+//             ["description", "players", "version"]
+//         };
+
+//         let keys = data
+//             .keys()
+//             .filter(|&k| correct_order.contains(&k.as_str()))
+//             .cloned()
+//             .collect::<Vec<_>>();
 
 //         let players = data.get("players").and_then(|s| s.as_object());
 //         let version = data.get("version").and_then(|s| s.as_object());
@@ -487,3 +1493,1508 @@ async fn send_to_webhook(webhook_url: String, message: String) {
 //         empty_favicon,
 //     })
 // }
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use serde_json::json;
+
+    use crate::database::{memory::MemoryStore, store::ServerStore};
+
+    use super::*;
+
+    /// Clean the response with the default config and filters.
+    fn clean_default(data: &serde_json::Value) -> Result<Document, FilterReason> {
+        clean_with_config(data, &ProcessingConfig::default())
+    }
+
+    /// Clean the response with the default filters.
+    fn clean_with_config(
+        data: &serde_json::Value,
+        config: &ProcessingConfig,
+    ) -> Result<Document, FilterReason> {
+        clean_response_data(data, config, &Filters::default(), None, SystemTime::now())
+    }
+
+    /// The config, an unconnected database, and the shared data for testing
+    /// `process`. `extra_config` is appended to a minimal config file.
+    async fn test_shared(extra_config: &str) -> (Config, Database, Arc<Mutex<SharedData>>) {
+        let config: Config = toml::from_str(&format!(
+            r#"
+            mongodb_uri = "mongodb://localhost:27017"
+            rate = 1000
+
+            [target]
+            addr = "example.com"
+            port = 25565
+            protocol_version = 767
+
+            [scanner]
+            enabled = true
+            {extra_config}
+            "#
+        ))
+        .unwrap();
+        let database = Database::unconnected(config.database.clone()).await;
+        let shared = Arc::new(Mutex::new(
+            SharedData::new(database.clone(), &config).unwrap(),
+        ));
+        (config, database, shared)
+    }
+
+    #[test]
+    fn rejects_non_json_without_parsing() {
+        assert!(!protocols::Minecraft::is_plausible(b""));
+        assert!(!protocols::Minecraft::is_plausible(
+            b"\x15\x03\x01\x00\x02\x02\x16"
+        ));
+        assert!(!protocols::Minecraft::is_plausible(
+            b"HTTP/1.1 400 Bad Request"
+        ));
+        assert!(protocols::Minecraft::is_plausible(br#"{"description":""}"#));
+    }
+
+    #[test]
+    fn drops_invalid_utf8() {
+        // this would parse if the invalid bytes were replaced
+        assert_eq!(
+            parse_response(b"{\"description\":\"\xff\xfe\x80\"}", 1024).unwrap_err(),
+            FilterReason::InvalidUtf8
+        );
+        assert_eq!(
+            parse_response(b"{\"description\":", 1024).unwrap_err(),
+            FilterReason::InvalidJson
+        );
+        assert_eq!(
+            parse_response(br#"{"description":"A Minecraft Server"}"#, 1024).unwrap(),
+            json!({ "description": "A Minecraft Server" })
+        );
+    }
+
+    #[tokio::test]
+    async fn process_result_for_each_kind_of_response() {
+        let (config, database, shared) = test_shared("").await;
+        let target = SocketAddrV4::new(std::net::Ipv4Addr::new(1, 2, 3, 4), 25566);
+        let process = |data: &[u8]| {
+            protocols::Minecraft::process(&shared, &config, target, data, None, &database)
+        };
+
+        let status = br#"{"description":"A Minecraft Server","version":{"name":"1.21","protocol":767},"players":{"max":20,"online":0}}"#;
+        assert!(matches!(process(status), ProcessResult::Update(_)));
+
+        assert!(matches!(
+            process(b"HTTP/1.1 400 Bad Request\r\n\r\n"),
+            ProcessResult::Filtered(FilterReason::Http)
+        ));
+        assert!(matches!(
+            process(br#"{"version":{"name":"1.21","protocol":767}}"#),
+            ProcessResult::Filtered(FilterReason::NoDescription)
+        ));
+
+        database.shared.lock().bad_ips.insert(*target.ip());
+        assert!(matches!(process(status), ProcessResult::BadIp));
+    }
+
+    #[tokio::test]
+    async fn skips_unchanged_repings() {
+        let (config, database, shared) = test_shared(
+            r#"
+            [skip_unchanged]
+            enabled = true
+            "#,
+        )
+        .await;
+        let target = SocketAddrV4::new(std::net::Ipv4Addr::new(1, 2, 3, 4), 25566);
+        let process = |data: &[u8]| {
+            protocols::Minecraft::process(&shared, &config, target, data, None, &database)
+        };
+
+        let update = |data: &[u8]| {
+            let ProcessResult::Update(update) = process(data) else {
+                panic!("expected an update");
+            };
+            update
+        };
+        let has_description = |update: &BulkUpdate| {
+            update
+                .update
+                .get_document("$set")
+                .unwrap()
+                .contains_key("description")
+        };
+
+        let status = br#"{"description":"A Minecraft Server","version":{"name":"1.21","protocol":767},"players":{"max":20,"online":0}}"#;
+        assert!(has_description(&update(status)));
+
+        // content_hashes forgot about the server but the filter still knows it's the
+        // same, so only the timestamps are written and it's not inserted again if it
+        // was deleted
+        database.shared.lock().content_hashes.remove(&target);
+        let reping = update(status);
+        assert!(!has_description(&reping));
+        assert!(reping
+            .update
+            .get_document("$set")
+            .unwrap()
+            .contains_key("lastSeen"));
+        assert_eq!(reping.options.unwrap().upsert, Some(false));
+
+        let changed = br#"{"description":"A Minecraft Server","version":{"name":"1.21","protocol":767},"players":{"max":50,"online":0}}"#;
+        assert!(has_description(&update(changed)));
+
+        // the filter is reset after the window
+        let content_hash = database
+            .shared
+            .lock()
+            .content_hashes
+            .get_mut(&target)
+            .copied()
+            .unwrap();
+        assert!(!database.shared.lock().record_unchanged_filter(
+            &target,
+            content_hash,
+            &config.skip_unchanged,
+            Instant::now() + Duration::from_secs(60 * 60 * 7),
+        ));
+    }
+
+    #[tokio::test]
+    async fn repeated_junk_flags_the_ip() {
+        let (config, database, shared) = test_shared(
+            r#"
+            [processing]
+            junk_response_threshold = 3
+            "#,
+        )
+        .await;
+        let target = SocketAddrV4::new(std::net::Ipv4Addr::new(1, 2, 3, 4), 25566);
+        let process = |data: &[u8]| {
+            protocols::Minecraft::process(&shared, &config, target, data, None, &database)
+        };
+
+        for _ in 0..2 {
+            assert!(matches!(
+                process(b"{\x00\x01garbage"),
+                ProcessResult::Filtered(FilterReason::InvalidJson)
+            ));
+        }
+        // junk from another ip doesn't count
+        shared
+            .lock()
+            .record_junk_response(std::net::Ipv4Addr::new(5, 6, 7, 8), 3);
+        assert!(!database.is_on_bad_ip(&target));
+
+        process(b"{\x00\x01garbage");
+        // let the spawned add_to_bad_ips run
+        tokio::task::yield_now().await;
+        assert!(database.is_on_bad_ip(&target));
+        let status = br#"{"description":"A Minecraft Server","version":{"name":"1.21","protocol":767},"players":{"max":20,"online":0}}"#;
+        assert!(matches!(process(status), ProcessResult::BadIp));
+    }
+
+    #[tokio::test]
+    async fn flags_ips_that_are_the_same_on_every_port() {
+        let (config, database, shared) = test_shared("").await;
+        let ip = std::net::Ipv4Addr::new(1, 2, 3, 4);
+        let per_ip_uniform = |port: u16, data: &[u8]| {
+            let target = SocketAddrV4::new(ip, port);
+            let ProcessResult::Update(update) =
+                protocols::Minecraft::process(&shared, &config, target, data, None, &database)
+            else {
+                panic!("expected an update");
+            };
+            update
+                .update
+                .get_document("$set")
+                .unwrap()
+                .get_bool("perIpUniform")
+                .unwrap()
+        };
+
+        let status = br#"{"description":"A Minecraft Server","version":{"name":"1.21","protocol":767},"players":{"max":20,"online":0}}"#;
+        assert!(!per_ip_uniform(25565, status));
+        for port in 25566..25570 {
+            assert!(per_ip_uniform(port, status));
+        }
+
+        let different = br#"{"description":"Another Minecraft Server","version":{"name":"1.21","protocol":767},"players":{"max":20,"online":0}}"#;
+        assert!(!per_ip_uniform(25570, different));
+    }
+
+    #[test]
+    fn parses_gzipped_responses() {
+        let gzip = |data: &[u8]| {
+            let mut encoder =
+                flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+            encoder.write_all(data).unwrap();
+            encoder.finish().unwrap()
+        };
+
+        let status =
+            br#"{"description":"A Minecraft Server","version":{"name":"1.21","protocol":767}}"#;
+        let compressed = gzip(status);
+        assert!(protocols::Minecraft::is_plausible(&compressed));
+        assert_eq!(
+            parse_response(&compressed, 1024).unwrap(),
+            json!({
+                "description": "A Minecraft Server",
+                "version": { "name": "1.21", "protocol": 767 }
+            })
+        );
+
+        // a zip bomb
+        let bomb = gzip(&vec![b' '; 1024 * 1024]);
+        assert!(bomb.len() < 8 * 1024);
+        assert_eq!(
+            parse_response(&bomb, 1024).unwrap_err(),
+            FilterReason::Oversized
+        );
+
+        assert_eq!(
+            parse_response(&[0x1f, 0x8b, 0, 0], 1024).unwrap_err(),
+            FilterReason::InvalidJson
+        );
+    }
+
+    #[test]
+    fn drops_oversized_responses() {
+        // valid json, so it would be parsed if it wasn't dropped first
+        let mut huge = br#"{"description":""#.to_vec();
+        huge.extend(std::iter::repeat(b'a').take(3 * 1024 * 1024));
+        huge.extend(br#""}"#);
+
+        let config = ProcessingConfig::default();
+        assert!(is_oversized(&huge, &config));
+        assert!(!is_oversized(br#"{"description":""}"#, &config));
+
+        let config = ProcessingConfig {
+            max_response_bytes: Some(4 * 1024 * 1024),
+            ..Default::default()
+        };
+        assert!(!is_oversized(&huge, &config));
+    }
+
+    #[test]
+    fn detects_no_chat_reports_mod() {
+        let data = json!({
+            "description": "A Minecraft Server",
+            "players": { "max": 20, "online": 0 },
+            "version": { "name": "1.20.1", "protocol": 763 },
+            "forgeData": {
+                "channels": [],
+                "mods": [
+                    { "modId": "forge", "modmarker": "47.1.0" },
+                    { "modId": "no-chat-reports", "modmarker": "2.2.2" }
+                ],
+                "fmlNetworkVersion": 3
+            }
+        });
+        let cleaned = clean_default(&data).unwrap();
+        assert_eq!(cleaned.get_bool("noChatReports").ok(), Some(true));
+    }
+
+    #[test]
+    fn no_chat_reports_unset_without_mod() {
+        let data = json!({
+            "description": "A Minecraft Server",
+            "players": { "max": 20, "online": 0 },
+            "version": { "name": "1.12.2", "protocol": 340 },
+            "modinfo": {
+                "type": "FML",
+                "modList": [{ "modid": "mcp", "version": "9.42" }]
+            }
+        });
+        let cleaned = clean_default(&data).unwrap();
+        assert!(!cleaned.contains_key("noChatReports"));
+        // pre-1.13 servers don't have an fml network version
+        assert!(!cleaned.contains_key("fmlNetworkVersion"));
+    }
+
+    #[test]
+    fn stores_fml_network_version() {
+        let data = json!({
+            "description": "A Minecraft Server",
+            "players": { "max": 20, "online": 0 },
+            "version": { "name": "1.16.5", "protocol": 754 },
+            "forgeData": {
+                "channels": [],
+                "mods": [{ "modId": "forge", "modmarker": "36.2.39" }],
+                "fmlNetworkVersion": 2
+            }
+        });
+        let cleaned = clean_default(&data).unwrap();
+        assert_eq!(cleaned.get_i32("fmlNetworkVersion").ok(), Some(2));
+    }
+
+    #[test]
+    fn stores_forge_channels() {
+        let data = json!({
+            "description": "A Minecraft Server",
+            "players": { "max": 20, "online": 0 },
+            "version": { "name": "1.16.5", "protocol": 754 },
+            "forgeData": {
+                "channels": [
+                    { "res": "fml:handshake", "version": "FML2", "required": true },
+                    { "res": "jei:channel", "version": "1.0.0", "required": false },
+                    { "version": "no res" }
+                ],
+                "mods": [{ "modId": "forge", "modmarker": "36.2.39" }],
+                "fmlNetworkVersion": 2
+            }
+        });
+        let cleaned = clean_default(&data).unwrap();
+        assert_eq!(
+            cleaned.get_array("channels").unwrap(),
+            &vec![
+                Bson::Document(doc! { "name": "fml:handshake", "version": "FML2" }),
+                Bson::Document(doc! { "name": "jei:channel", "version": "1.0.0" }),
+            ]
+        );
+
+        let mut data = data;
+        data["forgeData"]["channels"] = json!([]);
+        let cleaned = clean_default(&data).unwrap();
+        assert!(!cleaned.contains_key("channels"));
+    }
+
+    #[test]
+    fn prevents_chat_reports_top_level() {
+        let data = json!({
+            "description": "A Minecraft Server",
+            "players": { "max": 20, "online": 0 },
+            "version": { "name": "1.20.1", "protocol": 763 },
+            "enforcesSecureChat": true,
+            "preventsChatReports": true
+        });
+        let cleaned = clean_default(&data).unwrap();
+        assert_eq!(cleaned.get_bool("preventsChatReports").ok(), Some(true));
+        assert!(!cleaned.contains_key("isModded"));
+    }
+
+    #[test]
+    fn prevents_chat_reports_in_modinfo() {
+        let data = json!({
+            "description": "A Minecraft Server",
+            "players": { "max": 20, "online": 0 },
+            "version": { "name": "1.19.2", "protocol": 760 },
+            "modinfo": { "type": "FML", "modList": [], "preventsChatReports": false }
+        });
+        let cleaned = clean_default(&data).unwrap();
+        assert_eq!(cleaned.get_bool("preventsChatReports").ok(), Some(false));
+
+        let data = json!({
+            "description": "A Minecraft Server",
+            "players": { "max": 20, "online": 0 },
+            "version": { "name": "1.19.2", "protocol": 760 }
+        });
+        let cleaned = clean_default(&data).unwrap();
+        assert!(!cleaned.contains_key("preventsChatReports"));
+    }
+
+    #[test]
+    fn garbage_motds() {
+        let garbage_motd = |description: serde_json::Value| {
+            let data = json!({
+                "description": description,
+                "players": { "max": 20, "online": 0 },
+                "version": { "name": "1.20.1", "protocol": 763 },
+            });
+            clean_default(&data)
+                .unwrap()
+                .get_bool("garbageMotd")
+                .unwrap()
+        };
+
+        assert!(garbage_motd(json!(
+            "§kaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa"
+        )));
+        assert!(garbage_motd(json!({
+            "text": "||||||||||||||||||||||||||||||||",
+            "obfuscated": true,
+        })));
+        assert!(garbage_motd(json!("\u{1}\u{2}\u{3}\u{4}\u{5}\u{6}\u{7}\u{8}\u{e}\u{f}\u{10}\u{11}\u{12}\u{13}\u{14}\u{15}")));
+
+        assert!(!garbage_motd(json!({
+            "text": "",
+            "extra": [
+                { "text": "▬▬▬▬▬▬▬▬ ", "color": "gold", "strikethrough": true },
+                { "text": "Hypixel Network", "color": "aqua", "bold": true },
+                { "text": " ▬▬▬▬▬▬▬▬", "color": "gold", "strikethrough": true },
+                { "text": "\n§k||§r §eSkyBlock 0.20 §k||" },
+            ],
+        })));
+        assert!(!garbage_motd(json!(
+            "§6§l==== §bA Minecraft Server §6§l===="
+        )));
+        // too short to tell
+        assert!(!garbage_motd(json!("...")));
+    }
+
+    #[test]
+    fn non_string_favicons_are_invalid() {
+        for favicon in [
+            json!(["data:image/png;base64,iVBORw0KGgo="]),
+            json!({ "data": "data:image/png;base64,iVBORw0KGgo=" }),
+        ] {
+            let data = json!({
+                "description": "A Minecraft Server",
+                "players": { "max": 20, "online": 0 },
+                "version": { "name": "1.20.1", "protocol": 763 },
+                "favicon": favicon,
+            });
+            let mut cleaned = clean_default(&data).unwrap();
+            assert_eq!(cleaned.get_bool("faviconInvalid").ok(), Some(true));
+            assert!(!cleaned.contains_key("faviconHash"));
+
+            let favicon = data.get("favicon").and_then(|f| f.as_str());
+            assert!(apply_favicon_mode(&mut cleaned, favicon, FaviconMode::Store).is_none());
+            assert!(!cleaned.contains_key("hasFavicon"));
+        }
+
+        let data = json!({
+            "description": "A Minecraft Server",
+            "players": { "max": 20, "online": 0 },
+            "version": { "name": "1.20.1", "protocol": 763 },
+            "favicon": "data:image/png;base64,iVBORw0KGgo=",
+        });
+        let cleaned = clean_default(&data).unwrap();
+        assert!(!cleaned.contains_key("faviconInvalid"));
+        assert!(cleaned.contains_key("faviconHash"));
+    }
+
+    #[test]
+    fn stores_raw_sample_at_rate() {
+        let data = json!({
+            "description": "A Minecraft Server",
+            "players": { "max": 20, "online": 0 },
+            "version": { "name": "1.20.1", "protocol": 763 }
+        });
+        let config = ProcessingConfig {
+            store_raw_sample_rate: 0.25,
+            ..Default::default()
+        };
+
+        let stored = (0..2000)
+            .filter(|_| {
+                clean_with_config(&data, &config)
+                    .unwrap()
+                    .contains_key("raw")
+            })
+            .count();
+        assert!((400..600).contains(&stored), "stored {stored} of 2000");
+
+        let cleaned = clean_with_config(
+            &data,
+            &ProcessingConfig {
+                store_raw_sample_rate: 1.,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(cleaned.get("raw"), bson::to_bson(&data).ok().as_ref());
+    }
+
+    #[test]
+    fn skips_oversized_raw_sample() {
+        let data = json!({ "description": "a".repeat(MAX_RAW_SAMPLE_SIZE) });
+        assert_eq!(get_raw_sample(&data, 1., None), None);
+    }
+
+    #[test]
+    fn raw_sample_players_are_redacted() {
+        let data = json!({
+            "description": "A Minecraft Server",
+            "players": {
+                "max": 20,
+                "online": 1,
+                "sample": [{ "id": "072ec059-d331-3408-a785-1d9d2e6a9a55", "name": "Notch" }]
+            },
+            "version": { "name": "1.20.1", "protocol": 763 }
+        });
+        let config = ProcessingConfig {
+            store_raw_sample_rate: 1.,
+            hash_player_uuids: true,
+            player_uuid_hash_key: Some("key".to_string()),
+            ..Default::default()
+        };
+        let cleaned = clean_with_config(&data, &config).unwrap();
+
+        let raw = cleaned.get_document("raw").unwrap();
+        let player = raw
+            .get_document("players")
+            .unwrap()
+            .get_array("sample")
+            .unwrap()[0]
+            .as_document()
+            .unwrap();
+        let hashed = hash_player_uuid("072ec059d3313408a7851d9d2e6a9a55", "key");
+        assert_eq!(player.get_str("name"), Ok(REDACTED));
+        assert_eq!(player.get_str("id"), Ok(hashed.as_str()));
+        assert!(cleaned.contains_key(&format!("players.{hashed}")));
+
+        // whether the raw response was stored doesn't change the content
+        let mut without_raw = cleaned.clone();
+        without_raw.remove("raw");
+        assert_eq!(
+            content_hash(&cleaned).unwrap(),
+            content_hash(&without_raw).unwrap()
+        );
+    }
+
+    #[test]
+    fn dumps_roughly_the_sample_rate() {
+        let dumped = (0..10_000).filter(|_| is_sampled(0.1)).count();
+        assert!((800..1200).contains(&dumped), "dumped {dumped}");
+
+        assert!(!(0..1000).any(|_| is_sampled(0.)));
+        assert!((0..1000).all(|_| is_sampled(1.)));
+    }
+
+    #[test]
+    fn dumped_player_names_are_redacted() {
+        let mut raw = json!({
+            "description": "A Minecraft Server",
+            "players": {
+                "max": 20,
+                "online": 1,
+                "sample": [{ "id": "072ec059-d331-3408-a785-1d9d2e6a9a55", "name": "Notch" }]
+            }
+        });
+        let mut cleaned = doc! {
+            "description": "A Minecraft Server",
+            "players.abcd": { "lastSeen": bson::DateTime::from_millis(0), "name": "Notch" },
+        };
+        redact_player_names(&mut raw, &mut cleaned, "key");
+
+        assert_eq!(raw["players"]["sample"][0]["name"], REDACTED);
+        assert_eq!(
+            raw["players"]["sample"][0]["id"],
+            hash_player_uuid("072ec059d3313408a7851d9d2e6a9a55", "key")
+        );
+        assert_eq!(
+            cleaned
+                .get_document("players.abcd")
+                .unwrap()
+                .get_str("name")
+                .ok(),
+            Some(REDACTED)
+        );
+        assert_eq!(
+            cleaned.get_str("description").ok(),
+            Some("A Minecraft Server")
+        );
+    }
+
+    #[test]
+    fn offline_player_uuid_matches_java() {
+        let uuid = offline_player_uuid("Notch");
+        assert_eq!(
+            uuid.iter().map(|b| format!("{b:02x}")).collect::<String>(),
+            "b50ad385829d3141a2167e7d7539ba7f"
+        );
+    }
+
+    #[test]
+    fn detects_spoofed_online_mode() {
+        let sample = |id: &str| {
+            json!({
+                "description": "A Minecraft Server",
+                "players": { "max": 20, "online": 1, "sample": [{ "id": id, "name": "Notch" }] },
+                "version": { "name": "1.20.1", "protocol": 763 }
+            })
+        };
+
+        let genuine = clean_default(&sample("069a79f4-44e9-4726-a5be-fca90e38aaf5")).unwrap();
+        assert!(!genuine.contains_key("spoofedOnlineMode"));
+
+        let spoofed = clean_default(&sample("b50ad385-829d-4141-a216-7e7d7539ba7f")).unwrap();
+        assert_eq!(spoofed.get_bool("spoofedOnlineMode").ok(), Some(true));
+    }
+
+    #[test]
+    fn counts_filter_reasons() {
+        let placeholder = json!({
+            "description": "Start the server at FalixNodes.net/start",
+            "players": { "max": 20, "online": 0 },
+            "version": { "name": "1.20.1", "protocol": 763 }
+        });
+        let mitigation = json!({
+            "description": "A Minecraft Server",
+            "players": { "max": 20, "online": 0 },
+            "version": { "name": "TCPShield.com", "protocol": 763 }
+        });
+
+        let filter_counts = FilterCounts::new();
+        for data in [&placeholder, &placeholder, &placeholder, &mitigation] {
+            let reason = clean_default(data).unwrap_err();
+            filter_counts.record(reason);
+        }
+
+        assert_eq!(filter_counts.get(FilterReason::PlaceholderDescription), 3);
+        assert_eq!(filter_counts.get(FilterReason::MitigationVersion), 1);
+        assert_eq!(filter_counts.get(FilterReason::NoDescription), 0);
+        assert_eq!(filter_counts.total(), 4);
+    }
+
+    #[tokio::test]
+    async fn rescan_interval_adapts() {
+        let config = AdaptiveRescanConfig {
+            enabled: true,
+            min_interval_secs: Some(100),
+            max_interval_secs: Some(1000),
+            growth_factor: Some(2.),
+        };
+        let store = MemoryStore::new(vec![]);
+        let ping = |content_hash: u64| {
+            BulkUpdateBuilder::new()
+                .set(doc! { "contentHash": content_hash as i64 })
+                .computed(doc! {
+                    "rescanInterval": rescan_interval_expression(content_hash, &config),
+                })
+                .upsert(true)
+                .build(doc! { "ip": { "$eq": "1.2.3.4" }, "port": { "$eq": 25565_u32 } })
+                .unwrap()
+        };
+
+        let mut intervals = Vec::new();
+        for content_hash in [1, 1, 1, 1, 1, 1, 2, 3, 4, 5] {
+            store
+                .bulk_update_servers(&[ping(content_hash)], false)
+                .await
+                .unwrap();
+            intervals.push(store.servers()[0].get_i64("rescanInterval").unwrap());
+        }
+        // new servers start at the minimum, and then it grows while the server stays
+        // the same and shrinks once it changes
+        assert_eq!(
+            intervals,
+            [100, 200, 400, 800, 1000, 1000, 500, 250, 125, 100]
+        );
+    }
+
+    #[test]
+    fn long_player_names_are_truncated() {
+        let long_name = "a".repeat(10 * 1024);
+        let clean = |name: &str| {
+            let data = json!({
+                "description": "A Minecraft Server",
+                "version": { "name": "1.20.1", "protocol": 763 },
+                "players": {
+                    "max": 20,
+                    "online": 1,
+                    "sample": [{ "id": "072ec059-d331-3408-a785-1d9d2e6a9a55", "name": name }]
+                }
+            });
+            clean_default(&data).unwrap()
+        };
+
+        let cleaned = clean(&long_name);
+        let player = cleaned
+            .get_document("players.072ec059d3313408a7851d9d2e6a9a55")
+            .unwrap();
+        assert_eq!(player.get_str("name").unwrap(), "a".repeat(48));
+        assert_eq!(cleaned.get_bool("suspiciousSample").ok(), Some(true));
+
+        // it's set either way so it's cleared once the names are normal again
+        let cleaned = clean("Notch");
+        assert_eq!(cleaned.get_bool("suspiciousSample").ok(), Some(false));
+    }
+
+    #[test]
+    fn sample_without_online_players() {
+        let clean = |max: i32, sample: serde_json::Value| {
+            let data = json!({
+                "description": "A Minecraft Server",
+                "version": { "name": "1.20.1", "protocol": 763 },
+                "players": { "max": max, "online": 0, "sample": sample }
+            });
+            clean_default(&data).unwrap()
+        };
+
+        let cleaned = clean(
+            0,
+            json!([{ "id": "072ec059-d331-3408-a785-1d9d2e6a9a55", "name": "Notch" }]),
+        );
+        assert_eq!(cleaned.get_bool("sampleWithoutOnline").ok(), Some(true));
+        assert!(!cleaned.contains_key("players.072ec059d3313408a7851d9d2e6a9a55"));
+        assert!(!cleaned.contains_key("lastActive"));
+
+        // a normal empty server
+        let cleaned = clean(0, json!([]));
+        assert!(!cleaned.contains_key("sampleWithoutOnline"));
+        let cleaned = clean(20, json!([]));
+        assert!(!cleaned.contains_key("sampleWithoutOnline"));
+    }
+
+    #[test]
+    fn inconsistent_sample_size() {
+        let clean = |online: i32, sample_size: usize| {
+            let sample = (0..sample_size)
+                .map(|_| json!({ "id": "00000000-0000-0000-0000-000000000000", "name": "Anonymous Player" }))
+                .collect::<Vec<_>>();
+            let data = json!({
+                "description": "A Minecraft Server",
+                "version": { "name": "1.20.1", "protocol": 763 },
+                "players": { "max": 100, "online": online, "sample": sample }
+            });
+            clean_default(&data).unwrap()
+        };
+
+        assert_eq!(clean(3, 50).get_bool("sampleInconsistent").ok(), Some(true));
+        // within the tolerance
+        assert_eq!(clean(3, 5).get_bool("sampleInconsistent").ok(), Some(false));
+        // samples are usually smaller than the online count
+        assert_eq!(
+            clean(50, 12).get_bool("sampleInconsistent").ok(),
+            Some(false)
+        );
+    }
+
+    #[test]
+    fn stores_rtt() {
+        let data = json!({
+            "description": "A Minecraft Server",
+            "version": { "name": "1.20.1", "protocol": 763 },
+            "players": { "max": 20, "online": 0 }
+        });
+        let config = ProcessingConfig {
+            store_rtt: true,
+            ..Default::default()
+        };
+        let clean = |rtt| {
+            clean_response_data(&data, &config, &Filters::default(), rtt, SystemTime::now())
+                .unwrap()
+        };
+
+        let fast = clean(Some(Duration::from_millis(42)));
+        assert_eq!(fast.get_i64("rttMs").ok(), Some(42));
+        assert!(!clean(None).contains_key("rttMs"));
+
+        // a different rtt alone doesn't make the server look changed
+        let slow = clean(Some(Duration::from_millis(300)));
+        assert_eq!(content_hash(&fast).unwrap(), content_hash(&slow).unwrap());
+
+        // it's off by default
+        let cleaned = clean_response_data(
+            &data,
+            &ProcessingConfig::default(),
+            &Filters::default(),
+            Some(Duration::from_millis(42)),
+            SystemTime::now(),
+        )
+        .unwrap();
+        assert!(!cleaned.contains_key("rttMs"));
+    }
+
+    #[test]
+    fn string_version() {
+        let clean = |version: serde_json::Value| {
+            let data = json!({
+                "description": "A Minecraft Server",
+                "version": version,
+                "players": { "max": 20, "online": 0 }
+            });
+            clean_default(&data).unwrap()
+        };
+
+        let object = clean(json!({ "name": "1.20.1", "protocol": 763 }));
+        let string = clean(json!("1.20.1"));
+        assert_eq!(object.get_str("version").ok(), Some("1.20.1"));
+        assert_eq!(string.get_str("version").ok(), Some("1.20.1"));
+        assert_eq!(object.get_i32("protocol").ok(), Some(763));
+        assert_eq!(string.get("protocol"), Some(&Bson::Null));
+        assert_eq!(string.get_bool("protocolUnknown").ok(), Some(true));
+    }
+
+    #[test]
+    fn missing_version() {
+        let clean = |data: serde_json::Value| clean_default(&data).unwrap();
+
+        let missing = clean(json!({
+            "description": "A Minecraft Server",
+            "players": { "max": 20, "online": 0 }
+        }));
+        assert_eq!(missing.get_bool("versionMissing").ok(), Some(true));
+        assert!(!missing.contains_key("version"));
+        assert!(!missing.contains_key("versionNameResolved"));
+        assert!(!missing.contains_key("protocol"));
+
+        let empty_name = clean(json!({
+            "description": "A Minecraft Server",
+            "version": { "name": "", "protocol": 763 },
+            "players": { "max": 20, "online": 0 }
+        }));
+        assert_eq!(empty_name.get_bool("versionMissing").ok(), Some(false));
+        assert_eq!(empty_name.get_str("version").ok(), Some(""));
+        assert_eq!(empty_name.get_i32("protocol").ok(), Some(763));
+    }
+
+    #[test]
+    fn resolves_version_names() {
+        for (name, resolved) in [
+            ("1.20.1", "1.20.1"),
+            ("Paper 1.20.1", "1.20.1"),
+            ("Velocity 3.2.0 1.7.2-1.20.4", "1.20.4"),
+            ("1.20.2-pre1", "1.20.2"),
+            ("23w31a", "snapshot"),
+            ("TCPShield.com", "TCPShield.com"),
+        ] {
+            assert_eq!(resolve_version_name(name), resolved, "{name}");
+        }
+    }
+
+    #[test]
+    fn unknown_protocols() {
+        let clean = |version: serde_json::Value| {
+            let mut data = json!({
+                "description": "A Minecraft Server",
+                "players": { "max": 20, "online": 0 }
+            });
+            if !version.is_null() {
+                data["version"] = version;
+            }
+            let cleaned = clean_default(&data).unwrap();
+            (
+                cleaned.get("protocol").cloned(),
+                cleaned.get_bool("protocolUnknown").unwrap(),
+            )
+        };
+
+        assert_eq!(
+            clean(json!({ "name": "BungeeCord", "protocol": -1 })),
+            (Some(Bson::Null), true)
+        );
+        assert_eq!(clean(json!({ "name": "1.20.1" })), (Some(Bson::Null), true));
+        // there's no protocol to store if the version is missing
+        assert_eq!(clean(json!(null)), (None, true));
+        assert_eq!(
+            clean(json!({ "name": "1.7.2", "protocol": 0 })),
+            (Some(Bson::Int32(0)), false)
+        );
+        assert_eq!(
+            clean(json!({ "name": "1.20.1", "protocol": 763 })),
+            (Some(Bson::Int32(763)), false)
+        );
+    }
+
+    #[tokio::test]
+    async fn reloaded_filters_apply_mid_run() {
+        let (config, database, shared) = test_shared("").await;
+        let process = |port: u16| {
+            let target = SocketAddrV4::new(std::net::Ipv4Addr::new(1, 2, 3, 4), port);
+            let status = br#"{"description":"Welcome to the honeypot","version":{"name":"1.21","protocol":767},"players":{"max":20,"online":0}}"#;
+            protocols::Minecraft::process(&shared, &config, target, status, None, &database)
+        };
+
+        let path = std::env::temp_dir().join(format!(
+            "matscan-reload-filters-{}.toml",
+            std::process::id()
+        ));
+        std::fs::write(
+            &path,
+            r#"
+            [filters]
+            blocked_descriptions = ["honeypot"]
+            "#,
+        )
+        .unwrap();
+
+        // the sighup arrives after the first response in the queue was processed
+        shared.lock().is_processing = true;
+        assert!(matches!(process(25565), ProcessResult::Update(_)));
+        crate::processing::filters::reload(&path, &shared);
+        std::fs::remove_file(&path).unwrap();
+        assert!(matches!(
+            process(25566),
+            ProcessResult::Filtered(FilterReason::PlaceholderDescription)
+        ));
+    }
+
+    #[test]
+    fn min_players_to_store() {
+        let target = SocketAddrV4::new(std::net::Ipv4Addr::new(1, 2, 3, 4), 25565);
+        let set_data = |online_players: i32| {
+            doc! { "onlinePlayers": online_players, "lastEmpty": bson::DateTime::MIN }
+        };
+        let update = |online_players: i32| BulkUpdateBuilder::new().set(set_data(online_players));
+        let upsert = |bulk_update: &BulkUpdate| bulk_update.options.as_ref().unwrap().upsert;
+
+        // a new tiny server isn't inserted, but if it's already in the database then
+        // it still matches the query and gets updated
+        let tiny = build_server_update(&target, update(1), true, Some(5)).unwrap();
+        assert_eq!(upsert(&tiny), Some(false));
+        assert_eq!(tiny.update, doc! { "$set": set_data(1) });
+        assert_eq!(
+            tiny.query.get_document("ip").unwrap().get_str("$eq").ok(),
+            Some("1.2.3.4")
+        );
+
+        let populated = build_server_update(&target, update(5), true, Some(5)).unwrap();
+        assert_eq!(upsert(&populated), Some(true));
+
+        let no_minimum = build_server_update(&target, update(0), true, None).unwrap();
+        assert_eq!(upsert(&no_minimum), Some(true));
+    }
+
+    #[test]
+    fn upsert_can_be_disabled() {
+        let target = SocketAddrV4::new(std::net::Ipv4Addr::new(1, 2, 3, 4), 25565);
+        let update = || BulkUpdateBuilder::new().set(doc! { "onlinePlayers": 10 });
+        let upsert = |bulk_update: &BulkUpdate| bulk_update.options.as_ref().unwrap().upsert;
+
+        // a server that isn't in the database is inserted
+        let upserting = build_server_update(&target, update(), true, None).unwrap();
+        assert_eq!(upsert(&upserting), Some(true));
+
+        // or it doesn't match the query and the response is ignored, even if it
+        // has enough players to be stored
+        let updating = build_server_update(&target, update(), false, Some(5)).unwrap();
+        assert_eq!(upsert(&updating), Some(false));
+        assert_eq!(updating.query, upserting.query);
+        assert_eq!(updating.update, upserting.update);
+    }
+
+    #[test]
+    fn unchanged_server_gets_minimal_update() {
+        let data = json!({
+            "description": "A Minecraft Server",
+            "players": {
+                "max": 20,
+                "online": 1,
+                "sample": [{ "id": "069a79f4-44e9-4726-a5be-fca90e38aaf5", "name": "Notch" }]
+            },
+            "version": { "name": "1.20.1", "protocol": 763 }
+        });
+        let ping = |secs: u64| {
+            let now = SystemTime::UNIX_EPOCH + Duration::from_secs(secs);
+            clean_response_data(
+                &data,
+                &ProcessingConfig::default(),
+                &Filters::default(),
+                None,
+                now,
+            )
+            .unwrap()
+        };
+
+        let mut first = ping(1_700_000_000);
+        let (hash, is_unchanged) = deduplicate_update(&mut first, None).unwrap();
+        assert!(!is_unchanged);
+        assert_eq!(first.get_i64("contentHash").ok(), Some(hash as i64));
+        assert!(first.contains_key("description"));
+
+        // the same response a minute later only updates the timestamps
+        let mut second = ping(1_700_000_060);
+        let (_, is_unchanged) = deduplicate_update(&mut second, Some(hash)).unwrap();
+        assert!(is_unchanged);
+        let last_seen = bson::DateTime::from_system_time(
+            SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_060),
+        );
+        assert_eq!(
+            second,
+            doc! {
+                "updatedAt": last_seen,
+                "players.069a79f444e94726a5befca90e38aaf5.lastSeen": last_seen,
+                "lastSeen": last_seen,
+                "lastActive": last_seen,
+            }
+        );
+    }
+
+    #[test]
+    fn content_hash_ignores_sample_order_and_volatile_fields() {
+        let ping = |sample: serde_json::Value, protocol: i32| {
+            let data = json!({
+                "description": "A Minecraft Server",
+                "players": { "max": 20, "online": 2, "sample": sample },
+                "version": { "name": "1.20.1", "protocol": 763 }
+            });
+            let mut cleaned = clean_default(&data).unwrap();
+            cleaned.insert("respondedToProtocol", protocol);
+            cleaned
+        };
+        let notch = json!({ "id": "069a79f4-44e9-4726-a5be-fca90e38aaf5", "name": "Notch" });
+        let jeb = json!({ "id": "853c80ef-3c37-49fd-aa49-938b674adae6", "name": "jeb_" });
+
+        let mut first = ping(json!([notch, jeb]), 763);
+        let mut second = ping(json!([jeb, notch]), 47);
+        assert_eq!(
+            content_hash(&first).unwrap(),
+            content_hash(&second).unwrap()
+        );
+
+        // the volatile fields are still written when nothing else changed
+        let (hash, _) = deduplicate_update(&mut first, None).unwrap();
+        let (_, is_unchanged) = deduplicate_update(&mut second, Some(hash)).unwrap();
+        assert!(is_unchanged);
+        assert_eq!(second.get_i32("respondedToProtocol").ok(), Some(47));
+        assert!(!second.contains_key("sampleUuids"));
+    }
+
+    #[test]
+    fn favicon_modes() {
+        let favicon = "data:image/png;base64,iVBORw0KGgo=";
+        let cleaned = || doc! { "faviconHash": hash_description(favicon) };
+
+        let mut none = cleaned();
+        assert!(apply_favicon_mode(&mut none, Some(favicon), FaviconMode::None).is_none());
+        assert_eq!(none, cleaned());
+
+        let mut boolean = cleaned();
+        assert!(apply_favicon_mode(&mut boolean, Some(favicon), FaviconMode::Boolean).is_none());
+        assert_eq!(boolean.get_bool("hasFavicon").ok(), Some(true));
+        assert!(!boolean.contains_key("favicon"));
+
+        let mut store = cleaned();
+        let update = apply_favicon_mode(&mut store, Some(favicon), FaviconMode::Store).unwrap();
+        assert_eq!(store.get_bool("hasFavicon").ok(), Some(true));
+        assert!(!store.contains_key("favicon"));
+        assert_eq!(update.query, doc! { "_id": hash_description(favicon) });
+        assert_eq!(
+            update.update.get_document("$setOnInsert").unwrap(),
+            &doc! { "favicon": favicon }
+        );
+
+        // servers without a favicon are left alone in every mode
+        let mut no_favicon = Document::new();
+        assert!(apply_favicon_mode(&mut no_favicon, None, FaviconMode::Store).is_none());
+        assert!(no_favicon.is_empty());
+    }
+
+    #[tokio::test]
+    async fn last_changed_only_bumps_on_change() {
+        let store = MemoryStore::new(vec![]);
+        let ping = |description: &str, secs: i64, previous_content_hash| {
+            let now = bson::DateTime::from_millis(secs * 1000);
+            let mut set_data = doc! { "updatedAt": now, "description": description };
+            let (hash, _) = deduplicate_update(&mut set_data, previous_content_hash).unwrap();
+            let update = set_last_changed(BulkUpdateBuilder::new().set(set_data), hash)
+                .upsert(true)
+                .build(doc! { "ip": { "$eq": "1.2.3.4" }, "port": { "$eq": 25565_u32 } })
+                .unwrap();
+            (hash, update)
+        };
+        let last_changed = || *store.servers()[0].get_datetime("lastChanged").unwrap();
+
+        let (hash, update) = ping("A Minecraft Server", 1, None);
+        store.bulk_update_servers(&[update], false).await.unwrap();
+        assert_eq!(last_changed(), bson::DateTime::from_millis(1000));
+
+        // it's compared to the stored hash, so it doesn't matter whether we
+        // remember the last one
+        for previous_content_hash in [Some(hash), None] {
+            let (_, update) = ping("A Minecraft Server", 2, previous_content_hash);
+            store.bulk_update_servers(&[update], false).await.unwrap();
+            assert_eq!(last_changed(), bson::DateTime::from_millis(1000));
+        }
+
+        let (_, update) = ping("A Rebranded Server", 3, Some(hash));
+        store.bulk_update_servers(&[update], false).await.unwrap();
+        assert_eq!(last_changed(), bson::DateTime::from_millis(3000));
+    }
+
+    #[test]
+    fn sample_uuids_are_only_current_sample() {
+        let ping = |sample: serde_json::Value| {
+            let data = json!({
+                "description": "A Minecraft Server",
+                "players": { "max": 20, "online": 2, "sample": sample },
+                "version": { "name": "1.20.1", "protocol": 763 }
+            });
+            clean_default(&data).unwrap()
+        };
+
+        let first = ping(json!([
+            { "id": "069a79f4-44e9-4726-a5be-fca90e38aaf5", "name": "Notch" },
+            { "id": "853c80ef-3c37-49fd-aa49-938b674adae6", "name": "jeb_" }
+        ]));
+        assert_eq!(
+            first.get_array("sampleUuids").unwrap(),
+            &vec![
+                Bson::String("069a79f444e94726a5befca90e38aaf5".to_string()),
+                Bson::String("853c80ef3c3749fdaa49938b674adae6".to_string()),
+            ]
+        );
+
+        // jeb_ left, so he's not in the array anymore
+        let second = ping(json!([
+            { "id": "069a79f4-44e9-4726-a5be-fca90e38aaf5", "name": "Notch" }
+        ]));
+        assert_eq!(
+            second.get_array("sampleUuids").unwrap(),
+            &vec![Bson::String("069a79f444e94726a5befca90e38aaf5".to_string())]
+        );
+
+        let empty = ping(json!([]));
+        assert_eq!(empty.get_array("sampleUuids").unwrap(), &vec![]);
+    }
+
+    #[test]
+    fn partially_fake_sample() {
+        let ping = |sample: serde_json::Value, strict: bool| {
+            let data = json!({
+                "description": "A Minecraft Server",
+                "players": { "max": 20, "online": 2, "sample": sample },
+                "version": { "name": "1.20.1", "protocol": 763 }
+            });
+            let config = ProcessingConfig {
+                require_players_sample_match: strict,
+                ..Default::default()
+            };
+            clean_with_config(&data, &config).unwrap()
+        };
+        let notch = json!({ "id": "069a79f4-44e9-4726-a5be-fca90e38aaf5", "name": "Notch" });
+        let has_players = |doc: &Document| doc.keys().any(|k| k.starts_with("players."));
+
+        // a player that obviously isn't real means nobody in the sample is stored,
+        // in either mode
+        for strict in [false, true] {
+            let cleaned = ping(json!([notch, { "id": "abc", "name": "Fake" }]), strict);
+            assert!(!has_players(&cleaned));
+            assert!(!cleaned.contains_key("sampleUuids"));
+            assert!(!cleaned.contains_key("isCracked"));
+            assert_eq!(cleaned.get_bool("fakeSample").ok(), strict.then_some(true));
+        }
+
+        // only the strict mode catches uuids with junk around them and anonymous
+        // players that don't have the nil uuid
+        let sample = json!([
+            notch,
+            { "id": "069a79f4-44e9-4726-a5be-fca90e38aaf5ff", "name": "Jeb" },
+            { "id": "11111111-1111-1111-1111-111111111111", "name": "Anonymous Player" }
+        ]);
+        let lenient = ping(sample.clone(), false);
+        assert!(has_players(&lenient));
+        assert!(!lenient.contains_key("fakeSample"));
+        let strict = ping(sample, true);
+        assert!(!has_players(&strict));
+        assert_eq!(strict.get_bool("fakeSample").ok(), Some(true));
+
+        // a normal sample is fine in strict mode
+        let cleaned = ping(
+            json!([notch, { "id": "00000000-0000-0000-0000-000000000000", "name": "Anonymous Player" }]),
+            true,
+        );
+        assert!(has_players(&cleaned));
+        assert!(!cleaned.contains_key("fakeSample"));
+    }
+
+    #[test]
+    fn only_requested_description_formats() {
+        let data = json!({
+            "description": { "text": "A Minecraft Server", "color": "green" },
+            "players": { "max": 20, "online": 0 },
+            "version": { "name": "1.20.1", "protocol": 763 }
+        });
+        let config = ProcessingConfig {
+            description_formats: vec![DescriptionFormat::Ansi, DescriptionFormat::Section],
+            ..Default::default()
+        };
+        let cleaned = clean_with_config(&data, &config).unwrap();
+        assert_eq!(
+            cleaned.get_str("description").ok(),
+            Some("A Minecraft Server")
+        );
+        assert!(cleaned.contains_key("descriptionAnsi"));
+        assert_eq!(
+            cleaned.get_str("descriptionSection").ok(),
+            Some("§aA Minecraft Server")
+        );
+        assert!(!cleaned.contains_key("descriptionNormalized"));
+
+        let cleaned = clean_default(&data).unwrap();
+        for field in [
+            "descriptionAnsi",
+            "descriptionNormalized",
+            "descriptionSection",
+        ] {
+            assert!(!cleaned.contains_key(field));
+        }
+    }
+
+    #[test]
+    fn timestamps_use_given_time() {
+        let now = SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1_700_000_000);
+        let expected = bson::DateTime::from_system_time(now);
+
+        let data = json!({
+            "description": "A Minecraft Server",
+            "players": {
+                "max": 20,
+                "online": 1,
+                "sample": [{ "name": "Notch", "id": "069a79f4-44e9-4726-a5be-fca90e38aaf5" }]
+            },
+            "version": { "name": "1.20.1", "protocol": 763 }
+        });
+        let cleaned = clean_response_data(
+            &data,
+            &ProcessingConfig::default(),
+            &Filters::default(),
+            None,
+            now,
+        )
+        .unwrap();
+
+        assert_eq!(cleaned.get_datetime("updatedAt").ok(), Some(&expected));
+        assert_eq!(cleaned.get_datetime("lastSeen").ok(), Some(&expected));
+        assert_eq!(cleaned.get_datetime("lastActive").ok(), Some(&expected));
+        assert_eq!(
+            cleaned
+                .get_document("players.069a79f444e94726a5befca90e38aaf5")
+                .and_then(|p| p.get_datetime("lastSeen"))
+                .ok(),
+            Some(&expected)
+        );
+
+        let data = json!({
+            "description": "A Minecraft Server",
+            "players": { "max": 20, "online": 0 },
+            "version": { "name": "1.20.1", "protocol": 763 }
+        });
+        let cleaned = clean_response_data(
+            &data,
+            &ProcessingConfig::default(),
+            &Filters::default(),
+            None,
+            now,
+        )
+        .unwrap();
+        assert_eq!(cleaned.get_datetime("lastEmpty").ok(), Some(&expected));
+    }
+
+    #[test]
+    fn hashes_player_uuids() {
+        let data = json!({
+            "description": "A Minecraft Server",
+            "players": {
+                "max": 20,
+                "online": 1,
+                "sample": [{ "name": "Notch", "id": "069a79f4-44e9-4726-a5be-fca90e38aaf5" }]
+            },
+            "version": { "name": "1.20.1", "protocol": 763 }
+        });
+        let config = |key: &str| ProcessingConfig {
+            hash_player_uuids: true,
+            player_uuid_hash_key: Some(key.to_string()),
+            ..Default::default()
+        };
+        let player_keys = |cleaned: &Document| {
+            cleaned
+                .keys()
+                .filter(|k| k.starts_with("players."))
+                .cloned()
+                .collect::<Vec<_>>()
+        };
+
+        let unhashed = clean_default(&data).unwrap();
+        let hashed = clean_with_config(&data, &config("secret")).unwrap();
+        let hashed_again = clean_with_config(&data, &config("secret")).unwrap();
+        let hashed_other_key = clean_with_config(&data, &config("other secret")).unwrap();
+
+        assert_eq!(player_keys(&hashed).len(), 1);
+        assert!(!player_keys(&hashed)[0].contains("069a79f444e94726a5befca90e38aaf5"));
+        assert_eq!(player_keys(&hashed), player_keys(&hashed_again));
+        assert_ne!(player_keys(&hashed), player_keys(&hashed_other_key));
+
+        // online-mode detection is done on the real uuid
+        assert_eq!(hashed.get("isCracked"), unhashed.get("isCracked"));
+        assert!(hashed.get("isCracked").is_some());
+    }
+
+    #[tokio::test]
+    async fn detects_rotating_motd() {
+        let store = MemoryStore::new(vec![]);
+        let ping = |description: &str| {
+            let motd_hash = hash_description(description);
+            BulkUpdateBuilder::new()
+                .push(doc! {
+                    "motdHashes": {
+                        "$each": [motd_hash],
+                        "$slice": -(MOTD_HISTORY_LENGTH as i32),
+                    }
+                })
+                .computed(doc! { "motdRotates": motd_rotates_expression(motd_hash) })
+                .upsert(true)
+                .build(doc! { "ip": { "$eq": "1.2.3.4" }, "port": { "$eq": 25565_u32 } })
+                .unwrap()
+        };
+
+        let mut rotates = Vec::new();
+        for description in ["a", "b", "c", "c", "a", "b", "c"] {
+            store
+                .bulk_update_servers(&[ping(description)], false)
+                .await
+                .unwrap();
+            rotates.push(store.servers()[0].get_bool("motdRotates").unwrap());
+        }
+        assert_eq!(rotates, [false, false, true, false, false, true, true]);
+        // the oldest hash gets dropped
+        assert_eq!(
+            store.servers()[0].get_array("motdHashes").unwrap().len(),
+            MOTD_HISTORY_LENGTH
+        );
+    }
+
+    #[test]
+    fn latest_sighting_wins_regardless_of_order() {
+        let data = json!({
+            "description": "A Minecraft Server",
+            "players": {
+                "max": 20,
+                "online": 1,
+                "sample": [{ "name": "Notch", "id": "069a79f4-44e9-4726-a5be-fca90e38aaf5" }]
+            },
+            "version": { "name": "1.20.1", "protocol": 763 }
+        });
+        let earlier = SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1_700_000_000);
+        let later = earlier + std::time::Duration::from_secs(60);
+        let earlier_server = SocketAddrV4::new([1, 1, 1, 1].into(), 25565);
+        let later_server = SocketAddrV4::new([2, 2, 2, 2].into(), 25565);
+
+        let player_update = |target: &SocketAddrV4, now| {
+            let cleaned = clean_response_data(
+                &data,
+                &ProcessingConfig::default(),
+                &Filters::default(),
+                None,
+                now,
+            )
+            .unwrap();
+            let mut updates = create_player_updates(target, &cleaned);
+            assert_eq!(updates.len(), 1);
+            updates.remove(0)
+        };
+
+        for (target, now) in [(later_server, later), (earlier_server, earlier)] {
+            let update = player_update(&target, now);
+            assert_eq!(
+                update.query,
+                doc! { "_id": "069a79f444e94726a5befca90e38aaf5" }
+            );
+            assert_eq!(
+                update.update,
+                doc! {
+                    "$max": {
+                        "lastServer": {
+                            "lastSeen": bson::DateTime::from(now),
+                            "ip": target.ip().to_string(),
+                            "port": 25565_u32,
+                        }
+                    }
+                }
+            );
+            // documents are compared field by field, so lastSeen has to be first
+            // for $max to keep the latest sighting
+            let last_server = update
+                .update
+                .get_document("$max")
+                .and_then(|max| max.get_document("lastServer"))
+                .unwrap();
+            assert_eq!(
+                last_server.keys().next().map(String::as_str),
+                Some("lastSeen")
+            );
+            assert_eq!(update.options.unwrap().upsert, Some(true));
+        }
+    }
+
+    #[test]
+    fn other_protocols_are_plausible() {
+        // so they make it to process and get counted
+        assert!(protocols::Minecraft::is_plausible(&[0x16, 0x03, 0x01]));
+        assert!(protocols::Minecraft::is_plausible(b"HTTP/1.1 200 OK"));
+        assert!(!protocols::Minecraft::is_plausible(b"SSH-2.0-OpenSSH"));
+    }
+
+    #[test]
+    fn modpack_data() {
+        let cleaned = |modpack_data: serde_json::Value| {
+            let data = json!({
+                "description": "A Minecraft Server",
+                "players": { "max": 20, "online": 0 },
+                "version": { "name": "1.12.2", "protocol": 340 },
+                "modpackData": modpack_data
+            });
+            clean_default(&data).unwrap()
+        };
+
+        let server = cleaned(json!({
+            "projectID": 256289,
+            "name": "All the Mods 3",
+            "version": "5.12.3",
+            "versionID": 2702321,
+            "releaseType": "Release",
+            "isMetadata": true
+        }));
+        assert_eq!(server.get_bool("isModded").ok(), Some(true));
+        assert_eq!(
+            server.get_document("modpack").unwrap(),
+            &doc! { "name": "All the Mods 3", "version": "5.12.3", "projectId": 256289 }
+        );
+
+        let server = cleaned(json!("SkyFactory 4"));
+        assert_eq!(
+            server.get_document("modpack").unwrap(),
+            &doc! { "name": "SkyFactory 4" }
+        );
+
+        let server = cleaned(json!({ "releaseType": "Release" }));
+        assert!(!server.contains_key("modpack"));
+    }
+
+    #[test]
+    fn backend_offline() {
+        let cleaned = |description: &str, online: i32| {
+            let data = json!({
+                "description": description,
+                "players": { "max": 100, "online": online },
+                "version": { "name": "BungeeCord 1.8.x-1.21.x", "protocol": 47 }
+            });
+            clean_default(&data).unwrap()
+        };
+
+        for description in [
+            "§cServer is offline",
+            "Could not connect to a default or fallback server, please try again later",
+            "UNABLE TO CONNECT TO BACKEND SERVER",
+        ] {
+            let server = cleaned(description, 0);
+            assert_eq!(
+                server.get_bool("backendOffline").ok(),
+                Some(true),
+                "{description}"
+            );
+            assert!(!server.contains_key("lastEmpty"));
+        }
+
+        let server = cleaned("A Minecraft Server", 0);
+        assert_eq!(server.get_bool("backendOffline").ok(), Some(false));
+        assert!(server.contains_key("lastEmpty"));
+
+        // players are online so it's clearly up
+        let server = cleaned("Server is offline", 5);
+        assert_eq!(server.get_bool("backendOffline").ok(), Some(false));
+    }
+
+    #[test]
+    fn behind_proxy() {
+        let cleaned = |version_name: &str| {
+            let data = json!({
+                "description": "A Minecraft Server",
+                "players": { "max": 100, "online": 0 },
+                "version": { "name": version_name, "protocol": 767 }
+            });
+            clean_default(&data).unwrap()
+        };
+
+        let server = cleaned("Velocity 3.3.0 1.7.2-1.21");
+        assert_eq!(server.get_bool("behindProxy").ok(), Some(true));
+        assert_eq!(server.get_str("proxyType").ok(), Some("minecraft"));
+
+        // always set so it's cleared if the proxy is removed
+        let server = cleaned("Paper 1.21");
+        assert_eq!(server.get_bool("behindProxy").ok(), Some(false));
+        assert_eq!(server.get("proxyType"), Some(&Bson::Null));
+    }
+}
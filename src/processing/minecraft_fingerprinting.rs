@@ -2,11 +2,11 @@ use std::{
     fmt::Display,
     net::SocketAddrV4,
     sync::{Arc, LazyLock},
-    time::SystemTime,
+    time::{Duration, SystemTime},
 };
 
 use async_trait::async_trait;
-use bson::{doc, Bson};
+use bson::{doc, Bson, Document};
 use parking_lot::Mutex;
 use regex::Regex;
 
@@ -16,7 +16,7 @@ use crate::{
     scanner::protocols,
 };
 
-use super::{ProcessableProtocol, SharedData};
+use super::{ProcessResult, ProcessableProtocol, SharedData};
 
 static VANILLA_ERROR_REGEX: LazyLock<Regex> = LazyLock::new(|| {
     Regex::new(r"java\.io\.IOException: Packet (?:\d+|login)\/\d+ \(([^)]+)\)").unwrap()
@@ -35,13 +35,16 @@ enum ServerType {
 
 #[async_trait]
 impl ProcessableProtocol for protocols::MinecraftFingerprinting {
+    const NAME: &'static str = "minecraft_fingerprinting";
+
     fn process(
         _shared: &Arc<Mutex<SharedData>>,
         _config: &Config,
         target: SocketAddrV4,
         data: &[u8],
+        _rtt: Option<Duration>,
         _database: &Database,
-    ) -> Option<BulkUpdate> {
+    ) -> ProcessResult {
         let data_string = String::from_utf8_lossy(data);
         let server_type = if let Some(packet_name) = VANILLA_ERROR_REGEX
             .captures(&data_string)
@@ -83,12 +86,13 @@ impl ProcessableProtocol for protocols::MinecraftFingerprinting {
             mongo_update.insert("software", server_type.to_string());
         }
 
-        Some(BulkUpdate {
+        ProcessResult::Update(BulkUpdate {
             query: doc! {
                 "ip": { "$eq": target.ip().to_string() },
                 "port": { "$eq": target.port() as u32 }
             },
             update: doc! { "$set": mongo_update },
+            computed: Document::new(),
             options: None,
         })
     }
@@ -0,0 +1,124 @@
+//! Decides whether a server is behind a proxy, from the signs that used to be
+//! checked separately: DDoS mitigation branding, Minecraft proxy version names,
+//! and placeholder descriptions for an offline backend.
+//!
+//! Responses that are just our own request reflected back (which generic TCP
+//! proxies and middleboxes do) are still dropped before they get here, since
+//! there's no status to store the classification with.
+
+/// The kind of proxy that a server is behind, stored in `proxyType`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProxyType {
+    /// A DDoS mitigation service like TCPShield or Cloudflare Spectrum.
+    Mitigation,
+    /// A Minecraft proxy like BungeeCord or Velocity.
+    Minecraft,
+}
+
+impl ProxyType {
+    pub fn name(self) -> &'static str {
+        match self {
+            ProxyType::Mitigation => "mitigation",
+            ProxyType::Minecraft => "minecraft",
+        }
+    }
+}
+
+/// Brands that mitigation services put in the version name or description,
+/// matched case-insensitively.
+const MITIGATION_BRANDS: &[&str] = &[
+    "tcpshield",
+    "cosmic guard",
+    "neoprotect",
+    "cloudflare",
+    "infinity-filter",
+];
+
+/// The version names that Minecraft proxies send when the backend doesn't
+/// override them, matched case-insensitively at the start.
+const MINECRAFT_PROXY_VERSIONS: &[&str] = &[
+    "bungeecord",
+    "waterfall",
+    "flamecord",
+    "travertine",
+    "velocity",
+];
+
+/// What we know about a response that could mean it's from a proxy.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ProxySignals<'a> {
+    pub version_name: &'a str,
+    pub description: &'a str,
+    /// The description is one that proxies show when the server behind them
+    /// is offline.
+    pub backend_offline: bool,
+}
+
+/// The kind of proxy the server is behind, if it looks like it's behind one.
+/// Mitigation services often run a Minecraft proxy themselves, so they win if
+/// there are signs of both.
+pub fn classify(signals: ProxySignals) -> Option<ProxyType> {
+    let version_name = signals.version_name.to_lowercase();
+    let description = signals.description.to_lowercase();
+
+    if MITIGATION_BRANDS
+        .iter()
+        .any(|brand| version_name.contains(brand) || description.contains(brand))
+    {
+        return Some(ProxyType::Mitigation);
+    }
+    if signals.backend_offline
+        || MINECRAFT_PROXY_VERSIONS
+            .iter()
+            .any(|proxy| version_name.starts_with(proxy))
+    {
+        return Some(ProxyType::Minecraft);
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn classifies_proxy_indicators() {
+        let classify_version = |version_name| {
+            classify(ProxySignals {
+                version_name,
+                description: "A Minecraft Server",
+                ..Default::default()
+            })
+        };
+        assert_eq!(classify_version("Paper 1.21"), None);
+        assert_eq!(
+            classify_version("TCPShield.com"),
+            Some(ProxyType::Mitigation)
+        );
+        assert_eq!(
+            classify_version("Velocity 3.3.0 1.7.2-1.21"),
+            Some(ProxyType::Minecraft)
+        );
+        assert_eq!(
+            classify_version("BungeeCord 1.8.x-1.21.x"),
+            Some(ProxyType::Minecraft)
+        );
+
+        assert_eq!(
+            classify(ProxySignals {
+                version_name: "1.21",
+                description: "Protected by Cloudflare Spectrum",
+                ..Default::default()
+            }),
+            Some(ProxyType::Mitigation)
+        );
+        assert_eq!(
+            classify(ProxySignals {
+                version_name: "1.21",
+                description: "Server is offline",
+                backend_offline: true,
+            }),
+            Some(ProxyType::Minecraft)
+        );
+    }
+}
@@ -0,0 +1,60 @@
+//! A bounded map of the last time we saw something from each server, which
+//! the response deduplicator and the webhook cooldowns are both built on.
+
+use std::{
+    net::SocketAddrV4,
+    time::{Duration, Instant},
+};
+
+use lru_cache::LruCache;
+
+/// The most servers that are remembered. Older ones are forgotten first.
+const CAPACITY: usize = 65536;
+
+pub struct RecentServers<V> {
+    window: Duration,
+    /// The last value we saw from each server and when we saw it.
+    recent: LruCache<SocketAddrV4, (V, Instant)>,
+}
+
+impl<V: PartialEq> RecentServers<V> {
+    pub fn new(window: Duration) -> Self {
+        Self::with_capacity(window, CAPACITY)
+    }
+
+    pub fn with_capacity(window: Duration, capacity: usize) -> Self {
+        Self {
+            window,
+            recent: LruCache::new(capacity),
+        }
+    }
+
+    /// Whether we saw the same value from the server within the window. If we
+    /// didn't, the window for the server starts again with this value.
+    pub fn seen_recently(&mut self, target: SocketAddrV4, value: V, now: Instant) -> bool {
+        if let Some((previous, seen_at)) = self.recent.get_mut(&target) {
+            if *previous == value && now.duration_since(*seen_at) < self.window {
+                return true;
+            }
+        }
+        self.recent.insert(target, (value, now));
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    #[test]
+    fn memory_is_bounded() {
+        let mut recent = RecentServers::with_capacity(Duration::from_secs(5), 2);
+        let now = Instant::now();
+        for port in 0..10 {
+            recent.seen_recently(SocketAddrV4::new(Ipv4Addr::LOCALHOST, port), (), now);
+        }
+        assert_eq!(recent.recent.len(), 2);
+    }
+}
@@ -0,0 +1,77 @@
+//! Keeps servers from sending a webhook notification every time they're
+//! scanned, like when a server keeps going in and out of being revived.
+
+use std::{
+    collections::HashMap,
+    net::SocketAddrV4,
+    time::{Duration, Instant},
+};
+
+use super::recent::RecentServers;
+
+pub struct WebhookCooldowns {
+    /// When we last sent a notification for each server. Servers that are
+    /// forgotten to make room for others can notify again early.
+    last_sent: RecentServers<()>,
+}
+
+impl WebhookCooldowns {
+    pub fn new(cooldown: Duration) -> Self {
+        Self {
+            last_sent: RecentServers::new(cooldown),
+        }
+    }
+
+    /// Whether a notification for the server should be sent now. If it
+    /// should, the cooldown for the server starts again.
+    pub fn should_notify(&mut self, target: SocketAddrV4, now: Instant) -> bool {
+        !self.last_sent.seen_recently(target, (), now)
+    }
+}
+
+/// Post the message to the webhook. Nothing is sent if the URL is empty, since
+/// that means there's no webhook configured.
+pub async fn send_to_webhook(webhook_url: String, message: String) {
+    if webhook_url.is_empty() {
+        return;
+    }
+    let client = reqwest::Client::new();
+    if let Err(e) = client
+        .post(webhook_url)
+        .json(
+            &vec![("content".to_string(), message.to_string())]
+                .into_iter()
+                .collect::<HashMap<String, String>>(),
+        )
+        .send()
+        .await
+    {
+        println!("{}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    #[test]
+    fn one_notification_per_cooldown() {
+        let mut cooldowns = WebhookCooldowns::new(Duration::from_secs(300));
+        let target = SocketAddrV4::new(Ipv4Addr::new(1, 2, 3, 4), 25565);
+        let other = SocketAddrV4::new(Ipv4Addr::new(1, 2, 3, 4), 25566);
+        let start = Instant::now();
+
+        // the server is found on every rescan
+        let sent = (0..10)
+            .filter(|&i| cooldowns.should_notify(target, start + Duration::from_secs(i * 20)))
+            .count();
+        assert_eq!(sent, 1);
+
+        // other servers have their own cooldown
+        assert!(cooldowns.should_notify(other, start));
+        // and it can notify again once the cooldown is over
+        assert!(cooldowns.should_notify(target, start + Duration::from_secs(300)));
+    }
+}
@@ -15,6 +15,7 @@ use std::{
     time::{Duration, Instant},
 };
 
+use lru_cache::LruCache;
 use parking_lot::{Mutex, RwLock};
 use perfect_rand::PerfectRng;
 use pnet::packet::tcp::TcpFlags;
@@ -37,6 +38,9 @@ pub struct Scanner {
     pub seed: u64,
     pub client: StatelessTcp,
     pub conns: HashMap<SocketAddrV4, ConnState>,
+    /// When we sent the ping to each server that hasn't responded yet, so we
+    /// can tell how long it took to respond.
+    pub pings_sent: LruCache<SocketAddrV4, Instant>,
 }
 
 pub struct ActiveFingerprintingData {
@@ -58,9 +62,17 @@ impl Scanner {
             seed,
             client,
             conns: HashMap::<SocketAddrV4, ConnState>::new(),
+            // arbitrary capacity (2^20)
+            pings_sent: LruCache::new(1048576),
         }
     }
 
+    /// Get how long it's been since we sent the ping to the server, and forget
+    /// that we sent it.
+    fn take_rtt(&mut self, address: SocketAddrV4) -> Option<Duration> {
+        self.pings_sent.remove(&address).map(|sent| sent.elapsed())
+    }
+
     pub fn purge_old_conns(&mut self, ping_timeout: Duration) {
         let now = Instant::now();
         let mut to_delete = Vec::new();
@@ -112,7 +124,7 @@ impl ScannerReceiver {
                             self.shared_process_data
                                 .lock()
                                 .queue
-                                .push_back((address, data));
+                                .push_back((address, data, None));
                         }
                     }
 
@@ -145,7 +157,7 @@ impl ScannerReceiver {
                                 self.shared_process_data
                                     .lock()
                                     .queue
-                                    .push_back((address, data));
+                                    .push_back((address, data, conn.rtt));
                             }
                         } else {
                             trace!("FIN {}:{}", ipv4.source, tcp.source);
@@ -207,6 +219,7 @@ impl ScannerReceiver {
                         tcp.sequence + 1,
                         &payload,
                     );
+                    self.scanner.pings_sent.insert(address, Instant::now());
 
                     syn_acks_received += 1;
                     trace!("syn acks: {syn_acks_received}");
@@ -281,6 +294,7 @@ impl ScannerReceiver {
                             trace!("\n\n{address} {data_string}");
 
                             if !is_tracked {
+                                let rtt = self.scanner.take_rtt(address);
                                 self.scanner.conns.borrow_mut().insert(
                                     address,
                                     ConnState {
@@ -291,6 +305,7 @@ impl ScannerReceiver {
                                         local_seq: tcp.acknowledgement,
                                         started: Instant::now(),
                                         fin_sent: false,
+                                        rtt,
                                     },
                                 );
                                 connections_started += 1;
@@ -302,7 +317,7 @@ impl ScannerReceiver {
                             self.shared_process_data
                                 .lock()
                                 .queue
-                                .push_back((address, data));
+                                .push_back((address, data, conn.rtt));
 
                             self.scanner.client.write.send_ack(
                                 address,
@@ -329,6 +344,7 @@ impl ScannerReceiver {
                                 }
                                 ParseResponseError::Incomplete { .. } => {
                                     if !is_tracked {
+                                        let rtt = self.scanner.take_rtt(address);
                                         self.scanner.conns.borrow_mut().insert(
                                             address,
                                             ConnState {
@@ -339,6 +355,7 @@ impl ScannerReceiver {
                                                 local_seq: tcp.acknowledgement,
                                                 started: Instant::now(),
                                                 fin_sent: false,
+                                                rtt,
                                             },
                                         );
                                         connections_started += 1;
@@ -403,6 +420,10 @@ pub struct ConnState {
 
     /// Whether we've sent a fin packet.
     fin_sent: bool,
+
+    /// How long it took from sending the ping to getting the first data
+    /// packet back, if we know when the ping was sent.
+    rtt: Option<Duration>,
 }
 
 pub struct PingResponse {
@@ -3,7 +3,10 @@ mod minecraft_fingerprinting;
 
 use std::net::SocketAddrV4;
 
-pub use minecraft::Minecraft;
+pub use minecraft::{
+    build_latest_request, pick_protocol_version, Minecraft, OtherProtocol,
+    DEFAULT_PROTOCOL_VERSION, GZIP_MAGIC,
+};
 pub use minecraft_fingerprinting::MinecraftFingerprinting;
 
 #[derive(Debug)]
@@ -1,25 +1,98 @@
 use std::{
+    collections::hash_map::DefaultHasher,
+    hash::{Hash, Hasher},
     io::{Cursor, Read, Write},
     net::SocketAddrV4,
 };
 
 use super::{ParseResponseError, Protocol, Response};
 
+/// The protocol version servers are pinged with if none are given.
+pub const DEFAULT_PROTOCOL_VERSION: i32 = 47;
+
 #[derive(Clone)]
 pub struct Minecraft {
-    minecraft_request: Vec<u8>,
+    protocol_versions: Vec<i32>,
+    /// The request for each protocol version, in the same order as
+    /// `protocol_versions`.
+    minecraft_requests: Vec<Vec<u8>>,
+    rotation: u64,
+    /// Responses that say they're longer than this are dropped before the rest
+    /// of them is buffered.
+    max_response_bytes: usize,
 }
 
 impl Minecraft {
-    pub fn new(hostname: &str, port: u16, protocol_version: i32) -> Self {
-        let minecraft_request = build_latest_request(hostname, port, protocol_version);
-        Self { minecraft_request }
+    /// Create a protocol that pings servers with one of the given protocol
+    /// versions. Some servers only respond to certain versions, so each server
+    /// gets a different one every time the rotation changes. If there aren't
+    /// any, [`DEFAULT_PROTOCOL_VERSION`] is used.
+    pub fn new(hostname: &str, port: u16, protocol_versions: &[i32]) -> Self {
+        let protocol_versions = if protocol_versions.is_empty() {
+            vec![DEFAULT_PROTOCOL_VERSION]
+        } else {
+            protocol_versions.to_vec()
+        };
+        let minecraft_requests = protocol_versions
+            .iter()
+            .map(|&protocol_version| build_latest_request(hostname, port, protocol_version))
+            .collect();
+        Self {
+            protocol_versions,
+            minecraft_requests,
+            rotation: 0,
+            max_response_bytes: usize::MAX,
+        }
+    }
+
+    /// Drop responses that are longer than this instead of waiting for the
+    /// rest of them, so a server can't make us buffer a huge status. There's
+    /// no limit by default.
+    pub fn with_max_response_bytes(self, max_response_bytes: usize) -> Self {
+        Self {
+            max_response_bytes,
+            ..self
+        }
+    }
+
+    /// Return a copy of this protocol that uses the given rotation for picking
+    /// protocol versions. This should be the same value as
+    /// [`SharedData::handshake_rotation`] so the processing task knows which
+    /// version a server responded to.
+    ///
+    /// [`SharedData::handshake_rotation`]: crate::processing::SharedData::handshake_rotation
+    pub fn with_rotation(&self, rotation: u64) -> Self {
+        Self {
+            rotation,
+            ..self.clone()
+        }
+    }
+}
+
+/// Pick which of the protocol versions we should send to the given address.
+/// Every address starts at a different version and advances by one each time
+/// the rotation is incremented.
+pub fn pick_protocol_version(
+    protocol_versions: &[i32],
+    address: SocketAddrV4,
+    rotation: u64,
+) -> i32 {
+    protocol_versions[pick_index(protocol_versions.len(), address, rotation)]
+}
+
+fn pick_index(len: usize, address: SocketAddrV4, rotation: u64) -> usize {
+    if len == 1 {
+        return 0;
     }
+    let mut hasher = DefaultHasher::new();
+    address.hash(&mut hasher);
+    (hasher.finish().wrapping_add(rotation) % len as u64) as usize
 }
 
 impl Protocol for Minecraft {
-    fn payload(&self, _address: SocketAddrV4) -> Vec<u8> {
-        self.minecraft_request.clone()
+    fn payload(&self, address: SocketAddrV4) -> Vec<u8> {
+        let index = pick_index(self.protocol_versions.len(), address, self.rotation);
+        self.minecraft_requests[index].clone()
     }
 
     fn parse_response(&self, response: Response) -> Result<Vec<u8>, ParseResponseError> {
@@ -28,6 +101,14 @@ impl Protocol for Minecraft {
             Response::Rst => return Err(ParseResponseError::Invalid),
         };
 
+        if OtherProtocol::detect(&response).is_some() {
+            // passed on as-is so the processing task can count them
+            return Ok(response);
+        }
+        if is_reflected_request(&response, &self.minecraft_requests) {
+            return Err(ParseResponseError::Invalid);
+        }
+
         // ignore the packet length
         let mut stream = Cursor::new(response);
         read_varint(&mut stream).ok_or(ParseResponseError::Invalid)?;
@@ -36,6 +117,9 @@ impl Protocol for Minecraft {
         if packet_id != 0x00 || response_length < 0 {
             return Err(ParseResponseError::Invalid);
         }
+        if response_length as usize > self.max_response_bytes {
+            return Err(ParseResponseError::Invalid);
+        }
         // read until end
         let position = stream.position() as usize;
         let status_buffer = &stream.into_inner()[position..];
@@ -45,14 +129,55 @@ impl Protocol for Minecraft {
             });
         }
 
-        let status_string = String::from_utf8_lossy(status_buffer).to_string();
+        // it's decompressed when it's processed
+        if status_buffer.starts_with(&GZIP_MAGIC) {
+            return Ok(status_buffer.to_vec());
+        }
 
         // make sure it starts with {
-        if !status_string.starts_with('{') {
+        if status_buffer.first() != Some(&b'{') {
             return Err(ParseResponseError::Invalid);
         }
 
-        Ok(status_string.into_bytes())
+        // the bytes are passed on as they are, so the processing task can tell if
+        // they're not valid utf-8
+        Ok(status_buffer.to_vec())
+    }
+}
+
+/// Whether the data is (most of) one of the requests we sent, which happens
+/// when a misconfigured middlebox reflects our packets back at us.
+fn is_reflected_request(data: &[u8], requests: &[Vec<u8>]) -> bool {
+    requests.iter().any(|request| {
+        // the whole request with some junk around it
+        (data.len() >= request.len() && data.windows(request.len()).any(|w| w == request))
+            // or a truncated copy of it
+            || (data.len() * 2 >= request.len()
+                && data.len() < request.len()
+                && request.windows(data.len()).any(|w| w == data))
+    })
+}
+
+/// The first bytes of gzipped data. Some proxies gzip the status for some
+/// reason.
+pub const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+/// A protocol other than Minecraft that some servers respond with, usually
+/// because they're honeypots or reverse proxies.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum OtherProtocol {
+    Tls,
+    Http,
+}
+
+impl OtherProtocol {
+    pub fn detect(data: &[u8]) -> Option<Self> {
+        match data {
+            // a handshake or alert record
+            [0x16 | 0x15, 0x03, ..] => Some(OtherProtocol::Tls),
+            _ if data.starts_with(b"HTTP/1.") => Some(OtherProtocol::Http),
+            _ => None,
+        }
     }
 }
 
@@ -119,3 +244,168 @@ fn read_varint(reader: &mut (dyn Read + Unpin + Send)) -> Option<i32> {
     }
     Some(ans)
 }
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    /// Read the protocol version back out of a request built by
+    /// [`build_latest_request`].
+    fn sent_protocol_version(request: &[u8]) -> i32 {
+        let mut stream = Cursor::new(request);
+        // packet length
+        read_varint(&mut stream).unwrap();
+        let packet_id = read_varint(&mut stream).unwrap();
+        assert_eq!(packet_id, 0x00);
+        read_varint(&mut stream).unwrap()
+    }
+
+    #[test]
+    fn picked_version_is_the_one_sent() {
+        let protocol_versions = [47, 767, 4];
+        let minecraft = Minecraft::new("localhost", 25565, &protocol_versions);
+        let address = SocketAddrV4::new(Ipv4Addr::new(1, 2, 3, 4), 25565);
+
+        let mut sent = Vec::new();
+        for rotation in 0..protocol_versions.len() as u64 {
+            let payload = minecraft.with_rotation(rotation).payload(address);
+            let version = sent_protocol_version(&payload);
+            assert_eq!(
+                pick_protocol_version(&protocol_versions, address, rotation),
+                version
+            );
+            sent.push(version);
+        }
+
+        // every version gets tried once
+        sent.sort();
+        assert_eq!(sent, vec![4, 47, 767]);
+    }
+
+    #[test]
+    fn tls_and_http_are_passed_on() {
+        let minecraft = Minecraft::new("localhost", 25565, &[47]);
+
+        let tls = vec![0x16, 0x03, 0x01, 0x00, 0x2a, 0x02];
+        assert_eq!(OtherProtocol::detect(&tls), Some(OtherProtocol::Tls));
+        assert_eq!(
+            minecraft
+                .parse_response(Response::Data(tls.clone()))
+                .unwrap(),
+            tls
+        );
+
+        let http = b"HTTP/1.1 400 Bad Request\r\n\r\n".to_vec();
+        assert_eq!(OtherProtocol::detect(&http), Some(OtherProtocol::Http));
+        assert_eq!(
+            minecraft
+                .parse_response(Response::Data(http.clone()))
+                .unwrap(),
+            http
+        );
+
+        assert_eq!(OtherProtocol::detect(br#"{"description":""}"#), None);
+    }
+
+    /// A status response packet with the given length, which doesn't have to
+    /// be the length of the status.
+    fn status_packet(status_length: i32, status: &[u8]) -> Vec<u8> {
+        let mut packet = vec![0x00];
+        write_varint(&mut packet, status_length);
+        packet.extend_from_slice(status);
+        let mut framed = Vec::new();
+        write_varint(&mut framed, packet.len() as i32);
+        framed.extend(packet);
+        framed
+    }
+
+    #[test]
+    fn reflected_requests_are_dropped() {
+        let minecraft = Minecraft::new("127.0.0.1", 25565, &[763, 767]);
+        let address = SocketAddrV4::new(Ipv4Addr::new(1, 2, 3, 4), 25565);
+        let is_dropped = |data: Vec<u8>| {
+            matches!(
+                minecraft.parse_response(Response::Data(data)),
+                Err(ParseResponseError::Invalid)
+            )
+        };
+
+        let request = minecraft.payload(address);
+        assert!(is_dropped(request.clone()));
+        // a reflection with extra bytes after it
+        let mut reflected = request.clone();
+        reflected.extend_from_slice(&[0x01, 0x00]);
+        assert!(is_dropped(reflected));
+        // or only part of it
+        assert!(is_dropped(request[..request.len() - 2].to_vec()));
+        // the request for the other version is still ours
+        assert!(is_dropped(minecraft.with_rotation(1).payload(address)));
+
+        let status = br#"{"description":"A Minecraft Server"}"#;
+        assert!(!is_dropped(status_packet(status.len() as i32, status)));
+    }
+
+    #[test]
+    fn no_protocol_versions_uses_the_default() {
+        let minecraft = Minecraft::new("localhost", 25565, &[]);
+        let address = SocketAddrV4::new(Ipv4Addr::new(1, 2, 3, 4), 25565);
+        assert_eq!(
+            sent_protocol_version(&minecraft.payload(address)),
+            DEFAULT_PROTOCOL_VERSION
+        );
+    }
+
+    #[test]
+    fn invalid_utf8_is_passed_on_as_is() {
+        let minecraft = Minecraft::new("localhost", 25565, &[47]);
+        let status = b"{\"description\":\"\xff\xfe\"}";
+        let packet = status_packet(status.len() as i32, status);
+        assert_eq!(
+            minecraft.parse_response(Response::Data(packet)).unwrap(),
+            status
+        );
+    }
+
+    #[test]
+    fn gzipped_statuses_are_passed_on() {
+        let minecraft = Minecraft::new("localhost", 25565, &[47]);
+
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(br#"{"description":""}"#).unwrap();
+        let compressed = encoder.finish().unwrap();
+
+        let packet = status_packet(compressed.len() as i32, &compressed);
+        assert_eq!(
+            minecraft.parse_response(Response::Data(packet)).unwrap(),
+            compressed
+        );
+    }
+
+    #[test]
+    fn oversized_responses_are_dropped_before_buffering() {
+        let minecraft = Minecraft::new("localhost", 25565, &[47]).with_max_response_bytes(1024);
+
+        // the rest of this would've been waited for
+        let huge = status_packet(10 * 1024 * 1024, b"{\"description\":\"");
+        assert!(matches!(
+            minecraft.parse_response(Response::Data(huge)),
+            Err(ParseResponseError::Invalid)
+        ));
+
+        let status = br#"{"description":""}"#;
+        let small = status_packet(status.len() as i32, status);
+        assert_eq!(
+            minecraft.parse_response(Response::Data(small)).unwrap(),
+            status
+        );
+        let partial = status_packet(1000, status);
+        assert!(matches!(
+            minecraft.parse_response(Response::Data(partial)),
+            Err(ParseResponseError::Incomplete {
+                expected_length: 1000
+            })
+        ));
+    }
+}
@@ -1,6 +1,9 @@
 use std::{
+    fmt::{self, Display},
     mem,
     net::{Ipv4Addr, SocketAddrV4},
+    sync::atomic::{AtomicUsize, Ordering},
+    time::{Duration, Instant},
 };
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -59,6 +62,34 @@ impl ScanRange {
             port_end,
         }
     }
+
+    /// Every address in `addr/prefix` on the given port. The host bits of
+    /// `addr` are ignored, so `10.0.0.5/30` is the same as `10.0.0.4/30`.
+    /// Returns `None` if the prefix is more than 32.
+    pub fn cidr(addr: Ipv4Addr, prefix: u8, port: u16) -> Option<Self> {
+        Self::cidr_ports(addr, prefix, port, port)
+    }
+    /// Like [`Self::cidr`], but on every port from `port_start` to `port_end`.
+    /// Returns `None` if the ports are backwards.
+    pub fn cidr_ports(addr: Ipv4Addr, prefix: u8, port_start: u16, port_end: u16) -> Option<Self> {
+        if prefix > 32 || port_start > port_end {
+            return None;
+        }
+        let mask_bits = (u64::pow(2, 32 - prefix as u32) - 1) as u32;
+        let addr = u32::from(addr);
+        Some(Self {
+            addr_start: Ipv4Addr::from(addr & !mask_bits),
+            addr_end: Ipv4Addr::from(addr | mask_bits),
+            port_start,
+            port_end,
+        })
+    }
+
+    /// Every address and port in this range, in the same order as
+    /// [`Self::index`].
+    pub fn iter(&self) -> impl Iterator<Item = SocketAddrV4> + '_ {
+        (0..self.count()).map(|i| self.index(i))
+    }
 }
 
 #[derive(Default, Clone, Debug, PartialEq)]
@@ -252,6 +283,86 @@ impl StaticScanRanges {
     }
 }
 
+/// Tracks how far along we are in generating a large list of ranges, so the
+/// caller can log progress or an ETA. It only uses atomics so it can be shared
+/// with another thread that renders it.
+#[derive(Debug)]
+pub struct RangeProgress {
+    /// The number of ranges we expect to emit, or 0 if we don't know.
+    total: AtomicUsize,
+    emitted: AtomicUsize,
+    addresses: AtomicUsize,
+    started: Instant,
+}
+
+impl RangeProgress {
+    pub fn new(total: Option<usize>) -> Self {
+        Self {
+            total: AtomicUsize::new(total.unwrap_or_default()),
+            emitted: AtomicUsize::new(0),
+            addresses: AtomicUsize::new(0),
+            started: Instant::now(),
+        }
+    }
+
+    pub fn set_total(&self, total: usize) {
+        self.total.store(total, Ordering::Relaxed);
+    }
+
+    /// Record that we've emitted a range.
+    pub fn record(&self, range: &ScanRange) {
+        self.emitted.fetch_add(1, Ordering::Relaxed);
+        self.addresses.fetch_add(range.count_addresses(), Ordering::Relaxed);
+    }
+
+    pub fn total(&self) -> Option<usize> {
+        match self.total.load(Ordering::Relaxed) {
+            0 => None,
+            total => Some(total),
+        }
+    }
+    pub fn emitted(&self) -> usize {
+        self.emitted.load(Ordering::Relaxed)
+    }
+    /// The number of addresses in all of the ranges that were emitted so far.
+    pub fn addresses(&self) -> usize {
+        self.addresses.load(Ordering::Relaxed)
+    }
+
+    /// The estimated time until all the ranges are emitted, based on the rate
+    /// so far. None if we don't know the total or haven't emitted anything.
+    pub fn eta(&self) -> Option<Duration> {
+        let total = self.total()?;
+        let emitted = self.emitted();
+        if emitted == 0 {
+            return None;
+        }
+        let remaining = total.saturating_sub(emitted);
+        Some(self.started.elapsed().mul_f64(remaining as f64 / emitted as f64))
+    }
+}
+
+impl Default for RangeProgress {
+    fn default() -> Self {
+        Self::new(None)
+    }
+}
+
+impl Display for RangeProgress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let emitted = self.emitted();
+        let addresses = self.addresses();
+        match (self.total(), self.eta()) {
+            (Some(total), Some(eta)) => write!(
+                f,
+                "{emitted}/{total} ranges ({addresses} addresses), eta {}s",
+                eta.as_secs()
+            ),
+            _ => write!(f, "{emitted} ranges ({addresses} addresses)"),
+        }
+    }
+}
+
 #[derive(Debug, PartialEq, Eq, Copy, Clone)]
 pub struct Ipv4Range {
     pub start: Ipv4Addr,
@@ -316,6 +427,44 @@ impl Ipv4Ranges {
 mod test {
     use super::*;
 
+    #[test]
+    fn cidr_ranges() {
+        let range = ScanRange::cidr(Ipv4Addr::new(10, 0, 0, 0), 30, 25565).unwrap();
+        assert_eq!(
+            range.iter().collect::<Vec<_>>(),
+            [0, 1, 2, 3].map(|i| SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, i), 25565))
+        );
+
+        // the host bits are masked
+        assert_eq!(
+            ScanRange::cidr(Ipv4Addr::new(10, 0, 0, 6), 30, 25565),
+            Some(ScanRange::single_port(
+                Ipv4Addr::new(10, 0, 0, 4),
+                Ipv4Addr::new(10, 0, 0, 7),
+                25565
+            ))
+        );
+        assert_eq!(
+            ScanRange::cidr(Ipv4Addr::new(1, 2, 3, 4), 0, 25565)
+                .unwrap()
+                .count_addresses(),
+            1 << 32
+        );
+        assert_eq!(
+            ScanRange::cidr_ports(Ipv4Addr::new(10, 0, 0, 1), 32, 25565, 25566)
+                .unwrap()
+                .iter()
+                .collect::<Vec<_>>(),
+            [25565, 25566].map(|port| SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 1), port))
+        );
+
+        assert_eq!(ScanRange::cidr(Ipv4Addr::new(10, 0, 0, 0), 33, 25565), None);
+        assert_eq!(
+            ScanRange::cidr_ports(Ipv4Addr::new(10, 0, 0, 0), 24, 25566, 25565),
+            None
+        );
+    }
+
     #[test]
     fn test_subtract_center() {
         let mut ranges = ScanRanges::new();
@@ -472,6 +621,26 @@ mod test {
         assert_eq!(range.count(), 4294967296);
     }
 
+    #[test]
+    fn range_progress_counts() {
+        let fixture = vec![
+            ScanRange::single(Ipv4Addr::new(1, 2, 3, 4), 25565),
+            ScanRange::single_port(Ipv4Addr::new(1, 2, 3, 0), Ipv4Addr::new(1, 2, 3, 255), 25565),
+            ScanRange::single_address(Ipv4Addr::new(5, 6, 7, 8), 25565, 25575),
+        ];
+
+        let progress = RangeProgress::new(Some(fixture.len()));
+        assert_eq!(progress.eta(), None);
+        for range in &fixture {
+            progress.record(range);
+        }
+
+        assert_eq!(progress.total(), Some(3));
+        assert_eq!(progress.emitted(), 3);
+        assert_eq!(progress.addresses(), 1 + 256 + 1);
+        assert!(progress.eta().is_some());
+    }
+
     #[test]
     fn contains_but_is_empty() {
         let ranges = Ipv4Ranges::new(vec![]);
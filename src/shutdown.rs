@@ -0,0 +1,59 @@
+//! A token for telling long-running tasks that matscan is shutting down, so
+//! they can stop starting new work.
+
+use tokio::sync::watch;
+use tracing::info;
+
+#[derive(Clone)]
+pub struct ShutdownToken {
+    receiver: watch::Receiver<bool>,
+}
+
+/// The other half of a [`ShutdownToken`], which cancels it.
+pub struct ShutdownTrigger {
+    sender: watch::Sender<bool>,
+}
+
+impl ShutdownToken {
+    pub fn new() -> (ShutdownTrigger, Self) {
+        let (sender, receiver) = watch::channel(false);
+        (ShutdownTrigger { sender }, Self { receiver })
+    }
+
+    /// A token that's cancelled the first time we get a ctrl+c. The second one
+    /// exits immediately, in case whatever we're waiting on is stuck.
+    pub fn on_ctrl_c() -> Self {
+        let (trigger, token) = Self::new();
+        tokio::spawn(async move {
+            if tokio::signal::ctrl_c().await.is_err() {
+                return;
+            }
+            println!("shutting down after this scan, press ctrl+c again to exit now");
+            info!("Got ctrl+c, shutting down");
+            trigger.cancel();
+            if tokio::signal::ctrl_c().await.is_ok() {
+                std::process::exit(130);
+            }
+        });
+        token
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        *self.receiver.borrow()
+    }
+
+    /// Wait until the token is cancelled. If the trigger was dropped without
+    /// cancelling, this never returns.
+    pub async fn cancelled(&self) {
+        let mut receiver = self.receiver.clone();
+        if receiver.wait_for(|&cancelled| cancelled).await.is_err() {
+            std::future::pending::<()>().await;
+        }
+    }
+}
+
+impl ShutdownTrigger {
+    pub fn cancel(&self) {
+        self.sender.send_replace(true);
+    }
+}